@@ -0,0 +1,159 @@
+#[cfg(windows)]
+#[macro_use]
+extern crate criterion;
+#[cfg(windows)]
+extern crate hwndloop;
+#[cfg(windows)]
+extern crate winapi;
+
+#[cfg(windows)]
+mod bench {
+  use hwndloop::*;
+
+  use std::sync::mpsc::{channel, Sender};
+  use std::sync::Arc;
+  use std::time::{Duration, Instant};
+
+  use criterion::{BenchmarkId, Criterion, Throughput};
+
+  use winapi::shared::minwindef::{FALSE, LPARAM, LRESULT, UINT, WPARAM};
+  use winapi::shared::windef::HWND;
+  use winapi::um::winuser::{DefWindowProcW, PostMessageW, WM_USER};
+
+  #[derive(Debug)]
+  enum BenchCommand {
+    Nop,
+    Stamp(Instant, Sender<Duration>),
+    GetHWND(Sender<HwndWrapper>),
+  }
+
+  struct Bench;
+
+  impl HwndLoopCallbacks<BenchCommand> for Bench {
+    fn handle_message(&mut self, hwnd: HWND, msg: UINT, w: WPARAM, l: LPARAM) -> LRESULT {
+      unsafe { DefWindowProcW(hwnd, msg, w, l) }
+    }
+
+    fn handle_command(&mut self, hwnd: HWND, cmd: BenchCommand) {
+      match cmd {
+        BenchCommand::Nop => {}
+        BenchCommand::Stamp(sent, tx) => tx.send(sent.elapsed()).unwrap(),
+        BenchCommand::GetHWND(tx) => tx.send(HwndWrapper::new(hwnd)).unwrap(),
+      }
+    }
+  }
+
+  fn get_hwnd(hwndloop: &HwndLoop<BenchCommand>) -> HwndWrapper {
+    let (tx, rx) = channel();
+    hwndloop.send_command(BenchCommand::GetHWND(tx)).unwrap();
+    rx.recv().unwrap()
+  }
+
+  fn post_user(hwnd: &HwndWrapper, i: usize) {
+    assert_ne!(FALSE, unsafe { PostMessageW(hwnd.0, WM_USER, i as WPARAM, 0) });
+  }
+
+  /// Commands per second, with and without window messages interleaved as in the `ordering` test.
+  pub fn throughput(c: &mut Criterion) {
+    let hwndloop = HwndLoop::new(Box::new(Bench));
+    let hwnd = get_hwnd(&hwndloop);
+
+    let mut group = c.benchmark_group("throughput");
+    for &count in &[100usize, 1000, 5000] {
+      group.throughput(Throughput::Elements(count as u64));
+      group.bench_with_input(BenchmarkId::new("commands", count), &count, |b, &count| {
+        b.iter(|| {
+          for _ in 0..count {
+            hwndloop.send_command(BenchCommand::Nop).unwrap();
+          }
+          hwndloop.flush().unwrap();
+        })
+      });
+      group.bench_with_input(BenchmarkId::new("interleaved", count), &count, |b, &count| {
+        b.iter(|| {
+          for i in 0..count {
+            if i % 2 == 0 {
+              hwndloop.send_command(BenchCommand::Nop).unwrap();
+            } else {
+              post_user(&hwnd, i);
+            }
+          }
+          hwndloop.flush().unwrap();
+        })
+      });
+    }
+    group.finish();
+  }
+
+  /// Time from `send_command` until `handle_command` runs on the handler thread.
+  pub fn latency(c: &mut Criterion) {
+    let hwndloop = HwndLoop::new(Box::new(Bench));
+    let hwnd = get_hwnd(&hwndloop);
+
+    let mut group = c.benchmark_group("latency");
+    group.bench_function("enqueue_to_handle", |b| {
+      b.iter_custom(|iters| {
+        let mut total = Duration::default();
+        for _ in 0..iters {
+          let (tx, rx) = channel();
+          hwndloop.send_command(BenchCommand::Stamp(Instant::now(), tx)).unwrap();
+          total += rx.recv().unwrap();
+        }
+        total
+      })
+    });
+    group.bench_function("enqueue_to_handle_interleaved", |b| {
+      b.iter_custom(|iters| {
+        let mut total = Duration::default();
+        for i in 0..iters {
+          post_user(&hwnd, i as usize);
+          let (tx, rx) = channel();
+          hwndloop.send_command(BenchCommand::Stamp(Instant::now(), tx)).unwrap();
+          total += rx.recv().unwrap();
+        }
+        total
+      })
+    });
+    group.bench_function("flush", |b| b.iter(|| hwndloop.flush().unwrap()));
+    group.finish();
+  }
+
+  /// Several threads posting window messages to the loop at once.
+  pub fn fan_in(c: &mut Criterion) {
+    let hwndloop = HwndLoop::new(Box::new(Bench));
+    let hwnd = Arc::new(get_hwnd(&hwndloop));
+    let per_thread = 1000;
+
+    let mut group = c.benchmark_group("fan_in");
+    for &threads in &[1usize, 2, 4, 8] {
+      group.throughput(Throughput::Elements((threads * per_thread) as u64));
+      group.bench_with_input(BenchmarkId::new("post_message", threads), &threads, |b, &threads| {
+        b.iter(|| {
+          let handles: Vec<_> = (0..threads)
+            .map(|_| {
+              let hwnd = hwnd.clone();
+              std::thread::spawn(move || {
+                for i in 0..per_thread {
+                  post_user(&hwnd, i);
+                }
+              })
+            })
+            .collect();
+          for handle in handles {
+            handle.join().unwrap();
+          }
+          hwndloop.flush().unwrap();
+        })
+      });
+    }
+    group.finish();
+  }
+}
+
+#[cfg(windows)]
+criterion_group!(benches, bench::throughput, bench::latency, bench::fan_in);
+#[cfg(windows)]
+criterion_main!(benches);
+
+#[cfg(not(windows))]
+fn main() {}
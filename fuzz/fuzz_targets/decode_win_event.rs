@@ -0,0 +1,7 @@
+#![no_main]
+
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate hwndloop;
+
+fuzz_target!(|data: &[u8]| hwndloop::fuzz::decode_win_event(data));
@@ -0,0 +1,78 @@
+use winapi::shared::minwindef::{BYTE, WORD};
+use winapi::shared::windef::HACCEL;
+use winapi::um::winuser::{CreateAcceleratorTableW, DestroyAcceleratorTable, ACCEL, FVIRTKEY};
+
+use Result;
+
+/// A keystroke that produces an accelerator command, delivered to
+/// [`HwndLoopCallbacks::handle_accelerator`](::HwndLoopCallbacks::handle_accelerator).
+#[derive(Clone, Copy, Debug)]
+pub struct Accelerator {
+  /// Virtual-key code of the key.
+  pub key: WORD,
+
+  /// Any combination of `FALT`, `FCONTROL`, and `FSHIFT`.
+  pub modifiers: BYTE,
+
+  /// Identifier passed to the callback when the accelerator is hit.
+  pub id: WORD,
+}
+
+impl Accelerator {
+  /// Create an accelerator for a virtual-key code with the given modifiers.
+  pub fn new(key: WORD, modifiers: BYTE, id: WORD) -> Accelerator {
+    Accelerator { key, modifiers, id }
+  }
+}
+
+/// An accelerator table to be installed with [`HwndLoop::set_accelerators`](::HwndLoop::set_accelerators).
+#[derive(Debug)]
+pub struct AcceleratorTable {
+  haccel: HACCEL,
+  owned: bool,
+}
+
+unsafe impl Send for AcceleratorTable {}
+
+impl AcceleratorTable {
+  /// Create an accelerator table from a list of keystrokes.
+  pub fn new(accelerators: &[Accelerator]) -> Result<AcceleratorTable> {
+    let mut entries: Vec<ACCEL> = accelerators
+      .iter()
+      .map(|accel| ACCEL {
+        fVirt: accel.modifiers | FVIRTKEY,
+        key: accel.key,
+        cmd: accel.id,
+      })
+      .collect();
+
+    let haccel = unsafe { CreateAcceleratorTableW(entries.as_mut_ptr(), entries.len() as i32) };
+    if haccel.is_null() {
+      return Err(std::io::Error::last_os_error().into());
+    }
+
+    Ok(AcceleratorTable { haccel, owned: true })
+  }
+
+  /// Wrap an existing accelerator table, e.g. one loaded with `LoadAcceleratorsW`.
+  ///
+  /// # Safety
+  ///
+  /// `haccel` must be a valid accelerator table. It isn't destroyed when the wrapper is dropped, so
+  /// it must outlive its use by the loop.
+  pub unsafe fn from_raw(haccel: HACCEL) -> AcceleratorTable {
+    AcceleratorTable { haccel, owned: false }
+  }
+
+  pub(crate) fn as_raw(&self) -> HACCEL {
+    self.haccel
+  }
+}
+
+impl Drop for AcceleratorTable {
+  fn drop(&mut self) {
+    if self.owned {
+      unsafe { DestroyAcceleratorTable(self.haccel) };
+    }
+  }
+}
@@ -0,0 +1,101 @@
+use winapi::shared::basetsd::DWORD_PTR;
+use winapi::shared::minwindef::{BOOL, DWORD, FALSE, FARPROC, ULONG};
+use winapi::shared::winerror::ERROR_PROC_NOT_FOUND;
+use winapi::um::libloaderapi::{GetModuleHandleW, GetProcAddress};
+use winapi::um::processthreadsapi::{GetCurrentThread, SetThreadIdealProcessor};
+use winapi::um::winbase::SetThreadAffinityMask;
+use winapi::um::winnt::HANDLE;
+
+use util;
+use Result;
+
+type SetThreadSelectedCpuSetsFn = unsafe extern "system" fn(HANDLE, *const ULONG, ULONG) -> BOOL;
+
+lazy_static! {
+  /// `SetThreadSelectedCpuSets`, which isn't in winapi. It only exists since Windows 10, so it's
+  /// looked up at runtime instead of being imported, which would keep the binary from loading on
+  /// anything older.
+  static ref SET_THREAD_SELECTED_CPU_SETS: Option<SetThreadSelectedCpuSetsFn> = unsafe {
+    let kernel32 = GetModuleHandleW(util::to_utf16("kernel32.dll").as_ptr());
+    let proc = GetProcAddress(kernel32, b"SetThreadSelectedCpuSets\0".as_ptr() as *const _);
+    if proc.is_null() {
+      None
+    } else {
+      Some(std::mem::transmute::<FARPROC, SetThreadSelectedCpuSetsFn>(proc))
+    }
+  };
+}
+
+/// The processors that a loop's thread is allowed to run on, for
+/// [`HwndLoopBuilder::affinity`](::HwndLoopBuilder::affinity).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Affinity {
+  /// A mask of logical processors in the thread's processor group, as with
+  /// `SetThreadAffinityMask`. The thread never runs anywhere else.
+  Mask(usize),
+
+  /// CPU set IDs, as reported by `GetSystemCpuSetInformation` and taken by
+  /// `SetThreadSelectedCpuSets`. Unlike a mask, these can span processor groups, and the system
+  /// can still run the thread elsewhere if the CPU sets are unavailable. They need Windows 10, and
+  /// creating the loop fails with `ERROR_PROC_NOT_FOUND` on anything older.
+  CpuSets(Vec<u32>),
+}
+
+/// Processor settings applied to the current thread, which are undone when dropped.
+pub(crate) struct ThreadPlacement {
+  previous_mask: Option<DWORD_PTR>,
+  cpu_sets: bool,
+  previous_ideal: Option<DWORD>,
+}
+
+impl Drop for ThreadPlacement {
+  fn drop(&mut self) {
+    let thread = unsafe { GetCurrentThread() };
+    if let Some(mask) = self.previous_mask {
+      unsafe { SetThreadAffinityMask(thread, mask) };
+    }
+    if let (true, Some(set_cpu_sets)) = (self.cpu_sets, *SET_THREAD_SELECTED_CPU_SETS) {
+      unsafe { set_cpu_sets(thread, std::ptr::null(), 0) };
+    }
+    if let Some(ideal) = self.previous_ideal {
+      unsafe { SetThreadIdealProcessor(thread, ideal) };
+    }
+  }
+}
+
+/// Restrict the current thread to `affinity`, and make `ideal_processor` its preferred processor.
+pub(crate) fn apply(affinity: Option<&Affinity>, ideal_processor: Option<DWORD>) -> Result<ThreadPlacement> {
+  let thread = unsafe { GetCurrentThread() };
+  // Whatever was applied before a failure is undone when this is dropped.
+  let mut placement = ThreadPlacement {
+    previous_mask: None,
+    cpu_sets: false,
+    previous_ideal: None,
+  };
+
+  match affinity {
+    Some(&Affinity::Mask(mask)) => match unsafe { SetThreadAffinityMask(thread, mask as DWORD_PTR) } {
+      0 => return Err(std::io::Error::last_os_error().into()),
+      previous => placement.previous_mask = Some(previous),
+    },
+    Some(Affinity::CpuSets(ids)) => {
+      let set_cpu_sets = match *SET_THREAD_SELECTED_CPU_SETS {
+        Some(set_cpu_sets) => set_cpu_sets,
+        None => return Err(std::io::Error::from_raw_os_error(ERROR_PROC_NOT_FOUND as i32).into()),
+      };
+      if unsafe { set_cpu_sets(thread, ids.as_ptr(), ids.len() as ULONG) } == FALSE {
+        return Err(std::io::Error::last_os_error().into());
+      }
+      placement.cpu_sets = true;
+    }
+    None => {}
+  }
+
+  if let Some(ideal) = ideal_processor {
+    match unsafe { SetThreadIdealProcessor(thread, ideal) } {
+      DWORD::MAX => return Err(std::io::Error::last_os_error().into()),
+      previous => placement.previous_ideal = Some(previous),
+    }
+  }
+  Ok(placement)
+}
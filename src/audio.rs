@@ -0,0 +1,215 @@
+//! Audio endpoint notifications.
+//!
+//! This is only available with the `audio` feature. Loops built with
+//! [`HwndLoopBuilder::audio_notifications`](::HwndLoopBuilder::audio_notifications) register an
+//! `IMMNotificationClient` from the handler thread, and the events that it receives are delivered
+//! to [`HwndLoopCallbacks::handle_audio_event`](::HwndLoopCallbacks::handle_audio_event) on the
+//! handler thread, in order with commands.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use winapi::ctypes::c_void;
+use winapi::shared::guiddef::{IsEqualGUID, REFIID};
+use winapi::shared::minwindef::{DWORD, ULONG};
+use winapi::shared::winerror::{E_NOINTERFACE, HRESULT, S_OK};
+use winapi::shared::wtypes::PROPERTYKEY;
+use winapi::um::combaseapi::{CoCreateInstance, CLSCTX_ALL};
+use winapi::um::mmdeviceapi::*;
+use winapi::um::unknwnbase::{IUnknown, IUnknownVtbl};
+use winapi::um::winnt::LPCWSTR;
+use winapi::Interface;
+
+use util;
+use Result;
+
+/// The direction of audio data through an endpoint.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DataFlow {
+  Render,
+  Capture,
+}
+
+/// What a default endpoint is the default for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+  Console,
+  Multimedia,
+  Communications,
+}
+
+/// A change to the system's audio endpoints.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AudioEvent {
+  /// The default endpoint for `flow` and `role` changed to `device_id`, or to nothing.
+  DefaultDeviceChanged {
+    flow: DataFlow,
+    role: Role,
+    device_id: Option<String>,
+  },
+
+  /// An endpoint was added.
+  DeviceAdded(String),
+
+  /// An endpoint was removed.
+  DeviceRemoved(String),
+
+  /// An endpoint's state changed to `state`, one of the `DEVICE_STATE_*` constants.
+  DeviceStateChanged { device_id: String, state: DWORD },
+}
+
+/// A registered notification client, which is unregistered when dropped.
+pub(crate) struct AudioNotifications {
+  enumerator: *mut IMMDeviceEnumerator,
+  client: *mut NotificationClient,
+}
+
+impl AudioNotifications {
+  /// Register for notifications on the current thread, which must have initialized COM, passing
+  /// them to `deliver` from whatever thread they arrive on.
+  pub(crate) fn register(deliver: Box<dyn Fn(AudioEvent) + Send + Sync>) -> Result<AudioNotifications> {
+    let mut enumerator: *mut IMMDeviceEnumerator = std::ptr::null_mut();
+    util::check_hresult(unsafe {
+      CoCreateInstance(
+        &CLSID_MMDeviceEnumerator,
+        std::ptr::null_mut(),
+        CLSCTX_ALL,
+        &IMMDeviceEnumerator::uuidof(),
+        &mut enumerator as *mut _ as *mut _,
+      )
+    })?;
+
+    let client = Box::into_raw(Box::new(NotificationClient {
+      vtable: &NOTIFICATION_CLIENT_VTABLE,
+      refs: AtomicUsize::new(1),
+      deliver,
+    }));
+    let notifications = AudioNotifications { enumerator, client };
+    util::check_hresult(unsafe { (*enumerator).RegisterEndpointNotificationCallback(client as *mut _) })?;
+    Ok(notifications)
+  }
+}
+
+impl Drop for AudioNotifications {
+  fn drop(&mut self) {
+    unsafe {
+      (*self.enumerator).UnregisterEndpointNotificationCallback(self.client as *mut _);
+      NotificationClient::release(self.client as *mut IUnknown);
+      (*self.enumerator).Release();
+    }
+  }
+}
+
+fn device_id(id: LPCWSTR) -> Option<String> {
+  if id.is_null() {
+    return None;
+  }
+  unsafe {
+    let len = (0..).take_while(|&i| *id.add(i) != 0).count();
+    Some(String::from_utf16_lossy(std::slice::from_raw_parts(id, len)))
+  }
+}
+
+/// An `IMMNotificationClient` that forwards events to a closure.
+#[repr(C)]
+struct NotificationClient {
+  vtable: *const IMMNotificationClientVtbl,
+  refs: AtomicUsize,
+  deliver: Box<dyn Fn(AudioEvent) + Send + Sync>,
+}
+
+static NOTIFICATION_CLIENT_VTABLE: IMMNotificationClientVtbl = IMMNotificationClientVtbl {
+  parent: IUnknownVtbl {
+    QueryInterface: NotificationClient::query_interface,
+    AddRef: NotificationClient::add_ref,
+    Release: NotificationClient::release,
+  },
+  OnDeviceStateChanged: NotificationClient::on_device_state_changed,
+  OnDeviceAdded: NotificationClient::on_device_added,
+  OnDeviceRemoved: NotificationClient::on_device_removed,
+  OnDefaultDeviceChanged: NotificationClient::on_default_device_changed,
+  OnPropertyValueChanged: NotificationClient::on_property_value_changed,
+};
+
+impl NotificationClient {
+  unsafe fn deliver(this: *mut IMMNotificationClient, event: AudioEvent) -> HRESULT {
+    ((*(this as *mut NotificationClient)).deliver)(event);
+    S_OK
+  }
+
+  unsafe extern "system" fn query_interface(this: *mut IUnknown, iid: REFIID, result: *mut *mut c_void) -> HRESULT {
+    let iid = &*iid;
+    if IsEqualGUID(iid, &IUnknown::uuidof()) || IsEqualGUID(iid, &IMMNotificationClient::uuidof()) {
+      NotificationClient::add_ref(this);
+      *result = this as *mut _;
+      S_OK
+    } else {
+      *result = std::ptr::null_mut();
+      E_NOINTERFACE
+    }
+  }
+
+  unsafe extern "system" fn add_ref(this: *mut IUnknown) -> ULONG {
+    let this = this as *mut NotificationClient;
+    ((*this).refs.fetch_add(1, Ordering::SeqCst) + 1) as ULONG
+  }
+
+  unsafe extern "system" fn release(this: *mut IUnknown) -> ULONG {
+    let this = this as *mut NotificationClient;
+    let refs = (*this).refs.fetch_sub(1, Ordering::SeqCst) - 1;
+    if refs == 0 {
+      drop(Box::from_raw(this));
+    }
+    refs as ULONG
+  }
+
+  unsafe extern "system" fn on_device_state_changed(
+    this: *mut IMMNotificationClient,
+    id: LPCWSTR,
+    state: DWORD,
+  ) -> HRESULT {
+    let device_id = device_id(id).unwrap_or_default();
+    NotificationClient::deliver(this, AudioEvent::DeviceStateChanged { device_id, state })
+  }
+
+  unsafe extern "system" fn on_device_added(this: *mut IMMNotificationClient, id: LPCWSTR) -> HRESULT {
+    NotificationClient::deliver(this, AudioEvent::DeviceAdded(device_id(id).unwrap_or_default()))
+  }
+
+  unsafe extern "system" fn on_device_removed(this: *mut IMMNotificationClient, id: LPCWSTR) -> HRESULT {
+    NotificationClient::deliver(this, AudioEvent::DeviceRemoved(device_id(id).unwrap_or_default()))
+  }
+
+  unsafe extern "system" fn on_default_device_changed(
+    this: *mut IMMNotificationClient,
+    flow: EDataFlow,
+    role: ERole,
+    id: LPCWSTR,
+  ) -> HRESULT {
+    let flow = if flow == eRender {
+      DataFlow::Render
+    } else if flow == eCapture {
+      DataFlow::Capture
+    } else {
+      return S_OK;
+    };
+    let role = if role == eConsole {
+      Role::Console
+    } else if role == eMultimedia {
+      Role::Multimedia
+    } else if role == eCommunications {
+      Role::Communications
+    } else {
+      return S_OK;
+    };
+    let device_id = device_id(id);
+    NotificationClient::deliver(this, AudioEvent::DefaultDeviceChanged { flow, role, device_id })
+  }
+
+  unsafe extern "system" fn on_property_value_changed(
+    _this: *mut IMMNotificationClient,
+    _id: LPCWSTR,
+    _key: PROPERTYKEY,
+  ) -> HRESULT {
+    S_OK
+  }
+}
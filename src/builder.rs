@@ -0,0 +1,467 @@
+use std::any::Any;
+use std::sync::Arc;
+use std::time::Duration;
+
+use winapi::shared::minwindef::{DWORD, UINT};
+use winapi::shared::windef::HWND;
+
+use events::{Backpressure, EventChannel, NewChannel};
+#[cfg(feature = "serde")]
+use ipc;
+use {Affinity, ComApartment, Desktop, ExternalLoopAdapter, HwndLoop, HwndLoopCallbacks, HwndPump, HwndWrapper};
+use {LoopHandle, Result};
+
+/// Configuration for a [`HwndLoop`], used to create loops that need more than the defaults that
+/// [`HwndLoop::new`] provides.
+#[derive(Clone, Debug, Default)]
+pub struct HwndLoopBuilder {
+  pub(crate) translate_messages: bool,
+  pub(crate) message_filter: Option<(UINT, UINT)>,
+  pub(crate) hwnd_filter: Option<HwndWrapper>,
+  pub(crate) user_slots: usize,
+  pub(crate) user_data: Option<Arc<dyn Any + Send + Sync>>,
+  pub(crate) events: Option<(NewChannel, Backpressure)>,
+  pub(crate) start_hooks: Vec<StartHook>,
+  pub(crate) window_station: Option<String>,
+  pub(crate) desktop: Option<Desktop>,
+  pub(crate) com_apartment: Option<ComApartment>,
+  pub(crate) network_notifications: bool,
+  pub(crate) idle_threshold: Option<Duration>,
+  pub(crate) coalesce: Vec<(UINT, Duration)>,
+  pub(crate) spy: Option<usize>,
+  pub(crate) profile: bool,
+  pub(crate) receive_broadcasts: bool,
+  pub(crate) windowless: bool,
+  pub(crate) thread_control: bool,
+  pub(crate) stack_size: Option<usize>,
+  pub(crate) startup_timeout: Option<Duration>,
+  pub(crate) affinity: Option<Affinity>,
+  pub(crate) ideal_processor: Option<DWORD>,
+  pub(crate) mmcss_task: Option<String>,
+  pub(crate) shutdown_level: Option<DWORD>,
+  pub(crate) strict: bool,
+  pub(crate) terminate_policy: TerminatePolicy,
+  #[cfg(feature = "audio")]
+  pub(crate) audio_notifications: bool,
+  #[cfg(feature = "serde")]
+  pub(crate) ipc_decoder: Option<ipc::Decoder>,
+  #[cfg(feature = "dde")]
+  pub(crate) dde_server: Option<(String, Vec<String>)>,
+}
+
+/// What happens to the commands that are still queued when the last [`HwndLoop`] is dropped.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TerminatePolicy {
+  /// Handle everything that was sent before the loop was dropped, however long that takes.
+  #[default]
+  Drain,
+
+  /// Keep handling queued commands for at most `grace`, which can be zero, then terminate and pass
+  /// the rest to [`HwndLoopCallbacks::handle_dropped_command`](::HwndLoopCallbacks::handle_dropped_command)
+  /// with [`DropReason::Terminated`](::DropReason::Terminated). A command that's already being
+  /// handled still runs to completion.
+  Preempt { grace: Duration },
+}
+
+/// A closure registered with [`HwndLoopBuilder::on_start`].
+#[derive(Clone)]
+pub(crate) struct StartHook(pub(crate) Arc<dyn Fn(HWND) + Send + Sync>);
+
+impl std::fmt::Debug for StartHook {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    write!(f, "StartHook")
+  }
+}
+
+impl HwndLoopBuilder {
+  /// Create a builder with the same configuration as [`HwndLoop::new`].
+  pub fn new() -> HwndLoopBuilder {
+    HwndLoopBuilder::default()
+  }
+
+  /// Call [`TranslateMessage`](winapi::um::winuser::TranslateMessage) on every message before it's
+  /// dispatched, so that keyboard input generates WM_CHAR and friends.
+  ///
+  /// This is only useful for loops that host windows which receive keyboard input.
+  pub fn translate_messages(mut self, translate: bool) -> HwndLoopBuilder {
+    self.translate_messages = translate;
+    self
+  }
+
+  /// Only retrieve window messages in the range `min..=max`, like the `wMsgFilterMin` and
+  /// `wMsgFilterMax` arguments to `GetMessage`.
+  ///
+  /// The loop's own messages are always retrieved, so commands and flushes keep working, but
+  /// commands are no longer ordered with respect to window messages. Messages outside of the range
+  /// stay in the queue until something else retrieves them.
+  pub fn message_filter(mut self, min: UINT, max: UINT) -> HwndLoopBuilder {
+    self.message_filter = Some((min, max));
+    self
+  }
+
+  /// Only retrieve window messages for `hwnd` and its children, like the `hWnd` argument to
+  /// `GetMessage`.
+  ///
+  /// As with [`HwndLoopBuilder::message_filter`], the loop's own messages are exempt.
+  pub fn hwnd_filter(mut self, hwnd: HwndWrapper) -> HwndLoopBuilder {
+    self.hwnd_filter = Some(hwnd);
+    self
+  }
+
+  /// Reserve `count` pointer-sized slots in the loop window's extra bytes, for use with the
+  /// [`slots`](::slots) module.
+  pub fn user_slots(mut self, count: usize) -> HwndLoopBuilder {
+    self.user_slots = count;
+    self
+  }
+
+  /// Make `data` available to [`HwndLoopCallbacks::set_up`](::HwndLoopCallbacks::set_up) through
+  /// [`SetUpContext::user_data`](::SetUpContext::user_data).
+  pub fn user_data<T: Any + Send + Sync>(mut self, data: T) -> HwndLoopBuilder {
+    self.user_data = Some(Arc::new(data));
+    self
+  }
+
+  /// Give the loop a channel for sending events of type `E` out of its callbacks.
+  ///
+  /// Callbacks get the sending end from
+  /// [`SetUpContext::event_emitter`](::SetUpContext::event_emitter), and the owner gets the
+  /// receiving end from [`LoopHandle::event_receiver`](::LoopHandle::event_receiver), or more of
+  /// them from [`LoopHandle::subscribe`](::LoopHandle::subscribe). This is meant for unsolicited
+  /// events like device arrivals, which don't have a command to reply to.
+  ///
+  /// The first receiver buffers every event until it's taken, so an owner that might never take it
+  /// should bound it with [`HwndLoopBuilder::events_with`].
+  pub fn events<E: Clone + Send + 'static>(self) -> HwndLoopBuilder {
+    self.events_with::<E>(Backpressure::Unbounded)
+  }
+
+  /// Give the loop a channel for events of type `E`, as in [`HwndLoopBuilder::events`], whose first
+  /// receiver handles falling behind with `policy` instead of buffering everything.
+  pub fn events_with<E: Clone + Send + 'static>(mut self, policy: Backpressure) -> HwndLoopBuilder {
+    self.events = Some((EventChannel::<E>::new_erased, policy));
+    self
+  }
+
+  /// Accept commands sent from other processes with [`ipc::send_command`](::ipc::send_command).
+  ///
+  /// `CommandType` must be the loop's command type. Commands that arrive this way are queued as if
+  /// they had been sent with [`LoopHandle::send_command`](::LoopHandle::send_command).
+  #[cfg(feature = "serde")]
+  pub fn ipc_commands<CommandType: ::serde::de::DeserializeOwned + Send + 'static>(mut self) -> HwndLoopBuilder {
+    self.ipc_decoder = Some(ipc::decode::<CommandType>);
+    self
+  }
+
+  /// Run `f` on the handler thread once the window has been created and
+  /// [`HwndLoopCallbacks::set_up`](::HwndLoopCallbacks::set_up) has been called, before anything
+  /// is dispatched.
+  ///
+  /// This lets auxiliary components register themselves with the window without going through
+  /// the loop's callbacks. Hooks run in the order they were added, every time a loop is built
+  /// from this configuration.
+  pub fn on_start<F: Fn(HWND) + Send + Sync + 'static>(mut self, f: F) -> HwndLoopBuilder {
+    self.start_hooks.push(StartHook(Arc::new(f)));
+    self
+  }
+
+  /// Switch the process to the window station called `name` before creating the window, e.g.
+  /// `WinSta0` for a service that needs notifications from the interactive session.
+  ///
+  /// This affects the whole process, and requires access to the window station.
+  pub fn window_station(mut self, name: &str) -> HwndLoopBuilder {
+    self.window_station = Some(name.to_string());
+    self
+  }
+
+  /// Create the window on `desktop` instead of the thread's current desktop.
+  ///
+  /// Windows only receive some notifications (e.g. hooks and raw input) from the desktop that
+  /// they're on. The loop's thread is switched to the desktop for as long as the loop runs, which
+  /// fails if the thread already owns windows or hooks, so this is best used with
+  /// [`HwndLoopBuilder::build`].
+  pub fn desktop(mut self, desktop: Desktop) -> HwndLoopBuilder {
+    self.desktop = Some(desktop);
+    self
+  }
+
+  /// Initialize COM on the loop's thread before creating the window, and uninitialize it once
+  /// the window has been destroyed.
+  ///
+  /// Creating the loop fails if the thread is already in a different apartment.
+  pub fn com_apartment(mut self, apartment: ComApartment) -> HwndLoopBuilder {
+    self.com_apartment = Some(apartment);
+    self
+  }
+
+  /// Watch for network interface, address, and route changes, delivering them to
+  /// [`HwndLoopCallbacks::handle_network_event`](::HwndLoopCallbacks::handle_network_event).
+  ///
+  /// The notifications are waited on by the loop's own message pump, so they aren't delivered to
+  /// an [`ExternalLoopAdapter`], and are held back while the loop is paused.
+  pub fn network_notifications(mut self) -> HwndLoopBuilder {
+    self.network_notifications = true;
+    self
+  }
+
+  /// Check for user input on a timer, and tell
+  /// [`HwndLoopCallbacks::handle_idle_event`](::HwndLoopCallbacks::handle_idle_event) when there
+  /// hasn't been any for `threshold`, and again when there is.
+  ///
+  /// Input is checked at most every second, so transitions can be reported up to a second late.
+  /// The timer belongs to the loop's window, so this can't be used with windowless loops.
+  pub fn idle_threshold(mut self, threshold: Duration) -> HwndLoopBuilder {
+    self.idle_threshold = Some(threshold);
+    self
+  }
+
+  /// Coalesce floods of `msg`, like WM_DEVICECHANGE while devices are being reset, so that the
+  /// callbacks handle it at most once every `window`, with the parameters of the latest one.
+  ///
+  /// The first one starts a timer on the loop's window, and the ones that arrive before it fires
+  /// replace each other, so it's handled `window` after the flood started rather than as soon as
+  /// it ended. The ones that are held back are answered with 0, and handled as if they were posted
+  /// once the timer fires, so this is only for messages whose result doesn't matter.
+  ///
+  /// What the LPARAM of WM_DEVICECHANGE, WM_POWERBROADCAST, and WM_SETTINGCHANGE points at is only
+  /// valid while they're being sent, so it's copied, and the callbacks get a pointer to the copy.
+  /// Any other message is handled with the parameters it was sent with, so it must not be one
+  /// whose parameters point at anything. Calling this again for the same message replaces its
+  /// window, and like [`idle_threshold`](HwndLoopBuilder::idle_threshold), it can't be used with
+  /// windowless loops.
+  pub fn coalesce(mut self, msg: UINT, window: Duration) -> HwndLoopBuilder {
+    self.coalesce.retain(|&(coalesced, _)| coalesced != msg);
+    self.coalesce.push((msg, window));
+    self
+  }
+
+  /// Make the loop's window a hidden top-level window instead of a message-only window, so that it
+  /// receives messages broadcast to every top-level window, like WM_FONTCHANGE.
+  pub fn receive_broadcasts(mut self) -> HwndLoopBuilder {
+    self.receive_broadcasts = true;
+    self
+  }
+
+  /// Run the loop without a window, as a plain worker thread that only handles commands.
+  ///
+  /// Commands wake the thread through the loop's wake event instead of window messages, and
+  /// flushes, pauses, and termination work as usual. Callbacks get a null HWND, and since there's
+  /// no window to dispatch to, messages posted to the thread itself are passed straight to
+  /// [`HwndLoopCallbacks::try_handle_message`](::HwndLoopCallbacks::try_handle_message). Anything
+  /// that needs a window doesn't work: building the loop fails with
+  /// [`receive_broadcasts`](HwndLoopBuilder::receive_broadcasts),
+  /// [`user_slots`](HwndLoopBuilder::user_slots), IPC commands, or with
+  /// [`build_external`](HwndLoopBuilder::build_external), and hooks registered with
+  /// [`LoopHandle::once`](::LoopHandle::once) never run.
+  pub fn windowless(mut self) -> HwndLoopBuilder {
+    self.windowless = true;
+    self
+  }
+
+  /// Post the loop's internal messages (command wakeups, flushes, pauses, and termination) to its
+  /// thread with `PostThreadMessage`, instead of to its window, which is what windowless loops do
+  /// anyway.
+  ///
+  /// This keeps the loop controllable even if something else destroys its window out from under
+  /// it. The thread is kept from exiting with a handle so that its ID can't be reused while
+  /// anything can still post to it, and a `WH_GETMESSAGE` hook hands back the messages that would
+  /// otherwise be lost to someone else's message pump, like a modal dialog's. Only one loop on each
+  /// thread can do this, and it can't be used with
+  /// [`build_external`](HwndLoopBuilder::build_external), whose host pump would throw them away.
+  pub fn thread_control(mut self, enabled: bool) -> HwndLoopBuilder {
+    self.thread_control = enabled;
+    self
+  }
+
+  /// Give the handler thread a stack of `size` bytes, for callbacks that recurse deeply or call
+  /// into SDKs that need more than the standard library's default.
+  ///
+  /// This only applies to loops that get their own thread, from [`HwndLoopBuilder::build`] and
+  /// [`HwndLoopBuilder::try_build`].
+  pub fn stack_size(mut self, size: usize) -> HwndLoopBuilder {
+    self.stack_size = Some(size);
+    self
+  }
+
+  /// Give up on a loop whose thread hasn't finished starting up after `timeout`, which is 30
+  /// seconds by default, instead of waiting for it forever.
+  ///
+  /// Starting up includes [`HwndLoopCallbacks::set_up`](::HwndLoopCallbacks::set_up), so this
+  /// should leave room for whatever it does. A loop that's given up on is torn down as soon as its
+  /// thread gets around to it. This only applies to loops that get their own thread, from
+  /// [`HwndLoopBuilder::build`] and [`HwndLoopBuilder::try_build`].
+  pub fn startup_timeout(mut self, timeout: Duration) -> HwndLoopBuilder {
+    self.startup_timeout = Some(timeout);
+    self
+  }
+
+  /// Restrict the loop's thread to the processors in `affinity`, e.g. to keep a latency-sensitive
+  /// input loop from migrating across CCDs or NUMA nodes.
+  ///
+  /// This is applied to whichever thread runs the loop, and undone once the loop is gone, which
+  /// matters for [`HwndLoopBuilder::run_here`] and [`HwndLoopBuilder::build_pump`]. Creating the
+  /// loop fails if the processors don't exist.
+  pub fn affinity(mut self, affinity: Affinity) -> HwndLoopBuilder {
+    self.affinity = Some(affinity);
+    self
+  }
+
+  /// Make `processor` the preferred processor for the loop's thread, with
+  /// `SetThreadIdealProcessor`, without ruling out the others.
+  ///
+  /// As with [`HwndLoopBuilder::affinity`], this is undone once the loop is gone.
+  pub fn ideal_processor(mut self, processor: DWORD) -> HwndLoopBuilder {
+    self.ideal_processor = Some(processor);
+    self
+  }
+
+  /// Register the loop's thread with the Multimedia Class Scheduler Service as part of `task`, one
+  /// of the tasks under `SystemProfile\Tasks` in the registry like `Pro Audio` or `Games`, to boost
+  /// its scheduling priority.
+  ///
+  /// The registration is reverted once the loop is gone. Creating the loop fails if the task
+  /// doesn't exist or the service isn't running.
+  pub fn mmcss_task(mut self, task: &str) -> HwndLoopBuilder {
+    self.mmcss_task = Some(task.to_string());
+    self
+  }
+
+  /// Record the last `capacity` messages that the loop's window handles, with where they came from
+  /// and how long they took, for [`LoopHandle::spy_log`]. Each one is also logged at the trace
+  /// level.
+  ///
+  /// The loop's own messages aren't recorded, since they're commands and flushes rather than
+  /// window messages, and neither is anything that a windowless loop handles.
+  pub fn spy(mut self, capacity: usize) -> HwndLoopBuilder {
+    self.spy = Some(capacity);
+    self
+  }
+
+  /// Time every window message and command that the callbacks handle, adding them up by message ID
+  /// and command variant for [`LoopHandle::profile_snapshot`].
+  ///
+  /// This costs a couple of clock reads and an uncontended lock per message or command, so it's
+  /// cheap enough to leave on. Only messages that reach
+  /// [`HwndLoopCallbacks::try_handle_message`](::HwndLoopCallbacks::try_handle_message) are
+  /// counted, not the ones that go to more specific callbacks or are sent while the window is
+  /// being created.
+  pub fn profile(mut self, enabled: bool) -> HwndLoopBuilder {
+    self.profile = enabled;
+    self
+  }
+
+  /// Check the loop's invariants as it runs, panicking as soon as one of them is broken instead of
+  /// deadlocking or misbehaving later.
+  ///
+  /// This only has an effect in debug builds. It catches blocking [`LoopHandle`] calls made from
+  /// the handler thread, corruption of the window's pointer to the callbacks, and flushes that
+  /// complete out of order. Corruption is found in the window procedure, where a panic would abort
+  /// the process, so it's logged there, and the loop's own message pump panics once the message has
+  /// been dispatched; an [`ExternalLoopAdapter`] doesn't have one, so it only logs. Window messages
+  /// that take longer than Windows' default hook timeout to handle are only logged, since a
+  /// debugger pausing the thread does the same.
+  pub fn strict(mut self, strict: bool) -> HwndLoopBuilder {
+    self.strict = strict;
+    self
+  }
+
+  /// Choose what happens to the commands that are still queued when the last [`HwndLoop`] is
+  /// dropped, which by default are all handled first.
+  ///
+  /// This only applies to dropping the loop: [`LoopHandle::terminate`] always waits its turn
+  /// behind the commands sent before it.
+  pub fn terminate_policy(mut self, policy: TerminatePolicy) -> HwndLoopBuilder {
+    self.terminate_policy = policy;
+    self
+  }
+
+  /// Set the process's shutdown level with `SetProcessShutdownParameters` when the loop is
+  /// created, to change when it's asked to end relative to other processes during system shutdown.
+  ///
+  /// Applications can use levels from 0x100 to 0x3FF, and processes with higher levels are shut
+  /// down first; the default is 0x280. This applies to the whole process, so a loop that needs to
+  /// clean up after other processes have gone should ask for a lower level, and handle
+  /// [`HwndLoopCallbacks::handle_end_session`](::HwndLoopCallbacks::handle_end_session).
+  pub fn shutdown_level(mut self, level: DWORD) -> HwndLoopBuilder {
+    self.shutdown_level = Some(level);
+    self
+  }
+
+  /// Register for audio endpoint notifications once the loop has been set up, delivering them to
+  /// [`HwndLoopCallbacks::handle_audio_event`](::HwndLoopCallbacks::handle_audio_event).
+  ///
+  /// This requires COM, so it's normally combined with [`HwndLoopBuilder::com_apartment`].
+  #[cfg(feature = "audio")]
+  pub fn audio_notifications(mut self) -> HwndLoopBuilder {
+    self.audio_notifications = true;
+    self
+  }
+
+  /// Answer DDE conversations for `application` and any of `topics`, passing the commands that
+  /// clients execute to
+  /// [`HwndLoopCallbacks::handle_dde_execute`](::HwndLoopCallbacks::handle_dde_execute).
+  ///
+  /// Clients look for servers by broadcasting to top-level windows, so this implies
+  /// [`HwndLoopBuilder::receive_broadcasts`].
+  #[cfg(feature = "dde")]
+  pub fn dde_server(mut self, application: &str, topics: &[&str]) -> HwndLoopBuilder {
+    let topics = topics.iter().map(|topic| topic.to_string()).collect();
+    self.dde_server = Some((application.to_string(), topics));
+    self.receive_broadcasts = true;
+    self
+  }
+
+  /// Create a [`HwndLoop`] with this configuration.
+  ///
+  /// Panics with the error from [`HwndLoopBuilder::try_build`] if the loop can't be started, e.g.
+  /// because its window couldn't be created or its callbacks panicked while setting up.
+  pub fn build<CommandType: Send + std::fmt::Debug + 'static>(
+    self,
+    callbacks: Box<dyn HwndLoopCallbacks<CommandType>>,
+  ) -> HwndLoop<CommandType> {
+    match HwndLoop::spawn(self, callbacks) {
+      Ok(hwndloop) => hwndloop,
+      Err(err) => panic!("failed to start HwndLoop: {}", err),
+    }
+  }
+
+  /// Create a [`HwndLoop`] with this configuration, returning an error if its window can't be
+  /// created, e.g. because the requested desktop isn't accessible.
+  ///
+  /// If the thread panics while starting up, e.g. in
+  /// [`HwndLoopCallbacks::set_up`](::HwndLoopCallbacks::set_up), this fails with
+  /// [`Error::Panicked`](::Error::Panicked), and if it takes longer than the
+  /// [`startup_timeout`](HwndLoopBuilder::startup_timeout), with
+  /// [`Error::TimedOut`](::Error::TimedOut).
+  pub fn try_build<CommandType: Send + std::fmt::Debug + 'static>(
+    self,
+    callbacks: Box<dyn HwndLoopCallbacks<CommandType>>,
+  ) -> Result<HwndLoop<CommandType>> {
+    HwndLoop::spawn(self, callbacks)
+  }
+
+  /// Create a [`HwndPump`] with this configuration on the current thread.
+  pub fn build_pump<CommandType: Send + std::fmt::Debug + 'static>(
+    self,
+    callbacks: Box<dyn HwndLoopCallbacks<CommandType>>,
+  ) -> Result<HwndPump<CommandType>> {
+    HwndPump::with_config(&self, callbacks)
+  }
+
+  /// Create an [`ExternalLoopAdapter`] with this configuration on the current thread.
+  pub fn build_external<CommandType: Send + std::fmt::Debug + 'static>(
+    self,
+    callbacks: Box<dyn HwndLoopCallbacks<CommandType>>,
+  ) -> Result<ExternalLoopAdapter<CommandType>> {
+    ExternalLoopAdapter::with_config(&self, callbacks)
+  }
+
+  /// Run a loop with this configuration on the current thread, as in [`HwndLoop::run_here`].
+  pub fn run_here<CommandType: Send + std::fmt::Debug + 'static, F: FnOnce(LoopHandle<CommandType>)>(
+    self,
+    callbacks: Box<dyn HwndLoopCallbacks<CommandType>>,
+    ready: F,
+  ) -> Result<()> {
+    HwndLoop::run_with_config(&self, callbacks, ready)
+  }
+}
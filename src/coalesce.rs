@@ -0,0 +1,140 @@
+//! Coalescing of window messages that arrive in floods.
+//!
+//! Loops built with [`HwndLoopBuilder::coalesce`](::HwndLoopBuilder::coalesce) hold back the
+//! messages that they were told to coalesce, and set a timer on the loop's window for the first
+//! one. When it fires, the callbacks get the latest of them, and the next one starts a new window.
+//!
+//! The parameters of some messages point at data that's only valid while they're being sent, so
+//! that's copied when they're held back, and the LPARAM that they're handled with points at the
+//! copy instead.
+
+use std::time::Duration;
+
+use winapi::shared::basetsd::UINT_PTR;
+use winapi::shared::guiddef::GUID;
+use winapi::shared::minwindef::{DWORD, LPARAM, UINT, WPARAM};
+use winapi::shared::windef::HWND;
+use winapi::um::dbt::DEV_BROADCAST_HDR;
+use winapi::um::winuser::{KillTimer, SetTimer, POWERBROADCAST_SETTING, PBT_POWERSETTINGCHANGE};
+use winapi::um::winuser::{WM_DEVICECHANGE, WM_POWERBROADCAST, WM_SETTINGCHANGE};
+
+/// The ID of the timer for the first coalesced message, with the rest following it, which is
+/// unlikely to collide with a timer set by the callbacks.
+const COALESCE_TIMER_ID: UINT_PTR = 0x434f_4100;
+
+/// A message that was held back, to be handled once its timer fires.
+pub(crate) struct Held {
+  pub(crate) msg: UINT,
+  pub(crate) w: WPARAM,
+
+  /// Points at `payload` instead of the original data, if there was any.
+  pub(crate) l: LPARAM,
+  _payload: Option<Box<[u64]>>,
+}
+
+/// A message being coalesced, and the latest one that's being held back.
+struct Coalesced {
+  msg: UINT,
+  window: Duration,
+  pending: Option<Held>,
+}
+
+/// Holds back coalesced messages for the loop's window.
+pub(crate) struct Coalescer {
+  hwnd: HWND,
+  messages: Vec<Coalesced>,
+}
+
+impl Coalescer {
+  pub(crate) fn new(hwnd: HWND, messages: &[(UINT, Duration)]) -> Coalescer {
+    Coalescer {
+      hwnd,
+      messages: messages
+        .iter()
+        .map(|&(msg, window)| Coalesced {
+          msg,
+          window,
+          pending: None,
+        })
+        .collect(),
+    }
+  }
+
+  /// Hold back `msg` if it's being coalesced, returning whether it was.
+  pub(crate) fn hold(&mut self, msg: UINT, w: WPARAM, l: LPARAM) -> bool {
+    let (index, coalesced) = match self.messages.iter_mut().enumerate().find(|(_, coalesced)| coalesced.msg == msg) {
+      Some(found) => found,
+      None => return false,
+    };
+    if coalesced.pending.is_none() {
+      let millis = std::cmp::min(coalesced.window.as_millis(), u128::from(UINT::MAX)) as UINT;
+      if unsafe { SetTimer(self.hwnd, COALESCE_TIMER_ID + index, millis, None) } == 0 {
+        warn!("failed to coalesce message {:#x}: {}", msg, std::io::Error::last_os_error());
+        return false;
+      }
+    }
+    let payload = unsafe { copy_payload(msg, w, l) };
+    let l = payload.as_ref().map_or(l, |payload| payload.as_ptr() as LPARAM);
+    coalesced.pending = Some(Held {
+      msg,
+      w,
+      l,
+      _payload: payload,
+    });
+    true
+  }
+
+  /// Take the latest message held back for the timer `id`, if it's one of ours. Its LPARAM is only
+  /// valid for as long as it's kept around.
+  pub(crate) fn release(&mut self, id: UINT_PTR) -> Option<Held> {
+    let coalesced = self.messages.get_mut(id.checked_sub(COALESCE_TIMER_ID)?)?;
+    unsafe { KillTimer(self.hwnd, id) };
+    coalesced.pending.take()
+  }
+}
+
+/// Copy whatever the LPARAM of `msg` points at, for the messages that are known to point at
+/// something: the `DEV_BROADCAST_HDR` of WM_DEVICECHANGE, the `POWERBROADCAST_SETTING` of
+/// WM_POWERBROADCAST, and the string of WM_SETTINGCHANGE.
+unsafe fn copy_payload(msg: UINT, w: WPARAM, l: LPARAM) -> Option<Box<[u64]>> {
+  if l == 0 {
+    return None;
+  }
+  // The size that the sender gave, and the size of the struct, which it can be smaller than.
+  let (size, minimum) = match msg {
+    WM_DEVICECHANGE => {
+      let header = &*(l as *const DEV_BROADCAST_HDR);
+      (header.dbch_size as usize, std::mem::size_of::<DEV_BROADCAST_HDR>())
+    }
+    WM_POWERBROADCAST if w == PBT_POWERSETTINGCHANGE => {
+      let setting = &*(l as *const POWERBROADCAST_SETTING);
+      let size = std::mem::size_of::<GUID>() + std::mem::size_of::<DWORD>() + setting.DataLength as usize;
+      (size, std::mem::size_of::<POWERBROADCAST_SETTING>())
+    }
+    WM_SETTINGCHANGE => {
+      let s = l as *const u16;
+      let mut len = 0;
+      while *s.add(len) != 0 {
+        len += 1;
+      }
+      ((len + 1) * std::mem::size_of::<u16>(), 0)
+    }
+    _ => return None,
+  };
+
+  // Copied into u64s so that the copy is at least as aligned as any of the structs, and padded
+  // with zeroes so that the whole struct can be read.
+  let mut payload = vec![0u64; std::cmp::max(size, minimum).div_ceil(8)].into_boxed_slice();
+  std::ptr::copy_nonoverlapping(l as *const u8, payload.as_mut_ptr() as *mut u8, size);
+  Some(payload)
+}
+
+impl Drop for Coalescer {
+  fn drop(&mut self) {
+    for (index, coalesced) in self.messages.iter().enumerate() {
+      if coalesced.pending.is_some() {
+        unsafe { KillTimer(self.hwnd, COALESCE_TIMER_ID + index) };
+      }
+    }
+  }
+}
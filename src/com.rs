@@ -0,0 +1,37 @@
+use winapi::shared::winerror::HRESULT;
+use winapi::um::combaseapi::{CoInitializeEx, CoUninitialize};
+use winapi::um::objbase::{COINIT_APARTMENTTHREADED, COINIT_DISABLE_OLE1DDE, COINIT_MULTITHREADED};
+
+use util;
+use Result;
+
+/// The COM apartment that a loop's thread joins, for
+/// [`HwndLoopBuilder::com_apartment`](::HwndLoopBuilder::com_apartment).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ComApartment {
+  /// A single-threaded apartment, whose incoming calls are dispatched by the loop's message pump.
+  SingleThreaded,
+
+  /// The process's multithreaded apartment.
+  MultiThreaded,
+}
+
+/// COM initialization on the current thread, which is undone when dropped.
+pub(crate) struct ComGuard(());
+
+impl Drop for ComGuard {
+  fn drop(&mut self) {
+    unsafe { CoUninitialize() };
+  }
+}
+
+/// Initialize COM on the current thread, failing if it's already in a different apartment.
+pub(crate) fn initialize(apartment: ComApartment) -> Result<ComGuard> {
+  let coinit = match apartment {
+    ComApartment::SingleThreaded => COINIT_APARTMENTTHREADED,
+    ComApartment::MultiThreaded => COINIT_MULTITHREADED,
+  };
+  let hr: HRESULT = unsafe { CoInitializeEx(std::ptr::null_mut(), coinit | COINIT_DISABLE_OLE1DDE) };
+  util::check_hresult(hr)?;
+  Ok(ComGuard(()))
+}
@@ -0,0 +1,82 @@
+use std::any::Any;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use winapi::shared::minwindef::DWORD;
+
+use events::{EventChannel, Events};
+use {EventEmitter, LoopHandle};
+
+/// What [`HwndLoopCallbacks::set_up`](::HwndLoopCallbacks::set_up) gets to know about the loop
+/// it's setting up.
+pub struct SetUpContext<CommandType: Send + std::fmt::Debug + 'static> {
+  pub(crate) handle: LoopHandle<CommandType>,
+  pub(crate) thread_id: DWORD,
+  pub(crate) user_data: Option<Arc<dyn Any + Send + Sync>>,
+  pub(crate) events: Option<Arc<dyn Events>>,
+}
+
+impl<CommandType: Send + std::fmt::Debug + 'static> SetUpContext<CommandType> {
+  /// A handle to the loop, which can be kept for later or used to schedule initial commands.
+  ///
+  /// Commands sent from `set_up` are handled once the loop starts running.
+  pub fn handle(&self) -> LoopHandle<CommandType> {
+    self.handle.clone()
+  }
+
+  /// The id of the handler thread.
+  pub fn thread_id(&self) -> DWORD {
+    self.thread_id
+  }
+
+  /// The value passed to [`HwndLoopBuilder::user_data`](::HwndLoopBuilder::user_data), if it's a
+  /// `T`.
+  pub fn user_data<T: Any + Send + Sync>(&self) -> Option<&T> {
+    self.user_data.as_ref().and_then(|data| data.downcast_ref::<T>())
+  }
+
+  /// Something to send events out of the loop with, if it was built with
+  /// [`HwndLoopBuilder::events::<E>`](::HwndLoopBuilder::events).
+  pub fn event_emitter<E: Clone + Send + 'static>(&self) -> Option<EventEmitter<E>> {
+    let events = self.events.as_ref()?;
+    EventChannel::<E>::downcast(&**events).map(|channel| channel.emitter())
+  }
+}
+
+/// Lets [`HwndLoopCallbacks::tear_down`](::HwndLoopCallbacks::tear_down) keep the window around
+/// for a little longer.
+#[derive(Default)]
+pub struct TearDownContext {
+  pub(crate) drain: Option<(Duration, DrainHandle)>,
+}
+
+impl TearDownContext {
+  /// Keep dispatching window messages for up to `timeout` after `tear_down` returns, before the
+  /// window is destroyed, e.g. to wait for a device to confirm that it's been closed.
+  ///
+  /// Commands and flushes are no longer handled at this point. The drain ends early once
+  /// [`DrainHandle::finish`] is called on the returned handle, which callbacks can keep around
+  /// until the message they're waiting for shows up.
+  pub fn drain(&mut self, timeout: Duration) -> DrainHandle {
+    let handle = DrainHandle(Arc::new(AtomicBool::new(false)));
+    self.drain = Some((timeout, handle.clone()));
+    handle
+  }
+}
+
+/// Ends a drain requested with [`TearDownContext::drain`].
+#[derive(Clone, Debug)]
+pub struct DrainHandle(Arc<AtomicBool>);
+
+impl DrainHandle {
+  /// Stop draining, and let the window be destroyed.
+  pub fn finish(&self) {
+    self.0.store(true, Ordering::SeqCst);
+  }
+
+  /// Whether [`DrainHandle::finish`] has been called.
+  pub fn is_finished(&self) -> bool {
+    self.0.load(Ordering::SeqCst)
+  }
+}
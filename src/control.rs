@@ -0,0 +1,96 @@
+//! Delivery of a loop's internal messages to its thread's queue instead of its window, for
+//! [`HwndLoopBuilder::thread_control`](::HwndLoopBuilder::thread_control).
+//!
+//! Messages posted to a thread aren't dispatched to any window, so a message pump that isn't the
+//! loop's own (e.g. a modal dialog's, while a callback is running) just throws them away. A
+//! `WH_GETMESSAGE` hook on the loop's thread picks ours out as they're retrieved, and hands them
+//! back to the loop as strays instead.
+
+use std::cell::Cell;
+use std::collections::VecDeque;
+
+use winapi::shared::minwindef::{LPARAM, LRESULT, WPARAM};
+use winapi::shared::windef::HHOOK;
+use winapi::um::processthreadsapi::GetCurrentThreadId;
+use winapi::um::synchapi::SetEvent;
+use winapi::um::winnt::HANDLE;
+use winapi::um::winuser::{CallNextHookEx, SetWindowsHookExW, UnhookWindowsHookEx, HC_ACTION, MSG, PM_REMOVE};
+use winapi::um::winuser::{WH_GETMESSAGE, WM_NULL};
+
+use event_loop::is_internal_message;
+use {Result, INTERNAL_MESSAGE_TAG};
+
+/// Where the hook hands the messages it picks out to.
+#[derive(Clone, Copy)]
+struct Target {
+  strays: *mut VecDeque<MSG>,
+  wake_event: HANDLE,
+}
+
+thread_local! {
+  /// The loop on this thread whose internal messages are posted to the thread, since the hook
+  /// procedure isn't given any context of its own.
+  static TARGET: Cell<Option<Target>> = const { Cell::new(None) };
+
+  /// Whether the loop's own pump is the one retrieving messages, which it handles itself.
+  static OWN_PUMP: Cell<bool> = const { Cell::new(false) };
+}
+
+/// The hook on the current thread, which is removed when dropped.
+pub(crate) struct ControlHook(HHOOK);
+
+impl ControlHook {
+  /// Hook the current thread, handing internal messages that someone else's pump retrieves to
+  /// `strays` and signalling `wake_event`. Only one loop on each thread can have its internal
+  /// messages posted to the thread, since there'd be no telling whose they were.
+  pub(crate) fn install(strays: *mut VecDeque<MSG>, wake_event: HANDLE) -> Result<ControlHook> {
+    if TARGET.with(|target| target.get().is_some()) {
+      return Err(
+        std::io::Error::new(
+          std::io::ErrorKind::InvalidInput,
+          "only one loop on each thread can post its internal messages to the thread",
+        )
+        .into(),
+      );
+    }
+    let hook = unsafe {
+      SetWindowsHookExW(WH_GETMESSAGE, Some(get_message_hook), std::ptr::null_mut(), GetCurrentThreadId())
+    };
+    if hook.is_null() {
+      return Err(std::io::Error::last_os_error().into());
+    }
+    TARGET.with(|target| target.set(Some(Target { strays, wake_event })));
+    Ok(ControlHook(hook))
+  }
+}
+
+impl Drop for ControlHook {
+  fn drop(&mut self) {
+    TARGET.with(|target| target.set(None));
+    unsafe { UnhookWindowsHookEx(self.0) };
+  }
+}
+
+/// Retrieve messages with the loop's own pump, which the hook leaves alone.
+pub(crate) fn own_pump<R, F: FnOnce() -> R>(f: F) -> R {
+  let previous = OWN_PUMP.with(|own| own.replace(true));
+  let result = f();
+  OWN_PUMP.with(|own| own.set(previous));
+  result
+}
+
+unsafe extern "system" fn get_message_hook(code: i32, w: WPARAM, l: LPARAM) -> LRESULT {
+  if code == HC_ACTION && w == PM_REMOVE as WPARAM && !OWN_PUMP.with(|own| own.get()) {
+    let msg = &mut *(l as *mut MSG);
+    if msg.hwnd.is_null() && is_internal_message(msg.message) && msg.lParam == *INTERNAL_MESSAGE_TAG {
+      if let Some(target) = TARGET.with(|target| target.get()) {
+        (*target.strays).push_back(*msg);
+        SetEvent(target.wake_event);
+
+        // Whoever retrieved it gets a message that does nothing instead.
+        msg.message = WM_NULL;
+      }
+    }
+  }
+  CallNextHookEx(std::ptr::null_mut(), code, w, l)
+}
@@ -0,0 +1,333 @@
+//! Dynamic Data Exchange, for talking to software that still uses it, like shell "open" verbs that
+//! are registered with a `ddeexec` key.
+//!
+//! Loops built with [`HwndLoopBuilder::dde_server`](::HwndLoopBuilder::dde_server) answer
+//! conversations for an application name and a set of topics, and pass the commands that clients
+//! execute to [`HwndLoopCallbacks::handle_dde_execute`](::HwndLoopCallbacks::handle_dde_execute).
+//! [`execute`] is the other side of that: it finds a server and executes a single command.
+//!
+//! Only the message-based protocol's WM_DDE_EXECUTE is supported. Requests, pokes and advise
+//! loops are turned down.
+
+use std::cell::Cell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use winapi::shared::basetsd::{LONG_PTR, UINT_PTR};
+use winapi::shared::minwindef::{ATOM, BOOL, FALSE, HIWORD, LOWORD, LPARAM, LRESULT, MAKELONG, UINT, WPARAM};
+use winapi::shared::windef::HWND;
+use winapi::um::dde::{PackDDElParam, UnpackDDElParam};
+use winapi::um::winbase::{GlobalAddAtomW, GlobalAlloc, GlobalDeleteAtom, GlobalFree, GlobalGetAtomNameW};
+use winapi::um::winbase::{GlobalLock, GlobalSize, GlobalUnlock, GMEM_DDESHARE, GMEM_MOVEABLE, WAIT_FAILED};
+use winapi::um::winuser::{CreateWindowExW, DestroyWindow, IsWindowUnicode, MsgWaitForMultipleObjects, PeekMessageW};
+use winapi::um::winuser::{PostMessageW, RegisterClassExW, SendMessageTimeoutW, SendMessageW, UnregisterClassW};
+use winapi::um::winuser::{HWND_BROADCAST, HWND_MESSAGE, MSG, PM_REMOVE, QS_POSTMESSAGE, SMTO_ABORTIFHUNG, WNDCLASSEXW};
+
+use util;
+use {Error, Result};
+
+// Not in winapi.
+pub(crate) const WM_DDE_INITIATE: UINT = 0x03e0;
+const WM_DDE_TERMINATE: UINT = 0x03e1;
+const WM_DDE_ADVISE: UINT = 0x03e2;
+const WM_DDE_UNADVISE: UINT = 0x03e3;
+const WM_DDE_ACK: UINT = 0x03e4;
+const WM_DDE_REQUEST: UINT = 0x03e6;
+const WM_DDE_POKE: UINT = 0x03e7;
+const WM_DDE_EXECUTE: UINT = 0x03e8;
+pub(crate) const WM_DDE_LAST: UINT = WM_DDE_EXECUTE;
+const DDE_FACK: UINT_PTR = 0x8000;
+
+#[link(name = "user32")]
+extern "system" {
+  fn FreeDDElParam(msg: UINT, l: LPARAM) -> BOOL;
+  fn ReuseDDElParam(l: LPARAM, msg_in: UINT, msg_out: UINT, lo: UINT_PTR, hi: UINT_PTR) -> LPARAM;
+}
+
+lazy_static! {
+  /// Distinguishes the window classes of clients created at the same time.
+  static ref CLIENT_CLASS_SEQ: AtomicUsize = AtomicUsize::new(0);
+}
+
+/// Look up the name of a global atom.
+fn atom_name(atom: ATOM) -> Option<String> {
+  let mut buf = [0u16; 256];
+  match unsafe { GlobalGetAtomNameW(atom, buf.as_mut_ptr(), buf.len() as i32) } {
+    0 => None,
+    len => Some(String::from_utf16_lossy(&buf[..len as usize])),
+  }
+}
+
+/// Read the string in a global memory object, which is UTF-16 if both ends are Unicode windows.
+unsafe fn read_commands(commands: UINT_PTR, unicode: bool) -> Option<String> {
+  let ptr = GlobalLock(commands as _);
+  if ptr.is_null() {
+    return None;
+  }
+  let size = GlobalSize(commands as _);
+  let result = if unicode {
+    let data = std::slice::from_raw_parts(ptr as *const u16, size / 2);
+    let len = data.iter().position(|&c| c == 0).unwrap_or(data.len());
+    String::from_utf16_lossy(&data[..len])
+  } else {
+    let data = std::slice::from_raw_parts(ptr as *const u8, size);
+    let len = data.iter().position(|&c| c == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..len]).into_owned()
+  };
+  GlobalUnlock(commands as _);
+  Some(result)
+}
+
+/// The application and topics that a loop answers for, and the conversations it's in.
+pub(crate) struct DdeServer {
+  application: String,
+  topics: Vec<String>,
+
+  /// The topic of the conversation with each client window.
+  conversations: Vec<(HWND, String)>,
+}
+
+impl DdeServer {
+  pub(crate) fn new(application: &str, topics: &[String]) -> DdeServer {
+    DdeServer {
+      application: application.to_string(),
+      topics: topics.to_vec(),
+      conversations: Vec::new(),
+    }
+  }
+
+  /// Handle a DDE message sent or posted to the loop's window, calling `execute` with the topic
+  /// and command of every WM_DDE_EXECUTE.
+  pub(crate) unsafe fn handle_message<F: FnMut(&str, &str) -> bool>(
+    &mut self,
+    hwnd: HWND,
+    msg: UINT,
+    w: WPARAM,
+    l: LPARAM,
+    mut execute: F,
+  ) -> LRESULT {
+    let client = w as HWND;
+    match msg {
+      WM_DDE_INITIATE => {
+        // A null atom matches anything. Conversations are told apart by their windows, so a
+        // client gets one conversation with the first topic that matches.
+        let (application, topic) = (LOWORD(l as u32), HIWORD(l as u32));
+        let matches = |atom: ATOM, name: &str| {
+          atom == 0 || atom_name(atom).is_some_and(|atom_name| atom_name.eq_ignore_ascii_case(name))
+        };
+        if self.conversations.iter().any(|&(hwnd, _)| hwnd == client) || !matches(application, &self.application) {
+          return 0;
+        }
+        if let Some(topic) = self.topics.iter().find(|name| matches(topic, name)) {
+          // The client deletes the atoms in the acknowledgement.
+          let application = GlobalAddAtomW(util::to_utf16(&self.application).as_ptr());
+          let atom = GlobalAddAtomW(util::to_utf16(topic).as_ptr());
+          self.conversations.push((client, topic.clone()));
+          SendMessageW(client, WM_DDE_ACK, hwnd as WPARAM, MAKELONG(application, atom) as LPARAM);
+        }
+      }
+
+      WM_DDE_EXECUTE => {
+        let (mut lo, mut commands): (UINT_PTR, UINT_PTR) = (0, 0);
+        UnpackDDElParam(msg, l, &mut lo, &mut commands);
+        let topic = self.conversations.iter().find(|&&(hwnd, _)| hwnd == client).map(|(_, topic)| topic.clone());
+        let unicode = IsWindowUnicode(client) != FALSE;
+        let accepted = match (topic, read_commands(commands, unicode)) {
+          (Some(topic), Some(command)) => execute(&topic, &command),
+          _ => false,
+        };
+        let status = if accepted { DDE_FACK } else { 0 };
+        let ack = ReuseDDElParam(l, msg, WM_DDE_ACK, status, commands);
+        if PostMessageW(client, WM_DDE_ACK, hwnd as WPARAM, ack) == FALSE {
+          FreeDDElParam(WM_DDE_ACK, ack);
+        }
+      }
+
+      WM_DDE_TERMINATE => {
+        if let Some(index) = self.conversations.iter().position(|&(hwnd, _)| hwnd == client) {
+          self.conversations.remove(index);
+          PostMessageW(client, WM_DDE_TERMINATE, hwnd as WPARAM, 0);
+        }
+      }
+
+      WM_DDE_ADVISE | WM_DDE_UNADVISE | WM_DDE_REQUEST | WM_DDE_POKE => {
+        let (mut lo, mut item): (UINT_PTR, UINT_PTR) = (0, 0);
+        UnpackDDElParam(msg, l, &mut lo, &mut item);
+        let ack = ReuseDDElParam(l, msg, WM_DDE_ACK, 0, item);
+        if PostMessageW(client, WM_DDE_ACK, hwnd as WPARAM, ack) == FALSE {
+          FreeDDElParam(WM_DDE_ACK, ack);
+        }
+      }
+
+      _ => {}
+    }
+    0
+  }
+}
+
+/// The server that a client is talking to, pointed to by the client window's extra bytes.
+struct ClientState {
+  server: Cell<HWND>,
+}
+
+unsafe extern "system" fn client_wnd_proc(hwnd: HWND, msg: UINT, w: WPARAM, l: LPARAM) -> LRESULT {
+  if msg == WM_DDE_ACK {
+    let state = util::get_window_long_ptr(hwnd, 0) as *const ClientState;
+    GlobalDeleteAtom(LOWORD(l as u32));
+    GlobalDeleteAtom(HIWORD(l as u32));
+    if !state.is_null() && (*state).server.get().is_null() {
+      (*state).server.set(w as HWND);
+    } else {
+      // Only one conversation is needed, so the rest are ended right away.
+      PostMessageW(w as HWND, WM_DDE_TERMINATE, hwnd as WPARAM, 0);
+    }
+    return 0;
+  }
+  util::def_window_proc(hwnd, msg, w, l)
+}
+
+/// Wait for a message posted to `hwnd` in `msg`, until `deadline`.
+fn wait_for_message(hwnd: HWND, msg: UINT, deadline: Instant) -> Result<MSG> {
+  let mut message: MSG = unsafe { std::mem::zeroed() };
+  loop {
+    if unsafe { PeekMessageW(&mut message, hwnd, msg, msg, PM_REMOVE) } != FALSE {
+      return Ok(message);
+    }
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    if remaining == Duration::from_millis(0) {
+      return Err(Error::TimedOut);
+    }
+    let timeout = remaining.as_millis() as u32;
+    if unsafe { MsgWaitForMultipleObjects(0, std::ptr::null(), FALSE, timeout, QS_POSTMESSAGE) } == WAIT_FAILED {
+      return Err(std::io::Error::last_os_error().into());
+    }
+  }
+}
+
+/// Execute `command` on the first DDE server that answers for `application` and `topic`, waiting
+/// up to `timeout` for it to be accepted.
+///
+/// Fails with [`Error::Rejected`] if the server turned the command down, and with an
+/// [`Error::Os`] of kind `NotFound` if no server answered.
+pub fn execute(application: &str, topic: &str, command: &str, timeout: Duration) -> Result<()> {
+  let deadline = Instant::now() + timeout;
+  let class_name = util::to_utf16(&format!("hwndloop-dde-{}", CLIENT_CLASS_SEQ.fetch_add(1, Ordering::SeqCst)));
+  let wndclass = WNDCLASSEXW {
+    cbSize: std::mem::size_of::<WNDCLASSEXW>() as UINT,
+    style: 0,
+    lpfnWndProc: Some(client_wnd_proc),
+    cbClsExtra: 0,
+    cbWndExtra: std::mem::size_of::<LONG_PTR>() as i32,
+    hInstance: util::get_module_handle(),
+    hIcon: std::ptr::null_mut(),
+    hCursor: std::ptr::null_mut(),
+    hbrBackground: std::ptr::null_mut(),
+    lpszMenuName: std::ptr::null_mut(),
+    lpszClassName: class_name.as_ptr(),
+    hIconSm: std::ptr::null_mut(),
+  };
+  let window_class = match unsafe { RegisterClassExW(&wndclass) } {
+    0 => return Err(std::io::Error::last_os_error().into()),
+    atom => atom,
+  };
+  let hwnd = unsafe {
+    CreateWindowExW(
+      0,
+      util::atom_to_lpwstr(window_class),
+      std::ptr::null(),
+      0,
+      0,
+      0,
+      0,
+      0,
+      HWND_MESSAGE,
+      std::ptr::null_mut(),
+      util::get_module_handle(),
+      std::ptr::null_mut(),
+    )
+  };
+  let result = if hwnd.is_null() {
+    Err(std::io::Error::last_os_error().into())
+  } else {
+    let state = ClientState {
+      server: Cell::new(std::ptr::null_mut()),
+    };
+    unsafe { util::set_window_long_ptr(hwnd, 0, &state as *const ClientState as LONG_PTR) };
+    let result = converse(hwnd, &state, application, topic, command, deadline);
+    unsafe {
+      util::set_window_long_ptr(hwnd, 0, 0);
+      DestroyWindow(hwnd);
+    }
+    result
+  };
+  unsafe { UnregisterClassW(util::atom_to_lpwstr(window_class), util::get_module_handle()) };
+  result
+}
+
+fn converse(
+  hwnd: HWND,
+  state: &ClientState,
+  application: &str,
+  topic: &str,
+  command: &str,
+  deadline: Instant,
+) -> Result<()> {
+  // Servers answer while the broadcast is being sent, by sending WM_DDE_ACK back.
+  unsafe {
+    let application = GlobalAddAtomW(util::to_utf16(application).as_ptr());
+    let topic = GlobalAddAtomW(util::to_utf16(topic).as_ptr());
+    let timeout = deadline.saturating_duration_since(Instant::now()).as_millis() as UINT;
+    let mut result = 0;
+    let l = MAKELONG(application, topic) as LPARAM;
+    SendMessageTimeoutW(HWND_BROADCAST, WM_DDE_INITIATE, hwnd as WPARAM, l, SMTO_ABORTIFHUNG, timeout, &mut result);
+    GlobalDeleteAtom(application);
+    GlobalDeleteAtom(topic);
+  }
+  let server = state.server.get();
+  if server.is_null() {
+    let message = "no DDE server answered";
+    return Err(std::io::Error::new(std::io::ErrorKind::NotFound, message).into());
+  }
+
+  // The server frees the commands when it acknowledges them, or when the post fails.
+  let text = util::to_utf16(command);
+  let commands = unsafe { GlobalAlloc(GMEM_MOVEABLE | GMEM_DDESHARE, text.len() * 2) };
+  if commands.is_null() {
+    return Err(std::io::Error::last_os_error().into());
+  }
+  unsafe {
+    std::ptr::copy_nonoverlapping(text.as_ptr(), GlobalLock(commands) as *mut u16, text.len());
+    GlobalUnlock(commands);
+  }
+  let l = unsafe { PackDDElParam(WM_DDE_EXECUTE, 0, commands as UINT_PTR) };
+  let result = if unsafe { PostMessageW(server, WM_DDE_EXECUTE, hwnd as WPARAM, l) } == FALSE {
+    let err = std::io::Error::last_os_error();
+    unsafe {
+      FreeDDElParam(WM_DDE_EXECUTE, l);
+      GlobalFree(commands);
+    }
+    Err(err.into())
+  } else {
+    match wait_for_message(hwnd, WM_DDE_ACK, deadline) {
+      Ok(ack) => {
+        let (mut status, mut commands): (UINT_PTR, UINT_PTR) = (0, 0);
+        unsafe {
+          UnpackDDElParam(WM_DDE_ACK, ack.lParam, &mut status, &mut commands);
+          FreeDDElParam(WM_DDE_ACK, ack.lParam);
+          GlobalFree(commands as _);
+        }
+        if status & DDE_FACK != 0 {
+          Ok(())
+        } else {
+          Err(Error::Rejected)
+        }
+      }
+      Err(err) => Err(err),
+    }
+  };
+
+  // Wait for the server to agree to end the conversation, but not past the deadline.
+  unsafe { PostMessageW(server, WM_DDE_TERMINATE, hwnd as WPARAM, 0) };
+  let _ = wait_for_message(hwnd, WM_DDE_TERMINATE, deadline);
+  result
+}
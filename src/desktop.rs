@@ -0,0 +1,79 @@
+use winapi::shared::minwindef::FALSE;
+use winapi::shared::windef::HDESK;
+use winapi::um::processthreadsapi::GetCurrentThreadId;
+use winapi::um::winnt::MAXIMUM_ALLOWED;
+use winapi::um::winuser::{CloseDesktop, GetThreadDesktop, OpenDesktopW, OpenInputDesktop, SetThreadDesktop};
+use winapi::um::winuser::{OpenWindowStationW, SetProcessWindowStation};
+
+use util;
+use Result;
+
+/// The desktop that a loop's window is created on, for
+/// [`HwndLoopBuilder::desktop`](::HwndLoopBuilder::desktop).
+#[derive(Clone, Debug)]
+pub enum Desktop {
+  /// The desktop that currently receives user input, e.g. the secure desktop while a UAC prompt
+  /// or the lock screen is up.
+  Input,
+
+  /// A desktop in the process's window station, by name (e.g. `Default` or `Winlogon`).
+  Named(String),
+}
+
+/// A desktop that the current thread has switched to, which switches back when dropped.
+pub(crate) struct ThreadDesktop {
+  desktop: HDESK,
+  previous: HDESK,
+}
+
+impl Drop for ThreadDesktop {
+  fn drop(&mut self) {
+    unsafe {
+      if SetThreadDesktop(self.previous) == FALSE {
+        warn!("failed to restore thread desktop: {}", std::io::Error::last_os_error());
+      }
+      CloseDesktop(self.desktop);
+    }
+  }
+}
+
+/// Move the process to `window_station` and the current thread to `desktop`, before the loop
+/// creates its window.
+pub(crate) fn select(window_station: Option<&str>, desktop: Option<&Desktop>) -> Result<Option<ThreadDesktop>> {
+  if let Some(name) = window_station {
+    let winsta = unsafe { OpenWindowStationW(util::to_utf16(name).as_ptr(), FALSE, MAXIMUM_ALLOWED) };
+    if winsta.is_null() {
+      return Err(std::io::Error::last_os_error().into());
+    }
+
+    // The process keeps using the window station after this, so the handle stays open.
+    if unsafe { SetProcessWindowStation(winsta) } == FALSE {
+      return Err(std::io::Error::last_os_error().into());
+    }
+  }
+
+  let desktop = match desktop {
+    Some(desktop) => desktop,
+    None => return Ok(None),
+  };
+  let handle = unsafe {
+    match *desktop {
+      Desktop::Input => OpenInputDesktop(0, FALSE, MAXIMUM_ALLOWED),
+      Desktop::Named(ref name) => OpenDesktopW(util::to_utf16(name).as_ptr(), 0, FALSE, MAXIMUM_ALLOWED),
+    }
+  };
+  if handle.is_null() {
+    return Err(std::io::Error::last_os_error().into());
+  }
+
+  let previous = unsafe { GetThreadDesktop(GetCurrentThreadId()) };
+  if unsafe { SetThreadDesktop(handle) } == FALSE {
+    let err = std::io::Error::last_os_error();
+    unsafe { CloseDesktop(handle) };
+    return Err(err.into());
+  }
+  Ok(Some(ThreadDesktop {
+    desktop: handle,
+    previous,
+  }))
+}
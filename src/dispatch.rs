@@ -0,0 +1,95 @@
+//! Helpers for callbacks that need to know how the message they're handling was delivered.
+
+use std::time::Duration;
+
+use winapi::shared::minwindef::{DWORD, FALSE, LPARAM, LRESULT, UINT, WPARAM};
+use winapi::shared::winerror::ERROR_TIMEOUT;
+use winapi::um::winuser::{InSendMessageEx, ReplyMessage, SendMessageTimeoutW, ISMEX_REPLIED, ISMEX_SEND};
+use winapi::um::winuser::{HWND_BROADCAST, SMTO_ABORTIFHUNG, SMTO_ERRORONEXIT, WM_FONTCHANGE};
+
+use {Error, HwndWrapper, Result};
+
+/// Whether the current thread is handling a message that another thread sent with `SendMessage`,
+/// and that thread is still blocked waiting for the result.
+///
+/// Blocking on the sending thread in this state (e.g. waiting on a channel that it's supposed to
+/// feed, or flushing a loop that runs on it) deadlocks.
+pub fn in_send_message() -> bool {
+  let flags = unsafe { InSendMessageEx(std::ptr::null_mut()) };
+  flags & (ISMEX_SEND | ISMEX_REPLIED) == ISMEX_SEND
+}
+
+/// Whether the message that the current thread is handling was sent from another thread, and has
+/// already been replied to with [`reply_now`].
+pub fn has_replied() -> bool {
+  unsafe { InSendMessageEx(std::ptr::null_mut()) & ISMEX_REPLIED != 0 }
+}
+
+/// Unblock the thread that sent the message being handled, as if the window procedure had returned
+/// `result`, so that the handler can carry on with slow work.
+///
+/// Returns false if there's nobody to unblock: the message was posted or sent from the current
+/// thread, or it has already been replied to. Once this returns true, the value that the handler
+/// eventually returns is ignored.
+pub fn reply_now(result: LRESULT) -> bool {
+  if !in_send_message() {
+    return false;
+  }
+  unsafe { ReplyMessage(result) != FALSE }
+}
+
+/// Assert, in debug builds, that the current thread isn't servicing a `SendMessage` from another
+/// thread, for callbacks to call before they block on something that another thread provides.
+pub fn debug_assert_can_block() {
+  debug_assert!(
+    !in_send_message(),
+    "blocking while handling a message sent from another thread, which can deadlock"
+  );
+}
+
+/// Warn about a blocking call made while servicing a `SendMessage` from another thread.
+pub(crate) fn warn_if_in_send_message(what: &str) {
+  if cfg!(debug_assertions) && in_send_message() {
+    warn!(
+      "HwndLoop {} called while handling a message sent from another thread, which can deadlock",
+      what
+    );
+  }
+}
+
+/// Send a message to a window that might belong to another process, and wait for the result for
+/// at most `timeout`.
+///
+/// Returns [`Error::TimedOut`] if the target doesn't answer in time, or immediately if Windows
+/// considers its thread hung, so that a misbehaving process can't block the caller forever. Sent
+/// messages addressed to the calling thread are still handled while waiting. If `target` is the
+/// window of a loop that has since terminated, this fails with [`Error::Terminated`] without
+/// sending anything, rather than sending to whatever window has its handle now.
+pub fn send_request(target: HwndWrapper, msg: UINT, w: WPARAM, l: LPARAM, timeout: Duration) -> Result<LRESULT> {
+  if target.is_stale() {
+    return Err(Error::Terminated);
+  }
+  let ms = timeout.as_secs().saturating_mul(1000) + u64::from(timeout.subsec_millis());
+  let ms = std::cmp::min(ms, u64::from(DWORD::MAX - 1)) as UINT;
+  let mut result = 0;
+  let ok = unsafe { SendMessageTimeoutW(target.0, msg, w, l, SMTO_ABORTIFHUNG | SMTO_ERRORONEXIT, ms, &mut result) };
+  if ok != 0 {
+    return Ok(result as LRESULT);
+  }
+
+  let err = std::io::Error::last_os_error();
+  match err.raw_os_error() {
+    // A hung target fails without necessarily setting an error.
+    Some(code) if code == 0 || code == ERROR_TIMEOUT as i32 => Err(Error::TimedOut),
+    _ => Err(Error::Os(err)),
+  }
+}
+
+/// Tell every top-level window that the system's font table has changed, after adding or removing
+/// a font resource.
+///
+/// Each window gets a second to handle it, and windows that Windows considers hung are skipped.
+pub fn broadcast_font_change() -> Result<()> {
+  let broadcast = HwndWrapper::new(HWND_BROADCAST);
+  send_request(broadcast, WM_FONTCHANGE, 0, 0, Duration::from_secs(1)).map(|_| ())
+}
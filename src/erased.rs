@@ -0,0 +1,53 @@
+//! Commands that carry their own handlers, so that independent pieces of code can share one loop
+//! without agreeing on a single command enum.
+//!
+//! A loop built with [`ErasedCallbacks`] takes [`ErasedCommand`]s, boxed values of any type that
+//! implements [`LoopCommand`]. Each command is executed on the handler thread with the state that
+//! the callbacks were created with, which it can downcast to whatever it expects. Commands are
+//! sent with [`LoopHandle::send_erased`](::LoopHandle::send_erased), or boxed up and sent like any
+//! other command.
+
+use std::any::Any;
+
+use winapi::shared::windef::HWND;
+
+use {CommandSeq, HwndLoopCallbacks, LoopHandle, Result};
+
+/// A command that knows how to handle itself.
+pub trait LoopCommand: Send + std::fmt::Debug {
+  /// Handle the command on the handler thread, with the state that the loop's [`ErasedCallbacks`]
+  /// were created with.
+  fn execute(self: Box<Self>, state: &mut dyn Any, hwnd: HWND);
+}
+
+/// The command type of loops that take any [`LoopCommand`].
+pub type ErasedCommand = Box<dyn LoopCommand>;
+
+/// Callbacks that execute [`ErasedCommand`]s with a piece of shared state.
+///
+/// Loops that need to handle window messages as well can implement [`HwndLoopCallbacks`] for
+/// [`ErasedCommand`] themselves, and call [`LoopCommand::execute`] from
+/// [`HwndLoopCallbacks::handle_command`].
+pub struct ErasedCallbacks<State: Any + Send> {
+  state: State,
+}
+
+impl<State: Any + Send> ErasedCallbacks<State> {
+  /// Create callbacks that pass `state` to every command.
+  pub fn new(state: State) -> ErasedCallbacks<State> {
+    ErasedCallbacks { state }
+  }
+}
+
+impl<State: Any + Send> HwndLoopCallbacks<ErasedCommand> for ErasedCallbacks<State> {
+  fn handle_command(&mut self, hwnd: HWND, cmd: ErasedCommand) {
+    cmd.execute(&mut self.state, hwnd);
+  }
+}
+
+impl LoopHandle<ErasedCommand> {
+  /// Box up `cmd` and send it to the loop, like [`LoopHandle::send_command`].
+  pub fn send_erased<T: LoopCommand + 'static>(&self, cmd: T) -> Result<CommandSeq> {
+    self.send_command(Box::new(cmd))
+  }
+}
@@ -0,0 +1,77 @@
+use std::fmt;
+
+/// Errors returned by [`HwndLoop`](::HwndLoop) operations.
+#[derive(Debug)]
+pub enum Error {
+  /// The window's message queue was still full after retrying.
+  ///
+  /// Windows caps each thread's posted message queue (10,000 messages by default), and
+  /// `PostMessageW` fails with `ERROR_NOT_ENOUGH_QUOTA` once it's reached.
+  QueueSaturated,
+
+  /// The loop was told to terminate.
+  Terminated,
+
+  /// The operation can only be performed on a [`HwndLoop`](::HwndLoop)'s handler thread.
+  NotOnLoopThread,
+
+  /// The operation didn't complete in time, or its target was hung.
+  TimedOut,
+
+  /// The receiving loop didn't understand what it was sent.
+  Rejected,
+
+  /// The loop's [`try_handle_command`](::HwndLoopCallbacks::try_handle_command) failed to handle
+  /// a command sent with [`LoopHandle::call`](::LoopHandle::call).
+  Command(Box<dyn std::error::Error + Send + Sync>),
+
+  /// An underlying Win32 call failed.
+  Os(std::io::Error),
+
+  /// Loops can't run on this platform, because it isn't Windows.
+  UnsupportedPlatform,
+
+  /// The loop's thread panicked while it was starting up, with this message.
+  Panicked(String),
+}
+
+/// A specialized [`Result`](std::result::Result) type for [`HwndLoop`](::HwndLoop) operations.
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      Error::QueueSaturated => write!(f, "window message queue is saturated"),
+      Error::Terminated => write!(f, "loop was terminated"),
+      Error::NotOnLoopThread => write!(f, "not called on a loop's handler thread"),
+      Error::TimedOut => write!(f, "operation timed out"),
+      Error::Rejected => write!(f, "rejected by the receiving loop"),
+      Error::Command(ref err) => write!(f, "command failed: {}", err),
+      Error::Os(ref err) => write!(f, "{}", err),
+      Error::UnsupportedPlatform => write!(f, "event loops are only supported on Windows"),
+      Error::Panicked(ref message) => write!(f, "loop thread panicked: {}", message),
+    }
+  }
+}
+
+impl std::error::Error for Error {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match *self {
+      Error::QueueSaturated
+      | Error::Terminated
+      | Error::NotOnLoopThread
+      | Error::TimedOut
+      | Error::Rejected
+      | Error::UnsupportedPlatform
+      | Error::Panicked(_) => None,
+      Error::Command(ref err) => Some(&**err),
+      Error::Os(ref err) => Some(err),
+    }
+  }
+}
+
+impl From<std::io::Error> for Error {
+  fn from(err: std::io::Error) -> Error {
+    Error::Os(err)
+  }
+}
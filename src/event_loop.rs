@@ -0,0 +1,1853 @@
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use winapi::shared::basetsd::LONG_PTR;
+use winapi::shared::minwindef::{ATOM, DWORD, FALSE, HIWORD, LOWORD, LPARAM, LPVOID, LRESULT, UINT, WPARAM};
+use winapi::shared::windef::{HWINEVENTHOOK, HWND, POINT};
+use winapi::shared::winerror::ERROR_NOT_ENOUGH_QUOTA;
+use winapi::um::processthreadsapi::{GetCurrentThreadId, OpenThread, SetProcessShutdownParameters};
+use winapi::um::synchapi::WaitForSingleObject;
+use winapi::um::winbase::{SetThreadExecutionState, INFINITE, WAIT_FAILED, WAIT_OBJECT_0};
+use winapi::um::winnt::{ES_CONTINUOUS, LONG, SYNCHRONIZE};
+use winapi::um::winuser::*;
+
+use affinity::{self, ThreadPlacement};
+#[cfg(feature = "audio")]
+use audio::AudioNotifications;
+#[cfg(feature = "dde")]
+use dde::{self, DdeServer};
+use coalesce::Coalescer;
+use com::{self, ComGuard};
+use context::DrainHandle;
+use control::{self, ControlHook};
+use desktop::{self, ThreadDesktop};
+#[cfg(feature = "fault-injection")]
+use fault;
+use handle::{FlushRequest, KeepAwakeCounts, Shared};
+use idle::{IdleWatcher, IDLE_TIMER_ID};
+use mmcss::{self, MmcssGuard};
+use network::NetworkWatcher;
+#[cfg(feature = "serde")]
+use ipc;
+use payload::SharedPayload;
+use profile::Profiler;
+use service;
+use slots;
+use spy::{self, Spy, SpyRecord};
+#[cfg(feature = "toast")]
+use toast;
+use util;
+use winevent::{self, WinEvent, WinEventHook};
+use {AcceleratorTable, Error, HwndLoopBuilder, HwndLoopCallbacks, HwndLoopCommand, HwndWrapper, LoopHandle, Result};
+use {DropReason, Hook, MessageHook, MonitorPower, SetUpContext, TearDownContext, INTERNAL_MESSAGE_TAG};
+use {WM_HWNDLOOP_COMMAND, WM_HWNDLOOP_FLUSH, WM_HWNDLOOP_FLUSH_ALL, WM_HWNDLOOP_FLUSH_MARKER};
+use {WM_HWNDLOOP_PAUSE, WM_HWNDLOOP_PAYLOAD, WM_HWNDLOOP_RESUME, WM_HWNDLOOP_SERVICE_CONTROL};
+
+/// Upper bound on the number of messages [`LoopHandle::flush_all`] drains once it has seen its
+/// marker, so that a steady stream of new messages can't keep it from returning.
+const FLUSH_ALL_DRAIN_LIMIT: usize = 10000;
+
+/// Identifies a [`HwndLoopWndExtra`].
+const WND_EXTRA_MAGIC: usize = 0x4857_4e44;
+
+/// How long [`strict`](HwndLoopBuilder::strict) mode lets a window message take to handle before
+/// warning about it, which is the default for how long Windows waits for a low-level hook.
+const STRICT_MESSAGE_TIMEOUT: Duration = Duration::from_millis(300);
+
+/// Result of a single call to [`HwndPump::pump_once`](::HwndPump::pump_once).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PumpStatus {
+  /// There was nothing to do.
+  Idle,
+
+  /// A wakeup was processed, and there might be more waiting.
+  Processed,
+
+  /// The loop was told to terminate, and won't process anything else.
+  Terminated,
+}
+
+#[repr(C)]
+struct HwndLoopWndExtra<CommandType: Send + std::fmt::Debug + 'static> {
+  /// Always [`WND_EXTRA_MAGIC`], which [`strict`](HwndLoopBuilder::strict) mode checks on every
+  /// message to catch something else overwriting the window's pointer to this.
+  magic: usize,
+  strict: bool,
+  callbacks: *mut Box<dyn HwndLoopCallbacks<CommandType>>,
+
+  /// The loop that owns the window, if its internal messages are dispatched to the window by
+  /// someone else's message pump instead of being handled by [`EventLoop::pump`].
+  event_loop: *mut EventLoop<CommandType>,
+
+  /// Closures registered with [`LoopHandle::once`] that are still waiting for their message.
+  once: Vec<(UINT, MessageHook)>,
+
+  /// Whether the loop has an accelerator table, without which WM_COMMAND that looks like it came
+  /// from one is passed on to the callbacks like any other.
+  accelerators: bool,
+
+  /// Internal messages that someone else's message pump dispatched to the window (e.g. a modal
+  /// dialog's, while a callback is running), to be handed back to the loop's own pump.
+  strays: VecDeque<MSG>,
+
+  /// Used to wake up the loop's own pump when there are strays for it.
+  shared: Option<Arc<Shared<CommandType>>>,
+
+  /// Whether the loop's message pump is dispatching a posted message to the window.
+  dispatching: bool,
+
+  /// Whether the window has been destroyed, by someone other than the loop.
+  destroyed: bool,
+
+  /// How many calls into the window procedure are on the thread's stack, which this and the
+  /// callbacks have to outlive.
+  active: usize,
+
+  /// The loop, if one of its callbacks dropped its owner while the window procedure was running,
+  /// to be dropped once the outermost call returns.
+  orphan: Option<Box<EventLoop<CommandType>>>,
+
+  /// Where the messages that the window handles are recorded, for [`HwndLoopBuilder::spy`].
+  spy: Option<Arc<Spy>>,
+
+  /// Checks for the user going idle, when the timer fires.
+  idle: Option<IdleWatcher>,
+
+  /// Holds back messages that are coalesced, for [`HwndLoopBuilder::coalesce`].
+  coalescer: Option<Coalescer>,
+
+  /// Answers DDE conversations, if the loop is a DDE server.
+  #[cfg(feature = "dde")]
+  dde: Option<DdeServer>,
+
+  /// Where commands sent from other processes go, if the loop accepts them.
+  #[cfg(feature = "serde")]
+  ipc: Option<(ipc::Decoder, LoopHandle<CommandType>)>,
+}
+
+impl<CommandType: Send + std::fmt::Debug + 'static> HwndLoopWndExtra<CommandType> {
+  unsafe fn from_hwnd(hwnd: HWND) -> *mut HwndLoopWndExtra<CommandType> {
+    util::get_window_long_ptr(hwnd, 0) as *mut HwndLoopWndExtra<CommandType>
+  }
+}
+
+/// Marks a call into the window procedure as using the window's data and callbacks, so that a loop
+/// that's dropped by one of its own callbacks isn't torn down until the outermost call returns.
+struct ActiveGuard<CommandType: Send + std::fmt::Debug + 'static>(*mut HwndLoopWndExtra<CommandType>);
+
+impl<CommandType: Send + std::fmt::Debug + 'static> ActiveGuard<CommandType> {
+  unsafe fn enter(wnd_extra: *mut HwndLoopWndExtra<CommandType>) -> ActiveGuard<CommandType> {
+    (*wnd_extra).active += 1;
+    ActiveGuard(wnd_extra)
+  }
+}
+
+impl<CommandType: Send + std::fmt::Debug + 'static> Drop for ActiveGuard<CommandType> {
+  fn drop(&mut self) {
+    unsafe {
+      (*self.0).active -= 1;
+      if (*self.0).active == 0 {
+        // This frees the window's data, so it has to be the last thing that touches it.
+        drop((*self.0).orphan.take());
+      }
+    }
+  }
+}
+
+/// The window of a loop and the state of its message pump, owned by the thread that created it.
+pub(crate) struct EventLoop<CommandType: Send + std::fmt::Debug + 'static> {
+  shared: Arc<Shared<CommandType>>,
+  hwnd: HWND,
+  window_class: ATOM,
+  callbacks: *mut Box<dyn HwndLoopCallbacks<CommandType>>,
+  wnd_extra: *mut HwndLoopWndExtra<CommandType>,
+  paused: bool,
+  deferred: VecDeque<Deferred<CommandType>>,
+
+  /// User commands held back by a [`DeferGuard`](::DeferGuard), to be handled once the last one
+  /// is dropped.
+  held: VecDeque<HwndLoopCommand<CommandType>>,
+
+  /// The sequence number of the last command taken off of the queue.
+  dequeued_seq: u64,
+
+  /// The last flush completed for each thread, for [`strict`](HwndLoopBuilder::strict) mode.
+  completed_flushes: HashMap<DWORD, usize>,
+
+  /// The markers of outer [`flush_all`](LoopHandle::flush_all) drains that a nested drain came
+  /// across, so that the outer ones know to stop once they get control back.
+  consumed_markers: HashSet<usize>,
+  terminated: bool,
+
+  /// Whether a callback called [`request_shutdown`].
+  shutdown_requested: bool,
+  translate_messages: bool,
+  filter: Option<MessageFilter>,
+  accelerators: Option<AcceleratorTable>,
+  dialogs: Vec<HWND>,
+  user_slots: usize,
+  teardown_hooks: Vec<Hook>,
+
+  /// Where the callbacks go instead of being destroyed, for
+  /// [`HwndLoop::shutdown_into_inner`](::HwndLoop::shutdown_into_inner).
+  recover: Option<Sender<Box<dyn HwndLoopCallbacks<CommandType>>>>,
+  win_event_hooks: Vec<WinEventHook>,
+
+  /// Hands back internal messages posted to the thread that someone else's pump retrieved, for
+  /// [`HwndLoopBuilder::thread_control`].
+  control_hook: Option<ControlHook>,
+
+  /// The flags last passed to `SetThreadExecutionState`, for [`LoopHandle::keep_awake`].
+  execution_state: DWORD,
+
+  /// Switches the thread back to its original desktop, once the window is gone.
+  _desktop: Option<ThreadDesktop>,
+
+  /// Uninitializes COM, once everything else is gone.
+  _com: Option<ComGuard>,
+
+  /// Restores the thread's processor affinity.
+  _placement: Option<ThreadPlacement>,
+
+  /// Reverts the thread's MMCSS registration.
+  _mmcss: Option<MmcssGuard>,
+
+  network: Option<NetworkWatcher>,
+
+  #[cfg(feature = "audio")]
+  audio: Option<AudioNotifications>,
+
+  /// Whether WinRT has been initialized for toasts, and still needs to be uninitialized.
+  #[cfg(feature = "toast")]
+  winrt: Option<bool>,
+}
+
+/// Restricts which window messages the loop retrieves, as in the arguments to `GetMessage`.
+#[derive(Clone, Copy)]
+struct MessageFilter {
+  hwnd: HWND,
+  min: UINT,
+  max: UINT,
+}
+
+/// Something that arrived while the loop was paused, to be handled once it's resumed.
+enum Deferred<CommandType: Send + std::fmt::Debug + 'static> {
+  Command(HwndLoopCommand<CommandType>),
+  Message(MSG),
+}
+
+/// Type-erased access to the [`EventLoop`] running on the current thread, for [`run_nested`],
+/// [`request_shutdown`], and [`set_exit_value`].
+trait NestedLoop {
+  fn run_nested(&mut self, until: &mut dyn FnMut() -> bool) -> Result<()>;
+  fn request_shutdown(&mut self);
+  fn set_exit_value(&mut self, value: Box<dyn Any + Send>);
+}
+
+thread_local! {
+  static CURRENT_LOOP: Cell<Option<*mut dyn NestedLoop>> = Cell::new(None);
+
+  /// A broken invariant that [`strict`](HwndLoopBuilder::strict) mode found in the window
+  /// procedure, where panicking would abort the process, for the loop's own pump to panic with.
+  static STRICT_VIOLATION: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Panic with whatever [`strict`](HwndLoopBuilder::strict) mode found in the window procedure.
+fn raise_strict_violation() {
+  if let Some(violation) = STRICT_VIOLATION.with(|violation| violation.borrow_mut().take()) {
+    panic!("{}", violation);
+  }
+}
+
+/// Restores the previous value of [`CURRENT_LOOP`] when dropped.
+struct CurrentLoopGuard(Option<*mut dyn NestedLoop>);
+
+impl Drop for CurrentLoopGuard {
+  fn drop(&mut self) {
+    CURRENT_LOOP.with(|current| current.set(self.0));
+  }
+}
+
+/// Pump messages on a loop's handler thread until `until` returns true.
+///
+/// This is meant to be called from inside a callback that needs to block on something that itself
+/// requires messages to be pumped (e.g. a COM call into a single-threaded apartment, or a modal
+/// dialog). Commands, flushes, and window messages keep being handled while it runs, which means
+/// that callbacks can be reentered. `until` is checked before blocking and after every message,
+/// so a condition that's satisfied by another thread should be followed by a command or a flush to
+/// wake the loop up.
+///
+/// Returns [`Error::Terminated`] if the loop was told to terminate while pumping, in which case
+/// the callback should return promptly, or [`Error::NotOnLoopThread`] if called from a thread
+/// other than a handler thread.
+pub fn run_nested<F: FnMut() -> bool>(mut until: F) -> Result<()> {
+  match CURRENT_LOOP.with(|current| current.get()) {
+    Some(event_loop) => unsafe { (*event_loop).run_nested(&mut until) },
+    None => Err(Error::NotOnLoopThread),
+  }
+}
+
+/// Terminate the loop whose callback is running on this thread, once the callback returns.
+///
+/// This is for loops that decide to wind themselves down, e.g. because the device they exist to
+/// serve has gone away. Unlike [`LoopHandle::terminate`](::LoopHandle::terminate), it doesn't wait
+/// behind the commands that are already queued: those are passed to
+/// [`HwndLoopCallbacks::handle_dropped_command`](::HwndLoopCallbacks::handle_dropped_command),
+/// and [`HwndLoopCallbacks::tear_down`](::HwndLoopCallbacks::tear_down) runs as usual. Any
+/// [`HwndLoop`](::HwndLoop) for the loop is left with a terminated loop.
+///
+/// Returns [`Error::NotOnLoopThread`] if called from a thread other than a handler thread, which
+/// includes [`HwndLoopCallbacks::set_up`](::HwndLoopCallbacks::set_up), since the loop isn't
+/// running yet.
+pub fn request_shutdown() -> Result<()> {
+  match CURRENT_LOOP.with(|current| current.get()) {
+    Some(event_loop) => {
+      unsafe { (*event_loop).request_shutdown() };
+      Ok(())
+    }
+    None => Err(Error::NotOnLoopThread),
+  }
+}
+
+/// Leave `value` for [`HwndLoop::join`](::HwndLoop::join) to hand to the loop's owner once the
+/// loop has terminated, replacing whatever was left before.
+///
+/// This is how a loop can report why it ended, e.g. along with [`request_shutdown`], without
+/// setting up a channel of its own. Returns [`Error::NotOnLoopThread`] if called from a thread
+/// other than a handler thread, as with [`request_shutdown`].
+pub fn set_exit_value<V: Any + Send>(value: V) -> Result<()> {
+  match CURRENT_LOOP.with(|current| current.get()) {
+    Some(event_loop) => {
+      unsafe { (*event_loop).set_exit_value(Box::new(value)) };
+      Ok(())
+    }
+    None => Err(Error::NotOnLoopThread),
+  }
+}
+
+impl<CommandType: Send + std::fmt::Debug + 'static> NestedLoop for EventLoop<CommandType> {
+  fn run_nested(&mut self, until: &mut dyn FnMut() -> bool) -> Result<()> {
+    if self.terminated {
+      return Err(Error::Terminated);
+    }
+    if self.pump(until) {
+      Ok(())
+    } else {
+      Err(Error::Terminated)
+    }
+  }
+
+  fn request_shutdown(&mut self) {
+    self.shutdown_requested = true;
+
+    // Messages sent from other threads are handled while the pump is waiting, so it might not
+    // have anything else to wake up for.
+    let _ = self.shared.wake_event.set();
+  }
+
+  fn set_exit_value(&mut self, value: Box<dyn Any + Send>) {
+    *self.shared.exit_value.lock().unwrap() = Some(value);
+  }
+}
+
+/// Whether `msg` is one of the messages that handles post to drive the loop.
+pub(crate) fn is_internal_message(msg: UINT) -> bool {
+  msg == *WM_HWNDLOOP_COMMAND
+    || msg == *WM_HWNDLOOP_FLUSH
+    || msg == *WM_HWNDLOOP_FLUSH_ALL
+    || msg == *WM_HWNDLOOP_FLUSH_MARKER
+    || msg == *WM_HWNDLOOP_PAUSE
+    || msg == *WM_HWNDLOOP_RESUME
+}
+
+lazy_static! {
+  /// Distinguishes the window classes of loops created on the same thread.
+  static ref WINDOW_CLASS_SEQ: AtomicUsize = AtomicUsize::new(0);
+}
+
+impl<CommandType: Send + std::fmt::Debug + 'static> EventLoop<CommandType> {
+  /// Create the loop's window on the current thread, and set up its callbacks.
+  pub(crate) fn new(
+    config: &HwndLoopBuilder,
+    callbacks: Box<dyn HwndLoopCallbacks<CommandType>>,
+  ) -> Result<EventLoop<CommandType>> {
+    #[cfg(feature = "serde")]
+    let ipc = config.ipc_decoder.is_some();
+    #[cfg(not(feature = "serde"))]
+    let ipc = false;
+    let timers = config.idle_threshold.is_some() || !config.coalesce.is_empty();
+    if config.windowless && (config.receive_broadcasts || config.user_slots > 0 || ipc || timers) {
+      return Err(
+        std::io::Error::new(
+          std::io::ErrorKind::InvalidInput,
+          "windowless loops can't receive broadcasts, have user slots, accept IPC commands, detect idle, or \
+           coalesce messages",
+        )
+        .into(),
+      );
+    }
+
+    let class_name = util::to_utf16(&format!(
+      "RawInputRS{}-{}",
+      unsafe { GetCurrentThreadId() },
+      WINDOW_CLASS_SEQ.fetch_add(1, Ordering::SeqCst)
+    ));
+    let wndclass = WNDCLASSEXW {
+      cbSize: std::mem::size_of::<WNDCLASSEXW>() as UINT,
+      style: 0,
+      lpfnWndProc: Some(EventLoop::<CommandType>::wnd_proc),
+      // A LONG_PTR for the class magic, and one for the wnd_extra pointer followed by each slot,
+      // which are 4 bytes each on 32-bit Windows and 8 on 64-bit.
+      cbClsExtra: std::mem::size_of::<LONG_PTR>() as i32,
+      cbWndExtra: ((1 + config.user_slots) * std::mem::size_of::<LONG_PTR>()) as i32,
+      hInstance: util::get_module_handle(),
+      hIcon: std::ptr::null_mut(),
+      hCursor: std::ptr::null_mut(),
+      hbrBackground: std::ptr::null_mut(),
+      lpszMenuName: std::ptr::null_mut(),
+      lpszClassName: class_name.as_ptr(),
+      hIconSm: std::ptr::null_mut(),
+    };
+
+    let wake_event = util::Event::new()?;
+    if let Some(level) = config.shutdown_level {
+      if unsafe { SetProcessShutdownParameters(level, 0) } == FALSE {
+        return Err(std::io::Error::last_os_error().into());
+      }
+    }
+    let placement = if config.affinity.is_some() || config.ideal_processor.is_some() {
+      Some(affinity::apply(config.affinity.as_ref(), config.ideal_processor)?)
+    } else {
+      None
+    };
+    let mmcss = match config.mmcss_task {
+      Some(ref task) => Some(mmcss::register(task)?),
+      None => None,
+    };
+    let desktop = desktop::select(config.window_station.as_deref(), config.desktop.as_ref())?;
+    let com = match config.com_apartment {
+      Some(apartment) => Some(com::initialize(apartment)?),
+      None => None,
+    };
+    let network = if config.network_notifications {
+      Some(NetworkWatcher::new()?)
+    } else {
+      None
+    };
+
+    // Loops whose internal messages go to the thread keep it from exiting, so that nothing else can
+    // get its ID while handles can still post to it. Its message queue exists before any handles
+    // do, since it's created by the window, or by peeking below for windowless loops.
+    let thread = if config.windowless || config.thread_control {
+      Some(util::OwnedHandle::check(unsafe {
+        OpenThread(SYNCHRONIZE, FALSE, GetCurrentThreadId())
+      })?)
+    } else {
+      None
+    };
+
+    // Windowless loops don't need a window class either.
+    let window_class = if config.windowless {
+      0
+    } else {
+      match unsafe { RegisterClassExW(&wndclass) } {
+        0 => return Err(std::io::Error::last_os_error().into()),
+        atom => atom,
+      }
+    };
+
+    let spy = config.spy.map(|capacity| Arc::new(Spy::new(capacity)));
+
+    // Set up the callbacks to be called from wnd_proc. They're installed by WM_NCCREATE, so that
+    // they see every message that the window receives.
+    let callbacks = Box::into_raw(Box::new(callbacks));
+    let strict = config.strict && cfg!(debug_assertions);
+    let wnd_extra = Box::into_raw(Box::new(HwndLoopWndExtra {
+      magic: WND_EXTRA_MAGIC,
+      strict,
+      callbacks,
+      event_loop: std::ptr::null_mut(),
+      once: Vec::new(),
+      accelerators: false,
+      strays: VecDeque::new(),
+      shared: None,
+      dispatching: false,
+      destroyed: false,
+      active: 0,
+      orphan: None,
+      spy: spy.clone(),
+      idle: None,
+      coalescer: None,
+      #[cfg(feature = "dde")]
+      dde: config
+        .dde_server
+        .as_ref()
+        .map(|(application, topics)| DdeServer::new(application, topics)),
+      #[cfg(feature = "serde")]
+      ipc: None,
+    }));
+    let control_hook = if config.thread_control {
+      match ControlHook::install(unsafe { &mut (*wnd_extra).strays }, wake_event.handle()) {
+        Ok(hook) => Some(hook),
+        Err(err) => {
+          unsafe {
+            if window_class != 0 {
+              UnregisterClassW(util::atom_to_lpwstr(window_class), util::get_module_handle());
+            }
+            drop(Box::from_raw(wnd_extra));
+            drop(Box::from_raw(callbacks));
+          }
+          return Err(err);
+        }
+      }
+    } else {
+      None
+    };
+
+    let hwnd = if config.windowless {
+      // Make sure that the thread has a message queue for flushes to be posted to, since there's
+      // no window to create one.
+      let mut msg: MSG = unsafe { std::mem::zeroed() };
+      unsafe { PeekMessageW(&mut msg, std::ptr::null_mut(), WM_USER, WM_USER, PM_NOREMOVE) };
+      std::ptr::null_mut()
+    } else {
+      let hwnd = unsafe {
+        CreateWindowExW(
+          WS_EX_NOREDIRECTIONBITMAP,
+          util::atom_to_lpwstr(window_class),
+          util::to_utf16("rawinput window").as_ptr(),
+          0,
+          CW_USEDEFAULT,
+          CW_USEDEFAULT,
+          CW_USEDEFAULT,
+          CW_USEDEFAULT,
+          // Message-only windows don't see broadcasts. A top-level window stays hidden, since nothing shows it.
+          if config.receive_broadcasts { std::ptr::null_mut() } else { HWND_MESSAGE },
+          std::ptr::null_mut(),
+          util::get_module_handle(),
+          wnd_extra as LPVOID,
+        )
+      };
+
+      if hwnd.is_null() {
+        let err = std::io::Error::last_os_error();
+        unsafe {
+          UnregisterClassW(util::atom_to_lpwstr(window_class), util::get_module_handle());
+          drop(Box::from_raw(wnd_extra));
+          drop(Box::from_raw(callbacks));
+        }
+        return Err(err.into());
+      }
+      unsafe { util::set_class_long_ptr(hwnd, 0, slots::CLASS_MAGIC) };
+      hwnd
+    };
+
+    let shared = Arc::new(Shared {
+      hwnd: if hwnd.is_null() { HwndWrapper::new(hwnd) } else { HwndWrapper::issue(hwnd) },
+      thread_id: unsafe { GetCurrentThreadId() },
+      thread_control: config.thread_control,
+      _thread: thread,
+      strict,
+      command_queue: Mutex::new(VecDeque::new()),
+      urgent_queue: Mutex::new(VecDeque::new()),
+      defer_count: AtomicUsize::new(0),
+      buffered: AtomicUsize::new(0),
+      next_seq: AtomicU64::new(0),
+      processed_seq: AtomicU64::new(0),
+      seq_waiters: AtomicUsize::new(0),
+      seq_lock: Mutex::new(()),
+      seq_cond: Condvar::new(),
+      flush_requests: Mutex::new(VecDeque::<FlushRequest>::new()),
+      flush_seq: AtomicUsize::new(0),
+      wake_debt: AtomicUsize::new(0),
+      wake_event,
+      saturations: AtomicUsize::new(0),
+      terminated: AtomicBool::new(false),
+      created: Instant::now(),
+      last_dispatch_us: AtomicU64::new(0),
+      preempt_at_us: AtomicU64::new(u64::MAX),
+      exit_value: Mutex::new(None),
+      input_pending: AtomicBool::new(false),
+      events: config.events.map(|(new_channel, policy)| new_channel(policy)),
+      timer_periods: Mutex::new(HashMap::new()),
+      keep_awake: Mutex::new(KeepAwakeCounts::default()),
+      spy,
+      profiler: if config.profile { Some(Profiler::new()) } else { None },
+    });
+
+    unsafe { (*wnd_extra).shared = Some(shared.clone()) };
+    if !config.coalesce.is_empty() {
+      unsafe { (*wnd_extra).coalescer = Some(Coalescer::new(hwnd, &config.coalesce)) };
+    }
+    if let Some(threshold) = config.idle_threshold {
+      match IdleWatcher::new(hwnd, threshold) {
+        Ok(watcher) => unsafe { (*wnd_extra).idle = Some(watcher) },
+        Err(err) => {
+          shared.hwnd.retire();
+          unsafe {
+            util::set_window_long_ptr(hwnd, 0, 0);
+            DestroyWindow(hwnd);
+            UnregisterClassW(util::atom_to_lpwstr(window_class), util::get_module_handle());
+            drop(Box::from_raw(wnd_extra));
+            drop(Box::from_raw(callbacks));
+          }
+          return Err(err);
+        }
+      }
+    }
+    #[cfg(feature = "serde")]
+    unsafe {
+      (*wnd_extra).ipc = config.ipc_decoder.map(|decode| (decode, LoopHandle { shared: shared.clone() }));
+    }
+
+    let context = SetUpContext {
+      handle: LoopHandle { shared: shared.clone() },
+      thread_id: unsafe { GetCurrentThreadId() },
+      user_data: config.user_data.clone(),
+      events: shared.events.clone(),
+    };
+    unsafe { (*callbacks).set_up(hwnd, &context) };
+    for hook in &config.start_hooks {
+      (hook.0)(hwnd);
+    }
+
+    #[allow(unused_mut)]
+    let mut event_loop = EventLoop {
+      shared,
+      hwnd,
+      window_class,
+      callbacks,
+      wnd_extra,
+      paused: false,
+      held: VecDeque::new(),
+      deferred: VecDeque::new(),
+      dequeued_seq: 0,
+      completed_flushes: HashMap::new(),
+      consumed_markers: HashSet::new(),
+      terminated: false,
+      shutdown_requested: false,
+      translate_messages: config.translate_messages,
+      filter: if config.message_filter.is_some() || config.hwnd_filter.is_some() {
+        let (min, max) = config.message_filter.unwrap_or((0, 0));
+        Some(MessageFilter {
+          hwnd: config.hwnd_filter.as_ref().map_or(std::ptr::null_mut(), |hwnd| hwnd.0),
+          min,
+          max,
+        })
+      } else {
+        None
+      },
+      accelerators: None,
+      dialogs: Vec::new(),
+      user_slots: config.user_slots,
+      teardown_hooks: Vec::new(),
+      recover: None,
+      win_event_hooks: Vec::new(),
+      control_hook,
+      execution_state: ES_CONTINUOUS,
+      _desktop: desktop,
+      _com: com,
+      _placement: placement,
+      _mmcss: mmcss,
+      network,
+      #[cfg(feature = "audio")]
+      audio: None,
+      #[cfg(feature = "toast")]
+      winrt: None,
+    };
+
+    // Tearing down after a failure here goes through the usual path, now that the loop is set up.
+    #[cfg(feature = "audio")]
+    {
+      if config.audio_notifications {
+        let handle = event_loop.handle();
+        event_loop.audio = Some(AudioNotifications::register(Box::new(move |event| {
+          if let Err(err) = handle.send_command_internal(HwndLoopCommand::Audio(event), false) {
+            warn!("failed to deliver audio event: {}", err);
+          }
+        }))?);
+      }
+    }
+
+    Ok(event_loop)
+  }
+
+  /// Create a loop whose internal messages are handled by its window procedure, for use with a
+  /// message pump that the caller doesn't control.
+  pub(crate) fn new_external(
+    config: &HwndLoopBuilder,
+    callbacks: Box<dyn HwndLoopCallbacks<CommandType>>,
+  ) -> Result<Box<EventLoop<CommandType>>> {
+    if config.windowless || config.thread_control {
+      return Err(
+        std::io::Error::new(
+          std::io::ErrorKind::InvalidInput,
+          "windowless loops and loops with thread control can't be pumped externally",
+        )
+        .into(),
+      );
+    }
+
+    // The window procedure keeps a pointer to the loop, so it needs a stable address.
+    let mut event_loop = Box::new(EventLoop::new(config, callbacks)?);
+    let ptr: *mut EventLoop<CommandType> = &mut *event_loop;
+    unsafe { (*event_loop.wnd_extra).event_loop = ptr };
+    Ok(event_loop)
+  }
+
+  pub(crate) fn handle(&self) -> LoopHandle<CommandType> {
+    LoopHandle {
+      shared: self.shared.clone(),
+    }
+  }
+
+  /// Drop the loop, unless its owner is being dropped by one of its callbacks, in which case
+  /// that's left to the window procedure once the callback has returned.
+  pub(crate) fn release(self: Box<Self>) {
+    let wnd_extra = self.wnd_extra;
+    if unsafe { (*wnd_extra).active } > 0 {
+      debug!("HwndLoop dropped while its window procedure is running, deferring teardown");
+      unsafe { (*wnd_extra).orphan = Some(self) };
+    }
+  }
+
+  pub(crate) fn is_terminated(&self) -> bool {
+    self.terminated
+  }
+
+  /// Make this the loop that [`run_nested`] pumps, until the returned guard is dropped.
+  fn enter(&mut self) -> CurrentLoopGuard {
+    let ptr: *mut dyn NestedLoop = self;
+    CurrentLoopGuard(CURRENT_LOOP.with(|current| current.replace(Some(ptr))))
+  }
+
+  #[cfg(feature = "toast")]
+  fn show_toast(&mut self, builder: &toast::ToastBuilder) -> Result<()> {
+    if self.winrt.is_none() {
+      self.winrt = Some(toast::initialize()?);
+    }
+
+    // Activations are raised on a WinRT thread, so bounce them back to this one.
+    let activated = builder.on_activated.clone().map(|f| {
+      let handle = self.handle();
+      Box::new(move |arguments: String| {
+        let f = f.clone();
+        let hook = Hook(Box::new(move |hwnd| f(hwnd, arguments)));
+        if let Err(err) = handle.send_command_internal(HwndLoopCommand::Run(hook), false) {
+          warn!("failed to deliver toast activation: {}", err);
+        }
+      }) as Box<dyn Fn(String) + Send + Sync>
+    });
+    toast::show(builder, activated)
+  }
+
+  /// Pump messages until the loop is told to terminate.
+  pub(crate) fn run(&mut self) {
+    let _guard = self.enter();
+    self.pump(&mut || false);
+  }
+
+  /// Process at most one wakeup without blocking.
+  pub(crate) fn pump_once(&mut self) -> PumpStatus {
+    if self.terminated {
+      return PumpStatus::Terminated;
+    }
+
+    let _guard = self.enter();
+    let processed = if unsafe { !(*self.wnd_extra).strays.is_empty() } {
+      self.replay_strays();
+      true
+    } else if self.service_waits() {
+      true
+    } else if unsafe { WaitForSingleObject(self.shared.wake_event.handle(), 0) } == WAIT_OBJECT_0 {
+      self.dispatch_commands(0);
+      true
+    } else {
+      let mut msg: MSG = unsafe { std::mem::zeroed() };
+      if self.peek_message(&mut msg) {
+        self.process_message(&msg);
+        raise_strict_violation();
+        true
+      } else {
+        false
+      }
+    };
+
+    if self.terminated {
+      PumpStatus::Terminated
+    } else if processed {
+      PumpStatus::Processed
+    } else {
+      PumpStatus::Idle
+    }
+  }
+
+  /// Pump messages until `until` returns true, or return false if the loop was told to terminate.
+  fn pump(&mut self, until: &mut dyn FnMut() -> bool) -> bool {
+    let mut msg: MSG = unsafe { std::mem::zeroed() };
+    while !until() {
+      // Other handles stay signalled until they're serviced, so leave them out while paused.
+      let mut handles = [self.shared.wake_event.handle(); 3];
+      let mut count = 1;
+      if let Some(ref network) = self.network {
+        if !self.paused {
+          handles[1..].copy_from_slice(&network.handles());
+          count = 3;
+        }
+      }
+
+      let wait = unsafe {
+        MsgWaitForMultipleObjectsEx(
+          count,
+          handles.as_ptr(),
+          INFINITE,
+          QS_ALLINPUT,
+          // Messages that don't pass the filter stay in the queue, so only wake up for new ones.
+          if self.filter.is_some() { 0 } else { MWMO_INPUTAVAILABLE },
+        )
+      };
+      if wait == WAIT_FAILED {
+        panic!("MsgWaitForMultipleObjectsEx failed: {}", std::io::Error::last_os_error());
+      }
+
+      // Pokes that couldn't be posted because the queue was full are signalled via the wake
+      // event instead, and are also paid off by the next poke or flush that makes it through.
+      if !self.dispatch_commands(0) || !self.replay_strays() || self.terminated {
+        return false;
+      }
+
+      self.service_waits();
+      if self.terminated {
+        return false;
+      }
+
+      while self.peek_message(&mut msg) {
+        // A nested loop run by a callback might have been the one to see the terminate command.
+        let processed = self.process_message(&msg);
+        raise_strict_violation();
+        if !processed || self.terminated {
+          return false;
+        }
+        if until() {
+          return true;
+        }
+      }
+    }
+    true
+  }
+
+  /// Process the internal messages that were dispatched to the window by someone else's message
+  /// pump, returning false if one of them told the loop to terminate.
+  fn replay_strays(&mut self) -> bool {
+    loop {
+      let msg = match unsafe { (*self.wnd_extra).strays.pop_front() } {
+        Some(msg) => msg,
+        None => return true,
+      };
+      if !self.process_message(&msg) {
+        return false;
+      }
+    }
+  }
+
+  /// Handle whatever the handles that the loop waits on besides its wake event have signalled,
+  /// returning whether anything was delivered.
+  fn service_waits(&mut self) -> bool {
+    if self.paused {
+      return false;
+    }
+
+    let events = match self.network {
+      Some(ref mut network) => network.poll(),
+      None => return false,
+    };
+    for event in &events {
+      unsafe { (*self.callbacks).handle_network_event(self.hwnd, event.clone()) };
+    }
+    self.honor_shutdown_request();
+    !events.is_empty()
+  }
+
+  /// Where the loop's internal messages are posted: its window, or its thread if it doesn't have
+  /// one or was built with [`HwndLoopBuilder::thread_control`].
+  fn control_hwnd(&self) -> HWND {
+    if self.shared.thread_control {
+      std::ptr::null_mut()
+    } else {
+      self.hwnd
+    }
+  }
+
+  /// Remove the next message that the loop should process from the queue, if there is one.
+  fn peek_message(&self, msg: &mut MSG) -> bool {
+    control::own_pump(|| self.peek_message_filtered(msg))
+  }
+
+  fn peek_message_filtered(&self, msg: &mut MSG) -> bool {
+    let filter = match self.filter {
+      None => return unsafe { PeekMessageW(msg, std::ptr::null_mut(), 0, 0, PM_REMOVE) } != FALSE,
+      Some(filter) => filter,
+    };
+
+    // Our own messages are exempt from the filter. They're all registered messages, so look at the
+    // first one in that range to keep them in the order they were posted.
+    let control = self.control_hwnd();
+    if unsafe { PeekMessageW(msg, control, 0xC000, 0xFFFF, PM_NOREMOVE) } != FALSE {
+      if is_internal_message(msg.message) {
+        return unsafe { PeekMessageW(msg, control, msg.message, msg.message, PM_REMOVE) } != FALSE;
+      }
+
+      // Something else is in the way, so fall back to looking for each of ours individually.
+      for &internal in &[
+        *WM_HWNDLOOP_COMMAND,
+        *WM_HWNDLOOP_FLUSH,
+        *WM_HWNDLOOP_FLUSH_ALL,
+        *WM_HWNDLOOP_FLUSH_MARKER,
+        *WM_HWNDLOOP_PAUSE,
+        *WM_HWNDLOOP_RESUME,
+      ] {
+        if unsafe { PeekMessageW(msg, control, internal, internal, PM_REMOVE) } != FALSE {
+          return true;
+        }
+      }
+    }
+
+    unsafe { PeekMessageW(msg, filter.hwnd, filter.min, filter.max, PM_REMOVE) != FALSE }
+  }
+
+  /// Handle a message retrieved from the queue, returning false if the loop was told to terminate.
+  fn process_message(&mut self, msg: &MSG) -> bool {
+    #[cfg(feature = "fault-injection")]
+    fault::delay();
+    let result = self.process_message_inner(msg);
+    self.note_dispatch();
+    self.honor_shutdown_request();
+    result && !self.terminated
+  }
+
+  /// Terminate the loop if a callback asked for it with [`request_shutdown`], or if its window was
+  /// destroyed out from under it and nothing else could reach it.
+  fn honor_shutdown_request(&mut self) {
+    let requested = std::mem::replace(&mut self.shutdown_requested, false);
+    if self.terminated {
+      return;
+    }
+    if unsafe { (*self.wnd_extra).destroyed } && !self.shared.thread_control {
+      debug!("HwndLoop shutting down because its window was destroyed");
+      self.handle_command(HwndLoopCommand::Terminate);
+    } else if requested {
+      debug!("HwndLoop shutting down at the request of a callback");
+      self.handle_command(HwndLoopCommand::Terminate);
+    }
+  }
+
+  fn process_message_inner(&mut self, msg: &MSG) -> bool {
+    if is_internal_message(msg.message) && msg.lParam != *INTERNAL_MESSAGE_TAG {
+      warn!("HwndLoop ignoring internal message {:#x} that it didn't post", msg.message);
+      true
+    } else if msg.message == *WM_HWNDLOOP_COMMAND {
+      // Only process commands when we receive a poke, to ensure that we maintain ordering.
+      self.dispatch_commands(1)
+    } else if msg.message == *WM_HWNDLOOP_FLUSH {
+      if !self.dispatch_commands(0) {
+        return false;
+      }
+      self.complete_flush(msg.wParam, Ok(()));
+      true
+    } else if msg.message == *WM_HWNDLOOP_FLUSH_ALL {
+      if !self.dispatch_commands(0) {
+        return false;
+      }
+      match self.drain_queue(msg.wParam) {
+        Ok(true) => {
+          self.complete_flush(msg.wParam, Ok(()));
+          true
+        }
+        Ok(false) => false,
+        Err(err) => {
+          self.complete_flush(msg.wParam, Err(err));
+          true
+        }
+      }
+    } else if msg.message == *WM_HWNDLOOP_FLUSH_MARKER {
+      // A marker for a flush_all that was interrupted by termination, or for an outer drain
+      // while we're nested inside of another one, which has to hear that it's been reached.
+      self.consumed_markers.insert(msg.wParam);
+      true
+    } else if msg.message == *WM_HWNDLOOP_PAUSE {
+      if !self.dispatch_commands(0) {
+        return false;
+      }
+      self.paused = true;
+      self.complete_flush(msg.wParam, Ok(()));
+      true
+    } else if msg.message == *WM_HWNDLOOP_RESUME {
+      if !self.dispatch_commands(0) {
+        return false;
+      }
+      self.complete_flush(msg.wParam, Ok(()));
+      self.resume()
+    } else if msg.message == WM_QUIT {
+      panic!("HwndLoop received WM_QUIT");
+    } else if self.paused {
+      self.deferred.push_back(Deferred::Message(*msg));
+      true
+    } else {
+      self.dispatch_message(msg);
+      true
+    }
+  }
+
+  /// Hand a window message off to its window procedure.
+  fn dispatch_message(&mut self, msg: &MSG) {
+    // Without a window, the thread's own messages go straight to the callbacks.
+    if self.hwnd.is_null() && msg.hwnd.is_null() {
+      let start = Instant::now();
+      unsafe { (*self.callbacks).try_handle_message(msg.hwnd, msg.message, msg.wParam, msg.lParam) };
+      if let Some(ref profiler) = self.shared.profiler {
+        profiler.record_message(msg.message, start.elapsed());
+      }
+      return;
+    }
+
+    if let Some(ref table) = self.accelerators {
+      if unsafe { TranslateAcceleratorW(self.hwnd, table.as_raw(), msg as *const MSG as *mut MSG) } != 0 {
+        return;
+      }
+    }
+
+    // Forget about dialogs that were destroyed without being unregistered.
+    self.dialogs.retain(|&dialog| unsafe { IsWindow(dialog) } != FALSE);
+    for &dialog in &self.dialogs {
+      if unsafe { IsDialogMessageW(dialog, msg as *const MSG as *mut MSG) } != FALSE {
+        return;
+      }
+    }
+    if self.translate_messages {
+      unsafe { TranslateMessage(msg) };
+    }
+    unsafe {
+      (*self.wnd_extra).dispatching = msg.hwnd == self.hwnd;
+      DispatchMessageW(msg);
+      (*self.wnd_extra).dispatching = false;
+    }
+  }
+
+  /// Replay everything that was deferred while the loop was paused, returning false if a deferred
+  /// command told the loop to terminate.
+  fn resume(&mut self) -> bool {
+    self.paused = false;
+    self.dispatch_urgent();
+    self.release_held(false);
+    while let Some(deferred) = self.deferred.pop_front() {
+      match deferred {
+        Deferred::Command(_) if self.shared.preempting() => {
+          self.deferred.push_front(deferred);
+          return self.preempt();
+        }
+        Deferred::Command(cmd) => {
+          self.shared.buffered.fetch_sub(cmd.user_count(), Ordering::SeqCst);
+          if !self.handle_command(cmd) {
+            return false;
+          }
+        }
+        Deferred::Message(msg) => self.dispatch_message(&msg),
+      }
+    }
+    if !self.holding() {
+      self.shared.note_processed(self.dequeued_seq);
+    }
+    true
+  }
+
+  /// Handle `count` queued commands, plus any whose pokes were dropped because the message queue
+  /// was full, returning false if the loop was told to terminate.
+  fn dispatch_commands(&mut self, count: usize) -> bool {
+    let count = count + self.shared.wake_debt.swap(0, Ordering::SeqCst);
+    if self.shared.preempting() {
+      return self.preempt();
+    }
+    self.honor_shutdown_request();
+    if self.terminated {
+      return false;
+    }
+    self.dispatch_urgent();
+    self.release_held(false);
+    for _ in 0..count {
+      // The owner ran out of patience with whatever's left.
+      if self.shared.preempting() {
+        return self.preempt();
+      }
+      let entry = {
+        let mut queue = self.shared.command_queue.lock().unwrap();
+        let entry = queue.pop_front();
+        // Commands can be cancelled out from under their pokes, so once the queue is empty, every
+        // number that's been handed out is accounted for.
+        if queue.is_empty() {
+          self.dequeued_seq = self.shared.next_seq.load(Ordering::SeqCst);
+        } else if let Some((seq, _)) = entry.as_ref() {
+          self.dequeued_seq = *seq;
+        }
+        entry
+      };
+      let (seq, cmd) = match entry {
+        Some(entry) => entry,
+        None => {
+          if !self.paused && !self.holding() {
+            self.shared.note_processed(self.dequeued_seq);
+          }
+          break;
+        }
+      };
+      trace!("HwndLoop received command #{}: {:?}", seq, cmd);
+      #[cfg(feature = "fault-injection")]
+      fault::delay();
+      let result = self.handle_command(cmd);
+      if !self.paused && !self.holding() {
+        self.shared.note_processed(self.dequeued_seq);
+      }
+      self.note_dispatch();
+      self.honor_shutdown_request();
+      if !result || self.terminated {
+        return false;
+      }
+
+      // Urgent commands can show up while we're working through a batch.
+      self.dispatch_urgent();
+    }
+    true
+  }
+
+  /// Terminate without handling anything else, once the grace period of a
+  /// [`TerminatePolicy::Preempt`](::TerminatePolicy::Preempt) has run out. Commands that were
+  /// deferred or held back are dropped, and the rest of the queue goes the same way once the loop
+  /// has terminated.
+  fn preempt(&mut self) -> bool {
+    debug!("HwndLoop preempting {} queued commands", self.shared.command_queue.lock().unwrap().len());
+    self.paused = false;
+    let deferred = std::mem::take(&mut self.deferred).into_iter().filter_map(|deferred| match deferred {
+      Deferred::Command(cmd) => Some(cmd),
+      Deferred::Message(_) => None,
+    });
+    for cmd in deferred.chain(std::mem::take(&mut self.held)) {
+      self.shared.buffered.fetch_sub(cmd.user_count(), Ordering::SeqCst);
+      self.drop_command(cmd, DropReason::Terminated);
+    }
+    self.terminated = true;
+    false
+  }
+
+  /// Handle the commands sent with [`LoopHandle::send_urgent_command`], unless the loop is paused,
+  /// commands are being held, or the loop has already been told to terminate.
+  fn dispatch_urgent(&mut self) {
+    while !self.paused && !self.holding() && !self.terminated {
+      let cmd = match self.shared.urgent_queue.lock().unwrap().pop_front() {
+        Some(cmd) => cmd,
+        None => break,
+      };
+      trace!("HwndLoop received urgent command: {:?}", cmd);
+      self.handle_command(HwndLoopCommand::UserCommand(cmd));
+      self.note_dispatch();
+      self.honor_shutdown_request();
+    }
+  }
+
+  /// Whether a [`DeferGuard`](::DeferGuard) is holding back user commands.
+  fn holding(&self) -> bool {
+    self.shared.defer_count.load(Ordering::SeqCst) > 0
+  }
+
+  /// Set a command aside until the loop is resumed.
+  fn defer_command(&mut self, cmd: HwndLoopCommand<CommandType>) {
+    self.shared.buffered.fetch_add(cmd.user_count(), Ordering::SeqCst);
+    self.deferred.push_back(Deferred::Command(cmd));
+  }
+
+  /// Handle the user commands that were held back by [`DeferGuard`](::DeferGuard)s, once the last
+  /// one is gone, or regardless if `force` is set.
+  fn release_held(&mut self, force: bool) {
+    if self.held.is_empty() || self.paused || (self.holding() && !force) {
+      return;
+    }
+
+    while let Some(cmd) = self.held.pop_front() {
+      self.shared.buffered.fetch_sub(cmd.user_count(), Ordering::SeqCst);
+      self.run_user_command(cmd);
+      self.note_dispatch();
+      // A command might have taken another guard, which keeps the rest held.
+      if self.holding() && !force {
+        return;
+      }
+    }
+    self.shared.note_processed(self.dequeued_seq);
+  }
+
+  /// Update the loop's [`status`](LoopHandle::status) after handling something.
+  fn note_dispatch(&self) {
+    // GetQueueStatus only works on the calling thread's queue, so other threads have to rely on
+    // what we saw last.
+    let status = unsafe { GetQueueStatus(QS_ALLINPUT) };
+    self.shared.note_dispatch(HIWORD(status) != 0);
+  }
+
+  /// Hand a command sent by the user to the callbacks.
+  fn run_user_command(&mut self, cmd: HwndLoopCommand<CommandType>) {
+    match cmd {
+      HwndLoopCommand::UserCommand(cmd) => {
+        if let Err(err) = self.try_handle_command(cmd) {
+          warn!("HwndLoop command failed: {}", err);
+        }
+      }
+      HwndLoopCommand::Expiring(cmd, deadline) => {
+        if Instant::now() > deadline {
+          self.drop_command(HwndLoopCommand::UserCommand(cmd), DropReason::Expired);
+        } else {
+          self.run_user_command(HwndLoopCommand::UserCommand(cmd));
+        }
+      }
+      HwndLoopCommand::Call(cmd, tx) => {
+        let result = self.try_handle_command(cmd);
+        let _ = tx.send(result.map_err(Error::Command));
+      }
+      HwndLoopCommand::Transaction(cmds, tx) => {
+        // Everything gets handled regardless, and the first failure is reported.
+        let mut result = Ok(());
+        for cmd in cmds {
+          let cmd_result = self.try_handle_command(cmd);
+          if result.is_ok() {
+            result = cmd_result.map_err(Error::Command);
+          }
+        }
+        let _ = tx.send(result);
+      }
+      cmd => unreachable!("HwndLoop treating {:?} as a user command", cmd),
+    }
+  }
+
+  /// Hand a user command to the callbacks, timing it if the loop is being profiled.
+  fn try_handle_command(
+    &mut self,
+    cmd: CommandType,
+  ) -> std::result::Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let profiler = match self.shared.profiler {
+      Some(ref profiler) => profiler,
+      None => return unsafe { (*self.callbacks).try_handle_command(self.hwnd, cmd) },
+    };
+    let discriminant = std::mem::discriminant(&cmd);
+    let start = Instant::now();
+    let result = unsafe { (*self.callbacks).try_handle_command(self.hwnd, cmd) };
+    profiler.record_command(discriminant, start.elapsed());
+    result
+  }
+
+  /// Handle a single command, returning false if it told the loop to terminate.
+  fn handle_command(&mut self, cmd: HwndLoopCommand<CommandType>) -> bool {
+    match cmd {
+      HwndLoopCommand::Terminate => {
+        // Don't strand anything that was deferred by a pause the owner never undid, or held back by
+        // a guard that's still around.
+        if self.paused {
+          self.resume();
+        }
+        self.release_held(true);
+        self.terminated = true;
+        false
+      }
+
+      HwndLoopCommand::Shutdown(tx) => {
+        self.recover = Some(tx);
+        self.handle_command(HwndLoopCommand::Terminate)
+      }
+
+      cmd @ (HwndLoopCommand::UserCommand(_)
+      | HwndLoopCommand::Expiring(..)
+      | HwndLoopCommand::Call(..)
+      | HwndLoopCommand::Transaction(..)) => {
+        if self.paused {
+          self.defer_command(cmd);
+        } else if self.holding() {
+          self.shared.buffered.fetch_add(cmd.user_count(), Ordering::SeqCst);
+          self.held.push_back(cmd);
+        } else {
+          self.run_user_command(cmd);
+        }
+        true
+      }
+
+      cmd @ HwndLoopCommand::Dropped(..) => {
+        self.drop_command(cmd, DropReason::Cancelled);
+        true
+      }
+
+      HwndLoopCommand::SetAccelerators(table) => {
+        unsafe { (*self.wnd_extra).accelerators = table.is_some() };
+        self.accelerators = table;
+        true
+      }
+
+      HwndLoopCommand::RegisterDialog(dialog) => {
+        if !self.dialogs.contains(&dialog.0) {
+          self.dialogs.push(dialog.0);
+        }
+        true
+      }
+
+      HwndLoopCommand::UnregisterDialog(dialog) => {
+        self.dialogs.retain(|&hwnd| hwnd != dialog.0);
+        true
+      }
+
+      HwndLoopCommand::OnTeardown(hook) => {
+        self.teardown_hooks.push(hook);
+        true
+      }
+
+      HwndLoopCommand::Once(msg, hook) => {
+        unsafe { (*self.wnd_extra).once.push((msg, hook)) };
+        true
+      }
+
+      HwndLoopCommand::HookWinEvents(min, max, tx) => {
+        let hook = WinEventHook::install(self.hwnd, min, max, Some(EventLoop::<CommandType>::win_event_proc));
+        let _ = tx.send(hook.map(|hook| self.win_event_hooks.push(hook)));
+        true
+      }
+
+      HwndLoopCommand::UpdateExecutionState => {
+        let state = self.shared.keep_awake.lock().unwrap().execution_state();
+        if state != self.execution_state {
+          if unsafe { SetThreadExecutionState(state) } == 0 {
+            warn!("failed to set HwndLoop execution state to {:#x}", state);
+          }
+          self.execution_state = state;
+        }
+        true
+      }
+
+      #[cfg(feature = "toast")]
+      HwndLoopCommand::ShowToast(builder, tx) => {
+        let _ = tx.send(self.show_toast(&builder));
+        true
+      }
+
+      #[cfg(feature = "audio")]
+      HwndLoopCommand::Audio(event) => {
+        if self.paused {
+          self.defer_command(HwndLoopCommand::Audio(event));
+        } else {
+          unsafe { (*self.callbacks).handle_audio_event(self.hwnd, event) };
+        }
+        true
+      }
+
+      #[cfg(feature = "toast")]
+      HwndLoopCommand::Run(hook) => {
+        if self.paused {
+          self.defer_command(HwndLoopCommand::Run(hook));
+        } else {
+          (hook.0)(self.hwnd);
+        }
+        true
+      }
+
+      HwndLoopCommand::SetSlot(key, value) => {
+        if let Err(err) = slots::replace_slot(self.hwnd, key, value) {
+          warn!("failed to set HwndLoop slot {}: {}", key, err);
+        }
+        true
+      }
+    }
+  }
+
+  /// Process everything that's already in the thread's message queue, including messages that
+  /// Windows only synthesizes once the posted messages have run out (input, WM_PAINT, WM_TIMER).
+  ///
+  /// Returns false if the loop was told to terminate while draining.
+  fn drain_queue(&mut self, id: usize) -> Result<bool> {
+    // Anything posted before this marker was already in the queue when the flush was requested.
+    let target = self.control_hwnd();
+    if unsafe { PostMessageW(target, *WM_HWNDLOOP_FLUSH_MARKER, id, *INTERNAL_MESSAGE_TAG) } == FALSE {
+      let err = std::io::Error::last_os_error();
+      if err.raw_os_error() == Some(ERROR_NOT_ENOUGH_QUOTA as i32) {
+        return Err(Error::QueueSaturated);
+      }
+      return Err(Error::Os(err));
+    }
+
+    let mut msg: MSG = unsafe { std::mem::zeroed() };
+    loop {
+      // A nested drain might have come across our marker while handling something for us.
+      if self.consumed_markers.remove(&id) {
+        break;
+      }
+      if !self.peek_message(&mut msg) {
+        if unsafe { WaitMessage() } == FALSE {
+          panic!("WaitMessage failed: {}", std::io::Error::last_os_error());
+        }
+        continue;
+      }
+      if msg.message == *WM_HWNDLOOP_FLUSH_MARKER && msg.wParam == id {
+        break;
+      }
+      if !self.process_message(&msg) {
+        return Ok(false);
+      }
+    }
+
+    for _ in 0..FLUSH_ALL_DRAIN_LIMIT {
+      if !self.peek_message(&mut msg) {
+        break;
+      }
+      if !self.process_message(&msg) {
+        return Ok(false);
+      }
+    }
+    Ok(true)
+  }
+
+  /// Make handles fail from now on, and release anyone who's still waiting on a flush.
+  fn fail_pending(&self) {
+    // This has to happen in this order, so that a flush can't sneak in after we've emptied the
+    // requests.
+    self.shared.terminated.store(true, Ordering::SeqCst);
+    for req in self.shared.flush_requests.lock().unwrap().drain(..) {
+      let _ = req.tx.send(Err(Error::Terminated));
+    }
+    self.shared.wake_seq_waiters();
+
+    // Commands that never got handled are dropped along with whatever their senders are waiting
+    // on, e.g. the channel of a call or transaction, which tells them that the loop is gone.
+    let urgent = std::mem::take(&mut *self.shared.urgent_queue.lock().unwrap());
+    let unhandled = std::mem::take(&mut *self.shared.command_queue.lock().unwrap());
+    for cmd in urgent {
+      self.drop_command(HwndLoopCommand::UserCommand(cmd), DropReason::Terminated);
+    }
+    for (_, cmd) in unhandled {
+      self.drop_command(cmd, DropReason::Terminated);
+    }
+  }
+
+  /// Pass the user's commands in `cmd` to [`HwndLoopCallbacks::handle_dropped_command`], instead of
+  /// handling them.
+  fn drop_command(&self, cmd: HwndLoopCommand<CommandType>, reason: DropReason) {
+    let (cmds, reason) = match cmd {
+      HwndLoopCommand::UserCommand(cmd) | HwndLoopCommand::Expiring(cmd, _) | HwndLoopCommand::Call(cmd, _) => {
+        (vec![cmd], reason)
+      }
+      HwndLoopCommand::Transaction(cmds, _) => (cmds, reason),
+      HwndLoopCommand::Dropped(cmds, original) => (cmds, original),
+      // Internal commands don't carry anything of the user's.
+      _ => return,
+    };
+    for cmd in cmds {
+      unsafe { (*self.callbacks).handle_dropped_command(self.hwnd, cmd, reason) };
+    }
+  }
+
+  /// Dispatch window messages until `handle` is finished or `timeout` runs out, for a drain
+  /// requested by [`HwndLoopCallbacks::tear_down`].
+  fn drain_after_tear_down(&mut self, timeout: Duration, handle: &DrainHandle) {
+    let deadline = Instant::now() + timeout;
+    let mut msg: MSG = unsafe { std::mem::zeroed() };
+    while !handle.is_finished() {
+      let now = Instant::now();
+      if now >= deadline {
+        debug!("HwndLoop teardown drain timed out");
+        return;
+      }
+
+      let remaining = deadline - now;
+      let ms = remaining.as_secs() * 1000 + u64::from(remaining.subsec_millis()) + 1;
+      let wait = unsafe {
+        MsgWaitForMultipleObjectsEx(0, std::ptr::null(), ms as DWORD, QS_ALLINPUT, MWMO_INPUTAVAILABLE)
+      };
+      if wait == WAIT_FAILED {
+        panic!("MsgWaitForMultipleObjectsEx failed: {}", std::io::Error::last_os_error());
+      }
+
+      while !handle.is_finished() && self.peek_message(&mut msg) {
+        // Nobody's waiting on our own messages anymore.
+        if !is_internal_message(msg.message) && msg.message != WM_QUIT {
+          self.dispatch_message(&msg);
+        }
+      }
+    }
+  }
+
+  /// Release the flush identified by `id`.
+  fn complete_flush(&mut self, id: usize, result: Result<()>) {
+    // Flushes can be posted in a different order than they were queued in, so release the one
+    // that this message was posted for, rather than whichever is at the front.
+    let mut reqs = self.shared.flush_requests.lock().unwrap();
+    let req = match reqs.iter().position(|req| req.id == id) {
+      Some(index) => reqs.remove(index).unwrap(),
+      None => {
+        warn!("HwndLoop received flush {}, which nobody is waiting for", id);
+        return;
+      }
+    };
+    if self.shared.strict {
+      // A thread waits for each of its flushes before making another, so they can't overtake.
+      if let Some(last) = self.completed_flushes.insert(req.thread, id) {
+        assert!(last < id, "HwndLoop flush {} completed after flush {} from the same thread", id, last);
+      }
+    }
+    req.tx.send(result).unwrap();
+  }
+
+  unsafe extern "system" fn wnd_proc(hwnd: HWND, msg: UINT, w: WPARAM, l: LPARAM) -> LRESULT {
+    if msg == WM_NCCREATE {
+      let create = &*(l as *const CREATESTRUCTW);
+      util::set_window_long_ptr(hwnd, 0, create.lpCreateParams as LONG_PTR);
+    }
+
+    let wnd_extra = HwndLoopWndExtra::<CommandType>::from_hwnd(hwnd);
+    if wnd_extra.is_null() {
+      return util::def_window_proc(hwnd, msg, w, l);
+    }
+    if (*wnd_extra).strict && (*wnd_extra).magic != WND_EXTRA_MAGIC {
+      error!("HwndLoop window data was overwritten");
+      STRICT_VIOLATION.with(|violation| {
+        violation.borrow_mut().get_or_insert_with(|| "HwndLoop window data was overwritten".to_string());
+      });
+      return util::def_window_proc(hwnd, msg, w, l);
+    }
+    let _active = ActiveGuard::enter(wnd_extra);
+    if msg == WM_NCDESTROY {
+      EventLoop::<CommandType>::window_destroyed(wnd_extra, hwnd);
+    }
+    // Whatever the window is sent while handling this wasn't posted.
+    let posted = std::mem::replace(&mut (*wnd_extra).dispatching, false);
+
+    // Someone else's message pump dispatched one of our messages to us.
+    let event_loop = (*wnd_extra).event_loop;
+    if !event_loop.is_null() && is_internal_message(msg) {
+      if !(*event_loop).terminated {
+        let msg = MSG {
+          hwnd,
+          message: msg,
+          wParam: w,
+          lParam: l,
+          time: 0,
+          pt: POINT { x: 0, y: 0 },
+        };
+        let _guard = (*event_loop).enter();
+        (*event_loop).process_message(&msg);
+      }
+      if (*event_loop).terminated {
+        (*event_loop).fail_pending();
+      }
+      return 0;
+    }
+
+    // Our own messages never reach the callbacks. If they got here, someone else's message pump
+    // dispatched them, or someone sent them directly.
+    if is_internal_message(msg) {
+      if l != *INTERNAL_MESSAGE_TAG {
+        warn!("HwndLoop ignoring internal message {:#x} that it didn't post", msg);
+      } else {
+        (*wnd_extra).strays.push_back(MSG {
+          hwnd,
+          message: msg,
+          wParam: w,
+          lParam: l,
+          time: 0,
+          pt: POINT { x: 0, y: 0 },
+        });
+        if let Some(ref shared) = (*wnd_extra).shared {
+          let _ = shared.wake_event.set();
+        }
+      }
+      return 0;
+    }
+
+    // Coalesced messages are held back, and the latest one is handled once its timer fires. It has
+    // to be kept around until then, since its LPARAM can point at a copy that it owns.
+    let released = match (*wnd_extra).coalescer {
+      Some(ref mut coalescer) => {
+        if coalescer.hold(msg, w, l) {
+          return 0;
+        }
+        if msg == WM_TIMER {
+          coalescer.release(w)
+        } else {
+          None
+        }
+      }
+      None => None,
+    };
+    let (msg, w, l) = released.as_ref().map_or((msg, w, l), |held| (held.msg, held.w, held.l));
+
+    match (*wnd_extra).spy.clone() {
+      Some(spy) => {
+        let origin = spy::origin(posted, msg, w);
+        let time = Instant::now();
+        let result = EventLoop::<CommandType>::handle_window_message(wnd_extra, hwnd, msg, w, l);
+        spy.record(SpyRecord {
+          time,
+          hwnd: HwndWrapper::new(hwnd),
+          msg,
+          wparam: w,
+          lparam: l,
+          origin,
+          duration: time.elapsed(),
+        });
+        result
+      }
+      None => EventLoop::<CommandType>::handle_window_message(wnd_extra, hwnd, msg, w, l),
+    }
+  }
+
+  /// Something other than the loop destroyed its window, since the loop takes its data off of the
+  /// window before destroying it itself. Take it off here instead, so that nothing can find it
+  /// through a handle that's since been reused, and shut the loop down unless its messages go to
+  /// its thread, which reaps the callbacks once it's dropped. They still get the WM_NCDESTROY
+  /// that's being handled.
+  unsafe fn window_destroyed(wnd_extra: *mut HwndLoopWndExtra<CommandType>, hwnd: HWND) {
+    debug!("HwndLoop window {:?} was destroyed out from under the loop", hwnd);
+    (*wnd_extra).destroyed = true;
+    util::set_window_long_ptr(hwnd, 0, 0);
+    if let Some(ref shared) = (*wnd_extra).shared {
+      shared.hwnd.retire();
+      let _ = shared.wake_event.set();
+    }
+
+    // Nothing else will dispatch to a loop whose messages only ever went to its window.
+    let event_loop = (*wnd_extra).event_loop;
+    if !event_loop.is_null() {
+      (*event_loop).honor_shutdown_request();
+      if (*event_loop).terminated {
+        (*event_loop).fail_pending();
+      }
+    }
+  }
+
+  /// Handle a message for the window that isn't one of the loop's own.
+  unsafe fn handle_window_message(
+    wnd_extra: *mut HwndLoopWndExtra<CommandType>,
+    hwnd: HWND,
+    msg: UINT,
+    w: WPARAM,
+    l: LPARAM,
+  ) -> LRESULT {
+    if (*wnd_extra).once.iter().any(|&(once, _)| once == msg) {
+      // Take them out before running any, in case they register more.
+      let (matched, rest) = std::mem::take(&mut (*wnd_extra).once)
+        .into_iter()
+        .partition::<Vec<_>, _>(|&(once, _)| once == msg);
+      (*wnd_extra).once = rest;
+      for (_, hook) in matched {
+        (hook.0)(hwnd, msg, w, l);
+      }
+    }
+
+    #[cfg(feature = "serde")]
+    {
+      if msg == WM_COPYDATA {
+        if let Some((decode, ref handle)) = (*wnd_extra).ipc {
+          if let Some(data) = ipc::payload(l) {
+            return match decode(data).map(|cmd| cmd.downcast::<CommandType>()) {
+              Some(Ok(cmd)) => handle.send_command(*cmd).is_ok() as LRESULT,
+              Some(Err(_)) => {
+                warn!(
+                  "rejecting IPC command that isn't a {}, since the loop was built for a different command type",
+                  std::any::type_name::<CommandType>()
+                );
+                0
+              }
+              None => 0,
+            };
+          }
+        }
+      }
+    }
+
+    if msg == *WM_HWNDLOOP_PAYLOAD {
+      match SharedPayload::open(w, l) {
+        Ok(payload) => (*(*wnd_extra).callbacks).handle_payload(hwnd, payload),
+        Err(err) => warn!("failed to open payload {}-{}: {}", w, l, err),
+      }
+      return 0;
+    }
+
+    if msg == *WM_HWNDLOOP_SERVICE_CONTROL {
+      if let Some(control) = service::take_control(l) {
+        return (*(*wnd_extra).callbacks).handle_service_control(hwnd, control) as LRESULT;
+      }
+    }
+
+    if msg == WM_QUERYENDSESSION {
+      return (*(*wnd_extra).callbacks).handle_query_end_session(hwnd, l) as LRESULT;
+    }
+
+    if msg == WM_ENDSESSION {
+      (*(*wnd_extra).callbacks).handle_end_session(hwnd, w != 0, l);
+      return 0;
+    }
+
+    if msg == WM_TIMER && w == IDLE_TIMER_ID {
+      if let Some(event) = (*wnd_extra).idle.as_mut().and_then(IdleWatcher::poll) {
+        (*(*wnd_extra).callbacks).handle_idle_event(hwnd, event);
+      }
+      return 0;
+    }
+
+    #[cfg(feature = "dde")]
+    {
+      if (dde::WM_DDE_INITIATE..=dde::WM_DDE_LAST).contains(&msg) {
+        if let Some(ref mut server) = (*wnd_extra).dde {
+          let callbacks = (*wnd_extra).callbacks;
+          return server.handle_message(hwnd, msg, w, l, |topic, command| {
+            (*callbacks).handle_dde_execute(hwnd, topic, command)
+          });
+        }
+      }
+    }
+
+    if msg == WM_SYSCOMMAND {
+      // The low four bits of the command are used internally by the system.
+      let allow = match w & 0xfff0 {
+        SC_SCREENSAVE => Some((*(*wnd_extra).callbacks).handle_screensaver_request(hwnd)),
+        SC_MONITORPOWER => {
+          let state = match l {
+            1 => MonitorPower::LowPower,
+            2 => MonitorPower::Off,
+            _ => MonitorPower::On,
+          };
+          Some((*(*wnd_extra).callbacks).handle_monitor_power_request(hwnd, state))
+        }
+        _ => None,
+      };
+      if allow == Some(false) {
+        return 0;
+      }
+    }
+
+    if msg == WM_FONTCHANGE {
+      (*(*wnd_extra).callbacks).handle_font_change(hwnd);
+      return 0;
+    }
+
+    // Accelerators show up as WM_COMMAND with a high word of 1 and no control.
+    if (*wnd_extra).accelerators && msg == WM_COMMAND && HIWORD(w as DWORD) == 1 && l == 0 {
+      (*(*wnd_extra).callbacks).handle_accelerator(hwnd, LOWORD(w as DWORD));
+      return 0;
+    }
+
+    let start = Instant::now();
+    let result = match (*(*wnd_extra).callbacks).try_handle_message(hwnd, msg, w, l) {
+      Some(result) => result,
+      None => util::def_window_proc(hwnd, msg, w, l),
+    };
+    let elapsed = start.elapsed();
+    if let Some(profiler) = (*wnd_extra).shared.as_ref().and_then(|shared| shared.profiler.as_ref()) {
+      profiler.record_message(msg, elapsed);
+    }
+    // Not worth a panic, since sitting at a breakpoint takes just as long.
+    if (*wnd_extra).strict && elapsed > STRICT_MESSAGE_TIMEOUT {
+      warn!("HwndLoop took {:?} to handle message {:#x}", elapsed, msg);
+    }
+    result
+  }
+
+  unsafe extern "system" fn win_event_proc(
+    hook: HWINEVENTHOOK,
+    event: DWORD,
+    hwnd: HWND,
+    object: LONG,
+    child: LONG,
+    _thread: DWORD,
+    _time: DWORD,
+  ) {
+    let loop_hwnd = match winevent::loop_window(hook) {
+      Some(loop_hwnd) => loop_hwnd,
+      None => return,
+    };
+    let wnd_extra = HwndLoopWndExtra::<CommandType>::from_hwnd(loop_hwnd);
+    if !wnd_extra.is_null() {
+      let _active = ActiveGuard::enter(wnd_extra);
+      let event = WinEvent::from_raw(event, hwnd, object, child);
+      (*(*wnd_extra).callbacks).handle_win_event(loop_hwnd, event);
+    }
+  }
+}
+
+impl<CommandType: Send + std::fmt::Debug + 'static> Drop for EventLoop<CommandType> {
+  fn drop(&mut self) {
+    self.fail_pending();
+    self.shared.release_timer_periods();
+
+    #[cfg(feature = "audio")]
+    {
+      self.audio = None;
+    }
+
+    self.win_event_hooks.clear();
+    if self.execution_state != ES_CONTINUOUS {
+      unsafe { SetThreadExecutionState(ES_CONTINUOUS) };
+    }
+
+    let mut context = TearDownContext::default();
+    unsafe { (*self.callbacks).tear_down(self.hwnd, &mut context) };
+    while let Some(hook) = self.teardown_hooks.pop() {
+      (hook.0)(self.hwnd);
+    }
+    if let Some((timeout, handle)) = context.drain {
+      self.drain_after_tear_down(timeout, &handle);
+    }
+
+    let destroyed = unsafe { (*self.wnd_extra).destroyed };
+    if !self.hwnd.is_null() && !destroyed {
+      slots::clear_slots(self.hwnd, self.user_slots);
+
+      // Remove the callbacks from the window.
+      unsafe { util::set_window_long_ptr(self.hwnd, 0, 0) };
+    }
+
+    // The hook hands messages to the window's data, which goes away along with the callbacks.
+    self.control_hook = None;
+
+    // Destroy the callbacks, unless someone asked for them back.
+    unsafe {
+      drop(Box::from_raw(self.wnd_extra));
+      let callbacks = Box::from_raw(self.callbacks);
+      if let Some(tx) = self.recover.take() {
+        let _ = tx.send(*callbacks);
+      }
+    }
+    if let Some(ref events) = self.shared.events {
+      events.close();
+    }
+
+    // Destroy the window, once nothing can mistake whatever gets its handle next for it, unless
+    // something else already did.
+    if !self.hwnd.is_null() {
+      self.shared.hwnd.retire();
+      if !destroyed {
+        unsafe { assert_ne!(FALSE, DestroyWindow(self.hwnd)) };
+      }
+    }
+
+    #[cfg(feature = "toast")]
+    {
+      if self.winrt == Some(true) {
+        toast::uninitialize();
+      }
+    }
+
+    // Destroy the window class.
+    if self.window_class != 0 {
+      unsafe {
+        assert_ne!(
+          FALSE,
+          UnregisterClassW(util::atom_to_lpwstr(self.window_class), util::get_module_handle())
+        )
+      };
+    }
+  }
+}
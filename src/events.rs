@@ -0,0 +1,308 @@
+use std::any::Any;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use {Error, Result};
+
+/// What an [`EventEmitter`] does when an [`EventReceiver`] falls behind.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Backpressure {
+  /// Buffer as many events as it takes.
+  #[default]
+  Unbounded,
+
+  /// Block the emitter while the receiver has this many events waiting, which is at least 1.
+  ///
+  /// Emitting from a callback then stalls the loop until the receiver catches up, so this should
+  /// only be used for receivers that are known to keep up.
+  Block(usize),
+
+  /// Keep only this many of the most recent events, dropping the oldest ones.
+  DropOldest(usize),
+
+  /// Stop buffering once this many events are waiting, dropping new ones until the receiver
+  /// catches up.
+  DropNewest(usize),
+}
+
+impl Backpressure {
+  /// The policy to actually apply, since a receiver that can't hold a single event would block the
+  /// emitter forever.
+  fn normalize(self) -> Backpressure {
+    match self {
+      Backpressure::Block(0) => Backpressure::Block(1),
+      policy => policy,
+    }
+  }
+}
+
+/// The events waiting for one receiver.
+struct Queue<E> {
+  events: VecDeque<E>,
+  policy: Backpressure,
+  dropped: u64,
+}
+
+struct Subscribers<E> {
+  queues: HashMap<usize, Queue<E>>,
+  next_id: usize,
+  dropped: u64,
+  closed: bool,
+}
+
+impl<E> Subscribers<E> {
+  /// Whether an emitter has to wait for a receiver before adding another event.
+  fn must_block(&self) -> bool {
+    self.queues.values().any(|queue| match queue.policy {
+      Backpressure::Block(capacity) => queue.events.len() >= capacity,
+      _ => false,
+    })
+  }
+}
+
+struct Broadcast<E> {
+  subscribers: Mutex<Subscribers<E>>,
+
+  /// Signalled when an event is added, removed, or the loop goes away.
+  cond: Condvar,
+}
+
+/// The channel behind a loop's events, created for each loop built with
+/// [`HwndLoopBuilder::events`](::HwndLoopBuilder::events).
+pub(crate) struct EventChannel<E: Clone + Send + 'static> {
+  broadcast: Arc<Broadcast<E>>,
+
+  /// A receiver that exists from the start, so that the owner doesn't miss anything that happens
+  /// before it gets around to calling [`LoopHandle::event_receiver`](::LoopHandle::event_receiver).
+  first: Mutex<Option<EventReceiver<E>>>,
+}
+
+/// Creates an [`EventChannel`] of some type, whose first receiver has the given policy.
+pub(crate) type NewChannel = fn(Backpressure) -> Arc<dyn Events>;
+
+/// An [`EventChannel`] of some type.
+pub(crate) trait Events: Send + Sync {
+  fn as_any(&self) -> &dyn Any;
+
+  /// Let receivers know that no more events are coming.
+  fn close(&self);
+
+  /// Number of events dropped by all receivers, past and present.
+  fn dropped(&self) -> u64;
+}
+
+impl<E: Clone + Send + 'static> Events for EventChannel<E> {
+  fn as_any(&self) -> &dyn Any {
+    self
+  }
+
+  fn close(&self) {
+    self.broadcast.subscribers.lock().unwrap().closed = true;
+    self.broadcast.cond.notify_all();
+  }
+
+  fn dropped(&self) -> u64 {
+    self.broadcast.subscribers.lock().unwrap().dropped
+  }
+}
+
+impl<E: Clone + Send + 'static> EventChannel<E> {
+  pub(crate) fn new_erased(policy: Backpressure) -> Arc<dyn Events> {
+    let broadcast = Arc::new(Broadcast {
+      subscribers: Mutex::new(Subscribers {
+        queues: HashMap::<usize, Queue<E>>::new(),
+        next_id: 0,
+        dropped: 0,
+        closed: false,
+      }),
+      cond: Condvar::new(),
+    });
+    let first = EventReceiver::new(broadcast.clone(), policy);
+    Arc::new(EventChannel {
+      broadcast,
+      first: Mutex::new(Some(first)),
+    })
+  }
+
+  pub(crate) fn downcast(events: &dyn Events) -> Option<&EventChannel<E>> {
+    events.as_any().downcast_ref::<EventChannel<E>>()
+  }
+
+  pub(crate) fn emitter(&self) -> EventEmitter<E> {
+    EventEmitter {
+      broadcast: self.broadcast.clone(),
+    }
+  }
+
+  pub(crate) fn take_receiver(&self) -> Option<EventReceiver<E>> {
+    self.first.lock().unwrap().take()
+  }
+
+  pub(crate) fn subscribe(&self, policy: Backpressure) -> EventReceiver<E> {
+    EventReceiver::new(self.broadcast.clone(), policy)
+  }
+}
+
+/// Sends events out of a loop's callbacks, to every [`EventReceiver`] subscribed to them.
+///
+/// Callbacks get one from [`SetUpContext::event_emitter`](::SetUpContext::event_emitter).
+pub struct EventEmitter<E: Clone + Send + 'static> {
+  broadcast: Arc<Broadcast<E>>,
+}
+
+impl<E: Clone + Send + 'static> Clone for EventEmitter<E> {
+  fn clone(&self) -> EventEmitter<E> {
+    EventEmitter {
+      broadcast: self.broadcast.clone(),
+    }
+  }
+}
+
+impl<E: Clone + Send + 'static> EventEmitter<E> {
+  /// Send an event to every receiver, failing with [`Error::Terminated`] if the loop is gone.
+  ///
+  /// Events that nobody is subscribed to are dropped. Receivers that have fallen behind are dealt
+  /// with according to their [`Backpressure`] policy, so this only blocks if one of them asked
+  /// for that.
+  pub fn emit(&self, event: E) -> Result<()> {
+    let mut subscribers = self.broadcast.subscribers.lock().unwrap();
+    while !subscribers.closed && subscribers.must_block() {
+      subscribers = self.broadcast.cond.wait(subscribers).unwrap();
+    }
+    if subscribers.closed {
+      return Err(Error::Terminated);
+    }
+
+    let mut dropped = 0;
+    for queue in subscribers.queues.values_mut() {
+      match queue.policy {
+        Backpressure::DropOldest(capacity) => {
+          if queue.events.len() >= capacity {
+            queue.events.pop_front();
+            queue.dropped += 1;
+            dropped += 1;
+          }
+          if capacity == 0 {
+            continue;
+          }
+        }
+        Backpressure::DropNewest(capacity) => {
+          if queue.events.len() >= capacity {
+            queue.dropped += 1;
+            dropped += 1;
+            continue;
+          }
+        }
+        Backpressure::Unbounded | Backpressure::Block(_) => {}
+      }
+      queue.events.push_back(event.clone());
+    }
+    subscribers.dropped += dropped;
+    self.broadcast.cond.notify_all();
+    Ok(())
+  }
+}
+
+/// Receives the events that a loop's callbacks send with an [`EventEmitter`].
+///
+/// Every receiver gets every event sent after it subscribed, at its own pace, subject to its
+/// [`Backpressure`] policy.
+pub struct EventReceiver<E: Clone + Send + 'static> {
+  broadcast: Arc<Broadcast<E>>,
+  id: usize,
+}
+
+impl<E: Clone + Send + 'static> EventReceiver<E> {
+  fn new(broadcast: Arc<Broadcast<E>>, policy: Backpressure) -> EventReceiver<E> {
+    let policy = policy.normalize();
+    let id = {
+      let mut subscribers = broadcast.subscribers.lock().unwrap();
+      let id = subscribers.next_id;
+      subscribers.next_id += 1;
+      subscribers.queues.insert(
+        id,
+        Queue {
+          events: VecDeque::new(),
+          policy,
+          dropped: 0,
+        },
+      );
+      id
+    };
+    EventReceiver { broadcast, id }
+  }
+
+  /// Take the next event from our queue, if there is one.
+  fn next(&self, subscribers: &mut MutexGuard<Subscribers<E>>) -> Option<E> {
+    let event = subscribers.queues.get_mut(&self.id).unwrap().events.pop_front();
+    if event.is_some() {
+      // Let blocked emitters know that there's room.
+      self.broadcast.cond.notify_all();
+    }
+    event
+  }
+
+  /// Block until the next event, failing with [`Error::Terminated`] once the loop is gone and every
+  /// event has been received.
+  pub fn recv(&self) -> Result<E> {
+    let mut subscribers = self.broadcast.subscribers.lock().unwrap();
+    loop {
+      if let Some(event) = self.next(&mut subscribers) {
+        return Ok(event);
+      }
+      if subscribers.closed {
+        return Err(Error::Terminated);
+      }
+      subscribers = self.broadcast.cond.wait(subscribers).unwrap();
+    }
+  }
+
+  /// Get the next event if there is one, without blocking.
+  pub fn try_recv(&self) -> Result<Option<E>> {
+    let mut subscribers = self.broadcast.subscribers.lock().unwrap();
+    match self.next(&mut subscribers) {
+      Some(event) => Ok(Some(event)),
+      None if subscribers.closed => Err(Error::Terminated),
+      None => Ok(None),
+    }
+  }
+
+  /// Block until the next event for at most `timeout`.
+  pub fn recv_timeout(&self, timeout: Duration) -> Result<E> {
+    let deadline = Instant::now() + timeout;
+    let mut subscribers = self.broadcast.subscribers.lock().unwrap();
+    loop {
+      if let Some(event) = self.next(&mut subscribers) {
+        return Ok(event);
+      }
+      if subscribers.closed {
+        return Err(Error::Terminated);
+      }
+      let now = Instant::now();
+      if now >= deadline {
+        return Err(Error::TimedOut);
+      }
+      subscribers = self.broadcast.cond.wait_timeout(subscribers, deadline - now).unwrap().0;
+    }
+  }
+
+  /// Change what happens when this receiver falls behind.
+  pub fn set_backpressure(&self, policy: Backpressure) {
+    let mut subscribers = self.broadcast.subscribers.lock().unwrap();
+    subscribers.queues.get_mut(&self.id).unwrap().policy = policy.normalize();
+    self.broadcast.cond.notify_all();
+  }
+
+  /// Number of events that this receiver missed because of its [`Backpressure`] policy.
+  pub fn dropped(&self) -> u64 {
+    self.broadcast.subscribers.lock().unwrap().queues[&self.id].dropped
+  }
+}
+
+impl<E: Clone + Send + 'static> Drop for EventReceiver<E> {
+  fn drop(&mut self) {
+    self.broadcast.subscribers.lock().unwrap().queues.remove(&self.id);
+    self.broadcast.cond.notify_all();
+  }
+}
@@ -0,0 +1,61 @@
+use std::mem::ManuallyDrop;
+
+use event_loop::EventLoop;
+use {HwndLoopBuilder, HwndLoopCallbacks, LoopHandle, Result};
+
+/// A loop that piggybacks on a message pump that the current thread already runs, e.g. one owned
+/// by a GUI framework.
+///
+/// No thread is spawned and nothing needs to be pumped manually: the loop's window lives on the
+/// creating thread, and commands and flushes are handled when the host's
+/// `GetMessage`/`DispatchMessage` loop dispatches the loop's internal messages to it. Other threads
+/// can send commands through [`ExternalLoopAdapter::handle`].
+///
+/// Since the host owns the pump, accelerators, dialog registrations, and
+/// [`HwndLoopBuilder::translate_messages`] have no effect, and pausing only defers commands.
+/// Commands whose wakeups were dropped because the message queue was full are handled along with
+/// the next one that gets through, rather than via the wake event.
+///
+/// The window is destroyed, and [`HwndLoopCallbacks::tear_down`] is called, when the adapter is
+/// dropped, which must happen on the thread that created it.
+pub struct ExternalLoopAdapter<CommandType: Send + std::fmt::Debug + 'static> {
+  event_loop: ManuallyDrop<Box<EventLoop<CommandType>>>,
+}
+
+impl<CommandType: Send + std::fmt::Debug + 'static> ExternalLoopAdapter<CommandType> {
+  /// Create a new [`ExternalLoopAdapter`] on the current thread.
+  pub fn new(callbacks: Box<dyn HwndLoopCallbacks<CommandType>>) -> Result<ExternalLoopAdapter<CommandType>> {
+    HwndLoopBuilder::new().build_external(callbacks)
+  }
+
+  pub(crate) fn with_config(
+    config: &HwndLoopBuilder,
+    callbacks: Box<dyn HwndLoopCallbacks<CommandType>>,
+  ) -> Result<ExternalLoopAdapter<CommandType>> {
+    Ok(ExternalLoopAdapter {
+      event_loop: ManuallyDrop::new(EventLoop::new_external(config, callbacks)?),
+    })
+  }
+
+  /// Get a handle for sending commands to the loop from other threads.
+  pub fn handle(&self) -> LoopHandle<CommandType> {
+    self.event_loop.handle()
+  }
+
+  /// Whether the loop has been told to terminate through a handle.
+  ///
+  /// Once it has, commands are no longer handled and flushes fail with
+  /// [`Error::Terminated`](::Error::Terminated), but the window stays around until the adapter is
+  /// dropped.
+  pub fn is_terminated(&self) -> bool {
+    self.event_loop.is_terminated()
+  }
+}
+
+impl<CommandType: Send + std::fmt::Debug + 'static> Drop for ExternalLoopAdapter<CommandType> {
+  fn drop(&mut self) {
+    // The host's pump dispatches everything to the window, so this can easily be happening
+    // inside one of the loop's own callbacks.
+    unsafe { ManuallyDrop::take(&mut self.event_loop) }.release();
+  }
+}
@@ -0,0 +1,103 @@
+//! Fault injection for stress testing.
+//!
+//! This is only available with the `fault-injection` feature. Once faults are injected with
+//! [`inject`], every loop in the process randomly runs into them: the handler thread stalls
+//! before handling things, and handles find the message queue full when they post to it. Together
+//! with [`message_storm`], this exercises the paths that are hard to hit otherwise, like falling
+//! back to the wake event and flushing or terminating while the loop is behind.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use winapi::shared::minwindef::{FALSE, UINT};
+use winapi::um::winuser::PostMessageW;
+
+use HwndWrapper;
+
+/// The faults to inject, and how often.
+#[derive(Clone, Debug, Default)]
+pub struct Faults {
+  /// Chance, from 0 to 1, that the handler thread sleeps before handling each command or message.
+  pub delay_probability: f64,
+
+  /// Upper bound on how long each of those sleeps lasts.
+  pub max_delay: Duration,
+
+  /// Chance, from 0 to 1, that posting one of the loop's own messages fails as if the message
+  /// queue were full. Flushes keep retrying until their post goes through, so this should be well
+  /// below 1.
+  pub post_failure_probability: f64,
+}
+
+struct State {
+  faults: Faults,
+  rng: u64,
+}
+
+lazy_static! {
+  static ref STATE: Mutex<State> = Mutex::new(State {
+    faults: Faults::default(),
+    rng: 0x2545_f491_4f6c_dd1d,
+  });
+}
+
+/// Whether anything has been injected, to keep the lock off of the fast path.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Start injecting `faults` into every loop in the process, replacing whatever was injected before.
+///
+/// The same `seed` produces the same sequence of decisions, although which thread sees each one
+/// depends on scheduling.
+pub fn inject(faults: Faults, seed: u64) {
+  let mut state = STATE.lock().unwrap();
+  state.faults = faults;
+  state.rng = seed | 1;
+  ACTIVE.store(true, Ordering::SeqCst);
+}
+
+/// Stop injecting faults.
+pub fn clear() {
+  ACTIVE.store(false, Ordering::SeqCst);
+  STATE.lock().unwrap().faults = Faults::default();
+}
+
+/// Post `count` copies of `msg` to `target` as fast as possible, returning how many made it into
+/// the queue before it filled up.
+pub fn message_storm(target: HwndWrapper, msg: UINT, count: usize) -> usize {
+  (0..count)
+    .take_while(|_| unsafe { PostMessageW(target.0, msg, 0, 0) } != FALSE)
+    .count()
+}
+
+/// Roll the dice with probability `p`, returning a number from 0 to 1 to scale the outcome with.
+fn roll(p: impl Fn(&Faults) -> f64) -> Option<(Faults, f64)> {
+  if !ACTIVE.load(Ordering::SeqCst) {
+    return None;
+  }
+
+  let mut state = STATE.lock().unwrap();
+  // xorshift64*, which is plenty for deciding when to misbehave.
+  state.rng ^= state.rng >> 12;
+  state.rng ^= state.rng << 25;
+  state.rng ^= state.rng >> 27;
+  let value = (state.rng.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11) as f64 / (1u64 << 53) as f64;
+  let p = p(&state.faults);
+  if value < p {
+    Some((state.faults.clone(), value / p))
+  } else {
+    None
+  }
+}
+
+/// Maybe stall the handler thread, before it handles a command or message.
+pub(crate) fn delay() {
+  if let Some((faults, scale)) = roll(|faults| faults.delay_probability) {
+    std::thread::sleep(faults.max_delay.mul_f64(scale));
+  }
+}
+
+/// Whether the next post to a loop should pretend that its message queue is full.
+pub(crate) fn fail_post() -> bool {
+  roll(|faults| faults.post_failure_probability).is_some()
+}
@@ -0,0 +1,154 @@
+//! Entry points for fuzzing how loops route and decode the messages they receive.
+//!
+//! This is only built with `--cfg fuzzing`, which `cargo fuzz` sets; the targets themselves live
+//! in the `fuzz` directory. Each entry point takes arbitrary bytes, turns them into whatever the
+//! code under test expects, and feeds them through it, so that panics and out-of-bounds reads show
+//! up under the fuzzer's sanitizers.
+
+use std::cell::RefCell;
+
+use winapi::shared::minwindef::{DWORD, LPARAM, LPVOID, LRESULT, UINT, WPARAM};
+use winapi::shared::windef::HWND;
+use winapi::um::winnt::LONG;
+use winapi::um::winuser::*;
+
+use service::ServiceControl;
+use winevent::WinEvent;
+use {HwndLoopBuilder, HwndLoopCallbacks, HwndPump, INTERNAL_MESSAGE_TAG};
+use {WM_HWNDLOOP_COMMAND, WM_HWNDLOOP_FLUSH, WM_HWNDLOOP_FLUSH_ALL, WM_HWNDLOOP_FLUSH_MARKER};
+use {WM_HWNDLOOP_PAUSE, WM_HWNDLOOP_PAYLOAD, WM_HWNDLOOP_RESUME, WM_HWNDLOOP_SERVICE_CONTROL};
+
+/// A command type that IPC can decode into something nontrivial.
+type FuzzCommand = (u64, String, Vec<u8>);
+
+/// Callbacks that handle everything, so that nothing arbitrary reaches `DefWindowProc`.
+struct Fuzz;
+
+impl HwndLoopCallbacks<FuzzCommand> for Fuzz {
+  fn try_handle_message(&mut self, _hwnd: HWND, _msg: UINT, _w: WPARAM, _l: LPARAM) -> Option<LRESULT> {
+    Some(0)
+  }
+}
+
+thread_local! {
+  /// The loop that messages are dispatched to, which is reused across inputs.
+  static PUMP: RefCell<Option<HwndPump<FuzzCommand>>> = const { RefCell::new(None) };
+}
+
+/// Reads fixed-size values off of the front of the input, padding with zeroes once it runs out.
+struct Input<'a>(&'a [u8]);
+
+impl<'a> Input<'a> {
+  fn bytes<const N: usize>(&mut self) -> [u8; N] {
+    let mut buf = [0u8; N];
+    let len = std::cmp::min(N, self.0.len());
+    buf[..len].copy_from_slice(&self.0[..len]);
+    self.0 = &self.0[len..];
+    buf
+  }
+
+  fn u8(&mut self) -> u8 {
+    self.bytes::<1>()[0]
+  }
+
+  fn u32(&mut self) -> u32 {
+    u32::from_le_bytes(self.bytes())
+  }
+
+  fn usize(&mut self) -> usize {
+    u64::from_le_bytes(self.bytes()) as usize
+  }
+}
+
+/// Send a message built from `data` to a loop's window, and pump whatever it leads to.
+///
+/// The message is one of the loop's own registered messages, or one of the standard messages that
+/// it treats specially, with arbitrary parameters. The selector's top bit makes the message look
+/// genuine, to get past the checks for lookalikes: internal messages get the right tag, and
+/// WM_COPYDATA gets the marker for IPC commands.
+pub fn dispatch_message(data: &[u8]) {
+  let mut input = Input(data);
+  let selector = input.u8();
+  let genuine = selector & 0x80 != 0;
+  let w = input.usize() as WPARAM;
+  let mut l = input.usize() as LPARAM;
+
+  let internal = [
+    *WM_HWNDLOOP_COMMAND,
+    *WM_HWNDLOOP_FLUSH,
+    *WM_HWNDLOOP_FLUSH_ALL,
+    *WM_HWNDLOOP_FLUSH_MARKER,
+    *WM_HWNDLOOP_PAUSE,
+    *WM_HWNDLOOP_RESUME,
+  ];
+  let other = [
+    *WM_HWNDLOOP_PAYLOAD,
+    *WM_HWNDLOOP_SERVICE_CONTROL,
+    WM_COMMAND,
+    WM_FONTCHANGE,
+    WM_QUERYENDSESSION,
+    WM_ENDSESSION,
+    WM_COPYDATA,
+  ];
+  let index = (selector & 0x7f) as usize % (internal.len() + other.len());
+  let msg = if index < internal.len() {
+    if genuine {
+      l = *INTERNAL_MESSAGE_TAG;
+    }
+    internal[index]
+  } else {
+    other[index - internal.len()]
+  };
+
+  // WM_COPYDATA carries a pointer, so point it at the rest of the input.
+  let rest = input.0;
+  #[cfg(feature = "serde")]
+  let magic = if genuine { ::ipc::IPC_MAGIC } else { l as usize };
+  #[cfg(not(feature = "serde"))]
+  let magic = l as usize;
+  let mut cds = COPYDATASTRUCT {
+    dwData: magic,
+    cbData: rest.len() as DWORD,
+    lpData: rest.as_ptr() as LPVOID,
+  };
+  if msg == WM_COPYDATA {
+    l = &mut cds as *mut COPYDATASTRUCT as LPARAM;
+  }
+
+  PUMP.with(|pump| {
+    let mut pump = pump.borrow_mut();
+    let pump = pump.get_or_insert_with(|| {
+      let config = HwndLoopBuilder::new();
+      #[cfg(feature = "serde")]
+      let config = config.ipc_commands::<FuzzCommand>();
+      config.build_pump(Box::new(Fuzz)).expect("failed to create loop")
+    });
+    unsafe { SendMessageW(pump.handle().hwnd().0, msg, w, l) };
+    pump.pump_pending();
+  });
+}
+
+/// Decode a service control request built from `data`.
+///
+/// The service control manager always passes a complete structure for the control codes that come
+/// with one, so the data is padded out to a size that covers all of them.
+pub fn decode_service_control(data: &[u8]) {
+  let mut input = Input(data);
+  let control = input.u32();
+  let event = input.u32();
+  let mut buf = input.0.to_vec();
+  if buf.len() < 64 {
+    buf.resize(64, 0);
+  }
+  let _ = unsafe { ServiceControl::from_raw(control, event, buf.as_mut_ptr() as LPVOID) };
+}
+
+/// Decode a WinEvent built from `data`.
+pub fn decode_win_event(data: &[u8]) {
+  let mut input = Input(data);
+  let event = input.u32();
+  let hwnd = input.usize() as HWND;
+  let object = input.u32() as LONG;
+  let child = input.u32() as LONG;
+  let _ = WinEvent::from_raw(event, hwnd, object, child);
+}
@@ -0,0 +1,919 @@
+use std::any::Any;
+use std::collections::{HashMap, VecDeque};
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use winapi::shared::minwindef::{BOOL, DWORD, FALSE, LPARAM, UINT, WPARAM};
+use winapi::shared::windef::HWND;
+use winapi::shared::winerror::ERROR_NOT_ENOUGH_QUOTA;
+use winapi::um::mmsystem::TIMERR_NOERROR;
+use winapi::um::processthreadsapi::GetCurrentThreadId;
+use winapi::um::timeapi::{timeBeginPeriod, timeEndPeriod};
+use winapi::um::winnt::{ES_CONTINUOUS, ES_DISPLAY_REQUIRED, ES_SYSTEM_REQUIRED};
+use winapi::um::winuser::{PostMessageW, PostThreadMessageW};
+
+use dispatch;
+use events::{EventChannel, Events};
+#[cfg(feature = "fault-injection")]
+use fault;
+use profile::{ProfileSnapshot, Profiler};
+use spy::{Spy, SpyRecord};
+use util;
+use {AcceleratorTable, Error, Hook, HwndLoopCommand, HwndWrapper, MessageHook, MessageWaiter, Result};
+use {Backpressure, EventReceiver};
+use {INTERNAL_MESSAGE_TAG, WM_HWNDLOOP_COMMAND, WM_HWNDLOOP_FLUSH, WM_HWNDLOOP_FLUSH_ALL};
+use {WM_HWNDLOOP_PAUSE, WM_HWNDLOOP_RESUME};
+
+/// Number of times a post that fails due to a full message queue is retried before giving up.
+const POST_RETRY_LIMIT: u32 = 8;
+
+/// Initial delay between retries of a post that failed due to a full message queue.
+const POST_RETRY_DELAY: Duration = Duration::from_millis(1);
+
+/// Upper bound on the delay between retries of a post that failed due to a full message queue.
+const POST_RETRY_MAX_DELAY: Duration = Duration::from_millis(100);
+
+/// A caller waiting for the loop to acknowledge a control message (a flush, pause, or resume),
+/// identified by the WPARAM of that message.
+pub(crate) struct FlushRequest {
+  pub(crate) id: usize,
+  pub(crate) thread: DWORD,
+  pub(crate) tx: std::sync::mpsc::Sender<Result<()>>,
+}
+
+/// Number of outstanding [`KeepAwakeGuard`]s that ask for each kind of keep-awake.
+#[derive(Default)]
+pub(crate) struct KeepAwakeCounts {
+  system: usize,
+  display: usize,
+}
+
+impl KeepAwakeCounts {
+  /// The `SetThreadExecutionState` flags that the handler thread should have.
+  pub(crate) fn execution_state(&self) -> DWORD {
+    let mut state = ES_CONTINUOUS;
+    if self.system > 0 {
+      state |= ES_SYSTEM_REQUIRED;
+    }
+    if self.display > 0 {
+      state |= ES_DISPLAY_REQUIRED;
+    }
+    state
+  }
+
+  fn update(&mut self, flags: KeepAwake, acquire: bool) {
+    let (system, display) = (flags.system as usize, flags.display as usize);
+    if acquire {
+      self.system += system;
+      self.display += display;
+    } else {
+      self.system -= system;
+      self.display -= display;
+    }
+  }
+}
+
+/// State shared between a loop's handles and the thread that pumps its messages.
+pub(crate) struct Shared<CommandType: Send + std::fmt::Debug + 'static> {
+  pub(crate) hwnd: HwndWrapper,
+  pub(crate) thread_id: DWORD,
+
+  /// Whether internal messages are posted to the thread, even though the loop has a window.
+  pub(crate) thread_control: bool,
+
+  /// Keeps the thread's ID from being reused while handles can still post to it, for loops whose
+  /// internal messages go to the thread.
+  pub(crate) _thread: Option<util::OwnedHandle>,
+  pub(crate) strict: bool,
+  pub(crate) command_queue: Mutex<VecDeque<(u64, HwndLoopCommand<CommandType>)>>,
+  pub(crate) urgent_queue: Mutex<VecDeque<CommandType>>,
+  pub(crate) defer_count: AtomicUsize,
+  pub(crate) buffered: AtomicUsize,
+  pub(crate) next_seq: AtomicU64,
+  pub(crate) processed_seq: AtomicU64,
+  pub(crate) seq_waiters: AtomicUsize,
+  pub(crate) seq_lock: Mutex<()>,
+  pub(crate) seq_cond: Condvar,
+  pub(crate) flush_requests: Mutex<VecDeque<FlushRequest>>,
+  pub(crate) flush_seq: AtomicUsize,
+  pub(crate) wake_debt: AtomicUsize,
+  pub(crate) wake_event: util::Event,
+  pub(crate) saturations: AtomicUsize,
+  pub(crate) terminated: AtomicBool,
+  pub(crate) created: Instant,
+  pub(crate) last_dispatch_us: AtomicU64,
+
+  /// When queued commands start being dropped instead of handled, in microseconds since the loop
+  /// was created, for [`TerminatePolicy::Preempt`](::TerminatePolicy::Preempt).
+  pub(crate) preempt_at_us: AtomicU64,
+
+  /// What the callbacks left for [`HwndLoop::join`](::HwndLoop::join) with
+  /// [`set_exit_value`](::set_exit_value).
+  pub(crate) exit_value: Mutex<Option<Box<dyn Any + Send>>>,
+  pub(crate) input_pending: AtomicBool,
+  pub(crate) events: Option<Arc<dyn Events>>,
+  pub(crate) timer_periods: Mutex<HashMap<UINT, usize>>,
+  pub(crate) keep_awake: Mutex<KeepAwakeCounts>,
+  pub(crate) spy: Option<Arc<Spy>>,
+  pub(crate) profiler: Option<Profiler<CommandType>>,
+}
+
+impl<CommandType: Send + std::fmt::Debug + 'static> Shared<CommandType> {
+  /// Record that the loop just handled something, and whether there's more input waiting for it.
+  pub(crate) fn note_dispatch(&self, input_pending: bool) {
+    let elapsed = self.created.elapsed();
+    let us = elapsed.as_secs() * 1_000_000 + u64::from(elapsed.subsec_micros());
+    self.last_dispatch_us.store(us, Ordering::SeqCst);
+    self.input_pending.store(input_pending, Ordering::SeqCst);
+  }
+
+  /// Give the loop `grace` to work through its queue before it starts dropping what's left.
+  pub(crate) fn preempt_after(&self, grace: Duration) {
+    let at = self.created.elapsed() + grace;
+    let us = at.as_secs() * 1_000_000 + u64::from(at.subsec_micros());
+    self.preempt_at_us.fetch_min(us, Ordering::SeqCst);
+  }
+
+  /// Whether the grace period given by [`Shared::preempt_after`] has run out.
+  pub(crate) fn preempting(&self) -> bool {
+    let at = self.preempt_at_us.load(Ordering::SeqCst);
+    if at == u64::MAX {
+      return false;
+    }
+    let elapsed = self.created.elapsed();
+    elapsed.as_secs() * 1_000_000 + u64::from(elapsed.subsec_micros()) >= at
+  }
+
+  /// Record that every command up to `seq` has been handled, waking up anyone waiting for it.
+  pub(crate) fn note_processed(&self, seq: u64) {
+    self.processed_seq.store(seq, Ordering::SeqCst);
+    self.wake_seq_waiters();
+  }
+
+  /// Drop one request for a timer resolution of `period` milliseconds, ending it once nothing
+  /// else asks for it.
+  fn release_timer_period(&self, period: UINT) {
+    let mut periods = self.timer_periods.lock().unwrap();
+    if let Some(count) = periods.get_mut(&period) {
+      *count -= 1;
+      if *count == 0 {
+        periods.remove(&period);
+        unsafe { timeEndPeriod(period) };
+      }
+    }
+  }
+
+  /// End every timer resolution that's still requested, once the loop has terminated.
+  pub(crate) fn release_timer_periods(&self) {
+    for (period, _) in self.timer_periods.lock().unwrap().drain() {
+      unsafe { timeEndPeriod(period) };
+    }
+  }
+
+  /// Wake up everyone in [`LoopHandle::flush_until`], to check whether they're done.
+  pub(crate) fn wake_seq_waiters(&self) {
+    // Taking the lock keeps a waiter from missing this between checking and waiting.
+    if self.seq_waiters.load(Ordering::SeqCst) > 0 {
+      let _lock = self.seq_lock.lock().unwrap();
+      self.seq_cond.notify_all();
+    }
+  }
+}
+
+/// The position of a command in the order that a loop handles them, returned by
+/// [`LoopHandle::send_command`].
+///
+/// Sequence numbers increase with every command sent to a loop, including the ones that handles
+/// send internally, so they can be compared to find out which of two commands is handled first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CommandSeq(pub(crate) u64);
+
+impl std::fmt::Display for CommandSeq {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    write!(f, "#{}", self.0)
+  }
+}
+
+/// A snapshot of how busy a loop is, returned by [`LoopHandle::status`].
+#[derive(Clone, Debug)]
+pub struct LoopStatus {
+  /// Number of commands that have been sent but not yet handled.
+  pub pending_commands: usize,
+
+  /// Whether the handler thread's message queue had input waiting the last time the loop handled
+  /// something, according to `GetQueueStatus`.
+  pub input_pending: bool,
+
+  /// Time since the loop last handled a command or window message, or since it was created if it
+  /// hasn't handled anything yet.
+  pub since_last_dispatch: Duration,
+
+  /// Number of events that receivers have missed because of their
+  /// [`Backpressure`](::Backpressure) policies.
+  pub dropped_events: u64,
+}
+
+/// Why a loop dropped a command instead of handling it, passed to
+/// [`HwndLoopCallbacks::handle_dropped_command`](::HwndLoopCallbacks::handle_dropped_command).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DropReason {
+  /// The command's deadline passed while it was waiting to be handled.
+  Expired,
+
+  /// The command was removed from the queue by [`LoopHandle::cancel_pending`].
+  Cancelled,
+
+  /// The loop terminated before getting to the command.
+  Terminated,
+}
+
+/// What a [`KeepAwakeGuard`] keeps the system from doing, for [`LoopHandle::keep_awake`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct KeepAwake {
+  /// Keep the system from sleeping, as with `ES_SYSTEM_REQUIRED`.
+  pub system: bool,
+
+  /// Keep the display on, as with `ES_DISPLAY_REQUIRED`. This doesn't keep the system awake on
+  /// its own.
+  pub display: bool,
+}
+
+/// Holds back a loop's user commands until it's dropped, returned by
+/// [`LoopHandle::defer_commands`].
+pub struct DeferGuard<CommandType: Send + std::fmt::Debug + 'static> {
+  handle: LoopHandle<CommandType>,
+}
+
+impl<CommandType: Send + std::fmt::Debug + 'static> Drop for DeferGuard<CommandType> {
+  fn drop(&mut self) {
+    let shared = &self.handle.shared;
+    if shared.defer_count.fetch_sub(1, Ordering::SeqCst) == 1 {
+      // Wake up the loop to release what it's been holding.
+      if let Err(err) = shared.wake_event.set() {
+        warn!("failed to wake up HwndLoop after deferring commands: {}", err);
+      }
+    }
+  }
+}
+
+/// A timer resolution requested with [`LoopHandle::request_timer_resolution`], which is released
+/// when this is dropped or when the loop terminates, whichever comes first.
+pub struct TimerResolution<CommandType: Send + std::fmt::Debug + 'static> {
+  handle: LoopHandle<CommandType>,
+  period: UINT,
+}
+
+impl<CommandType: Send + std::fmt::Debug + 'static> Drop for TimerResolution<CommandType> {
+  fn drop(&mut self) {
+    self.handle.shared.release_timer_period(self.period);
+  }
+}
+
+/// Keeps the system awake until it's dropped, returned by [`LoopHandle::keep_awake`].
+pub struct KeepAwakeGuard<CommandType: Send + std::fmt::Debug + 'static> {
+  handle: LoopHandle<CommandType>,
+  flags: KeepAwake,
+}
+
+impl<CommandType: Send + std::fmt::Debug + 'static> Drop for KeepAwakeGuard<CommandType> {
+  fn drop(&mut self) {
+    self.handle.shared.keep_awake.lock().unwrap().update(self.flags, false);
+    // If the loop's gone, so is its execution state.
+    let _ = self.handle.send_command_internal(HwndLoopCommand::UpdateExecutionState, false);
+  }
+}
+
+/// A handle for sending commands to a loop from any thread.
+///
+/// Handles don't keep the loop alive: once it terminates, everything sent through a handle fails
+/// with [`Error::Terminated`].
+pub struct LoopHandle<CommandType: Send + std::fmt::Debug + 'static> {
+  pub(crate) shared: Arc<Shared<CommandType>>,
+}
+
+impl<CommandType: Send + std::fmt::Debug + 'static> Clone for LoopHandle<CommandType> {
+  fn clone(&self) -> LoopHandle<CommandType> {
+    LoopHandle {
+      shared: self.shared.clone(),
+    }
+  }
+}
+
+impl<CommandType: Send + std::fmt::Debug + 'static> LoopHandle<CommandType> {
+  /// The loop's window.
+  pub fn hwnd(&self) -> HwndWrapper {
+    self.shared.hwnd.clone()
+  }
+
+  /// Whether this is being called from the loop's handler thread.
+  pub(crate) fn on_loop_thread(&self) -> bool {
+    unsafe { GetCurrentThreadId() == self.shared.thread_id }
+  }
+
+  /// Panic if a call that waits for the loop is made from its own thread, in
+  /// [`strict`](::HwndLoopBuilder::strict) mode.
+  pub(crate) fn check_blocking(&self, what: &str) {
+    if self.shared.strict && self.on_loop_thread() {
+      panic!("HwndLoop {} called from the loop's own thread, which deadlocks", what);
+    }
+  }
+
+  /// Post a message to the loop's window, or to its thread if it doesn't have one or was built
+  /// with [`thread_control`](::HwndLoopBuilder::thread_control).
+  fn post(&self, msg: UINT, w: WPARAM) -> BOOL {
+    let l = *INTERNAL_MESSAGE_TAG;
+    if self.shared.hwnd.0.is_null() || self.shared.thread_control {
+      unsafe { PostThreadMessageW(self.shared.thread_id, msg, w, l) }
+    } else {
+      unsafe { PostMessageW(self.shared.hwnd.0, msg, w, l) }
+    }
+  }
+
+  /// Post a message to the loop's window, backing off while its message queue is full.
+  ///
+  /// Gives up with [`Error::QueueSaturated`] after `retry_limit` retries, or keeps retrying until
+  /// the post succeeds if there's no limit.
+  fn post_message(&self, msg: UINT, w: WPARAM, retry_limit: Option<u32>) -> Result<()> {
+    let mut delay = POST_RETRY_DELAY;
+    let mut attempt = 0;
+    loop {
+      if self.shared.terminated.load(Ordering::SeqCst) {
+        return Err(Error::Terminated);
+      }
+
+      #[cfg(feature = "fault-injection")]
+      let injected = fault::fail_post();
+      #[cfg(not(feature = "fault-injection"))]
+      let injected = false;
+
+      if !injected && self.post(msg, w) != FALSE {
+        return Ok(());
+      }
+
+      let err = if injected {
+        std::io::Error::from_raw_os_error(ERROR_NOT_ENOUGH_QUOTA as i32)
+      } else {
+        std::io::Error::last_os_error()
+      };
+      if err.raw_os_error() != Some(ERROR_NOT_ENOUGH_QUOTA as i32) {
+        return Err(Error::Os(err));
+      }
+
+      if Some(attempt) == retry_limit {
+        self.shared.saturations.fetch_add(1, Ordering::SeqCst);
+        return Err(Error::QueueSaturated);
+      }
+
+      trace!("HwndLoop message queue saturated, retrying in {:?}", delay);
+      std::thread::sleep(delay);
+      delay = std::cmp::min(delay * 2, POST_RETRY_MAX_DELAY);
+      attempt += 1;
+    }
+  }
+
+  pub(crate) fn send_command_internal(&self, cmd: HwndLoopCommand<CommandType>, blocking: bool) -> Result<()> {
+    self.enqueue_command(cmd, blocking).map(|_| ())
+  }
+
+  fn enqueue_command(&self, cmd: HwndLoopCommand<CommandType>, blocking: bool) -> Result<CommandSeq> {
+    // If an earlier poke was already dropped, the loop is behind: try once without backing off, so
+    // that a burst of sends doesn't spend the retry budget on every single command.
+    let retry_limit = if blocking {
+      None
+    } else if self.shared.wake_debt.load(Ordering::SeqCst) > 0 {
+      Some(0)
+    } else {
+      Some(POST_RETRY_LIMIT)
+    };
+
+    // Numbered under the lock, so that the numbers are in the same order as the queue.
+    let seq = {
+      let mut queue = self.shared.command_queue.lock().unwrap();
+      let seq = self.shared.next_seq.fetch_add(1, Ordering::SeqCst) + 1;
+      trace!("HwndLoop sending command #{}: {:?}", seq, cmd);
+      queue.push_back((seq, cmd));
+      CommandSeq(seq)
+    };
+    if self.shared.hwnd.0.is_null() {
+      // Windowless loops have nothing to post pokes to, so they're always woken up by the event.
+      if self.shared.terminated.load(Ordering::SeqCst) {
+        return Err(Error::Terminated);
+      }
+      self.shared.wake_debt.fetch_add(1, Ordering::SeqCst);
+      return self.shared.wake_event.set().map(|()| seq).map_err(Error::from);
+    }
+    match self.post_message(*WM_HWNDLOOP_COMMAND, 0, retry_limit) {
+      Err(Error::QueueSaturated) => {
+        // Fall back to the wake event, which doesn't count against the message queue's quota.
+        self.shared.wake_debt.fetch_add(1, Ordering::SeqCst);
+        self.shared.wake_event.set().map_err(Error::from)?;
+        Ok(seq)
+      }
+      result => result.map(|()| seq),
+    }
+  }
+
+  /// Send a command to the loop, to be handled by
+  /// [`HwndLoopCallbacks::handle_command`](::HwndLoopCallbacks::handle_command) on the handler
+  /// thread, returning its place in the loop's order.
+  ///
+  /// If the window's message queue stays full, the loop is woken up via an event instead of a
+  /// window message. Commands are still handled in order, but a command delivered this way may be
+  /// handled before window messages that were posted ahead of it.
+  pub fn send_command(&self, cmd: CommandType) -> Result<CommandSeq> {
+    self.enqueue_command(HwndLoopCommand::UserCommand(cmd), false)
+  }
+
+  /// Send a command to the loop that's dropped if it's still waiting to be handled once `deadline`
+  /// passes, for things like forwarded input, where a late event is worse than none at all.
+  ///
+  /// Expired commands are passed to
+  /// [`HwndLoopCallbacks::handle_dropped_command`](::HwndLoopCallbacks::handle_dropped_command)
+  /// instead of being handled. The deadline is checked right before the command would be handled,
+  /// including after the loop has held on to it while paused or deferring commands.
+  pub fn send_command_with_deadline(&self, cmd: CommandType, deadline: Instant) -> Result<CommandSeq> {
+    self.enqueue_command(HwndLoopCommand::Expiring(cmd, deadline), false)
+  }
+
+  /// Send a command to the loop that jumps ahead of every command that's still waiting to be
+  /// handled, for emergencies like stopping a device that's misbehaving while a large backlog is
+  /// pending.
+  ///
+  /// Urgent commands are handled in the order they were sent, at the loop's next wakeup, before
+  /// the next queued command. They wake the loop through its wake event, so they don't count
+  /// against the message queue's quota, and are handled even if it's full. They aren't numbered,
+  /// since they don't keep their place in the loop's order, and they're still held back while the
+  /// loop is paused, to be handled first when it's resumed. With an
+  /// [`ExternalLoopAdapter`](::ExternalLoopAdapter), whose host doesn't wait on the wake event,
+  /// they're handled at the next wakeup of any kind.
+  pub fn send_urgent_command(&self, cmd: CommandType) -> Result<()> {
+    if self.shared.terminated.load(Ordering::SeqCst) {
+      return Err(Error::Terminated);
+    }
+    trace!("HwndLoop sending urgent command: {:?}", cmd);
+    self.shared.urgent_queue.lock().unwrap().push_back(cmd);
+    self.shared.wake_event.set().map_err(Error::from)
+  }
+
+  /// Remove the commands that are still waiting to be handled and match `pred`, like the ones for a
+  /// device that was just unplugged, returning how many were removed.
+  ///
+  /// Only commands sent with [`LoopHandle::send_command`], [`LoopHandle::send_command_with_deadline`]
+  /// or [`LoopHandle::send_urgent_command`] can be cancelled, and only until the loop takes them
+  /// off of its queues: commands it's holding on to while it's paused or deferring commands are
+  /// handled regardless. A cancelled command's [`CommandSeq`] counts as handled once everything
+  /// before it has been.
+  ///
+  /// The removed commands are passed to
+  /// [`HwndLoopCallbacks::handle_dropped_command`](::HwndLoopCallbacks::handle_dropped_command) on
+  /// the handler thread, to clean up whatever they hold on to.
+  pub fn cancel_pending<F: FnMut(&CommandType) -> bool>(&self, pred: F) -> usize {
+    let cancelled = self.take_pending(pred);
+    let count = cancelled.len();
+    if count > 0 {
+      // If the loop is already gone, so are the callbacks that would have cleaned up after these.
+      let _ = self.send_command_internal(HwndLoopCommand::Dropped(cancelled, DropReason::Cancelled), false);
+    }
+    count
+  }
+
+  /// Like [`LoopHandle::cancel_pending`], but hands the removed commands back, urgent ones first,
+  /// instead of passing them to the callbacks.
+  pub fn take_pending<F: FnMut(&CommandType) -> bool>(&self, mut pred: F) -> Vec<CommandType> {
+    let mut taken = Vec::new();
+    let mut urgent = self.shared.urgent_queue.lock().unwrap();
+    for cmd in std::mem::take(&mut *urgent) {
+      if pred(&cmd) {
+        taken.push(cmd);
+      } else {
+        urgent.push_back(cmd);
+      }
+    }
+    drop(urgent);
+
+    let mut queue = self.shared.command_queue.lock().unwrap();
+    for (seq, cmd) in std::mem::take(&mut *queue) {
+      match cmd {
+        HwndLoopCommand::UserCommand(cmd) | HwndLoopCommand::Expiring(cmd, _) if pred(&cmd) => taken.push(cmd),
+        cmd => queue.push_back((seq, cmd)),
+      }
+    }
+    if !taken.is_empty() {
+      trace!("HwndLoop cancelled {} commands", taken.len());
+    }
+    taken
+  }
+
+  /// Hold back user commands until the returned guard is dropped, while the loop keeps handling
+  /// window messages.
+  ///
+  /// This is for reconfiguring state that commands read, without them seeing it half-done. It can
+  /// be called from any thread, including from inside a callback. Commands that the loop gets to
+  /// while any guard is around, including urgent ones, are buffered and handled in order once the
+  /// last guard is dropped. As with [`LoopHandle::pause`], flushes are answered once everything
+  /// before them has been buffered, and [`LoopHandle::call`] blocks until its command is released,
+  /// so it deadlocks if it's made by the thread that holds the guard.
+  pub fn defer_commands(&self) -> DeferGuard<CommandType> {
+    self.shared.defer_count.fetch_add(1, Ordering::SeqCst);
+    DeferGuard { handle: self.clone() }
+  }
+
+  /// Ask for the system timer to tick at least every `resolution`, with `timeBeginPeriod`, for loops
+  /// that pace themselves with short `SetTimer` periods or `Sleep`.
+  ///
+  /// The resolution is rounded down to whole milliseconds, and to no less than 1ms. The timer
+  /// resolution is shared by the whole process and costs power, so it's only held until the
+  /// returned guard is dropped or the loop terminates, whichever comes first. Requests for the
+  /// same resolution share one `timeBeginPeriod` call.
+  pub fn request_timer_resolution(&self, resolution: Duration) -> Result<TimerResolution<CommandType>> {
+    let period = std::cmp::max(1, std::cmp::min(resolution.as_millis(), UINT::MAX as u128)) as UINT;
+    // Checked under the lock, so that the loop can't release everything in between.
+    let mut periods = self.shared.timer_periods.lock().unwrap();
+    if self.shared.terminated.load(Ordering::SeqCst) {
+      return Err(Error::Terminated);
+    }
+    if !periods.contains_key(&period) && unsafe { timeBeginPeriod(period) } != TIMERR_NOERROR {
+      let message = format!("timer resolution of {}ms isn't supported", period);
+      return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, message).into());
+    }
+    *periods.entry(period).or_insert(0) += 1;
+    Ok(TimerResolution {
+      handle: self.clone(),
+      period,
+    })
+  }
+
+  /// Keep the system from sleeping or turning off the display while the returned guard is around,
+  /// with `SetThreadExecutionState` on the handler thread, for loops that stream from a device
+  /// without any user input to keep the system awake.
+  ///
+  /// Guards can overlap: the handler thread's execution state is what the outstanding guards ask
+  /// for between them. It's applied in order with respect to previously sent commands, and cleared
+  /// when the loop terminates.
+  pub fn keep_awake(&self, flags: KeepAwake) -> Result<KeepAwakeGuard<CommandType>> {
+    self.shared.keep_awake.lock().unwrap().update(flags, true);
+    let guard = KeepAwakeGuard {
+      handle: self.clone(),
+      flags,
+    };
+    self.send_command_internal(HwndLoopCommand::UpdateExecutionState, false)?;
+    Ok(guard)
+  }
+
+  /// The messages that the loop's window handled most recently, oldest first, if it was built
+  /// with [`HwndLoopBuilder::spy`](::HwndLoopBuilder::spy).
+  pub fn spy_log(&self) -> Vec<SpyRecord> {
+    self.shared.spy.as_ref().map_or_else(Vec::new, |spy| spy.dump())
+  }
+
+  /// The `top` window messages and commands that the loop spent the most time handling since the
+  /// last snapshot, if it was built with [`HwndLoopBuilder::profile`](::HwndLoopBuilder::profile),
+  /// along with any others that were among the `top` slowest to handle once.
+  ///
+  /// Each snapshot starts over from the one before it, so this is meant to be called periodically
+  /// by a single caller. Without profiling, the snapshot is empty.
+  pub fn profile_snapshot(&self, top: usize) -> ProfileSnapshot<CommandType> {
+    match self.shared.profiler {
+      Some(ref profiler) => profiler.snapshot(top),
+      None => ProfileSnapshot {
+        elapsed: Duration::default(),
+        messages: Vec::new(),
+        commands: Vec::new(),
+      },
+    }
+  }
+
+  /// Send a command to the loop and wait for it to be handled, returning the error from
+  /// [`HwndLoopCallbacks::try_handle_command`](::HwndLoopCallbacks::try_handle_command) as
+  /// [`Error::Command`].
+  ///
+  /// This must not be called from the handler thread.
+  pub fn call(&self, cmd: CommandType) -> Result<()> {
+    self.check_blocking("call");
+    let (tx, rx) = channel();
+    self.send_command_internal(HwndLoopCommand::Call(cmd, tx), true)?;
+    dispatch::warn_if_in_send_message("call");
+    rx.recv().unwrap_or(Err(Error::Terminated))
+  }
+
+  /// Send a group of commands to be handled back to back, without any other command in between,
+  /// and wait for them to be handled.
+  ///
+  /// The group takes up a single place in the loop's order, so commands from other threads can't
+  /// interleave with it, and it's held back as a whole while the loop is paused or commands are
+  /// deferred. Every command in the group is handled, and the first error from
+  /// [`HwndLoopCallbacks::try_handle_command`](::HwndLoopCallbacks::try_handle_command) is
+  /// returned as [`Error::Command`]. If the loop terminates before getting to the group, none of
+  /// it is handled, and this fails with [`Error::Terminated`].
+  ///
+  /// This must not be called from the handler thread.
+  pub fn call_transaction<I: IntoIterator<Item = CommandType>>(&self, cmds: I) -> Result<()> {
+    self.check_blocking("call_transaction");
+    let (tx, rx) = channel();
+    let cmds = cmds.into_iter().collect();
+    self.send_command_internal(HwndLoopCommand::Transaction(cmds, tx), true)?;
+    dispatch::warn_if_in_send_message("call_transaction");
+    rx.recv().unwrap_or(Err(Error::Terminated))
+  }
+
+  /// The sequence number of the last command that the loop has finished handling, if any.
+  ///
+  /// Every command up to and including it has been handled. Commands that are held back while the
+  /// loop is paused only count once they've been handled after it's resumed.
+  pub fn last_processed_seq(&self) -> Option<CommandSeq> {
+    match self.shared.processed_seq.load(Ordering::SeqCst) {
+      0 => None,
+      seq => Some(CommandSeq(seq)),
+    }
+  }
+
+  /// Tell the loop to terminate once it has handled all previously sent commands.
+  ///
+  /// This doesn't wait for the loop to finish tearing down. Loops owned by a [`HwndLoop`](::HwndLoop)
+  /// are normally terminated by dropping it, which also joins the handler thread.
+  pub fn terminate(&self) -> Result<()> {
+    self.send_command_internal(HwndLoopCommand::Terminate, true)
+  }
+
+  /// Wait until all previously enqueued commands and flushes have been processed.
+  ///
+  /// Window messages posted from this thread before the call are also guaranteed to have been
+  /// handled, but messages that Windows generates rather than posts (input, WM_PAINT, WM_TIMER)
+  /// and messages posted by other threads might not be. Use [`LoopHandle::flush_all`] for those.
+  pub fn flush(&self) -> Result<()> {
+    self.flush_internal(*WM_HWNDLOOP_FLUSH)
+  }
+
+  /// Wait until everything already in the handler thread's message queue has been processed,
+  /// including window messages posted by other threads or generated by Windows.
+  ///
+  /// Returns [`Error::QueueSaturated`] if the message queue is too full for the loop to mark where
+  /// the flush ends.
+  pub fn flush_all(&self) -> Result<()> {
+    self.flush_internal(*WM_HWNDLOOP_FLUSH_ALL)
+  }
+
+  /// Wait until the command numbered `seq` has been handled, or for at most `timeout`.
+  ///
+  /// Unlike [`LoopHandle::flush`], this doesn't add anything to the loop's queue, and only waits
+  /// for the commands up to `seq`. Fails with [`Error::TimedOut`] if the command isn't handled in
+  /// time, or with [`Error::Terminated`] if the loop terminates first.
+  pub fn flush_until(&self, seq: CommandSeq, timeout: Duration) -> Result<()> {
+    self.check_blocking("flush_until");
+    dispatch::warn_if_in_send_message("flush_until");
+    let deadline = Instant::now() + timeout;
+    self.shared.seq_waiters.fetch_add(1, Ordering::SeqCst);
+    let mut lock = self.shared.seq_lock.lock().unwrap();
+    let result = loop {
+      if self.shared.processed_seq.load(Ordering::SeqCst) >= seq.0 {
+        break Ok(());
+      }
+      if self.shared.terminated.load(Ordering::SeqCst) {
+        break Err(Error::Terminated);
+      }
+      let now = Instant::now();
+      if now >= deadline {
+        break Err(Error::TimedOut);
+      }
+      lock = self.shared.seq_cond.wait_timeout(lock, deadline - now).unwrap().0;
+    };
+    drop(lock);
+    self.shared.seq_waiters.fetch_sub(1, Ordering::SeqCst);
+    result
+  }
+
+  /// Block until the loop has terminated, however that happens.
+  pub(crate) fn wait_terminated(&self) {
+    self.shared.seq_waiters.fetch_add(1, Ordering::SeqCst);
+    let mut lock = self.shared.seq_lock.lock().unwrap();
+    while !self.shared.terminated.load(Ordering::SeqCst) {
+      lock = self.shared.seq_cond.wait(lock).unwrap();
+    }
+    drop(lock);
+    self.shared.seq_waiters.fetch_sub(1, Ordering::SeqCst);
+  }
+
+  /// Install an accelerator table, or remove the current one with `None`.
+  ///
+  /// Keystrokes in the table that reach any window on the handler thread are delivered to
+  /// [`HwndLoopCallbacks::handle_accelerator`](::HwndLoopCallbacks::handle_accelerator) instead of
+  /// being dispatched. The table is replaced in order with respect to previously sent commands.
+  pub fn set_accelerators(&self, table: Option<AcceleratorTable>) -> Result<()> {
+    self.send_command_internal(HwndLoopCommand::SetAccelerators(table), false)
+  }
+
+  /// Have the message pump call `IsDialogMessageW` for a modeless dialog, so that keyboard
+  /// navigation (tab, arrow keys, default buttons) works in it.
+  ///
+  /// The dialog must have been created on the handler thread. Dialogs that are destroyed are
+  /// unregistered automatically.
+  pub fn register_dialog(&self, dialog: HwndWrapper) -> Result<()> {
+    self.send_command_internal(HwndLoopCommand::RegisterDialog(dialog), false)
+  }
+
+  /// Stop calling `IsDialogMessageW` for a dialog registered with [`LoopHandle::register_dialog`].
+  pub fn unregister_dialog(&self, dialog: HwndWrapper) -> Result<()> {
+    self.send_command_internal(HwndLoopCommand::UnregisterDialog(dialog), false)
+  }
+
+  /// Store `value` in one of the window's [`slots`](::slots), dropping whatever was in it before.
+  ///
+  /// The slot is filled in order with respect to previously sent commands. Slots that don't exist
+  /// are reported in the log.
+  pub fn set_slot<T: std::any::Any + Send>(&self, key: usize, value: T) -> Result<()> {
+    self.send_command_internal(HwndLoopCommand::SetSlot(key, Some(Box::new(value))), false)
+  }
+
+  /// Empty one of the window's [`slots`](::slots), dropping whatever was in it.
+  pub fn clear_slot(&self, key: usize) -> Result<()> {
+    self.send_command_internal(HwndLoopCommand::SetSlot(key, None), false)
+  }
+
+  /// Run `f` on the handler thread while the loop is being torn down, before its window is
+  /// destroyed.
+  ///
+  /// This lets independent components clean up after themselves without going through
+  /// [`HwndLoopCallbacks::tear_down`](::HwndLoopCallbacks::tear_down). Hooks run right after it,
+  /// most recently registered first.
+  pub fn on_teardown<F: FnOnce(HWND) + Send + 'static>(&self, f: F) -> Result<()> {
+    self.send_command_internal(HwndLoopCommand::OnTeardown(Hook(Box::new(f))), false)
+  }
+
+  /// Run `f` on the handler thread the next time the loop's window receives `msg`, e.g. to wait
+  /// for the next WM_DEVICECHANGE after plugging something in.
+  ///
+  /// `f` only observes the message, which is still passed on to
+  /// [`HwndLoopCallbacks::handle_message`](::HwndLoopCallbacks::handle_message) afterwards. It's
+  /// registered in order with respect to previously sent commands, so occurrences that are
+  /// handled before then don't count.
+  pub fn once<F: FnOnce(HWND, UINT, WPARAM, LPARAM) + Send + 'static>(&self, msg: UINT, f: F) -> Result<()> {
+    self.send_command_internal(HwndLoopCommand::Once(msg, MessageHook(Box::new(f))), false)
+  }
+
+  /// Take the loop's first event receiver, if it was built with
+  /// [`HwndLoopBuilder::events::<E>`](::HwndLoopBuilder::events).
+  ///
+  /// This receiver exists from the moment the loop is created, so it doesn't miss anything, but
+  /// there's only one, so this returns `None` after the first call.
+  pub fn event_receiver<E: Clone + Send + 'static>(&self) -> Option<EventReceiver<E>> {
+    let events = self.shared.events.as_ref()?;
+    EventChannel::<E>::downcast(&**events)?.take_receiver()
+  }
+
+  /// Subscribe another receiver to the loop's events, which gets every event sent from now on
+  /// independently of the others.
+  pub fn subscribe<E: Clone + Send + 'static>(&self) -> Option<EventReceiver<E>> {
+    self.subscribe_with(Backpressure::Unbounded)
+  }
+
+  /// Subscribe another receiver to the loop's events, with a policy for when it falls behind.
+  pub fn subscribe_with<E: Clone + Send + 'static>(&self, policy: Backpressure) -> Option<EventReceiver<E>> {
+    let events = self.shared.events.as_ref()?;
+    EventChannel::<E>::downcast(&**events).map(|channel| channel.subscribe(policy))
+  }
+
+  /// Start waiting for the next time the loop's window receives `msg`, e.g. for the reply in a
+  /// request/ack protocol with another window.
+  ///
+  /// This waits for the registration to be processed, so the message can be triggered as soon as
+  /// it returns without racing the waiter. It must not be called from the handler thread.
+  pub fn expect_message(&self, msg: UINT) -> Result<MessageWaiter> {
+    let (waiter, completion) = MessageWaiter::new();
+    self.once(msg, move |_hwnd, _msg, w, l| completion.complete(w, l))?;
+    self.flush()?;
+    Ok(waiter)
+  }
+
+  /// Block until the loop's window next receives `msg`, returning its WPARAM and LPARAM.
+  ///
+  /// Fails with [`Error::TimedOut`] if it doesn't arrive within `timeout`. Use
+  /// [`LoopHandle::expect_message`] instead if the message is triggered by the caller, or to wait
+  /// for it asynchronously.
+  pub fn wait_for_message(&self, msg: UINT, timeout: Duration) -> Result<(WPARAM, LPARAM)> {
+    self.expect_message(msg)?.wait(timeout)
+  }
+
+  /// Show a toast notification from the handler thread, waiting until it's been shown.
+  ///
+  /// WinRT is initialized on the handler thread the first time this is called. The toast's
+  /// activation callback is called on the handler thread, in order with commands.
+  #[cfg(feature = "toast")]
+  pub fn show_toast(&self, toast: ::toast::ToastBuilder) -> Result<()> {
+    self.check_blocking("show_toast");
+    let (tx, rx) = channel();
+    self.send_command_internal(HwndLoopCommand::ShowToast(toast, tx), true)?;
+    dispatch::warn_if_in_send_message("show_toast");
+    rx.recv().unwrap_or(Err(Error::Terminated))
+  }
+
+  /// Install a WinEvent hook for the events in `events` from every process, delivering them to
+  /// [`HwndLoopCallbacks::handle_win_event`](::HwndLoopCallbacks::handle_win_event).
+  ///
+  /// The hook is installed on the handler thread and is removed when the loop terminates. Events
+  /// are delivered by the loop's message pump like sent messages, so they're still delivered
+  /// while the loop is paused.
+  pub fn hook_winevents(&self, events: RangeInclusive<DWORD>) -> Result<()> {
+    self.check_blocking("hook_winevents");
+    let (tx, rx) = channel();
+    let (min, max) = events.into_inner();
+    self.send_command_internal(HwndLoopCommand::HookWinEvents(min, max, tx), true)?;
+    dispatch::warn_if_in_send_message("hook_winevents");
+    rx.recv().unwrap_or(Err(Error::Terminated))
+  }
+
+  /// Stop handling user commands and posted window messages until [`LoopHandle::resume`] is
+  /// called.
+  ///
+  /// Once this returns, no callbacks will run for commands or posted messages, which are instead
+  /// buffered to be handled in order when the loop is resumed. Flushes are still answered once
+  /// everything before them has been buffered. Messages sent with `SendMessage` can't be deferred,
+  /// and are still delivered to [`HwndLoopCallbacks::handle_message`](::HwndLoopCallbacks::handle_message)
+  /// while paused.
+  pub fn pause(&self) -> Result<()> {
+    self.flush_internal(*WM_HWNDLOOP_PAUSE)
+  }
+
+  /// Resume a loop that was paused with [`LoopHandle::pause`].
+  ///
+  /// Everything buffered while the loop was paused is handled before anything that arrives later.
+  pub fn resume(&self) -> Result<()> {
+    self.flush_internal(*WM_HWNDLOOP_RESUME)
+  }
+
+  fn flush_internal(&self, msg: UINT) -> Result<()> {
+    self.check_blocking("flush");
+    dispatch::warn_if_in_send_message("flush");
+    let (tx, rx) = channel();
+    let id = self.shared.flush_seq.fetch_add(1, Ordering::SeqCst);
+    let thread = unsafe { GetCurrentThreadId() };
+    self.shared.flush_requests.lock().unwrap().push_back(FlushRequest { id, thread, tx });
+
+    if let Err(err) = self.post_message(msg, id, None) {
+      self.shared.flush_requests.lock().unwrap().retain(|req| req.id != id);
+      return Err(err);
+    }
+
+    // The loop fails outstanding requests when it shuts down, but the sender could also have been
+    // dropped if it shut down before our request was queued.
+    rx.recv().unwrap_or(Err(Error::Terminated))
+  }
+
+  /// Report how far behind the loop is, so that producers can throttle themselves.
+  ///
+  /// A loop that's stuck in a callback shows up as a growing
+  /// [`since_last_dispatch`](LoopStatus::since_last_dispatch) with work still pending.
+  pub fn status(&self) -> LoopStatus {
+    let last_dispatch = Duration::from_micros(self.shared.last_dispatch_us.load(Ordering::SeqCst));
+    LoopStatus {
+      pending_commands: self.pending_commands(),
+      input_pending: self.shared.input_pending.load(Ordering::SeqCst),
+      since_last_dispatch: self.shared.created.elapsed().checked_sub(last_dispatch).unwrap_or_default(),
+      dropped_events: self.shared.events.as_ref().map_or(0, |events| events.dropped()),
+    }
+  }
+
+  /// Number of commands that have been sent but not yet handled, including urgent ones and ones
+  /// that the loop is holding on to while it's paused or deferring commands.
+  ///
+  /// Only commands for the callbacks count, not the loop's own, like the ones sent by
+  /// [`LoopHandle::set_slot`] or [`LoopHandle::once`]. Each command in a
+  /// [`call_transaction`](LoopHandle::call_transaction) counts separately.
+  pub fn pending_commands(&self) -> usize {
+    self.shared.command_queue.lock().unwrap().iter().map(|(_, cmd)| cmd.user_count()).sum::<usize>()
+      + self.shared.urgent_queue.lock().unwrap().len()
+      + self.shared.buffered.load(Ordering::SeqCst)
+  }
+
+  /// Render the commands that are waiting in the loop's queues with their `Debug` implementations,
+  /// for diagnosing a backlog. This is only available in debug builds.
+  ///
+  /// Urgent commands come first, in the order they'll be handled, followed by queued commands
+  /// prefixed with their [`CommandSeq`], with each command in a transaction listed separately. The
+  /// loop's own commands aren't included, and neither are commands that the loop has already taken
+  /// off of the queue to hold on to while it's paused or deferring commands.
+  #[cfg(debug_assertions)]
+  pub fn snapshot_commands(&self) -> Vec<String> {
+    let mut snapshot: Vec<String> =
+      self.shared.urgent_queue.lock().unwrap().iter().map(|cmd| format!("urgent {:?}", cmd)).collect();
+    for (seq, cmd) in self.shared.command_queue.lock().unwrap().iter() {
+      let cmds = match cmd {
+        HwndLoopCommand::UserCommand(cmd) | HwndLoopCommand::Expiring(cmd, _) | HwndLoopCommand::Call(cmd, _) => {
+          std::slice::from_ref(cmd)
+        }
+        HwndLoopCommand::Transaction(cmds, _) => &cmds[..],
+        _ => &[],
+      };
+      snapshot.extend(cmds.iter().map(|cmd| format!("{} {:?}", CommandSeq(*seq), cmd)));
+    }
+    snapshot
+  }
+
+  /// Number of times a send found the window's message queue saturated and had to fall back to
+  /// the wake event, over the lifetime of the loop.
+  pub fn saturation_count(&self) -> usize {
+    self.shared.saturations.load(Ordering::SeqCst)
+  }
+}
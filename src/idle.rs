@@ -0,0 +1,86 @@
+//! User input idle detection.
+//!
+//! Loops built with [`HwndLoopBuilder::idle_threshold`](::HwndLoopBuilder::idle_threshold) check
+//! `GetLastInputInfo` on a timer, and tell
+//! [`HwndLoopCallbacks::handle_idle_event`](::HwndLoopCallbacks::handle_idle_event) whenever the
+//! user goes idle for longer than the threshold, or comes back.
+
+use std::time::Duration;
+
+use winapi::shared::basetsd::UINT_PTR;
+use winapi::shared::minwindef::{FALSE, UINT};
+use winapi::shared::windef::HWND;
+use winapi::um::sysinfoapi::GetTickCount;
+use winapi::um::winuser::{GetLastInputInfo, KillTimer, SetTimer, LASTINPUTINFO};
+
+use Result;
+
+/// The ID of the timer on the loop's window that checks for input, which is unlikely to collide
+/// with a timer set by the callbacks.
+pub(crate) const IDLE_TIMER_ID: UINT_PTR = 0x4849_444c;
+
+/// Bounds on how often the timer fires, as a fraction of the threshold.
+const MIN_INTERVAL: Duration = Duration::from_millis(100);
+const MAX_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A transition between the user being active and idle.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IdleEvent {
+  /// There's been no input for this long, which is at least the threshold.
+  UserIdle(Duration),
+
+  /// There was input after the user had gone idle.
+  UserActive,
+}
+
+/// Watches for the user going idle, with a timer on the loop's window.
+pub(crate) struct IdleWatcher {
+  hwnd: HWND,
+  threshold: Duration,
+  idle: bool,
+}
+
+impl IdleWatcher {
+  pub(crate) fn new(hwnd: HWND, threshold: Duration) -> Result<IdleWatcher> {
+    let interval = std::cmp::max(MIN_INTERVAL, std::cmp::min(MAX_INTERVAL, threshold / 4));
+    if unsafe { SetTimer(hwnd, IDLE_TIMER_ID, interval.as_millis() as UINT, None) } == 0 {
+      return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(IdleWatcher {
+      hwnd,
+      threshold,
+      idle: false,
+    })
+  }
+
+  /// Check how long it's been since the last input, returning a transition if there was one.
+  pub(crate) fn poll(&mut self) -> Option<IdleEvent> {
+    let mut info = LASTINPUTINFO {
+      cbSize: std::mem::size_of::<LASTINPUTINFO>() as UINT,
+      dwTime: 0,
+    };
+    if unsafe { GetLastInputInfo(&mut info) } == FALSE {
+      warn!("failed to get last input time: {}", std::io::Error::last_os_error());
+      return None;
+    }
+    // Both are tick counts, which wrap around every 49.7 days.
+    let idle_time = Duration::from_millis(u64::from(unsafe { GetTickCount() }.wrapping_sub(info.dwTime)));
+    match (self.idle, idle_time >= self.threshold) {
+      (false, true) => {
+        self.idle = true;
+        Some(IdleEvent::UserIdle(idle_time))
+      }
+      (true, false) => {
+        self.idle = false;
+        Some(IdleEvent::UserActive)
+      }
+      _ => None,
+    }
+  }
+}
+
+impl Drop for IdleWatcher {
+  fn drop(&mut self) {
+    unsafe { KillTimer(self.hwnd, IDLE_TIMER_ID) };
+  }
+}
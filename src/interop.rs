@@ -0,0 +1,22 @@
+//! Conversions to and from the handle types of `windows-sys`, for applications that use it
+//! instead of `winapi`.
+//!
+//! Message IDs, WPARAM, LPARAM and LRESULT are plain integers in both crates, so they can be
+//! passed back and forth as they are. Window handles are pointers to different types, so
+//! [`HwndWrapper`] converts to and from `windows-sys`'s `HWND`.
+
+use windows_sys::Win32::Foundation::HWND;
+
+use HwndWrapper;
+
+impl From<HwndWrapper> for HWND {
+  fn from(hwnd: HwndWrapper) -> HWND {
+    hwnd.0 as HWND
+  }
+}
+
+impl From<HWND> for HwndWrapper {
+  fn from(hwnd: HWND) -> HwndWrapper {
+    HwndWrapper::new(hwnd as _)
+  }
+}
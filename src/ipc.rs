@@ -0,0 +1,84 @@
+//! Sending commands to a loop in another process, as WM_COPYDATA messages.
+//!
+//! This is only available with the `serde` feature. Commands are serialized with `bincode`, so
+//! both ends need to agree on the definition of the command type. Commands that the receiving
+//! loop can't deserialize (e.g. a variant that it doesn't know about yet) are rejected rather
+//! than handled.
+
+use std::any::Any;
+use std::time::Duration;
+
+use bincode;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use winapi::shared::basetsd::ULONG_PTR;
+use winapi::shared::minwindef::{DWORD, LPARAM};
+use winapi::um::winuser::{COPYDATASTRUCT, WM_COPYDATA};
+
+use dispatch;
+use {Error, HwndWrapper, Result};
+
+/// Identifies WM_COPYDATA messages that carry commands.
+pub(crate) const IPC_MAGIC: ULONG_PTR = 0x4857_4c50;
+
+/// Version of the envelope that commands are sent in, which is bumped whenever its format changes.
+pub const IPC_VERSION: u32 = 1;
+
+/// Turns the payload of a WM_COPYDATA message back into a command, boxed up so that builders
+/// don't need to know the command type.
+pub(crate) type Decoder = fn(&[u8]) -> Option<Box<dyn Any + Send>>;
+
+pub(crate) fn decode<CommandType: DeserializeOwned + Send + 'static>(data: &[u8]) -> Option<Box<dyn Any + Send>> {
+  match bincode::deserialize::<CommandType>(data) {
+    Ok(cmd) => Some(Box::new(cmd)),
+    Err(err) => {
+      warn!("rejecting IPC command that failed to deserialize: {}", err);
+      None
+    }
+  }
+}
+
+/// Get the serialized command out of a WM_COPYDATA message, if it's one of ours.
+pub(crate) unsafe fn payload<'a>(l: LPARAM) -> Option<&'a [u8]> {
+  // Anyone can send WM_COPYDATA, so don't trust it to point at anything.
+  if l == 0 {
+    return None;
+  }
+  let cds = &*(l as *const COPYDATASTRUCT);
+  if cds.dwData != IPC_MAGIC || (cds.cbData as usize) < 4 || cds.lpData.is_null() {
+    return None;
+  }
+
+  let data = std::slice::from_raw_parts(cds.lpData as *const u8, cds.cbData as usize);
+  let version = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+  if version != IPC_VERSION {
+    warn!("rejecting IPC command with unsupported version {}", version);
+    return Some(&[]);
+  }
+  Some(&data[4..])
+}
+
+/// Send a command to a loop in another process, which must have been built with
+/// [`HwndLoopBuilder::ipc_commands`](::HwndLoopBuilder::ipc_commands).
+///
+/// This returns once the command has been queued on the receiving loop, as if it had been sent
+/// with [`LoopHandle::send_command`](::LoopHandle::send_command) there. It fails with
+/// [`Error::Rejected`] if the receiver couldn't make sense of it, or [`Error::TimedOut`] as in
+/// [`dispatch::send_request`].
+pub fn send_command<CommandType: Serialize>(target: HwndWrapper, cmd: &CommandType, timeout: Duration) -> Result<()> {
+  let mut data = IPC_VERSION.to_le_bytes().to_vec();
+  if let Err(err) = bincode::serialize_into(&mut data, cmd) {
+    return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, err).into());
+  }
+
+  let cds = COPYDATASTRUCT {
+    dwData: IPC_MAGIC,
+    cbData: data.len() as DWORD,
+    lpData: data.as_mut_ptr() as _,
+  };
+  match dispatch::send_request(target, WM_COPYDATA, 0, &cds as *const COPYDATASTRUCT as LPARAM, timeout)? {
+    0 => Err(Error::Rejected),
+    _ => Ok(()),
+  }
+}
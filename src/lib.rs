@@ -1,287 +1,670 @@
 //! An implementation of an event loop backed by a Win32 window.
+//!
+//! On other platforms, the crate builds a stub with the core types, whose loops always fail to
+//! start with [`Error::UnsupportedPlatform`], so that cross-platform crates can depend on it
+//! unconditionally and decide at runtime what to do without it.
 
-#![cfg(windows)]
-
+#[cfg(windows)]
 #[macro_use]
 extern crate log;
 
+#[cfg(windows)]
 #[macro_use]
 extern crate lazy_static;
 
+#[cfg(windows)]
 extern crate winapi;
 
+#[cfg(all(windows, feature = "serde"))]
+extern crate bincode;
+#[cfg(all(windows, feature = "serde"))]
+extern crate serde;
+#[cfg(all(windows, feature = "windows-sys"))]
+extern crate windows_sys;
+
+#[cfg(windows)]
+mod accel;
+#[cfg(windows)]
+mod affinity;
+#[cfg(all(windows, feature = "audio"))]
+pub mod audio;
+#[cfg(windows)]
+mod builder;
+#[cfg(windows)]
+mod coalesce;
+#[cfg(windows)]
+mod com;
+#[cfg(windows)]
+mod context;
+#[cfg(windows)]
+mod control;
+#[cfg(all(windows, feature = "dde"))]
+pub mod dde;
+#[cfg(windows)]
+mod desktop;
+#[cfg(windows)]
+pub mod dispatch;
+mod error;
+#[cfg(not(windows))]
+mod stub;
+#[cfg(windows)]
+pub mod erased;
+#[cfg(windows)]
+mod event_loop;
+#[cfg(windows)]
+mod events;
+#[cfg(windows)]
+mod external;
+#[cfg(all(windows, feature = "fault-injection"))]
+pub mod fault;
+#[cfg(all(windows, fuzzing))]
+pub mod fuzz;
+#[cfg(windows)]
+mod handle;
+#[cfg(windows)]
+pub mod idle;
+#[cfg(all(windows, feature = "windows-sys"))]
+pub mod interop;
+#[cfg(all(windows, feature = "serde"))]
+pub mod ipc;
+#[cfg(windows)]
+mod mmcss;
+#[cfg(windows)]
+mod names;
+#[cfg(windows)]
+pub mod network;
+#[cfg(windows)]
+pub mod params;
+#[cfg(windows)]
+pub mod payload;
+#[cfg(windows)]
+pub mod profile;
+#[cfg(windows)]
+mod pump;
+#[cfg(windows)]
+pub mod service;
+#[cfg(windows)]
+pub mod slots;
+#[cfg(windows)]
+pub mod spy;
+#[cfg(all(windows, feature = "toast"))]
+pub mod toast;
+#[cfg(windows)]
 mod util;
-
-use std::collections::VecDeque;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::mpsc::channel;
+#[cfg(windows)]
+mod wait;
+#[cfg(windows)]
+pub mod winevent;
+
+#[cfg(windows)]
+pub use accel::{Accelerator, AcceleratorTable};
+#[cfg(windows)]
+pub use affinity::Affinity;
+#[cfg(windows)]
+pub use builder::{HwndLoopBuilder, TerminatePolicy};
+#[cfg(windows)]
+pub use com::ComApartment;
+#[cfg(windows)]
+pub use context::{DrainHandle, SetUpContext, TearDownContext};
+#[cfg(windows)]
+pub use desktop::Desktop;
+pub use error::{Error, Result};
+#[cfg(not(windows))]
+pub use stub::*;
+#[cfg(windows)]
+pub use event_loop::{request_shutdown, run_nested, set_exit_value, PumpStatus};
+#[cfg(windows)]
+pub use events::{Backpressure, EventEmitter, EventReceiver};
+#[cfg(windows)]
+pub use external::ExternalLoopAdapter;
+#[cfg(windows)]
+pub use handle::{CommandSeq, DeferGuard, DropReason, KeepAwake, KeepAwakeGuard, LoopHandle, LoopStatus};
+#[cfg(windows)]
+pub use handle::TimerResolution;
+#[cfg(windows)]
+pub use names::msg_name;
+#[cfg(windows)]
+pub use pump::HwndPump;
+#[cfg(windows)]
+pub use wait::MessageWaiter;
+
+#[cfg(windows)]
+use std::any::Any;
+#[cfg(windows)]
+use std::collections::HashMap;
+#[cfg(windows)]
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(windows)]
+use std::sync::mpsc::{channel, RecvTimeoutError};
+#[cfg(windows)]
 use std::sync::{Arc, Mutex};
+#[cfg(windows)]
+use std::time::Duration;
 
-use winapi::shared::minwindef::{FALSE, LPARAM, LRESULT, UINT, WPARAM};
+#[cfg(windows)]
+use winapi::shared::minwindef::{DWORD, FALSE, LPARAM, LRESULT, UINT, WORD, WPARAM};
+#[cfg(windows)]
 use winapi::shared::windef::HWND;
+#[cfg(windows)]
+use winapi::um::winuser::IsWindow;
 
-use winapi::um::processthreadsapi::GetCurrentThreadId;
-use winapi::um::winuser::*;
+#[cfg(windows)]
+use event_loop::EventLoop;
 
+#[cfg(windows)]
 #[derive(Debug)]
 enum HwndLoopCommand<CommandType: Send + std::fmt::Debug> {
   Terminate,
+  Shutdown(std::sync::mpsc::Sender<Box<dyn HwndLoopCallbacks<CommandType>>>),
   UserCommand(CommandType),
+  Expiring(CommandType, std::time::Instant),
+  Call(CommandType, std::sync::mpsc::Sender<Result<()>>),
+  Transaction(Vec<CommandType>, std::sync::mpsc::Sender<Result<()>>),
+  Dropped(Vec<CommandType>, DropReason),
+  SetAccelerators(Option<AcceleratorTable>),
+  RegisterDialog(HwndWrapper),
+  UnregisterDialog(HwndWrapper),
+  SetSlot(usize, Option<Box<dyn std::any::Any + Send>>),
+  OnTeardown(Hook),
+  Once(UINT, MessageHook),
+  HookWinEvents(DWORD, DWORD, std::sync::mpsc::Sender<Result<()>>),
+  UpdateExecutionState,
+  #[cfg(feature = "toast")]
+  ShowToast(toast::ToastBuilder, std::sync::mpsc::Sender<Result<()>>),
+  #[cfg(feature = "toast")]
+  Run(Hook),
+  #[cfg(feature = "audio")]
+  Audio(audio::AudioEvent),
+}
+
+#[cfg(windows)]
+impl<CommandType: Send + std::fmt::Debug> HwndLoopCommand<CommandType> {
+  /// How many of the commands sent through a [`LoopHandle`] this carries, as opposed to the loop's
+  /// own bookkeeping.
+  fn user_count(&self) -> usize {
+    match self {
+      HwndLoopCommand::UserCommand(_) | HwndLoopCommand::Expiring(..) | HwndLoopCommand::Call(..) => 1,
+      HwndLoopCommand::Transaction(cmds, _) => cmds.len(),
+      _ => 0,
+    }
+  }
+}
+
+/// A closure to be run on the handler thread.
+#[cfg(windows)]
+struct Hook(Box<dyn FnOnce(HWND) + Send>);
+
+#[cfg(windows)]
+impl std::fmt::Debug for Hook {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    write!(f, "Hook")
+  }
+}
+
+/// A closure to be run on the handler thread when a window message arrives.
+#[cfg(windows)]
+struct MessageHook(Box<dyn FnOnce(HWND, UINT, WPARAM, LPARAM) + Send>);
+
+#[cfg(windows)]
+impl std::fmt::Debug for MessageHook {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    write!(f, "MessageHook")
+  }
 }
 
 /// Send and Sync wrapper for [`HWND`].
 ///
 /// [`HWND`] is a raw pointer, which can't be made [`Send`] or [`Sync`] directly, so wrap it in a helper type.
-#[derive(Clone)]
-pub struct HwndWrapper(pub HWND);
+///
+/// Wrappers of a loop's window also remember which loop it belonged to, so that
+/// [`HwndWrapper::is_valid`] can tell once it's gone, even if Windows has handed the same handle
+/// out to someone else's window since.
+#[cfg(windows)]
+#[derive(Clone, Debug)]
+pub struct HwndWrapper(pub HWND, u64);
+#[cfg(windows)]
 unsafe impl Send for HwndWrapper {}
+#[cfg(windows)]
 unsafe impl Sync for HwndWrapper {}
 
+#[cfg(windows)]
+lazy_static! {
+  /// The generation of each loop window that currently exists, keyed by its handle.
+  static ref WINDOW_GENERATIONS: Mutex<HashMap<usize, u64>> = Mutex::new(HashMap::new());
+  static ref NEXT_GENERATION: AtomicU64 = AtomicU64::new(1);
+}
+
+#[cfg(windows)]
+impl HwndWrapper {
+  /// Wrap a window, which is valid for as long as it exists, or if it's a loop's window, for as
+  /// long as it's still that loop's.
+  pub fn new(hwnd: HWND) -> HwndWrapper {
+    let generation = WINDOW_GENERATIONS.lock().unwrap().get(&(hwnd as usize)).cloned();
+    HwndWrapper(hwnd, generation.unwrap_or(0))
+  }
+
+  /// Wrap a loop's newly created window, starting a new generation for its handle.
+  pub(crate) fn issue(hwnd: HWND) -> HwndWrapper {
+    let generation = NEXT_GENERATION.fetch_add(1, Ordering::SeqCst);
+    WINDOW_GENERATIONS.lock().unwrap().insert(hwnd as usize, generation);
+    HwndWrapper(hwnd, generation)
+  }
+
+  /// Invalidate every wrapper of a loop's window that was handed out, before destroying it.
+  pub(crate) fn retire(&self) {
+    let mut generations = WINDOW_GENERATIONS.lock().unwrap();
+    if generations.get(&(self.0 as usize)) == Some(&self.1) {
+      generations.remove(&(self.0 as usize));
+    }
+  }
+
+  /// Whether this wraps a loop's window that has since been destroyed.
+  pub(crate) fn is_stale(&self) -> bool {
+    self.1 != 0 && WINDOW_GENERATIONS.lock().unwrap().get(&(self.0 as usize)) != Some(&self.1)
+  }
+
+  /// Whether the window still exists, and if it was a loop's window, whether it's still that loop's.
+  ///
+  /// Like any check of a window that can be destroyed by another thread, the answer can be out of
+  /// date by the time it's acted on, unless the window belongs to the calling thread.
+  pub fn is_valid(&self) -> bool {
+    !self.is_stale() && unsafe { IsWindow(self.0) } != FALSE
+  }
+}
+
+#[cfg(windows)]
+impl From<HWND> for HwndWrapper {
+  fn from(hwnd: HWND) -> HwndWrapper {
+    HwndWrapper::new(hwnd)
+  }
+}
+
+/// The state that the system wants to put the monitors in, for
+/// [`HwndLoopCallbacks::handle_monitor_power_request`].
+#[cfg(windows)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MonitorPower {
+  /// Turn the monitors on.
+  On,
+
+  /// Put the monitors into low power mode.
+  LowPower,
+
+  /// Turn the monitors off.
+  Off,
+}
+
 /// Callbacks called by a [`HwndLoop`].
+#[cfg(windows)]
 #[allow(unused_variables)]
-pub trait HwndLoopCallbacks<CommandType: std::fmt::Debug>: Send {
+pub trait HwndLoopCallbacks<CommandType: Send + std::fmt::Debug + 'static>: Send {
   /// Called on the handler thread just before the [`HwndLoop`] starts.
-  fn set_up(&mut self, hwnd: HWND) {}
+  ///
+  /// The window already exists at this point, and [`HwndLoopCallbacks::handle_message`] has
+  /// already seen the messages sent while creating it, starting with WM_NCCREATE.
+  fn set_up(&mut self, hwnd: HWND, context: &SetUpContext<CommandType>) {}
 
   /// Called on the handler thread just before the [`HwndLoop`] terminates.
   ///
-  /// Note that if you need to wait for a message to finish tearing down, it is too late by this
-  /// point. The HWND and thread will be destroyed immediateliy after this function returns.
-  fn tear_down(&mut self, hwnd: HWND) {}
+  /// The HWND and thread are destroyed as soon as this function returns, unless it asks for more
+  /// messages to be dispatched first with [`TearDownContext::drain`], e.g. to wait for a message
+  /// that confirms that something has finished tearing down.
+  fn tear_down(&mut self, hwnd: HWND, context: &mut TearDownContext) {}
 
   /// Handle a Windows message.
   ///
-  /// Note that most messages need to have [`DefWindowProcA`] called on them for cleanup.
+  /// The loop's window is a Unicode window, so messages that carry text (e.g. WM_SETTEXT, WM_CHAR)
+  /// carry UTF-16. Note that most messages need to have the default window procedure called on
+  /// them for cleanup, which is what this does by default, with whichever of `DefWindowProcW` and
+  /// `DefWindowProcA` matches the window. Implement [`HwndLoopCallbacks::try_handle_message`]
+  /// instead to have that done automatically for the messages that aren't handled.
   fn handle_message(&mut self, hwnd: HWND, msg: UINT, w: WPARAM, l: LPARAM) -> LRESULT {
-    unsafe { DefWindowProcA(hwnd, msg, w, l) }
+    unsafe { util::def_window_proc(hwnd, msg, w, l) }
+  }
+
+  /// Handle a Windows message, returning `None` to pass it on to the default window procedure.
+  ///
+  /// This is what the loop actually calls for every message, and defaults to calling
+  /// [`HwndLoopCallbacks::handle_message`]. The default window procedure is `DefWindowProcW` or
+  /// `DefWindowProcA`, depending on whether the window is Unicode.
+  fn try_handle_message(&mut self, hwnd: HWND, msg: UINT, w: WPARAM, l: LPARAM) -> Option<LRESULT> {
+    Some(self.handle_message(hwnd, msg, w, l))
   }
 
-  /// Handle a command sent via [`HwndLoop::send_command`].
+  /// Handle a command sent via [`LoopHandle::send_command`].
   fn handle_command(&mut self, hwnd: HWND, cmd: CommandType) {}
+
+  /// Handle a command, reporting failure to its sender.
+  ///
+  /// This is what the loop actually calls for every command, and defaults to calling
+  /// [`HwndLoopCallbacks::handle_command`]. Errors are returned from [`LoopHandle::call`] as
+  /// [`Error::Command`], or logged for commands sent with [`LoopHandle::send_command`], which
+  /// doesn't wait for them to be handled.
+  fn try_handle_command(
+    &mut self,
+    hwnd: HWND,
+    cmd: CommandType,
+  ) -> std::result::Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    self.handle_command(hwnd, cmd);
+    Ok(())
+  }
+
+  /// Handle a command that the loop dropped instead of handling, because the deadline it was sent
+  /// with by [`LoopHandle::send_command_with_deadline`] passed, because it was cancelled with
+  /// [`LoopHandle::cancel_pending`], or because the loop terminated while it was still queued.
+  ///
+  /// This is the last chance to clean up whatever the command holds on to, e.g. to answer a reply
+  /// channel inside it. Commands dropped by termination are passed here before
+  /// [`HwndLoopCallbacks::tear_down`], including the ones sent with [`LoopHandle::call`], whose
+  /// callers get [`Error::Terminated`]. By default, the command is logged and dropped.
+  fn handle_dropped_command(&mut self, hwnd: HWND, cmd: CommandType, reason: DropReason) {
+    debug!("HwndLoop dropped command ({:?}): {:?}", reason, cmd);
+  }
+
+  /// Handle a keystroke from the table installed with [`LoopHandle::set_accelerators`].
+  fn handle_accelerator(&mut self, hwnd: HWND, id: WORD) {}
+
+  /// Handle the user's session being asked to end, returning false to ask for it to be kept alive.
+  ///
+  /// `reason` holds the `ENDSESSION_*` flags from WM_QUERYENDSESSION. This is sent to top-level
+  /// windows, so it's only delivered to loops built with [`HwndLoopBuilder::receive_broadcasts`].
+  fn handle_query_end_session(&mut self, hwnd: HWND, reason: LPARAM) -> bool {
+    true
+  }
+
+  /// Handle the outcome of a request to end the session, for loops built with
+  /// [`HwndLoopBuilder::receive_broadcasts`].
+  ///
+  /// If `ending` is true, the process can be terminated as soon as this returns, so this is the
+  /// last chance to clean up; see [`HwndLoopBuilder::shutdown_level`] to control when that happens
+  /// relative to other processes.
+  fn handle_end_session(&mut self, hwnd: HWND, ending: bool, reason: LPARAM) {}
+
+  /// Handle the system's font table changing, as announced by WM_FONTCHANGE, e.g. from
+  /// [`dispatch::broadcast_font_change`].
+  ///
+  /// This is broadcast to top-level windows, so it's only delivered to loops built with
+  /// [`HwndLoopBuilder::receive_broadcasts`].
+  fn handle_font_change(&mut self, hwnd: HWND) {}
+
+  /// Handle the system asking to start the screensaver, returning false to keep it from starting.
+  ///
+  /// This is a WM_SYSCOMMAND that the system only sends to the foreground window, so it's only
+  /// delivered to loops built with [`HwndLoopBuilder::receive_broadcasts`] that own it.
+  fn handle_screensaver_request(&mut self, hwnd: HWND) -> bool {
+    true
+  }
+
+  /// Handle the system asking to change the monitors' power state, returning false to keep them
+  /// as they are.
+  ///
+  /// As with [`HwndLoopCallbacks::handle_screensaver_request`], this is only delivered to loops
+  /// built with [`HwndLoopBuilder::receive_broadcasts`].
+  fn handle_monitor_power_request(&mut self, hwnd: HWND, state: MonitorPower) -> bool {
+    true
+  }
+
+  /// Handle a buffer sent from another process with [`payload::send_payload`].
+  ///
+  /// The sender is told that the payload has been consumed when `payload` is dropped, which can
+  /// happen on another thread if the contents take a while to process.
+  fn handle_payload(&mut self, hwnd: HWND, payload: payload::SharedPayload) {}
+
+  /// Handle a request from the service control manager, for loops run with [`service::run`].
+  ///
+  /// The return value is passed back to the service control manager, e.g. `BROADCAST_QUERY_DENY`
+  /// to veto a power or device event that asks for permission.
+  fn handle_service_control(&mut self, hwnd: HWND, control: service::ServiceControl) -> DWORD {
+    0
+  }
+
+  /// Handle a change to the system's network configuration, for loops built with
+  /// [`HwndLoopBuilder::network_notifications`].
+  fn handle_network_event(&mut self, hwnd: HWND, event: network::NetworkEvent) {}
+
+  /// Handle the user going idle or coming back, for loops built with
+  /// [`HwndLoopBuilder::idle_threshold`].
+  fn handle_idle_event(&mut self, hwnd: HWND, event: idle::IdleEvent) {}
+
+  /// Handle an event from a hook installed with [`LoopHandle::hook_winevents`].
+  fn handle_win_event(&mut self, hwnd: HWND, event: winevent::WinEvent) {}
+
+  /// Handle a change to the system's audio endpoints, for loops built with
+  /// [`HwndLoopBuilder::audio_notifications`].
+  #[cfg(feature = "audio")]
+  fn handle_audio_event(&mut self, hwnd: HWND, event: audio::AudioEvent) {}
+
+  /// Handle a command that a DDE client executed, for loops built with
+  /// [`HwndLoopBuilder::dde_server`], returning whether it was accepted.
+  ///
+  /// `topic` is the topic of the client's conversation, which is one of the topics the loop was
+  /// built with.
+  #[cfg(feature = "dde")]
+  fn handle_dde_execute(&mut self, hwnd: HWND, topic: &str, command: &str) -> bool {
+    false
+  }
 }
 
 /// An event loop backed by a Win32 window and thread.
 ///
 /// A [`HwndLoop`] consists of a message window and handler thread on which all callbacks happen.
+/// Commands are sent through the [`LoopHandle`] that it dereferences to. Cloning a [`HwndLoop`]
+/// shares ownership of the loop, which is terminated when the last clone is dropped.
+#[cfg(windows)]
 pub struct HwndLoop<CommandType: Send + std::fmt::Debug + 'static> {
-  hwnd: HwndWrapper,
-  terminated: Arc<AtomicBool>,
-  command_queue: Arc<Mutex<VecDeque<HwndLoopCommand<CommandType>>>>,
-  join_handle: Arc<Mutex<Option<std::thread::JoinHandle<()>>>>,
-  flush_requests: Arc<Mutex<Vec<std::sync::mpsc::Sender<()>>>>,
-}
-
-#[repr(C)]
-struct HwndLoopWndExtra<CommandType: Send + std::fmt::Debug> {
-  callbacks: *mut Box<HwndLoopCallbacks<CommandType>>,
+  handle: LoopHandle<CommandType>,
+  owner: Arc<LoopOwner<CommandType>>,
 }
 
-impl<CommandType: Send + std::fmt::Debug> HwndLoopWndExtra<CommandType> {
-  unsafe fn from_hwnd(hwnd: HWND) -> *mut HwndLoopWndExtra<CommandType> {
-    let ptr = GetWindowLongPtrA(hwnd, 0);
-    std::mem::transmute(ptr)
-  }
+/// Terminates a loop's thread and waits for it to exit, once every [`HwndLoop`] for it is gone.
+#[cfg(windows)]
+struct LoopOwner<CommandType: Send + std::fmt::Debug + 'static> {
+  handle: LoopHandle<CommandType>,
+  join_handle: Option<std::thread::JoinHandle<()>>,
+  terminate_policy: TerminatePolicy,
 }
 
+#[cfg(windows)]
 lazy_static! {
-  static ref WM_HWNDLOOP_INIT: u32 = {
-    let msg = unsafe { RegisterWindowMessageA(b"WM_HWNDLOOP_INIT\0".as_ptr() as *const i8) };
-    assert_ne!(0, msg);
-    msg
-  };
-  static ref WM_HWNDLOOP_COMMAND: u32 = {
-    let msg = unsafe { RegisterWindowMessageA(b"WM_HWNDLOOP_COMMAND\0".as_ptr() as *const i8) };
-    assert_ne!(0, msg);
-    msg
-  };
-  static ref WM_HWNDLOOP_FLUSH: u32 = {
-    let msg = unsafe { RegisterWindowMessageA(b"WM_HWNDLOOP_FLUSH\0".as_ptr() as *const i8) };
-    assert_ne!(0, msg);
-    msg
+  static ref WM_HWNDLOOP_COMMAND: u32 = util::register_message("WM_HWNDLOOP_COMMAND");
+  static ref WM_HWNDLOOP_FLUSH: u32 = util::register_message("WM_HWNDLOOP_FLUSH");
+  static ref WM_HWNDLOOP_FLUSH_ALL: u32 = util::register_message("WM_HWNDLOOP_FLUSH_ALL");
+  static ref WM_HWNDLOOP_PAUSE: u32 = util::register_message("WM_HWNDLOOP_PAUSE");
+  static ref WM_HWNDLOOP_RESUME: u32 = util::register_message("WM_HWNDLOOP_RESUME");
+  static ref WM_HWNDLOOP_FLUSH_MARKER: u32 = util::register_message("WM_HWNDLOOP_FLUSH_MARKER");
+  /// The LPARAM of the internal messages that handles post, which tells them apart from lookalikes
+  /// posted by anyone else.
+  static ref INTERNAL_MESSAGE_TAG: LPARAM = {
+    let pid = unsafe { winapi::um::processthreadsapi::GetCurrentProcessId() };
+    (0x484c_0000 | (pid & 0xffff)) as LPARAM
   };
+  static ref WM_HWNDLOOP_PAYLOAD: u32 = util::register_message("WM_HWNDLOOP_PAYLOAD");
+  static ref WM_HWNDLOOP_SERVICE_CONTROL: u32 = util::register_message("WM_HWNDLOOP_SERVICE_CONTROL");
 }
 
+/// How long a loop's thread gets to start up, unless [`HwndLoopBuilder::startup_timeout`] says
+/// otherwise.
+#[cfg(windows)]
+const DEFAULT_STARTUP_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[cfg(windows)]
 impl<CommandType: Send + std::fmt::Debug + 'static> HwndLoop<CommandType> {
   /// Create a new [`HwndLoop`].
-  pub fn new(mut callbacks: Box<HwndLoopCallbacks<CommandType>>) -> HwndLoop<CommandType> {
-    let (tx, rx) = channel();
-    let join_handle = std::thread::spawn(move || {
-      let class_name = util::to_utf16(&format!("RawInputRS{}", unsafe { GetCurrentThreadId() }));
-      let wndclass = WNDCLASSEXW {
-        cbSize: std::mem::size_of::<WNDCLASSEXW>() as UINT,
-        style: 0,
-        lpfnWndProc: Some(HwndLoop::<CommandType>::wnd_proc),
-        cbClsExtra: 0,
-        cbWndExtra: std::mem::size_of::<*mut HwndLoopWndExtra<CommandType>>() as i32,
-        hInstance: util::get_module_handle(),
-        hIcon: std::ptr::null_mut(),
-        hCursor: std::ptr::null_mut(),
-        hbrBackground: std::ptr::null_mut(),
-        lpszMenuName: std::ptr::null_mut(),
-        lpszClassName: class_name.as_ptr(),
-        hIconSm: std::ptr::null_mut(),
-      };
-
-      let window_class = unsafe { RegisterClassExW(&wndclass) };
-      if window_class == 0 {
-        panic!("RegisterClassExW failed: {}", std::io::Error::last_os_error());
-      }
+  pub fn new(callbacks: Box<dyn HwndLoopCallbacks<CommandType>>) -> HwndLoop<CommandType> {
+    HwndLoopBuilder::new().build(callbacks)
+  }
 
-      let hwnd = unsafe {
-        CreateWindowExW(
-          WS_EX_NOREDIRECTIONBITMAP,
-          util::atom_to_lpwstr(window_class),
-          util::to_utf16("rawinput window").as_ptr(),
-          0,
-          CW_USEDEFAULT,
-          CW_USEDEFAULT,
-          CW_USEDEFAULT,
-          CW_USEDEFAULT,
-          HWND_MESSAGE,
-          std::ptr::null_mut(),
-          util::get_module_handle(),
-          std::ptr::null_mut(),
-        )
+  pub(crate) fn spawn(
+    config: HwndLoopBuilder,
+    callbacks: Box<dyn HwndLoopCallbacks<CommandType>>,
+  ) -> Result<HwndLoop<CommandType>> {
+    let (tx, rx) = channel();
+    let terminate_policy = config.terminate_policy;
+    let startup_timeout = config.startup_timeout.unwrap_or(DEFAULT_STARTUP_TIMEOUT);
+    let mut thread = std::thread::Builder::new();
+    if let Some(size) = config.stack_size {
+      thread = thread.stack_size(size);
+    }
+    let join_handle = thread.spawn(move || {
+      let mut event_loop = match EventLoop::new(&config, callbacks) {
+        Ok(event_loop) => event_loop,
+        Err(err) => {
+          let _ = tx.send(Err(err));
+          return;
+        }
       };
 
-      if hwnd == std::ptr::null_mut() {
-        panic!("CreateWindowExW failed");
+      // We're started, time to return the result, unless whoever's waiting for it gave up.
+      if tx.send(Ok(event_loop.handle())).is_err() {
+        warn!("HwndLoop started after its startup timeout, tearing it down");
+        return;
       }
 
-      let command_queue = Arc::new(Mutex::new(VecDeque::new()));
-      let flush_requests = Arc::new(Mutex::new(Vec::<std::sync::mpsc::Sender<()>>::new()));
-
-      let mut msg = unsafe { std::mem::uninitialized() };
+      event_loop.run();
+    })?;
 
-      let result = unsafe { PostMessageW(hwnd, *WM_HWNDLOOP_INIT, 0, 1) };
-      if result == 0 {
-        panic!(
-          "failed to PostMessageW during message window startup: {}",
-          std::io::Error::last_os_error()
-        );
+    let handle = match rx.recv_timeout(startup_timeout) {
+      Ok(Ok(handle)) => handle,
+      Ok(Err(err)) => {
+        join_handle.join().unwrap();
+        return Err(err);
       }
-
-      callbacks.set_up(hwnd);
-
-      // Set up the callbacks to be called from wnd_proc.
-      let raw_cb = Box::into_raw(Box::new(callbacks));
-      let wnd_extra = Box::into_raw(Box::new(HwndLoopWndExtra { callbacks: raw_cb }));
-      unsafe { SetWindowLongPtrA(hwnd, 0, std::mem::transmute(wnd_extra)) };
-
-      'eventloop: loop {
-        let result = unsafe { GetMessageW(&mut msg, std::ptr::null_mut(), 0, 0) };
-        if result <= 0 {
-          panic!("GetMessageW failed");
-        }
-
-        // We're started, time to return the result.
-        if msg.message == *WM_HWNDLOOP_INIT {
-          tx.send((HwndWrapper(hwnd), command_queue.clone(), flush_requests.clone()))
-            .unwrap();
-        } else if msg.message == *WM_HWNDLOOP_COMMAND {
-          // Only process commands when we receive a poke, to ensure that we maintain ordering.
-          let mut queue = command_queue.lock().unwrap();
-          if !queue.is_empty() {
-            let cmd = queue.pop_front().unwrap();
-            trace!("HwndLoop received command: {:?}", cmd);
-            match cmd {
-              HwndLoopCommand::Terminate => {
-                break 'eventloop;
-              }
-
-              HwndLoopCommand::UserCommand(cmd) => {
-                unsafe { (*raw_cb).handle_command(hwnd, cmd) };
-              }
-            }
-          }
-        } else if msg.message == *WM_HWNDLOOP_FLUSH {
-          let mut reqs = flush_requests.lock().unwrap();
-          (*reqs).pop().unwrap().send(()).unwrap();
-        } else {
-          unsafe { DispatchMessageW(&msg) };
-        }
+      // The thread went away without saying anything, so it must have panicked.
+      Err(RecvTimeoutError::Disconnected) => {
+        let message = match join_handle.join() {
+          Ok(()) => "thread exited without starting the loop".to_string(),
+          Err(payload) => match payload.downcast::<String>() {
+            Ok(message) => *message,
+            Err(payload) => match payload.downcast::<&'static str>() {
+              Ok(message) => message.to_string(),
+              Err(_) => "unknown panic".to_string(),
+            },
+          },
+        };
+        return Err(Error::Panicked(message));
       }
-
-      unsafe { (*raw_cb).tear_down(hwnd) };
-
-      // Remove the callbacks from the window.
-      unsafe { SetWindowLongPtrA(hwnd, 0, 0) };
-
-      // Destroy the callbacks.
-      unsafe { Box::from_raw(raw_cb) };
-
-      // Destroy the window.
-      unsafe { assert_ne!(FALSE, DestroyWindow(hwnd)) };
-
-      // Destroy the window class.
-      unsafe {
-        assert_ne!(
-          FALSE,
-          UnregisterClassW(util::atom_to_lpwstr(window_class), util::get_module_handle())
-        )
-      };
-    });
-
-    let (hwnd, command_queue, flush_requests) = rx.recv().unwrap();
-    HwndLoop {
-      terminated: Arc::new(AtomicBool::from(false)),
-      hwnd,
-      command_queue,
-      join_handle: Arc::new(Mutex::new(Some(join_handle))),
-      flush_requests,
-    }
+      // There's no joining a thread that's stuck, so it's left to clean up after itself.
+      Err(RecvTimeoutError::Timeout) => return Err(Error::TimedOut),
+    };
+    Ok(HwndLoop {
+      handle: handle.clone(),
+      owner: Arc::new(LoopOwner {
+        handle,
+        join_handle: Some(join_handle),
+        terminate_policy,
+      }),
+    })
   }
 
-  unsafe extern "system" fn wnd_proc(hwnd: HWND, msg: UINT, w: WPARAM, l: LPARAM) -> LRESULT {
-    let wnd_extra = HwndLoopWndExtra::<CommandType>::from_hwnd(hwnd);
-    if wnd_extra == std::ptr::null_mut() {
-      return DefWindowProcA(hwnd, msg, w, l);
-    }
+  /// Create the loop's window on the current thread, and pump its messages until it's terminated
+  /// with [`LoopHandle::terminate`].
+  ///
+  /// This is for APIs that need to be used from a particular thread (e.g. the process's first
+  /// thread). `ready` is called with a handle to the loop before the first message is processed,
+  /// and is the place to hand it off to other threads.
+  pub fn run_here<F: FnOnce(LoopHandle<CommandType>)>(
+    callbacks: Box<dyn HwndLoopCallbacks<CommandType>>,
+    ready: F,
+  ) -> Result<()> {
+    HwndLoopBuilder::new().run_here(callbacks, ready)
+  }
 
-    (*(*wnd_extra).callbacks).handle_message(hwnd, msg, w, l)
+  pub(crate) fn run_with_config<F: FnOnce(LoopHandle<CommandType>)>(
+    config: &HwndLoopBuilder,
+    callbacks: Box<dyn HwndLoopCallbacks<CommandType>>,
+    ready: F,
+  ) -> Result<()> {
+    let mut event_loop = EventLoop::new(config, callbacks)?;
+    ready(event_loop.handle());
+    event_loop.run();
+    Ok(())
   }
 
-  fn send_command_internal(&self, cmd: HwndLoopCommand<CommandType>) {
-    let mut queue = self.command_queue.lock().unwrap();
-    queue.push_back(cmd);
-    let result = unsafe { PostMessageW(self.hwnd.0, *WM_HWNDLOOP_COMMAND, 0, 1) };
-    if result == FALSE {
-      panic!("PostMessageW failed: {}", std::io::Error::last_os_error());
-    }
+  /// Terminate the loop and hand back its callbacks, instead of destroying them along with the
+  /// window.
+  ///
+  /// [`HwndLoopCallbacks::tear_down`] and the teardown hooks run first, as usual. This terminates
+  /// the loop even if it has other clones, which are left with a terminated loop. Fails with
+  /// [`Error::Terminated`] if the loop had already been terminated, in which case the callbacks
+  /// are already gone.
+  pub fn shutdown_into_inner(self) -> Result<Box<dyn HwndLoopCallbacks<CommandType>>> {
+    self.handle.check_blocking("shutdown_into_inner");
+    let (tx, rx) = channel();
+    self.handle.send_command_internal(HwndLoopCommand::Shutdown(tx), true)?;
+    rx.recv().map_err(|_| Error::Terminated)
   }
 
-  /// Send a command to a [`HwndLoop`], to be handled by [`HwndLoopCallbacks::handle_command`] on
-  /// the handler thread.
-  pub fn send_command(&self, cmd: CommandType) {
-    trace!("HwndLoop sending user command: {:?}", cmd);
-    self.send_command_internal(HwndLoopCommand::UserCommand(cmd))
+  /// Wait for the loop to terminate, and take the value that its callbacks left with
+  /// [`set_exit_value`], if it's a `V`.
+  ///
+  /// Unlike dropping the loop, this doesn't terminate it: something else has to, like a callback
+  /// calling [`request_shutdown`] or someone calling [`LoopHandle::terminate`]. Once it has, this
+  /// drops the [`HwndLoop`] as usual, which waits for the thread to exit if it was the last clone.
+  /// Only one caller gets the value, and a value of another type is thrown away.
+  pub fn join<V: Any + Send>(self) -> Option<V> {
+    self.handle.check_blocking("join");
+    self.handle.wait_terminated();
+    let shared = self.handle.shared.clone();
+    drop(self);
+    let value = shared.exit_value.lock().unwrap().take();
+    value.and_then(|value| value.downcast().ok()).map(|value| *value)
   }
 
-  /// Wait until all previously enqueued messages have been processed.
-  pub fn flush(&self) {
-    let (tx, rx) = channel();
-    let mut requests = self.flush_requests.lock().unwrap();
+  /// Get a handle for sending commands to the loop, which can outlive the [`HwndLoop`].
+  pub fn handle(&self) -> LoopHandle<CommandType> {
+    self.handle.clone()
+  }
+}
 
-    (*requests).push(tx);
-    let result = unsafe { PostMessageW(self.hwnd.0, *WM_HWNDLOOP_FLUSH, 0, 0) };
-    if result == FALSE {
-      panic!("PostMessageW failed: {}", std::io::Error::last_os_error());
-    }
+#[cfg(windows)]
+impl<CommandType: Send + std::fmt::Debug + 'static> std::ops::Deref for HwndLoop<CommandType> {
+  type Target = LoopHandle<CommandType>;
 
-    drop(requests);
+  fn deref(&self) -> &LoopHandle<CommandType> {
+    &self.handle
+  }
+}
 
-    rx.recv().unwrap();
+#[cfg(windows)]
+impl<CommandType: Send + std::fmt::Debug + 'static> Clone for HwndLoop<CommandType> {
+  fn clone(&self) -> HwndLoop<CommandType> {
+    HwndLoop {
+      handle: self.handle.clone(),
+      owner: self.owner.clone(),
+    }
   }
 }
 
-impl<CommandType: Send + std::fmt::Debug + 'static> Drop for HwndLoop<CommandType> {
+#[cfg(windows)]
+impl<CommandType: Send + std::fmt::Debug + 'static> Drop for LoopOwner<CommandType> {
   fn drop(&mut self) {
-    let terminated = self.terminated.swap(true, Ordering::SeqCst);
-    if !terminated {
-      self.send_command_internal(HwndLoopCommand::Terminate);
-      let mut opt = self.join_handle.lock().unwrap();
-      let join_handle = std::mem::replace(&mut *opt, None);
-      join_handle.unwrap().join().unwrap();
+    // If the last clone was dropped by one of the loop's own callbacks, the thread can't wait for
+    // itself to exit. Queue the termination without waiting on a full message queue, let the
+    // current callback return, and leave joining the thread to a reaper.
+    let deferred = self.handle.on_loop_thread();
+    match self.handle.send_command_internal(HwndLoopCommand::Terminate, !deferred) {
+      // Somebody already terminated the loop through a handle.
+      Ok(()) | Err(Error::Terminated) => {}
+      Err(err) => panic!("failed to terminate HwndLoop: {}", err),
+    }
+    if let TerminatePolicy::Preempt { grace } = self.terminate_policy {
+      self.handle.shared.preempt_after(grace);
+    }
+    if let Some(join_handle) = self.join_handle.take() {
+      if deferred {
+        debug!("HwndLoop dropped from its own thread, terminating once the current callback returns");
+        let reaper = std::thread::Builder::new().name("hwndloop-reaper".into());
+        if let Err(err) = reaper.spawn(move || {
+          if join_handle.join().is_err() {
+            warn!("HwndLoop thread panicked");
+          }
+        }) {
+          // The thread is detached instead, which frees it all the same once it exits.
+          warn!("failed to spawn HwndLoop reaper thread: {}", err);
+        }
+      } else {
+        join_handle.join().unwrap();
+      }
     }
   }
 }
@@ -0,0 +1,26 @@
+use winapi::shared::minwindef::DWORD;
+use winapi::um::avrt::{AvRevertMmThreadCharacteristics, AvSetMmThreadCharacteristicsW};
+use winapi::um::winnt::HANDLE;
+
+use util;
+use Result;
+
+/// Registration of the current thread with the Multimedia Class Scheduler Service, which is
+/// reverted when dropped.
+pub(crate) struct MmcssGuard(HANDLE);
+
+impl Drop for MmcssGuard {
+  fn drop(&mut self) {
+    unsafe { AvRevertMmThreadCharacteristics(self.0) };
+  }
+}
+
+/// Register the current thread with MMCSS as part of `task`, e.g. `Pro Audio` or `Games`.
+pub(crate) fn register(task: &str) -> Result<MmcssGuard> {
+  let mut index: DWORD = 0;
+  let handle = unsafe { AvSetMmThreadCharacteristicsW(util::to_utf16(task).as_ptr(), &mut index) };
+  if handle.is_null() {
+    return Err(std::io::Error::last_os_error().into());
+  }
+  Ok(MmcssGuard(handle))
+}
@@ -0,0 +1,124 @@
+//! Symbolic names of window messages, for logging.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use winapi::shared::minwindef::UINT;
+use winapi::um::winuser::*;
+
+use {WM_HWNDLOOP_COMMAND, WM_HWNDLOOP_FLUSH, WM_HWNDLOOP_FLUSH_ALL, WM_HWNDLOOP_FLUSH_MARKER, WM_HWNDLOOP_PAUSE};
+use {WM_HWNDLOOP_PAYLOAD, WM_HWNDLOOP_RESUME, WM_HWNDLOOP_SERVICE_CONTROL};
+
+/// Registered messages, which the system hands out from the same atom table as clipboard formats.
+const REGISTERED_MESSAGES: std::ops::RangeInclusive<UINT> = 0xc000..=0xffff;
+
+macro_rules! system_message_names {
+  ($($name:ident,)*) => {
+    /// The name of a message defined by the system.
+    fn system_message_name(msg: UINT) -> Option<&'static str> {
+      match msg {
+        $($name => Some(stringify!($name)),)*
+        // Not in winapi.
+        0x03e0 => Some("WM_DDE_INITIATE"),
+        0x03e1 => Some("WM_DDE_TERMINATE"),
+        0x03e2 => Some("WM_DDE_ADVISE"),
+        0x03e3 => Some("WM_DDE_UNADVISE"),
+        0x03e4 => Some("WM_DDE_ACK"),
+        0x03e5 => Some("WM_DDE_DATA"),
+        0x03e6 => Some("WM_DDE_REQUEST"),
+        0x03e7 => Some("WM_DDE_POKE"),
+        0x03e8 => Some("WM_DDE_EXECUTE"),
+        _ => None,
+      }
+    }
+  };
+}
+
+system_message_names! {
+  WM_NULL, WM_CREATE, WM_DESTROY, WM_MOVE, WM_SIZE, WM_ACTIVATE, WM_SETFOCUS, WM_KILLFOCUS, WM_ENABLE, WM_SETREDRAW,
+  WM_SETTEXT, WM_GETTEXT, WM_GETTEXTLENGTH, WM_PAINT, WM_CLOSE, WM_QUERYENDSESSION, WM_QUIT, WM_QUERYOPEN,
+  WM_ERASEBKGND, WM_SYSCOLORCHANGE, WM_ENDSESSION, WM_SHOWWINDOW, WM_SETTINGCHANGE, WM_DEVMODECHANGE, WM_ACTIVATEAPP,
+  WM_FONTCHANGE, WM_TIMECHANGE, WM_CANCELMODE, WM_SETCURSOR, WM_MOUSEACTIVATE, WM_CHILDACTIVATE, WM_QUEUESYNC,
+  WM_GETMINMAXINFO, WM_PAINTICON, WM_ICONERASEBKGND, WM_NEXTDLGCTL, WM_SPOOLERSTATUS, WM_DRAWITEM, WM_MEASUREITEM,
+  WM_DELETEITEM, WM_VKEYTOITEM, WM_CHARTOITEM, WM_SETFONT, WM_GETFONT, WM_SETHOTKEY, WM_GETHOTKEY, WM_QUERYDRAGICON,
+  WM_COMPAREITEM, WM_GETOBJECT, WM_COMPACTING, WM_COMMNOTIFY, WM_WINDOWPOSCHANGING, WM_WINDOWPOSCHANGED, WM_POWER,
+  WM_COPYDATA, WM_CANCELJOURNAL, WM_NOTIFY, WM_INPUTLANGCHANGEREQUEST, WM_INPUTLANGCHANGE, WM_TCARD, WM_HELP,
+  WM_USERCHANGED, WM_NOTIFYFORMAT, WM_CONTEXTMENU, WM_STYLECHANGING, WM_STYLECHANGED, WM_DISPLAYCHANGE, WM_GETICON,
+  WM_SETICON, WM_NCCREATE, WM_NCDESTROY, WM_NCCALCSIZE, WM_NCHITTEST, WM_NCPAINT, WM_NCACTIVATE, WM_GETDLGCODE,
+  WM_SYNCPAINT, WM_NCMOUSEMOVE, WM_NCLBUTTONDOWN, WM_NCLBUTTONUP, WM_NCLBUTTONDBLCLK, WM_NCRBUTTONDOWN, WM_NCRBUTTONUP,
+  WM_NCRBUTTONDBLCLK, WM_NCMBUTTONDOWN, WM_NCMBUTTONUP, WM_NCMBUTTONDBLCLK, WM_NCXBUTTONDOWN, WM_NCXBUTTONUP,
+  WM_NCXBUTTONDBLCLK, WM_INPUT_DEVICE_CHANGE, WM_INPUT, WM_KEYDOWN, WM_KEYUP, WM_CHAR, WM_DEADCHAR, WM_SYSKEYDOWN,
+  WM_SYSKEYUP, WM_SYSCHAR, WM_SYSDEADCHAR, WM_UNICHAR, WM_IME_STARTCOMPOSITION, WM_IME_ENDCOMPOSITION,
+  WM_IME_COMPOSITION, WM_INITDIALOG, WM_COMMAND, WM_SYSCOMMAND, WM_TIMER, WM_HSCROLL, WM_VSCROLL, WM_INITMENU,
+  WM_INITMENUPOPUP, WM_GESTURE, WM_GESTURENOTIFY, WM_MENUSELECT, WM_MENUCHAR, WM_ENTERIDLE, WM_MENURBUTTONUP,
+  WM_MENUDRAG, WM_MENUGETOBJECT, WM_UNINITMENUPOPUP, WM_MENUCOMMAND, WM_CHANGEUISTATE, WM_UPDATEUISTATE,
+  WM_QUERYUISTATE, WM_CTLCOLORMSGBOX, WM_CTLCOLOREDIT, WM_CTLCOLORLISTBOX, WM_CTLCOLORBTN, WM_CTLCOLORDLG,
+  WM_CTLCOLORSCROLLBAR, WM_CTLCOLORSTATIC, WM_MOUSEMOVE, WM_LBUTTONDOWN, WM_LBUTTONUP, WM_LBUTTONDBLCLK, WM_RBUTTONDOWN,
+  WM_RBUTTONUP, WM_RBUTTONDBLCLK, WM_MBUTTONDOWN, WM_MBUTTONUP, WM_MBUTTONDBLCLK, WM_MOUSEWHEEL, WM_XBUTTONDOWN,
+  WM_XBUTTONUP, WM_XBUTTONDBLCLK, WM_MOUSEHWHEEL, WM_PARENTNOTIFY, WM_ENTERMENULOOP, WM_EXITMENULOOP, WM_NEXTMENU,
+  WM_SIZING, WM_CAPTURECHANGED, WM_MOVING, WM_POWERBROADCAST, WM_DEVICECHANGE, WM_MDICREATE, WM_MDIDESTROY,
+  WM_MDIACTIVATE, WM_MDIRESTORE, WM_MDINEXT, WM_MDIMAXIMIZE, WM_MDITILE, WM_MDICASCADE, WM_MDIICONARRANGE,
+  WM_MDIGETACTIVE, WM_MDISETMENU, WM_ENTERSIZEMOVE, WM_EXITSIZEMOVE, WM_DROPFILES, WM_MDIREFRESHMENU,
+  WM_POINTERDEVICECHANGE, WM_POINTERDEVICEINRANGE, WM_POINTERDEVICEOUTOFRANGE, WM_TOUCH, WM_NCPOINTERUPDATE,
+  WM_NCPOINTERDOWN, WM_NCPOINTERUP, WM_POINTERUPDATE, WM_POINTERDOWN, WM_POINTERUP, WM_POINTERENTER, WM_POINTERLEAVE,
+  WM_POINTERACTIVATE, WM_POINTERCAPTURECHANGED, WM_TOUCHHITTESTING, WM_POINTERWHEEL, WM_POINTERHWHEEL,
+  WM_POINTERROUTEDTO, WM_POINTERROUTEDAWAY, WM_POINTERROUTEDRELEASED, WM_IME_SETCONTEXT, WM_IME_NOTIFY, WM_IME_CONTROL,
+  WM_IME_COMPOSITIONFULL, WM_IME_SELECT, WM_IME_CHAR, WM_IME_REQUEST, WM_IME_KEYDOWN, WM_IME_KEYUP, WM_NCMOUSEHOVER,
+  WM_MOUSEHOVER, WM_NCMOUSELEAVE, WM_MOUSELEAVE, WM_WTSSESSION_CHANGE, WM_DPICHANGED, WM_DPICHANGED_BEFOREPARENT,
+  WM_DPICHANGED_AFTERPARENT, WM_GETDPISCALEDSIZE, WM_CUT, WM_COPY, WM_PASTE, WM_CLEAR, WM_UNDO, WM_RENDERFORMAT,
+  WM_RENDERALLFORMATS, WM_DESTROYCLIPBOARD, WM_DRAWCLIPBOARD, WM_PAINTCLIPBOARD, WM_VSCROLLCLIPBOARD, WM_SIZECLIPBOARD,
+  WM_ASKCBFORMATNAME, WM_CHANGECBCHAIN, WM_HSCROLLCLIPBOARD, WM_QUERYNEWPALETTE, WM_PALETTEISCHANGING,
+  WM_PALETTECHANGED, WM_HOTKEY, WM_PRINT, WM_PRINTCLIENT, WM_APPCOMMAND, WM_THEMECHANGED, WM_CLIPBOARDUPDATE,
+  WM_DWMCOMPOSITIONCHANGED, WM_DWMNCRENDERINGCHANGED, WM_DWMCOLORIZATIONCOLORCHANGED, WM_DWMWINDOWMAXIMIZEDCHANGE,
+  WM_DWMSENDICONICTHUMBNAIL, WM_DWMSENDICONICLIVEPREVIEWBITMAP, WM_GETTITLEBARINFOEX,
+}
+
+lazy_static! {
+  /// The names of registered messages that have been looked up, which are kept forever so that
+  /// they can be handed out as `&'static str`. There's at most one per registered message.
+  static ref REGISTERED_NAMES: Mutex<HashMap<UINT, &'static str>> = Mutex::new(HashMap::new());
+}
+
+/// The name of a message registered with `RegisterWindowMessage`, by this crate or anyone else.
+fn registered_message_name(msg: UINT) -> Option<&'static str> {
+  // Ours are known without asking.
+  let ours = [
+    (*WM_HWNDLOOP_COMMAND, "WM_HWNDLOOP_COMMAND"),
+    (*WM_HWNDLOOP_FLUSH, "WM_HWNDLOOP_FLUSH"),
+    (*WM_HWNDLOOP_FLUSH_ALL, "WM_HWNDLOOP_FLUSH_ALL"),
+    (*WM_HWNDLOOP_FLUSH_MARKER, "WM_HWNDLOOP_FLUSH_MARKER"),
+    (*WM_HWNDLOOP_PAUSE, "WM_HWNDLOOP_PAUSE"),
+    (*WM_HWNDLOOP_RESUME, "WM_HWNDLOOP_RESUME"),
+    (*WM_HWNDLOOP_PAYLOAD, "WM_HWNDLOOP_PAYLOAD"),
+    (*WM_HWNDLOOP_SERVICE_CONTROL, "WM_HWNDLOOP_SERVICE_CONTROL"),
+  ];
+  if let Some(&(_, name)) = ours.iter().find(|&&(ours, _)| ours == msg) {
+    return Some(name);
+  }
+
+  let mut names = REGISTERED_NAMES.lock().unwrap();
+  if let Some(&name) = names.get(&msg) {
+    return Some(name);
+  }
+  let mut buf = [0u16; 256];
+  let len = unsafe { GetClipboardFormatNameW(msg, buf.as_mut_ptr(), buf.len() as i32) };
+  if len <= 0 {
+    return None;
+  }
+  let name: &'static str = Box::leak(String::from_utf16_lossy(&buf[..len as usize]).into_boxed_str());
+  names.insert(msg, name);
+  Some(name)
+}
+
+/// Look up the symbolic name of a window message, e.g. `"WM_SIZE"` for `WM_SIZE`.
+///
+/// This knows the messages that the system defines, and looks up the names of registered
+/// messages, including the ones this crate uses internally. Messages in the `WM_USER` and `WM_APP`
+/// ranges mean different things to different windows, so they don't have names.
+pub fn msg_name(msg: UINT) -> Option<&'static str> {
+  if REGISTERED_MESSAGES.contains(&msg) {
+    registered_message_name(msg)
+  } else {
+    system_message_name(msg)
+  }
+}
@@ -0,0 +1,208 @@
+//! Network interface and address change notifications.
+//!
+//! Loops built with
+//! [`HwndLoopBuilder::network_notifications`](::HwndLoopBuilder::network_notifications) wait for
+//! `NotifyAddrChange` and `NotifyRouteChange` alongside their messages, and work out what changed
+//! by comparing the interface and address tables before and after. The results are delivered to
+//! [`HwndLoopCallbacks::handle_network_event`](::HwndLoopCallbacks::handle_network_event) on the
+//! handler thread.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::Ipv4Addr;
+
+use winapi::shared::ifmib::MIB_IFTABLE;
+use winapi::shared::ipifcons::{IF_OPER_STATUS_CONNECTED, IF_OPER_STATUS_OPERATIONAL};
+use winapi::shared::ipmib::MIB_IPADDRTABLE;
+use winapi::shared::minwindef::{DWORD, FALSE, ULONG};
+use winapi::shared::winerror::{ERROR_INSUFFICIENT_BUFFER, ERROR_IO_PENDING, NO_ERROR};
+use winapi::um::iphlpapi::{CancelIPChangeNotify, GetIfTable, GetIpAddrTable, NotifyAddrChange, NotifyRouteChange};
+use winapi::um::minwinbase::OVERLAPPED;
+use winapi::um::synchapi::{ResetEvent, WaitForSingleObject};
+use winapi::um::winbase::WAIT_OBJECT_0;
+use winapi::um::winnt::HANDLE;
+
+use util::Event;
+use Result;
+
+/// A change to the system's network configuration.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NetworkEvent {
+  /// The interface with this index became operational.
+  InterfaceUp(u32),
+
+  /// The interface with this index stopped being operational, or went away.
+  InterfaceDown(u32),
+
+  /// An IPv4 address was assigned to an interface.
+  AddressAdded { interface: u32, address: Ipv4Addr },
+
+  /// An IPv4 address was removed from an interface.
+  AddressRemoved { interface: u32, address: Ipv4Addr },
+
+  /// The IPv4 routing table changed.
+  RouteChanged,
+}
+
+/// An outstanding `NotifyAddrChange` or `NotifyRouteChange` call.
+struct Notification {
+  notify: unsafe extern "system" fn(*mut HANDLE, *mut OVERLAPPED) -> DWORD,
+  event: Event,
+  // The system writes to this until the call is cancelled, so it can't move.
+  overlapped: Box<OVERLAPPED>,
+}
+
+impl Notification {
+  fn new(notify: unsafe extern "system" fn(*mut HANDLE, *mut OVERLAPPED) -> DWORD) -> Result<Notification> {
+    // Manual reset, so that the event stays signalled until the notification is rearmed.
+    let event = Event::new_manual_reset()?;
+    let mut overlapped: Box<OVERLAPPED> = Box::new(unsafe { std::mem::zeroed() });
+    overlapped.hEvent = event.handle();
+    let mut notification = Notification {
+      notify,
+      event,
+      overlapped,
+    };
+    notification.arm()?;
+    Ok(notification)
+  }
+
+  fn arm(&mut self) -> Result<()> {
+    unsafe { ResetEvent(self.event.handle()) };
+    let mut handle = std::ptr::null_mut();
+    match unsafe { (self.notify)(&mut handle, &mut *self.overlapped) } {
+      ERROR_IO_PENDING => Ok(()),
+      err => Err(std::io::Error::from_raw_os_error(err as i32).into()),
+    }
+  }
+
+  /// Rearm the notification if it fired, returning whether it did.
+  fn poll(&mut self) -> bool {
+    if unsafe { WaitForSingleObject(self.event.handle(), 0) } != WAIT_OBJECT_0 {
+      return false;
+    }
+    if let Err(err) = self.arm() {
+      warn!("failed to rearm network notification: {}", err);
+    }
+    true
+  }
+}
+
+impl Drop for Notification {
+  fn drop(&mut self) {
+    unsafe { CancelIPChangeNotify(&mut *self.overlapped) };
+  }
+}
+
+/// What the interface and address tables looked like the last time we checked.
+#[derive(Default)]
+struct Snapshot {
+  interfaces: BTreeMap<u32, bool>,
+  addresses: BTreeSet<(u32, Ipv4Addr)>,
+}
+
+/// Call one of the `Get*Table` functions, which ask for a bigger buffer when it's too small.
+unsafe fn get_table<T>(get: unsafe extern "system" fn(*mut T, *mut ULONG, i32) -> DWORD) -> Result<Vec<u64>> {
+  let mut size: ULONG = 0;
+  let mut buf: Vec<u64> = Vec::new();
+  loop {
+    match get(buf.as_mut_ptr() as *mut T, &mut size, FALSE) {
+      NO_ERROR => return Ok(buf),
+      ERROR_INSUFFICIENT_BUFFER => buf.resize((size as usize).div_ceil(8), 0),
+      err => return Err(std::io::Error::from_raw_os_error(err as i32).into()),
+    }
+  }
+}
+
+impl Snapshot {
+  fn take() -> Result<Snapshot> {
+    let mut snapshot = Snapshot::default();
+    unsafe {
+      let buf = get_table::<MIB_IFTABLE>(GetIfTable)?;
+      if !buf.is_empty() {
+        let table = &*(buf.as_ptr() as *const MIB_IFTABLE);
+        let rows = std::slice::from_raw_parts(table.table.as_ptr(), table.dwNumEntries as usize);
+        for row in rows {
+          let up = row.dwOperStatus == IF_OPER_STATUS_OPERATIONAL || row.dwOperStatus == IF_OPER_STATUS_CONNECTED;
+          snapshot.interfaces.insert(row.dwIndex, up);
+        }
+      }
+
+      let buf = get_table::<MIB_IPADDRTABLE>(GetIpAddrTable)?;
+      if !buf.is_empty() {
+        let table = &*(buf.as_ptr() as *const MIB_IPADDRTABLE);
+        let rows = std::slice::from_raw_parts(table.table.as_ptr(), table.dwNumEntries as usize);
+        for row in rows {
+          // The address is in network byte order.
+          let address = Ipv4Addr::from(u32::from_be(row.dwAddr));
+          snapshot.addresses.insert((row.dwIndex, address));
+        }
+      }
+    }
+    Ok(snapshot)
+  }
+
+  /// Describe how `self` turned into `next`.
+  fn diff(&self, next: &Snapshot, events: &mut Vec<NetworkEvent>) {
+    for (&index, &up) in &next.interfaces {
+      let was_up = self.interfaces.get(&index).cloned().unwrap_or(false);
+      if up && !was_up {
+        events.push(NetworkEvent::InterfaceUp(index));
+      } else if !up && was_up {
+        events.push(NetworkEvent::InterfaceDown(index));
+      }
+    }
+    for (&index, &was_up) in &self.interfaces {
+      if was_up && !next.interfaces.contains_key(&index) {
+        events.push(NetworkEvent::InterfaceDown(index));
+      }
+    }
+
+    for &(interface, address) in self.addresses.difference(&next.addresses) {
+      events.push(NetworkEvent::AddressRemoved { interface, address });
+    }
+    for &(interface, address) in next.addresses.difference(&self.addresses) {
+      events.push(NetworkEvent::AddressAdded { interface, address });
+    }
+  }
+}
+
+/// Watches for network changes on behalf of a loop.
+pub(crate) struct NetworkWatcher {
+  addresses: Notification,
+  routes: Notification,
+  snapshot: Snapshot,
+}
+
+impl NetworkWatcher {
+  pub(crate) fn new() -> Result<NetworkWatcher> {
+    Ok(NetworkWatcher {
+      addresses: Notification::new(NotifyAddrChange)?,
+      routes: Notification::new(NotifyRouteChange)?,
+      snapshot: Snapshot::take()?,
+    })
+  }
+
+  /// The handles that are signalled when something might have changed.
+  pub(crate) fn handles(&self) -> [HANDLE; 2] {
+    [self.addresses.event.handle(), self.routes.event.handle()]
+  }
+
+  /// Work out what's changed since the last call, without blocking.
+  pub(crate) fn poll(&mut self) -> Vec<NetworkEvent> {
+    let mut events = Vec::new();
+    if self.addresses.poll() {
+      // Interfaces coming and going also show up as address changes.
+      match Snapshot::take() {
+        Ok(snapshot) => {
+          self.snapshot.diff(&snapshot, &mut events);
+          self.snapshot = snapshot;
+        }
+        Err(err) => warn!("failed to read network configuration: {}", err),
+      }
+    }
+    if self.routes.poll() {
+      events.push(NetworkEvent::RouteChanged);
+    }
+    events
+  }
+}
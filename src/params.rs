@@ -0,0 +1,268 @@
+//! Typed parameters of frequently handled window messages, so that
+//! [`HwndLoopCallbacks::handle_message`](::HwndLoopCallbacks::handle_message) can match on them
+//! instead of picking WPARAM and LPARAM apart by hand.
+//!
+//! Parameters that point at more data, like the device of a WM_DEVICECHANGE, are copied out,
+//! since what they point at is only valid while the message is being handled. That's also why
+//! decoding them is unsafe.
+
+use std::fmt;
+
+use winapi::shared::guiddef::{IsEqualGUID, GUID};
+use winapi::shared::minwindef::{DWORD, HIWORD, LOWORD, LPARAM, UINT, WORD, WPARAM};
+use winapi::shared::windef::HWND;
+use winapi::um::dbt::{DBT_DEVICEARRIVAL, DBT_DEVICEQUERYREMOVE, DBT_DEVICEREMOVECOMPLETE, DBT_DEVNODES_CHANGED};
+use winapi::um::dbt::DEV_BROADCAST_HDR;
+use winapi::um::winuser::*;
+
+/// Why a window was resized, from WM_SIZE.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SizeKind {
+  Restored,
+  Minimized,
+  Maximized,
+
+  /// Another window was restored to its former size.
+  MaxShow,
+
+  /// Another window was maximized.
+  MaxHide,
+  Other(WPARAM),
+}
+
+/// The parameters of WM_SIZE.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Size {
+  pub kind: SizeKind,
+
+  /// The new size of the client area.
+  pub width: u16,
+  pub height: u16,
+}
+
+/// The parameters of WM_ACTIVATE.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Activate {
+  /// Whether the window is being activated, as opposed to deactivated.
+  pub active: bool,
+
+  /// Whether it was activated by a mouse click.
+  pub click: bool,
+
+  /// Whether the window is minimized.
+  pub minimized: bool,
+
+  /// The window being deactivated or activated in its place, which can be null.
+  pub other: HWND,
+}
+
+/// The parameters of WM_COMMAND.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command {
+  /// A menu item with this ID was chosen.
+  Menu(WORD),
+
+  /// An accelerator with this ID was pressed.
+  Accelerator(WORD),
+
+  /// A control sent a notification.
+  Control { id: WORD, code: WORD, hwnd: HWND },
+}
+
+/// The parameters of WM_HOTKEY.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Hotkey {
+  /// The ID that the hotkey was registered with, or one of the negative `IDHOT_*` values.
+  pub id: i32,
+
+  /// The `MOD_*` flags of the modifiers that were held down.
+  pub modifiers: UINT,
+
+  /// The virtual key code of the key that was pressed.
+  pub vk: u16,
+}
+
+/// A power setting that changed, copied out of a `POWERBROADCAST_SETTING`.
+#[derive(Clone)]
+pub struct PowerSetting {
+  /// The GUID that the setting was registered with `RegisterPowerSettingNotification`.
+  pub setting: GUID,
+
+  /// The setting's new value, whose layout depends on the setting.
+  pub data: Vec<u8>,
+}
+
+impl fmt::Debug for PowerSetting {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let guid = &self.setting;
+    f.debug_struct("PowerSetting")
+      .field(
+        "setting",
+        &format_args!("{:08x}-{:04x}-{:04x}-{:02x?}", guid.Data1, guid.Data2, guid.Data3, guid.Data4),
+      )
+      .field("data", &self.data)
+      .finish()
+  }
+}
+
+impl PartialEq for PowerSetting {
+  fn eq(&self, other: &PowerSetting) -> bool {
+    IsEqualGUID(&self.setting, &other.setting) && self.data == other.data
+  }
+}
+
+impl Eq for PowerSetting {}
+
+/// The parameters of WM_POWERBROADCAST.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PowerBroadcast {
+  /// The system is about to suspend.
+  Suspend,
+
+  /// The system resumed from suspend, whether or not the user is there.
+  ResumeAutomatic,
+
+  /// The system resumed from suspend because of user activity.
+  ResumeSuspend,
+
+  /// The power source or battery level changed.
+  PowerStatusChange,
+
+  /// A power setting registered with `RegisterPowerSettingNotification` changed.
+  PowerSettingChange(PowerSetting),
+
+  /// Any other `PBT_*` event, with the raw LPARAM, which is only valid while the message is being
+  /// handled if it points at anything.
+  Other { event: WPARAM, data: LPARAM },
+}
+
+/// A device that a WM_DEVICECHANGE is about, copied out of a `DEV_BROADCAST_HDR`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceBroadcast {
+  /// One of the `DBT_DEVTYP_*` constants, which says what `data` is.
+  pub device_type: DWORD,
+
+  /// The rest of the `DEV_BROADCAST_*` structure for the device type, after the header.
+  pub data: Vec<u8>,
+}
+
+/// The parameters of WM_DEVICECHANGE.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DeviceChange {
+  /// A device was added to the system.
+  Arrival(DeviceBroadcast),
+
+  /// A device is about to be removed, which can be vetoed.
+  QueryRemove(DeviceBroadcast),
+
+  /// A device was removed from the system.
+  RemoveComplete(DeviceBroadcast),
+
+  /// A device was added to or removed from the system, without any details.
+  NodesChanged,
+
+  /// Any other `DBT_*` event, with the raw LPARAM, which is only valid while the message is being
+  /// handled if it points at anything.
+  Other { event: WPARAM, data: LPARAM },
+}
+
+/// Copy the `DEV_BROADCAST_HDR` that `l` points at.
+unsafe fn device_broadcast(l: LPARAM) -> DeviceBroadcast {
+  let header = &*(l as *const DEV_BROADCAST_HDR);
+  let start = std::mem::size_of::<DEV_BROADCAST_HDR>();
+  let len = (header.dbch_size as usize).saturating_sub(start);
+  DeviceBroadcast {
+    device_type: header.dbch_devicetype,
+    data: std::slice::from_raw_parts((l as *const u8).add(start), len).to_vec(),
+  }
+}
+
+/// Copy the `POWERBROADCAST_SETTING` that `l` points at.
+unsafe fn power_setting(l: LPARAM) -> PowerSetting {
+  let setting = &*(l as *const POWERBROADCAST_SETTING);
+  PowerSetting {
+    setting: setting.PowerSetting,
+    data: std::slice::from_raw_parts(setting.Data.as_ptr(), setting.DataLength as usize).to_vec(),
+  }
+}
+
+/// A message whose parameters have been decoded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Params {
+  Size(Size),
+  Activate(Activate),
+  Command(Command),
+  Hotkey(Hotkey),
+  PowerBroadcast(PowerBroadcast),
+  DeviceChange(DeviceChange),
+}
+
+impl Params {
+  /// Decode the parameters of `msg`, if it's one of the messages that this module knows about.
+  ///
+  /// # Safety
+  ///
+  /// `w` and `l` have to be what was actually sent with `msg`, while it's still being handled, since
+  /// whatever they point at is copied out.
+  pub unsafe fn decode(msg: UINT, w: WPARAM, l: LPARAM) -> Option<Params> {
+    let (wlo, whi) = (LOWORD(w as DWORD), HIWORD(w as DWORD));
+    let (llo, lhi) = (LOWORD(l as DWORD), HIWORD(l as DWORD));
+    Some(match msg {
+      WM_SIZE => Params::Size(Size {
+        kind: match w {
+          SIZE_RESTORED => SizeKind::Restored,
+          SIZE_MINIMIZED => SizeKind::Minimized,
+          SIZE_MAXIMIZED => SizeKind::Maximized,
+          SIZE_MAXSHOW => SizeKind::MaxShow,
+          SIZE_MAXHIDE => SizeKind::MaxHide,
+          _ => SizeKind::Other(w),
+        },
+        width: llo,
+        height: lhi,
+      }),
+
+      WM_ACTIVATE => Params::Activate(Activate {
+        active: wlo != WA_INACTIVE,
+        click: wlo == WA_CLICKACTIVE,
+        minimized: whi != 0,
+        other: l as HWND,
+      }),
+
+      // Menus and accelerators don't come from a control, and are told apart by the high word.
+      WM_COMMAND => Params::Command(match (l, whi) {
+        (0, 0) => Command::Menu(wlo),
+        (0, 1) => Command::Accelerator(wlo),
+        _ => Command::Control {
+          id: wlo,
+          code: whi,
+          hwnd: l as HWND,
+        },
+      }),
+
+      WM_HOTKEY => Params::Hotkey(Hotkey {
+        id: w as i32,
+        modifiers: UINT::from(llo),
+        vk: lhi,
+      }),
+
+      WM_POWERBROADCAST => Params::PowerBroadcast(match w {
+        PBT_APMSUSPEND => PowerBroadcast::Suspend,
+        PBT_APMRESUMEAUTOMATIC => PowerBroadcast::ResumeAutomatic,
+        PBT_APMRESUMESUSPEND => PowerBroadcast::ResumeSuspend,
+        PBT_APMPOWERSTATUSCHANGE => PowerBroadcast::PowerStatusChange,
+        PBT_POWERSETTINGCHANGE if l != 0 => PowerBroadcast::PowerSettingChange(power_setting(l)),
+        _ => PowerBroadcast::Other { event: w, data: l },
+      }),
+
+      WM_DEVICECHANGE => Params::DeviceChange(match w {
+        DBT_DEVICEARRIVAL if l != 0 => DeviceChange::Arrival(device_broadcast(l)),
+        DBT_DEVICEQUERYREMOVE if l != 0 => DeviceChange::QueryRemove(device_broadcast(l)),
+        DBT_DEVICEREMOVECOMPLETE if l != 0 => DeviceChange::RemoveComplete(device_broadcast(l)),
+        DBT_DEVNODES_CHANGED => DeviceChange::NodesChanged,
+        _ => DeviceChange::Other { event: w, data: l },
+      }),
+
+      _ => return None,
+    })
+  }
+}
@@ -0,0 +1,255 @@
+//! Sending large buffers to a loop in another process through shared memory.
+//!
+//! WM_COPYDATA copies the data into the receiving process while the sender waits, which gets slow
+//! for multi-megabyte payloads. [`send_payload`] instead copies the buffer into a named file
+//! mapping once, and posts a notification to the receiving loop, which maps the same memory and
+//! hands it to [`HwndLoopCallbacks::handle_payload`](::HwndLoopCallbacks::handle_payload) without
+//! copying it again. The sender can carry on immediately, and find out when the receiver is done
+//! with the memory through the returned [`PendingPayload`].
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use winapi::shared::minwindef::{DWORD, FALSE, LPARAM, TRUE, WPARAM};
+use winapi::shared::winerror::{ERROR_ALREADY_EXISTS, ERROR_INVALID_HANDLE, WAIT_TIMEOUT};
+use winapi::um::handleapi::INVALID_HANDLE_VALUE;
+use winapi::um::memoryapi::{CreateFileMappingW, MapViewOfFile, OpenFileMappingW, UnmapViewOfFile, VirtualQuery};
+use winapi::um::memoryapi::{FILE_MAP_READ, FILE_MAP_WRITE};
+use winapi::um::processthreadsapi::GetCurrentProcessId;
+use winapi::um::synchapi::{CreateEventW, OpenEventW, SetEvent, WaitForSingleObject};
+use winapi::um::winbase::WAIT_OBJECT_0;
+use winapi::um::winnt::{EVENT_MODIFY_STATE, HANDLE, MEMORY_BASIC_INFORMATION, PAGE_READWRITE};
+use winapi::um::winuser::PostMessageW;
+
+use util::{self, OwnedHandle};
+use {Error, HwndWrapper, Result, WM_HWNDLOOP_PAYLOAD};
+
+/// Identifies the start of a payload mapping.
+const PAYLOAD_MAGIC: u32 = 0x4857_4c44;
+
+/// Version of the mapping's layout, which is bumped whenever it changes.
+const PAYLOAD_VERSION: u32 = 1;
+
+/// The magic, version, and length of the data that follows them.
+const HEADER_LEN: usize = 16;
+
+/// How many times to pick a new name for a payload's objects when the old one was taken.
+const NAME_ATTEMPTS: usize = 16;
+
+lazy_static! {
+  /// Distinguishes the payloads sent by this process.
+  static ref PAYLOAD_SEQ: AtomicUsize = AtomicUsize::new(0);
+}
+
+fn mapping_name(pid: DWORD, seq: u32) -> Vec<u16> {
+  util::to_utf16(&format!("Local\\hwndloop-payload-{}-{}", pid, seq))
+}
+
+fn event_name(pid: DWORD, seq: u32) -> Vec<u16> {
+  util::to_utf16(&format!("Local\\hwndloop-payload-{}-{}-done", pid, seq))
+}
+
+/// Create a named object with `create`, failing if something else already has the name, since
+/// whoever created it could read the payload or release it early.
+unsafe fn create_new<F: FnOnce() -> HANDLE>(create: F) -> std::io::Result<OwnedHandle> {
+  let handle = OwnedHandle::check(create())?;
+  let err = std::io::Error::last_os_error();
+  if err.raw_os_error() == Some(ERROR_ALREADY_EXISTS as i32) {
+    return Err(err);
+  }
+  Ok(handle)
+}
+
+/// Whether creating a named object failed because an object of any type already has its name.
+fn name_taken(err: &std::io::Error) -> bool {
+  err.raw_os_error() == Some(ERROR_ALREADY_EXISTS as i32) || err.raw_os_error() == Some(ERROR_INVALID_HANDLE as i32)
+}
+
+/// Create the release event and file mapping for payload `seq`.
+fn create_objects(pid: DWORD, seq: u32, size: u64) -> std::io::Result<(OwnedHandle, OwnedHandle)> {
+  let released_name = event_name(pid, seq);
+  let released = unsafe { create_new(|| CreateEventW(std::ptr::null_mut(), TRUE, FALSE, released_name.as_ptr())) }?;
+  let mapping = unsafe {
+    create_new(|| {
+      CreateFileMappingW(
+        INVALID_HANDLE_VALUE,
+        std::ptr::null_mut(),
+        PAGE_READWRITE,
+        (size >> 32) as DWORD,
+        size as DWORD,
+        mapping_name(pid, seq).as_ptr(),
+      )
+    })
+  }?;
+  Ok((released, mapping))
+}
+
+/// Copy `data` into shared memory, and tell the loop that owns `target` about it.
+///
+/// The memory stays around until the returned [`PendingPayload`] is dropped, so it needs to be
+/// kept until the receiver has had a chance to map it, typically by calling
+/// [`PendingPayload::wait`]. The notification is an ordinary posted message, so it's subject to
+/// [`HwndLoopBuilder::message_filter`](::HwndLoopBuilder::message_filter) and is held back while
+/// the loop is paused. As with [`dispatch::send_request`](::dispatch::send_request), this fails
+/// with [`Error::Terminated`] if `target` is the window of a loop that has since terminated.
+pub fn send_payload(target: HwndWrapper, data: &[u8]) -> Result<PendingPayload> {
+  if target.is_stale() {
+    return Err(Error::Terminated);
+  }
+  let pid = unsafe { GetCurrentProcessId() };
+  let size = (HEADER_LEN + data.len()) as u64;
+
+  // The names are predictable, so another process could have taken them first.
+  let mut attempts = 1;
+  let (seq, released, mapping) = loop {
+    let seq = PAYLOAD_SEQ.fetch_add(1, Ordering::SeqCst) as u32;
+    match create_objects(pid, seq, size) {
+      Ok((released, mapping)) => break (seq, released, mapping),
+      Err(ref err) if name_taken(err) && attempts < NAME_ATTEMPTS => {
+        warn!("payload {} of process {} was already taken: {}", seq, pid, err);
+        attempts += 1;
+      }
+      Err(err) => return Err(err.into()),
+    }
+  };
+
+  unsafe {
+    let view = MapViewOfFile(mapping.0, FILE_MAP_WRITE, 0, 0, size as usize) as *mut u8;
+    if view.is_null() {
+      return Err(std::io::Error::last_os_error().into());
+    }
+    std::ptr::copy_nonoverlapping(PAYLOAD_MAGIC.to_le_bytes().as_ptr(), view, 4);
+    std::ptr::copy_nonoverlapping(PAYLOAD_VERSION.to_le_bytes().as_ptr(), view.add(4), 4);
+    std::ptr::copy_nonoverlapping((data.len() as u64).to_le_bytes().as_ptr(), view.add(8), 8);
+    std::ptr::copy_nonoverlapping(data.as_ptr(), view.add(HEADER_LEN), data.len());
+    UnmapViewOfFile(view as _);
+
+    if PostMessageW(target.0, *WM_HWNDLOOP_PAYLOAD, pid as WPARAM, seq as LPARAM) == FALSE {
+      return Err(std::io::Error::last_os_error().into());
+    }
+  }
+
+  Ok(PendingPayload {
+    _mapping: mapping,
+    released,
+  })
+}
+
+/// The sending side of a payload, which keeps its shared memory alive.
+pub struct PendingPayload {
+  _mapping: OwnedHandle,
+  released: OwnedHandle,
+}
+
+impl PendingPayload {
+  /// Wait for at most `timeout` for the receiver to finish with the payload.
+  ///
+  /// Returns [`Error::TimedOut`] if it doesn't. A receiver that has gone away, or that doesn't
+  /// understand the notification, never finishes.
+  pub fn wait(&self, timeout: Duration) -> Result<()> {
+    let ms = timeout.as_secs().saturating_mul(1000) + u64::from(timeout.subsec_millis());
+    let ms = std::cmp::min(ms, u64::from(DWORD::MAX - 1)) as DWORD;
+    match unsafe { WaitForSingleObject(self.released.0, ms) } {
+      WAIT_OBJECT_0 => Ok(()),
+      WAIT_TIMEOUT => Err(Error::TimedOut),
+      _ => Err(std::io::Error::last_os_error().into()),
+    }
+  }
+
+  /// Whether the receiver has finished with the payload.
+  pub fn is_released(&self) -> bool {
+    self.wait(Duration::from_secs(0)).is_ok()
+  }
+}
+
+impl std::fmt::Debug for PendingPayload {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    f.debug_struct("PendingPayload")
+      .field("released", &self.is_released())
+      .finish()
+  }
+}
+
+/// The receiving side of a payload, which dereferences to its contents.
+///
+/// Dropping it unmaps the memory and tells the sender that it's been consumed.
+pub struct SharedPayload {
+  view: *mut u8,
+  len: usize,
+  _mapping: OwnedHandle,
+  released: OwnedHandle,
+}
+
+// The view is mapped into the whole process, and we never write to it.
+unsafe impl Send for SharedPayload {}
+unsafe impl Sync for SharedPayload {}
+
+impl SharedPayload {
+  /// Map the payload announced by a WM_HWNDLOOP_PAYLOAD message.
+  pub(crate) fn open(w: WPARAM, l: LPARAM) -> Result<SharedPayload> {
+    let (pid, seq) = (w as DWORD, l as u32);
+    let released = unsafe { OpenEventW(EVENT_MODIFY_STATE, FALSE, event_name(pid, seq).as_ptr()) };
+    let released = OwnedHandle::check(released)?;
+    let mapping = unsafe { OpenFileMappingW(FILE_MAP_READ, FALSE, mapping_name(pid, seq).as_ptr()) };
+    let mapping = OwnedHandle::check(mapping)?;
+
+    unsafe {
+      let view = MapViewOfFile(mapping.0, FILE_MAP_READ, 0, 0, 0) as *mut u8;
+      if view.is_null() {
+        return Err(std::io::Error::last_os_error().into());
+      }
+      let mut payload = SharedPayload {
+        view,
+        len: 0,
+        _mapping: mapping,
+        released,
+      };
+
+      // Don't trust the sender's idea of how big the mapping is.
+      let mut info: MEMORY_BASIC_INFORMATION = std::mem::zeroed();
+      let info_size = std::mem::size_of::<MEMORY_BASIC_INFORMATION>();
+      if VirtualQuery(view as _, &mut info, info_size) != info_size {
+        return Err(std::io::Error::last_os_error().into());
+      }
+
+      let mut header = [0u8; HEADER_LEN];
+      if info.RegionSize >= HEADER_LEN {
+        std::ptr::copy_nonoverlapping(view, header.as_mut_ptr(), HEADER_LEN);
+      }
+      let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+      let version = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+      let mut len = [0u8; 8];
+      len.copy_from_slice(&header[8..]);
+      let len = u64::from_le_bytes(len);
+      if magic != PAYLOAD_MAGIC || version != PAYLOAD_VERSION || len > (info.RegionSize - HEADER_LEN) as u64 {
+        return Err(Error::Rejected);
+      }
+
+      payload.len = len as usize;
+      Ok(payload)
+    }
+  }
+}
+
+impl std::ops::Deref for SharedPayload {
+  type Target = [u8];
+
+  fn deref(&self) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(self.view.add(HEADER_LEN), self.len) }
+  }
+}
+
+impl std::fmt::Debug for SharedPayload {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    f.debug_struct("SharedPayload").field("len", &self.len).finish()
+  }
+}
+
+impl Drop for SharedPayload {
+  fn drop(&mut self) {
+    unsafe {
+      UnmapViewOfFile(self.view as _);
+      SetEvent(self.released.0);
+    }
+  }
+}
@@ -0,0 +1,115 @@
+//! Where a loop spends its time, for attributing latency to specific handlers.
+//!
+//! Loops built with [`HwndLoopBuilder::profile`](::HwndLoopBuilder::profile) time every window
+//! message and command that the callbacks handle, and add it up by message ID and by command
+//! variant. [`LoopHandle::profile_snapshot`](::LoopHandle::profile_snapshot) takes the totals so
+//! far and starts over, so that each snapshot covers the time since the one before it.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::mem::Discriminant;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use winapi::shared::minwindef::UINT;
+
+/// How long the callbacks took to handle one message ID or command variant.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HandlerTime {
+  /// How many times it was handled.
+  pub count: u64,
+
+  /// How long it took altogether.
+  pub total: Duration,
+
+  /// How long the slowest one took.
+  pub worst: Duration,
+}
+
+impl HandlerTime {
+  fn add(&mut self, elapsed: Duration) {
+    self.count += 1;
+    self.total += elapsed;
+    self.worst = std::cmp::max(self.worst, elapsed);
+  }
+}
+
+/// The time that a loop's callbacks spent handling messages and commands, from
+/// [`LoopHandle::profile_snapshot`](::LoopHandle::profile_snapshot).
+///
+/// Each list has the handlers with the most total time, along with any others that had one of the
+/// slowest single calls, most total time first.
+#[derive(Debug)]
+pub struct ProfileSnapshot<CommandType> {
+  /// How long the snapshot covers, since the previous one or since the loop was created.
+  pub elapsed: Duration,
+
+  /// Window messages, by message ID.
+  pub messages: Vec<(UINT, HandlerTime)>,
+
+  /// Commands, by which variant of the command type they were, to be compared with
+  /// `std::mem::discriminant`.
+  pub commands: Vec<(Discriminant<CommandType>, HandlerTime)>,
+}
+
+/// The totals since the last snapshot.
+struct Profile<CommandType> {
+  since: Instant,
+  messages: HashMap<UINT, HandlerTime>,
+  commands: HashMap<Discriminant<CommandType>, HandlerTime>,
+}
+
+impl<CommandType> Profile<CommandType> {
+  fn new() -> Profile<CommandType> {
+    Profile {
+      since: Instant::now(),
+      messages: HashMap::new(),
+      commands: HashMap::new(),
+    }
+  }
+}
+
+/// Adds up the time that a loop's callbacks spend handling things.
+pub(crate) struct Profiler<CommandType> {
+  profile: Mutex<Profile<CommandType>>,
+}
+
+impl<CommandType> Profiler<CommandType> {
+  pub(crate) fn new() -> Profiler<CommandType> {
+    Profiler {
+      profile: Mutex::new(Profile::new()),
+    }
+  }
+
+  pub(crate) fn record_message(&self, msg: UINT, elapsed: Duration) {
+    self.profile.lock().unwrap().messages.entry(msg).or_default().add(elapsed);
+  }
+
+  pub(crate) fn record_command(&self, discriminant: Discriminant<CommandType>, elapsed: Duration) {
+    self.profile.lock().unwrap().commands.entry(discriminant).or_default().add(elapsed);
+  }
+
+  /// Take the `top` handlers of each kind so far, and start over.
+  pub(crate) fn snapshot(&self, top: usize) -> ProfileSnapshot<CommandType> {
+    let profile = std::mem::replace(&mut *self.profile.lock().unwrap(), Profile::new());
+    ProfileSnapshot {
+      elapsed: profile.since.elapsed(),
+      messages: rank(profile.messages, top),
+      commands: rank(profile.commands, top),
+    }
+  }
+}
+
+/// The `top` entries by total time, plus the `top` by worst time, most total time first.
+fn rank<K: Copy + Eq + Hash>(times: HashMap<K, HandlerTime>, top: usize) -> Vec<(K, HandlerTime)> {
+  let mut ranked: Vec<_> = times.into_iter().collect();
+  ranked.sort_by_key(|&(_, time)| std::cmp::Reverse(time.worst));
+  let slowest: Vec<K> = ranked.iter().take(top).map(|&(key, _)| key).collect();
+  ranked.sort_by_key(|&(_, time)| std::cmp::Reverse(time.total));
+  ranked
+    .into_iter()
+    .enumerate()
+    .filter(|(index, (key, _))| *index < top || slowest.contains(key))
+    .map(|(_, entry)| entry)
+    .collect()
+}
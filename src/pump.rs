@@ -0,0 +1,67 @@
+use std::mem::ManuallyDrop;
+
+use event_loop::{EventLoop, PumpStatus};
+use {HwndLoopBuilder, HwndLoopCallbacks, LoopHandle, Result};
+
+/// A loop whose messages are pumped manually by the thread that created it.
+///
+/// No thread is spawned: the window lives on the creating thread, and callbacks only run from
+/// inside [`HwndPump::pump_once`]. This is meant for integrating the loop into an existing frame
+/// loop, e.g. in a game engine. Other threads can send commands through [`HwndPump::handle`].
+///
+/// The window is destroyed, and [`HwndLoopCallbacks::tear_down`] is called, when the pump is
+/// dropped.
+pub struct HwndPump<CommandType: Send + std::fmt::Debug + 'static> {
+  event_loop: ManuallyDrop<Box<EventLoop<CommandType>>>,
+}
+
+impl<CommandType: Send + std::fmt::Debug + 'static> HwndPump<CommandType> {
+  /// Create a new [`HwndPump`] on the current thread.
+  pub fn new(callbacks: Box<dyn HwndLoopCallbacks<CommandType>>) -> Result<HwndPump<CommandType>> {
+    HwndLoopBuilder::new().build_pump(callbacks)
+  }
+
+  pub(crate) fn with_config(
+    config: &HwndLoopBuilder,
+    callbacks: Box<dyn HwndLoopCallbacks<CommandType>>,
+  ) -> Result<HwndPump<CommandType>> {
+    Ok(HwndPump {
+      event_loop: ManuallyDrop::new(Box::new(EventLoop::new(config, callbacks)?)),
+    })
+  }
+
+  /// Get a handle for sending commands to the pump from other threads.
+  pub fn handle(&self) -> LoopHandle<CommandType> {
+    self.event_loop.handle()
+  }
+
+  /// Process at most one wakeup (a window message, or a batch of commands) without blocking.
+  ///
+  /// Once the pump has been told to terminate through a handle, this always returns
+  /// [`PumpStatus::Terminated`].
+  pub fn pump_once(&mut self) -> PumpStatus {
+    self.event_loop.pump_once()
+  }
+
+  /// Process wakeups until there's nothing left to do, returning the final status.
+  pub fn pump_pending(&mut self) -> PumpStatus {
+    loop {
+      match self.pump_once() {
+        PumpStatus::Processed => continue,
+        status => return status,
+      }
+    }
+  }
+
+  /// Whether the pump has been told to terminate.
+  pub fn is_terminated(&self) -> bool {
+    self.event_loop.is_terminated()
+  }
+}
+
+impl<CommandType: Send + std::fmt::Debug + 'static> Drop for HwndPump<CommandType> {
+  fn drop(&mut self) {
+    // A callback might be dropping the pump from a message dispatched by someone else's pump.
+    unsafe { ManuallyDrop::take(&mut self.event_loop) }.release();
+  }
+}
@@ -0,0 +1,284 @@
+//! Running a loop inside an NT service process.
+//!
+//! [`run`] connects the process to the service control manager, and runs a loop on the thread
+//! that it starts the service on. Service controls (stop, power, session, and device events) are
+//! delivered to [`HwndLoopCallbacks::handle_service_control`](::HwndLoopCallbacks::handle_service_control)
+//! on the handler thread, like window messages, and a stop or shutdown terminates the loop once
+//! the callbacks have seen it.
+
+use std::ffi::c_void;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::Mutex;
+
+use winapi::shared::minwindef::{DWORD, LPARAM, LPVOID};
+use winapi::shared::winerror::{ERROR_CALL_NOT_IMPLEMENTED, ERROR_SERVICE_ALREADY_RUNNING, NO_ERROR};
+use winapi::shared::winerror::ERROR_SERVICE_SPECIFIC_ERROR;
+use winapi::um::winnt::{LPWSTR, SERVICE_WIN32_OWN_PROCESS};
+use winapi::um::winsvc::*;
+use winapi::um::winuser::{RegisterDeviceNotificationW, SendMessageW, UnregisterDeviceNotification};
+use winapi::um::winuser::{DEVICE_NOTIFY_SERVICE_HANDLE, HDEVNOTIFY};
+
+use util;
+use {Error, HwndLoop, HwndLoopBuilder, HwndLoopCallbacks, LoopHandle, Result, WM_HWNDLOOP_SERVICE_CONTROL};
+
+/// The controls that a running service accepts, on top of device events, which don't need to be
+/// asked for.
+const CONTROLS_ACCEPTED: DWORD =
+  SERVICE_ACCEPT_STOP | SERVICE_ACCEPT_SHUTDOWN | SERVICE_ACCEPT_POWEREVENT | SERVICE_ACCEPT_SESSIONCHANGE;
+
+/// A request from the service control manager.
+///
+/// The pointers are only valid until the callback that receives them returns.
+#[derive(Clone, Copy, Debug)]
+pub enum ServiceControl {
+  /// The service is being stopped. The loop terminates once this has been handled.
+  Stop,
+
+  /// The system is shutting down. The loop terminates once this has been handled.
+  Shutdown,
+
+  /// A power event, as for WM_POWERBROADCAST: `event` is one of the `PBT_*` constants, and `data`
+  /// points to a `POWERBROADCAST_SETTING` for `PBT_POWERSETTINGCHANGE`.
+  Power { event: DWORD, data: LPVOID },
+
+  /// A session change, as for WM_WTSSESSION_CHANGE: `event` is one of the `WTS_*` constants.
+  SessionChange { event: DWORD, session_id: DWORD },
+
+  /// A device event, as for WM_DEVICECHANGE, for notifications registered with
+  /// [`register_device_notification`]: `event` is one of the `DBT_*` constants, and `data` points
+  /// to a `DEV_BROADCAST_HDR`.
+  DeviceEvent { event: DWORD, data: LPVOID },
+
+  /// Any other control code.
+  Other { control: DWORD, event: DWORD, data: LPVOID },
+}
+
+impl ServiceControl {
+  pub(crate) unsafe fn from_raw(control: DWORD, event: DWORD, data: LPVOID) -> ServiceControl {
+    match control {
+      SERVICE_CONTROL_STOP => ServiceControl::Stop,
+      SERVICE_CONTROL_SHUTDOWN => ServiceControl::Shutdown,
+      SERVICE_CONTROL_POWEREVENT => ServiceControl::Power { event, data },
+      SERVICE_CONTROL_SESSIONCHANGE => {
+        // WTSSESSION_NOTIFICATION is a size, followed by the session id.
+        let session_id = *(data as *const DWORD).add(1);
+        ServiceControl::SessionChange { event, session_id }
+      }
+      SERVICE_CONTROL_DEVICEEVENT => ServiceControl::DeviceEvent { event, data },
+      _ => ServiceControl::Other { control, event, data },
+    }
+  }
+}
+
+type ServiceMain = Box<dyn FnOnce(Vec<String>) + Send>;
+
+lazy_static! {
+  /// What [`run`] wants to happen when the service starts, since the service's entry point can't
+  /// carry any context.
+  static ref SERVICE_MAIN: Mutex<Option<ServiceMain>> = Mutex::new(None);
+
+  /// The control that's currently being delivered to the loop, to make sure that the message
+  /// that carries it came from us.
+  static ref PENDING_CONTROL: AtomicPtr<ServiceControl> = AtomicPtr::new(std::ptr::null_mut());
+
+  /// The running service's status handle, for device notifications.
+  static ref STATUS_HANDLE: AtomicPtr<SERVICE_STATUS_HANDLE__> = AtomicPtr::new(std::ptr::null_mut());
+}
+
+/// State shared between the service's thread and its control handler.
+struct ServiceContext<CommandType: Send + std::fmt::Debug + 'static> {
+  handle: Mutex<Option<LoopHandle<CommandType>>>,
+}
+
+/// Run the service called `name`, with a loop built from `config`, returning once it's stopped.
+///
+/// This must be called from the process's main thread, soon after the service control manager
+/// starts the process. `callbacks` is called on the service's thread with the service's arguments
+/// to create the loop's callbacks. Fails with `ERROR_FAILED_SERVICE_CONTROLLER_CONNECT` if the
+/// process wasn't started as a service.
+///
+/// Controls are delivered to the loop's window, so `config` can't be
+/// [`windowless`](::HwndLoopBuilder::windowless).
+pub fn run<CommandType, F>(name: &str, config: HwndLoopBuilder, callbacks: F) -> Result<()>
+where
+  CommandType: Send + std::fmt::Debug + 'static,
+  F: FnOnce(Vec<String>) -> Box<dyn HwndLoopCallbacks<CommandType>> + Send + 'static,
+{
+  if config.windowless {
+    let message = "service loops need a window to receive controls";
+    return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, message).into());
+  }
+
+  let name = util::to_utf16(name);
+  {
+    let mut main = SERVICE_MAIN.lock().unwrap();
+    if main.is_some() {
+      return Err(std::io::Error::from_raw_os_error(ERROR_SERVICE_ALREADY_RUNNING as i32).into());
+    }
+    let service_name = name.clone();
+    *main = Some(Box::new(move |args| unsafe {
+      run_service(&service_name, &config, callbacks(args))
+    }));
+  }
+
+  let table = [
+    SERVICE_TABLE_ENTRYW {
+      lpServiceName: name.as_ptr(),
+      lpServiceProc: Some(service_main),
+    },
+    SERVICE_TABLE_ENTRYW {
+      lpServiceName: std::ptr::null(),
+      lpServiceProc: None,
+    },
+  ];
+  let result = if unsafe { StartServiceCtrlDispatcherW(table.as_ptr()) } == 0 {
+    Err(std::io::Error::last_os_error().into())
+  } else {
+    Ok(())
+  };
+  *SERVICE_MAIN.lock().unwrap() = None;
+  result
+}
+
+unsafe extern "system" fn service_main(argc: DWORD, argv: *mut LPWSTR) {
+  let args = (0..argc as usize)
+    .map(|i| {
+      let arg = *argv.add(i);
+      let len = (0..).take_while(|&j| *arg.add(j) != 0).count();
+      String::from_utf16_lossy(std::slice::from_raw_parts(arg, len))
+    })
+    .collect();
+  let main = SERVICE_MAIN.lock().unwrap().take();
+  if let Some(main) = main {
+    main(args);
+  }
+}
+
+unsafe fn run_service<CommandType: Send + std::fmt::Debug + 'static>(
+  name: &[u16],
+  config: &HwndLoopBuilder,
+  callbacks: Box<dyn HwndLoopCallbacks<CommandType>>,
+) {
+  // The service control manager can still call the handler after we report that we've stopped,
+  // so the context lives for the rest of the process.
+  let context = Box::into_raw(Box::new(ServiceContext::<CommandType> {
+    handle: Mutex::new(None),
+  }));
+  let status = RegisterServiceCtrlHandlerExW(name.as_ptr(), Some(control_handler::<CommandType>), context as LPVOID);
+  if status.is_null() {
+    error!("failed to register service control handler: {}", std::io::Error::last_os_error());
+    return;
+  }
+  STATUS_HANDLE.store(status, Ordering::SeqCst);
+  set_status(status, SERVICE_START_PENDING, 0, NO_ERROR);
+
+  let result = HwndLoop::run_with_config(config, callbacks, |handle| {
+    *(*context).handle.lock().unwrap() = Some(handle);
+    set_status(status, SERVICE_RUNNING, CONTROLS_ACCEPTED, NO_ERROR);
+  });
+  *(*context).handle.lock().unwrap() = None;
+
+  let exit_code = match result {
+    Ok(()) => NO_ERROR,
+    Err(Error::Os(ref err)) => err.raw_os_error().map_or(ERROR_SERVICE_SPECIFIC_ERROR, |code| code as DWORD),
+    Err(ref err) => {
+      error!("service loop failed: {}", err);
+      ERROR_SERVICE_SPECIFIC_ERROR
+    }
+  };
+  STATUS_HANDLE.store(std::ptr::null_mut(), Ordering::SeqCst);
+  set_status(status, SERVICE_STOPPED, 0, exit_code);
+}
+
+unsafe fn set_status(status: SERVICE_STATUS_HANDLE, state: DWORD, accepted: DWORD, exit_code: DWORD) {
+  let pending = state == SERVICE_START_PENDING || state == SERVICE_STOP_PENDING;
+  let mut service_status = SERVICE_STATUS {
+    dwServiceType: SERVICE_WIN32_OWN_PROCESS,
+    dwCurrentState: state,
+    dwControlsAccepted: accepted,
+    dwWin32ExitCode: exit_code,
+    dwServiceSpecificExitCode: 0,
+    dwCheckPoint: pending as DWORD,
+    dwWaitHint: if pending { 5000 } else { 0 },
+  };
+  if SetServiceStatus(status, &mut service_status) == 0 {
+    warn!("failed to set service status: {}", std::io::Error::last_os_error());
+  }
+}
+
+unsafe extern "system" fn control_handler<CommandType: Send + std::fmt::Debug + 'static>(
+  control: DWORD,
+  event: DWORD,
+  data: LPVOID,
+  context: LPVOID,
+) -> DWORD {
+  if control == SERVICE_CONTROL_INTERROGATE {
+    return NO_ERROR;
+  }
+
+  let context = &*(context as *const ServiceContext<CommandType>);
+  let handle = match *context.handle.lock().unwrap() {
+    Some(ref handle) => handle.clone(),
+    None => return ERROR_CALL_NOT_IMPLEMENTED,
+  };
+
+  let stopping = control == SERVICE_CONTROL_STOP || control == SERVICE_CONTROL_SHUTDOWN;
+  if stopping {
+    set_status(STATUS_HANDLE.load(Ordering::SeqCst), SERVICE_STOP_PENDING, 0, NO_ERROR);
+  }
+
+  let mut control = ServiceControl::from_raw(control, event, data);
+  PENDING_CONTROL.store(&mut control, Ordering::SeqCst);
+  let result = SendMessageW(handle.hwnd().0, *WM_HWNDLOOP_SERVICE_CONTROL, 0, &mut control as *mut _ as LPARAM);
+  PENDING_CONTROL.store(std::ptr::null_mut(), Ordering::SeqCst);
+
+  if stopping {
+    if let Err(err) = handle.terminate() {
+      warn!("failed to terminate service loop: {}", err);
+    }
+  }
+  result as DWORD
+}
+
+/// Get the control carried by a WM_HWNDLOOP_SERVICE_CONTROL message, if we sent it.
+pub(crate) fn take_control(l: LPARAM) -> Option<ServiceControl> {
+  let pending = PENDING_CONTROL.load(Ordering::SeqCst);
+  if pending.is_null() || pending as LPARAM != l {
+    return None;
+  }
+  Some(unsafe { *pending })
+}
+
+/// A device notification registration, which is removed when this is dropped.
+#[derive(Debug)]
+pub struct DeviceNotification(HDEVNOTIFY);
+unsafe impl Send for DeviceNotification {}
+
+impl Drop for DeviceNotification {
+  fn drop(&mut self) {
+    unsafe { UnregisterDeviceNotification(self.0) };
+  }
+}
+
+/// Register for device events matching `filter` (a `DEV_BROADCAST_HDR`, as for
+/// `RegisterDeviceNotification`), delivered as [`ServiceControl::DeviceEvent`].
+///
+/// Services get device events through their control handler rather than as window messages, so
+/// this registers the running service's status handle instead of a window. It can be called from
+/// [`HwndLoopCallbacks::set_up`](::HwndLoopCallbacks::set_up) onwards.
+///
+/// # Safety
+///
+/// `filter` has to point at a valid `DEV_BROADCAST_HDR`, followed by the rest of the structure for
+/// its device type, `dbch_size` bytes in all.
+pub unsafe fn register_device_notification(filter: *const c_void) -> Result<DeviceNotification> {
+  let status = STATUS_HANDLE.load(Ordering::SeqCst);
+  if status.is_null() {
+    return Err(std::io::Error::from_raw_os_error(ERROR_CALL_NOT_IMPLEMENTED as i32).into());
+  }
+  let notification = RegisterDeviceNotificationW(status as _, filter as LPVOID, DEVICE_NOTIFY_SERVICE_HANDLE);
+  if notification.is_null() {
+    return Err(std::io::Error::last_os_error().into());
+  }
+  Ok(DeviceNotification(notification))
+}
@@ -0,0 +1,101 @@
+//! Per-window storage for companion code (hooks, subclasses, other crates), kept in extra slots
+//! of the loop window's `cbWndExtra`, after the loop's own pointer.
+//!
+//! The number of slots is set with [`HwndLoopBuilder::user_slots`](::HwndLoopBuilder::user_slots).
+//! Slots can be filled from any thread with [`LoopHandle::set_slot`](::LoopHandle::set_slot), but
+//! the functions in this module can only be called on the thread that owns the window, which
+//! usually means from inside a callback.
+
+use std::any::Any;
+
+use winapi::shared::basetsd::LONG_PTR;
+use winapi::shared::windef::HWND;
+use winapi::shared::winerror::{ERROR_INVALID_INDEX, ERROR_INVALID_WINDOW_HANDLE};
+use winapi::um::processthreadsapi::GetCurrentThreadId;
+use winapi::um::winuser::{GetWindowThreadProcessId, GCL_CBWNDEXTRA};
+
+use util;
+use {Error, Result};
+
+/// Stored in the class extra bytes of loop windows, so that we don't go poking at the window extra
+/// bytes of windows that aren't ours.
+pub(crate) const CLASS_MAGIC: LONG_PTR = 0x6877_6e64;
+
+type SlotValue = Box<dyn Any + Send>;
+
+fn slot_offset(key: usize) -> i32 {
+  ((key + 1) * std::mem::size_of::<LONG_PTR>()) as i32
+}
+
+/// Make sure that `hwnd` is a loop window owned by the current thread, and that it has a slot
+/// numbered `key`.
+fn check(hwnd: HWND, key: usize) -> Result<()> {
+  if unsafe { util::get_class_long_ptr(hwnd, 0) } as LONG_PTR != CLASS_MAGIC {
+    return Err(std::io::Error::from_raw_os_error(ERROR_INVALID_WINDOW_HANDLE as i32).into());
+  }
+  if unsafe { GetWindowThreadProcessId(hwnd, std::ptr::null_mut()) != GetCurrentThreadId() } {
+    return Err(Error::NotOnLoopThread);
+  }
+
+  let extra = unsafe { util::get_class_long_ptr(hwnd, GCL_CBWNDEXTRA) } as usize;
+  if slot_offset(key) as usize + std::mem::size_of::<LONG_PTR>() > extra {
+    return Err(std::io::Error::from_raw_os_error(ERROR_INVALID_INDEX as i32).into());
+  }
+  Ok(())
+}
+
+/// Swap the value in a slot, returning the previous one.
+pub(crate) fn replace_slot(hwnd: HWND, key: usize, value: Option<SlotValue>) -> Result<Option<SlotValue>> {
+  check(hwnd, key)?;
+  let new = value.map_or(0, |value| Box::into_raw(Box::new(value)) as LONG_PTR);
+  let old = unsafe { util::set_window_long_ptr(hwnd, slot_offset(key), new) } as *mut SlotValue;
+  if old.is_null() {
+    Ok(None)
+  } else {
+    Ok(Some(*unsafe { Box::from_raw(old) }))
+  }
+}
+
+/// Release everything in the first `count` slots of a loop window that's being destroyed.
+pub(crate) fn clear_slots(hwnd: HWND, count: usize) {
+  for key in 0..count {
+    if let Err(err) = replace_slot(hwnd, key, None) {
+      warn!("failed to clear HwndLoop slot {}: {}", key, err);
+    }
+  }
+}
+
+/// Store `value` in a slot, dropping whatever was in it before.
+pub fn set_slot<T: Any + Send>(hwnd: HWND, key: usize, value: T) -> Result<()> {
+  replace_slot(hwnd, key, Some(Box::new(value))).map(|_| ())
+}
+
+/// Empty a slot, dropping whatever was in it.
+pub fn clear_slot(hwnd: HWND, key: usize) -> Result<()> {
+  replace_slot(hwnd, key, None).map(|_| ())
+}
+
+/// Call `f` with the value in a slot, if there is one of type `T`.
+///
+/// The value is taken out of the slot while `f` runs, so `f` is free to call back into this
+/// module. If it stores something else in the same slot, that replaces the value passed to `f`.
+pub fn with_slot<T: Any + Send, R, F: FnOnce(&mut T) -> R>(hwnd: HWND, key: usize, f: F) -> Result<Option<R>> {
+  let mut value = match replace_slot(hwnd, key, None)? {
+    Some(value) => value,
+    None => return Ok(None),
+  };
+
+  let result = value.downcast_mut::<T>().map(f);
+
+  // Put it back, unless it was replaced in the meantime.
+  let old = replace_slot(hwnd, key, Some(value))?;
+  if old.is_some() {
+    replace_slot(hwnd, key, old)?;
+  }
+  Ok(result)
+}
+
+/// Get a copy of the value in a slot, if there is one of type `T`.
+pub fn get_slot<T: Any + Send + Clone>(hwnd: HWND, key: usize) -> Result<Option<T>> {
+  with_slot(hwnd, key, |value: &mut T| value.clone())
+}
@@ -0,0 +1,143 @@
+//! A record of the messages that a loop's window handled, for debugging.
+//!
+//! Loops built with [`HwndLoopBuilder::spy`](::HwndLoopBuilder::spy) keep the most recent window
+//! messages in a ring buffer, along with where they came from and how long they took to handle,
+//! which can be dumped at any time with [`LoopHandle::spy_log`](::LoopHandle::spy_log). Each one
+//! is also logged at the trace level as it's handled.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use winapi::shared::minwindef::{DWORD, HIWORD, LOWORD, LPARAM, UINT, WPARAM};
+use winapi::shared::windef::HWND;
+use winapi::um::winuser::*;
+
+use {msg_name, HwndWrapper};
+
+/// Where a message that the window handled came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageOrigin {
+  /// It was posted, and dispatched by the loop's message pump.
+  Posted,
+
+  /// It was sent by the handler thread itself, or dispatched by someone else's message pump.
+  Sent,
+
+  /// It was sent by another thread. Windows doesn't say which, so the thread and process are
+  /// only known for messages that carry the sender's window in their WPARAM, like WM_COPYDATA.
+  SentFromOtherThread { thread: Option<DWORD>, process: Option<DWORD> },
+}
+
+/// A message that the window handled.
+#[derive(Clone, Debug)]
+pub struct SpyRecord {
+  /// When the window started handling the message.
+  pub time: Instant,
+  pub hwnd: HwndWrapper,
+  pub msg: UINT,
+  pub wparam: WPARAM,
+  pub lparam: LPARAM,
+  pub origin: MessageOrigin,
+
+  /// How long the window took to handle the message, including anything it sent in the meantime.
+  pub duration: Duration,
+}
+
+impl SpyRecord {
+  /// The message's symbolic name, from [`msg_name`](::msg_name).
+  pub fn name(&self) -> Option<&'static str> {
+    msg_name(self.msg)
+  }
+}
+
+impl fmt::Display for SpyRecord {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self.name() {
+      Some(name) => write!(f, "{:?} {}", self.hwnd.0, name)?,
+      None => write!(f, "{:?} {:#06x}", self.hwnd.0, self.msg)?,
+    }
+    let (w, l) = (self.wparam, self.lparam);
+    let (lo, hi) = (LOWORD(l as DWORD), HIWORD(l as DWORD));
+    match self.msg {
+      WM_SIZE => write!(f, " type={} {}x{}", w, lo, hi)?,
+      WM_MOVE => write!(f, " {},{}", lo as i16, hi as i16)?,
+      WM_MOUSEMOVE | WM_LBUTTONDOWN | WM_LBUTTONUP | WM_RBUTTONDOWN | WM_RBUTTONUP | WM_MBUTTONDOWN
+      | WM_MBUTTONUP => write!(f, " {},{} keys={:#x}", lo as i16, hi as i16, w)?,
+      WM_KEYDOWN | WM_KEYUP | WM_SYSKEYDOWN | WM_SYSKEYUP => write!(f, " vk={:#04x} repeat={}", w, lo)?,
+      WM_CHAR | WM_SYSCHAR => match std::char::from_u32(w as u32) {
+        Some(c) => write!(f, " {:?}", c)?,
+        None => write!(f, " {:#06x}", w)?,
+      },
+      WM_COMMAND => write!(f, " id={} code={} control={:#x}", LOWORD(w as DWORD), HIWORD(w as DWORD), l)?,
+      WM_TIMER => write!(f, " id={:#x}", w)?,
+      WM_ACTIVATE => write!(f, " state={} other={:#x}", LOWORD(w as DWORD), l)?,
+      _ => write!(f, " w={:#x} l={:#x}", w, l)?,
+    }
+    match self.origin {
+      MessageOrigin::Posted => write!(f, " posted")?,
+      MessageOrigin::Sent => write!(f, " sent")?,
+      MessageOrigin::SentFromOtherThread { thread, process } => {
+        write!(f, " sent from")?;
+        match (thread, process) {
+          (Some(thread), Some(process)) => write!(f, " thread {} in process {}", thread, process)?,
+          _ => write!(f, " another thread")?,
+        }
+      }
+    }
+    write!(f, " in {:?}", self.duration)
+  }
+}
+
+/// Work out where the message that the window is handling came from.
+pub(crate) unsafe fn origin(posted: bool, msg: UINT, w: WPARAM) -> MessageOrigin {
+  if posted {
+    return MessageOrigin::Posted;
+  }
+  if InSendMessageEx(std::ptr::null_mut()) == ISMEX_NOSEND {
+    return MessageOrigin::Sent;
+  }
+  let (mut thread, mut process) = (None, None);
+  if msg == WM_COPYDATA {
+    let mut pid = 0;
+    match GetWindowThreadProcessId(w as HWND, &mut pid) {
+      0 => {}
+      tid => {
+        thread = Some(tid);
+        process = Some(pid);
+      }
+    }
+  }
+  MessageOrigin::SentFromOtherThread { thread, process }
+}
+
+/// The ring buffer of messages that a loop's window handled.
+pub(crate) struct Spy {
+  capacity: usize,
+  records: Mutex<VecDeque<SpyRecord>>,
+}
+
+impl Spy {
+  pub(crate) fn new(capacity: usize) -> Spy {
+    Spy {
+      capacity,
+      records: Mutex::new(VecDeque::with_capacity(capacity)),
+    }
+  }
+
+  pub(crate) fn record(&self, record: SpyRecord) {
+    trace!("HwndLoop spy: {}", record);
+    let mut records = self.records.lock().unwrap();
+    if records.len() == self.capacity {
+      records.pop_front();
+    }
+    if self.capacity > 0 {
+      records.push_back(record);
+    }
+  }
+
+  pub(crate) fn dump(&self) -> Vec<SpyRecord> {
+    self.records.lock().unwrap().iter().cloned().collect()
+  }
+}
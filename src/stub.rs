@@ -0,0 +1,948 @@
+//! Stand-ins for the core types on platforms other than Windows.
+//!
+//! These have the same names and signatures as the real ones, but no loop can ever be created:
+//! everything that would start one fails with [`Error::UnsupportedPlatform`], and the types that
+//! only exist while a loop is running can't be constructed at all.
+
+use std::any::Any;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use {Error, Result};
+
+/// Stand-ins for the Win32 types in the callbacks' signatures, which `winapi` only provides on
+/// Windows.
+pub type HWND = *mut std::os::raw::c_void;
+pub type UINT = u32;
+pub type WPARAM = usize;
+pub type LPARAM = isize;
+pub type LRESULT = isize;
+pub type DWORD = u32;
+pub type WORD = u16;
+
+/// Where a loop spends its time, which is never here.
+pub mod profile {
+  use std::mem::Discriminant;
+  use std::time::Duration;
+
+  use super::UINT;
+
+  /// How long the callbacks took to handle one message ID or command variant.
+  #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+  pub struct HandlerTime {
+    /// How many times it was handled.
+    pub count: u64,
+
+    /// How long it took altogether.
+    pub total: Duration,
+
+    /// How long the slowest one took.
+    pub worst: Duration,
+  }
+
+  /// The time that a loop's callbacks spent handling messages and commands, from
+  /// [`LoopHandle::profile_snapshot`](::LoopHandle::profile_snapshot).
+  #[derive(Debug)]
+  pub struct ProfileSnapshot<CommandType> {
+    /// How long the snapshot covers.
+    pub elapsed: Duration,
+
+    /// Window messages, by message ID.
+    pub messages: Vec<(UINT, HandlerTime)>,
+
+    /// Commands, by which variant of the command type they were.
+    pub commands: Vec<(Discriminant<CommandType>, HandlerTime)>,
+  }
+}
+
+/// User input idle detection, which never reports anything here.
+pub mod idle {
+  use std::time::Duration;
+
+  /// A transition between the user being active and idle.
+  #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+  pub enum IdleEvent {
+    /// There's been no input for this long, which is at least the threshold.
+    UserIdle(Duration),
+
+    /// There was input after the user had gone idle.
+    UserActive,
+  }
+}
+
+/// A record of the messages that a loop's window handled, which is always empty here.
+pub mod spy {
+  use std::time::{Duration, Instant};
+
+  use super::{HwndWrapper, DWORD, LPARAM, UINT, WPARAM};
+
+  /// Where a message that the window handled came from.
+  #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+  pub enum MessageOrigin {
+    /// It was posted, and dispatched by the loop's message pump.
+    Posted,
+
+    /// It was sent by the handler thread itself, or dispatched by someone else's message pump.
+    Sent,
+
+    /// It was sent by another thread.
+    SentFromOtherThread { thread: Option<DWORD>, process: Option<DWORD> },
+  }
+
+  /// A message that the window handled.
+  #[derive(Clone, Debug)]
+  pub struct SpyRecord {
+    /// When the window started handling the message.
+    pub time: Instant,
+    pub hwnd: HwndWrapper,
+    pub msg: UINT,
+    pub wparam: WPARAM,
+    pub lparam: LPARAM,
+    pub origin: MessageOrigin,
+
+    /// How long the window took to handle the message.
+    pub duration: Duration,
+  }
+}
+
+/// Proof that a loop is running, which can't exist here.
+#[derive(Clone, Copy, Debug)]
+enum Unsupported {}
+
+/// Send and Sync wrapper for [`HWND`].
+#[derive(Clone, Debug)]
+pub struct HwndWrapper(pub HWND, #[allow(dead_code)] u64);
+unsafe impl Send for HwndWrapper {}
+unsafe impl Sync for HwndWrapper {}
+
+impl HwndWrapper {
+  /// Wrap a window that doesn't belong to a loop.
+  pub fn new(hwnd: HWND) -> HwndWrapper {
+    HwndWrapper(hwnd, 0)
+  }
+
+  /// Whether the window still exists, which it can't here.
+  pub fn is_valid(&self) -> bool {
+    false
+  }
+}
+
+impl From<HWND> for HwndWrapper {
+  fn from(hwnd: HWND) -> HwndWrapper {
+    HwndWrapper::new(hwnd)
+  }
+}
+
+/// Callbacks called by a [`HwndLoop`], which never happens on this platform.
+#[allow(unused_variables)]
+pub trait HwndLoopCallbacks<CommandType: Send + std::fmt::Debug + 'static>: Send {
+  /// Called on the handler thread just before the [`HwndLoop`] starts.
+  fn set_up(&mut self, hwnd: HWND, context: &SetUpContext<CommandType>) {}
+
+  /// Called on the handler thread just before the [`HwndLoop`] terminates.
+  fn tear_down(&mut self, hwnd: HWND, context: &mut TearDownContext) {}
+
+  /// Handle a Windows message.
+  fn handle_message(&mut self, hwnd: HWND, msg: UINT, w: WPARAM, l: LPARAM) -> LRESULT {
+    0
+  }
+
+  /// Handle a Windows message, returning `None` to pass it on to the default window procedure.
+  fn try_handle_message(&mut self, hwnd: HWND, msg: UINT, w: WPARAM, l: LPARAM) -> Option<LRESULT> {
+    Some(self.handle_message(hwnd, msg, w, l))
+  }
+
+  /// Handle a command sent via [`LoopHandle::send_command`].
+  fn handle_command(&mut self, hwnd: HWND, cmd: CommandType) {}
+
+  /// Handle a command, reporting failure to its sender.
+  fn try_handle_command(
+    &mut self,
+    hwnd: HWND,
+    cmd: CommandType,
+  ) -> std::result::Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    self.handle_command(hwnd, cmd);
+    Ok(())
+  }
+
+  /// Handle a command that the loop dropped instead of handling.
+  fn handle_dropped_command(&mut self, hwnd: HWND, cmd: CommandType, reason: DropReason) {}
+
+  /// Handle a keystroke from an accelerator table.
+  fn handle_accelerator(&mut self, hwnd: HWND, id: WORD) {}
+
+  /// Handle the user's session being asked to end, returning false to ask for it to be kept alive.
+  fn handle_query_end_session(&mut self, hwnd: HWND, reason: LPARAM) -> bool {
+    true
+  }
+
+  /// Handle the outcome of a request to end the session.
+  fn handle_end_session(&mut self, hwnd: HWND, ending: bool, reason: LPARAM) {}
+
+  /// Handle the system's font table changing.
+  fn handle_font_change(&mut self, hwnd: HWND) {}
+
+  /// Handle the system asking to start the screensaver, returning false to keep it from starting.
+  fn handle_screensaver_request(&mut self, hwnd: HWND) -> bool {
+    true
+  }
+
+  /// Handle the system asking to change the monitors' power state, returning false to keep them
+  /// as they are.
+  fn handle_monitor_power_request(&mut self, hwnd: HWND, state: MonitorPower) -> bool {
+    true
+  }
+
+  /// Handle the user going idle or coming back, for loops built with
+  /// [`HwndLoopBuilder::idle_threshold`].
+  fn handle_idle_event(&mut self, hwnd: HWND, event: idle::IdleEvent) {}
+}
+
+/// What [`HwndLoopCallbacks::set_up`] gets to know about the loop it's setting up.
+pub struct SetUpContext<CommandType: Send + std::fmt::Debug + 'static> {
+  never: Unsupported,
+  _marker: PhantomData<fn() -> CommandType>,
+}
+
+impl<CommandType: Send + std::fmt::Debug + 'static> SetUpContext<CommandType> {
+  /// A handle to the loop.
+  pub fn handle(&self) -> LoopHandle<CommandType> {
+    match self.never {}
+  }
+
+  /// The id of the handler thread.
+  pub fn thread_id(&self) -> DWORD {
+    match self.never {}
+  }
+
+  /// The value passed to [`HwndLoopBuilder::user_data`], if it's a `T`.
+  pub fn user_data<T: Any + Send + Sync>(&self) -> Option<&T> {
+    match self.never {}
+  }
+
+  /// The sending end of the loop's events, if it was built with
+  /// [`HwndLoopBuilder::events::<E>`](HwndLoopBuilder::events).
+  pub fn event_emitter<E: Clone + Send + 'static>(&self) -> Option<EventEmitter<E>> {
+    match self.never {}
+  }
+}
+
+/// Lets [`HwndLoopCallbacks::tear_down`] keep the window around for a little longer.
+pub struct TearDownContext {
+  never: Unsupported,
+}
+
+impl TearDownContext {
+  /// Keep dispatching window messages for up to `timeout` after `tear_down` returns.
+  pub fn drain(&mut self, _timeout: Duration) -> DrainHandle {
+    match self.never {}
+  }
+}
+
+/// Ends a drain requested with [`TearDownContext::drain`].
+#[derive(Clone, Debug)]
+pub struct DrainHandle {
+  never: Unsupported,
+}
+
+impl DrainHandle {
+  /// Stop draining, and let the window be destroyed.
+  pub fn finish(&self) {
+    match self.never {}
+  }
+
+  /// Whether [`DrainHandle::finish`] has been called.
+  pub fn is_finished(&self) -> bool {
+    match self.never {}
+  }
+}
+
+/// What an [`EventEmitter`] does when an [`EventReceiver`] falls behind.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Backpressure {
+  /// Buffer as many events as it takes.
+  #[default]
+  Unbounded,
+
+  /// Block the emitter while the receiver has this many events waiting, which is at least 1.
+  Block(usize),
+
+  /// Keep only this many of the most recent events, dropping the oldest ones.
+  DropOldest(usize),
+
+  /// Stop buffering once this many events are waiting, dropping new ones until the receiver
+  /// catches up.
+  DropNewest(usize),
+}
+
+/// Sends events out of a loop's callbacks, which can't run here.
+pub struct EventEmitter<E: Clone + Send + 'static> {
+  never: Unsupported,
+  _marker: PhantomData<fn() -> E>,
+}
+
+impl<E: Clone + Send + 'static> Clone for EventEmitter<E> {
+  fn clone(&self) -> EventEmitter<E> {
+    match self.never {}
+  }
+}
+
+impl<E: Clone + Send + 'static> EventEmitter<E> {
+  /// Send an event to every receiver.
+  pub fn emit(&self, _event: E) -> Result<()> {
+    match self.never {}
+  }
+}
+
+/// Receives the events that a loop's callbacks send with an [`EventEmitter`].
+pub struct EventReceiver<E: Clone + Send + 'static> {
+  never: Unsupported,
+  _marker: PhantomData<fn() -> E>,
+}
+
+impl<E: Clone + Send + 'static> EventReceiver<E> {
+  /// Block until the next event.
+  pub fn recv(&self) -> Result<E> {
+    match self.never {}
+  }
+
+  /// Get the next event if there is one, without blocking.
+  pub fn try_recv(&self) -> Result<Option<E>> {
+    match self.never {}
+  }
+
+  /// Block until the next event for at most `timeout`.
+  pub fn recv_timeout(&self, _timeout: Duration) -> Result<E> {
+    match self.never {}
+  }
+
+  /// Change what happens when this receiver falls behind.
+  pub fn set_backpressure(&self, _policy: Backpressure) {
+    match self.never {}
+  }
+
+  /// Number of events that this receiver missed because of its [`Backpressure`] policy.
+  pub fn dropped(&self) -> u64 {
+    match self.never {}
+  }
+}
+
+/// Waits for a window message, as registered by [`LoopHandle::expect_message`].
+pub struct MessageWaiter {
+  never: Unsupported,
+}
+
+impl MessageWaiter {
+  /// Block until the message arrives, returning its WPARAM and LPARAM.
+  pub fn wait(self, _timeout: Duration) -> Result<(WPARAM, LPARAM)> {
+    match self.never {}
+  }
+}
+
+impl Future for MessageWaiter {
+  type Output = Result<(WPARAM, LPARAM)>;
+
+  fn poll(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Self::Output> {
+    match self.never {}
+  }
+}
+
+/// The sequence number of a command sent through [`LoopHandle::send_command`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CommandSeq(u64);
+
+impl std::fmt::Display for CommandSeq {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    write!(f, "#{}", self.0)
+  }
+}
+
+/// The state that the system wants to put the monitors in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MonitorPower {
+  /// Turn the monitors on.
+  On,
+
+  /// Put the monitors into low power mode.
+  LowPower,
+
+  /// Turn the monitors off.
+  Off,
+}
+
+/// Why a loop dropped a command instead of handling it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DropReason {
+  /// The command's deadline passed while it was waiting to be handled.
+  Expired,
+
+  /// The command was removed from the queue by [`LoopHandle::cancel_pending`].
+  Cancelled,
+
+  /// The loop terminated before getting to the command.
+  Terminated,
+}
+
+/// What a [`KeepAwakeGuard`] keeps the system from doing.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct KeepAwake {
+  /// Keep the system from sleeping.
+  pub system: bool,
+
+  /// Keep the display on.
+  pub display: bool,
+}
+
+/// The processors that a loop's thread is allowed to run on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Affinity {
+  /// A mask of logical processors in the thread's processor group.
+  Mask(usize),
+
+  /// CPU set IDs.
+  CpuSets(Vec<u32>),
+}
+
+/// A snapshot of how busy a loop is, returned by [`LoopHandle::status`].
+#[derive(Clone, Debug)]
+pub struct LoopStatus {
+  /// Number of commands that have been sent but not yet handled.
+  pub pending_commands: usize,
+
+  /// Whether the handler thread's message queue had input waiting.
+  pub input_pending: bool,
+
+  /// Time since the loop last handled a command or window message.
+  pub since_last_dispatch: Duration,
+
+  /// Number of events that receivers have missed.
+  pub dropped_events: u64,
+}
+
+/// Result of a single call to [`HwndPump::pump_once`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PumpStatus {
+  /// There was nothing to do.
+  Idle,
+
+  /// A wakeup was processed, and there might be more waiting.
+  Processed,
+
+  /// The loop was told to terminate, and won't process anything else.
+  Terminated,
+}
+
+/// What happens to the commands that are still queued when the last [`HwndLoop`] is dropped.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TerminatePolicy {
+  /// Handle everything that was sent before the loop was dropped.
+  #[default]
+  Drain,
+
+  /// Keep handling queued commands for at most `grace`, then drop the rest.
+  Preempt { grace: Duration },
+}
+
+/// A builder for [`HwndLoop`], whose loops always fail to start on this platform.
+#[derive(Default)]
+pub struct HwndLoopBuilder {
+  _private: (),
+}
+
+impl HwndLoopBuilder {
+  /// Create a builder with the default configuration.
+  pub fn new() -> HwndLoopBuilder {
+    HwndLoopBuilder::default()
+  }
+
+  /// Set whether to call `TranslateMessage` on messages before dispatching them.
+  pub fn translate_messages(self, _translate: bool) -> HwndLoopBuilder {
+    self
+  }
+
+  /// Only retrieve messages in the range `min..=max`.
+  pub fn message_filter(self, _min: UINT, _max: UINT) -> HwndLoopBuilder {
+    self
+  }
+
+  /// Only retrieve messages for `hwnd`.
+  pub fn hwnd_filter(self, _hwnd: HwndWrapper) -> HwndLoopBuilder {
+    self
+  }
+
+  /// Reserve `count` user slots.
+  pub fn user_slots(self, _count: usize) -> HwndLoopBuilder {
+    self
+  }
+
+  /// Make `data` available to [`SetUpContext::user_data`].
+  pub fn user_data<T: Any + Send + Sync>(self, _data: T) -> HwndLoopBuilder {
+    self
+  }
+
+  /// Give the loop a channel for sending events of type `E` out of its callbacks.
+  pub fn events<E: Clone + Send + 'static>(self) -> HwndLoopBuilder {
+    self
+  }
+
+  /// Give the loop a channel for events of type `E`, whose first receiver uses `policy`.
+  pub fn events_with<E: Clone + Send + 'static>(self, _policy: Backpressure) -> HwndLoopBuilder {
+    self
+  }
+
+  /// Run `f` on the handler thread once the loop has been set up.
+  pub fn on_start<F: Fn(HWND) + Send + Sync + 'static>(self, _f: F) -> HwndLoopBuilder {
+    self
+  }
+
+  /// Handle floods of `msg` at most once every `window`.
+  pub fn coalesce(self, _msg: UINT, _window: Duration) -> HwndLoopBuilder {
+    self
+  }
+
+  /// Tell [`HwndLoopCallbacks::handle_idle_event`] when there hasn't been any user input for
+  /// `threshold`, and again when there is.
+  pub fn idle_threshold(self, _threshold: Duration) -> HwndLoopBuilder {
+    self
+  }
+
+  /// Create the window as a top-level window, so that it receives broadcasts.
+  pub fn receive_broadcasts(self) -> HwndLoopBuilder {
+    self
+  }
+
+  /// Run the loop without a window, as a plain worker thread.
+  pub fn windowless(self) -> HwndLoopBuilder {
+    self
+  }
+
+  /// Post the loop's internal messages to its thread instead of its window.
+  pub fn thread_control(self, _enabled: bool) -> HwndLoopBuilder {
+    self
+  }
+
+  /// Give the handler thread a stack of `size` bytes.
+  pub fn stack_size(self, _size: usize) -> HwndLoopBuilder {
+    self
+  }
+
+  /// Give up on a loop whose thread hasn't finished starting up after `timeout`.
+  pub fn startup_timeout(self, _timeout: Duration) -> HwndLoopBuilder {
+    self
+  }
+
+  /// Restrict the loop's thread to the processors in `affinity`.
+  pub fn affinity(self, _affinity: Affinity) -> HwndLoopBuilder {
+    self
+  }
+
+  /// Make `processor` the preferred processor for the loop's thread.
+  pub fn ideal_processor(self, _processor: DWORD) -> HwndLoopBuilder {
+    self
+  }
+
+  /// Register the loop's thread with the Multimedia Class Scheduler Service.
+  pub fn mmcss_task(self, _task: &str) -> HwndLoopBuilder {
+    self
+  }
+
+  /// Record the last `capacity` messages that the loop's window handles.
+  pub fn spy(self, _capacity: usize) -> HwndLoopBuilder {
+    self
+  }
+
+  /// Time the messages and commands that the callbacks handle.
+  pub fn profile(self, _enabled: bool) -> HwndLoopBuilder {
+    self
+  }
+
+  /// Check the loop's invariants in debug builds.
+  pub fn strict(self, _strict: bool) -> HwndLoopBuilder {
+    self
+  }
+
+  /// Set the process's shutdown level.
+  pub fn shutdown_level(self, _level: DWORD) -> HwndLoopBuilder {
+    self
+  }
+
+  /// Choose what happens to the commands that are still queued when the last [`HwndLoop`] is
+  /// dropped.
+  pub fn terminate_policy(self, _policy: TerminatePolicy) -> HwndLoopBuilder {
+    self
+  }
+
+  /// Create a [`HwndLoop`] with this configuration.
+  ///
+  /// This always panics on this platform; see [`HwndLoopBuilder::try_build`].
+  pub fn build<CommandType: Send + std::fmt::Debug + 'static>(
+    self,
+    callbacks: Box<dyn HwndLoopCallbacks<CommandType>>,
+  ) -> HwndLoop<CommandType> {
+    match self.try_build(callbacks) {
+      Ok(hwndloop) => hwndloop,
+      Err(err) => panic!("failed to start HwndLoop: {}", err),
+    }
+  }
+
+  /// Create a [`HwndLoop`] with this configuration, which always fails with
+  /// [`Error::UnsupportedPlatform`] on this platform.
+  pub fn try_build<CommandType: Send + std::fmt::Debug + 'static>(
+    self,
+    _callbacks: Box<dyn HwndLoopCallbacks<CommandType>>,
+  ) -> Result<HwndLoop<CommandType>> {
+    Err(Error::UnsupportedPlatform)
+  }
+
+  /// Create a [`HwndPump`] with this configuration, which always fails with
+  /// [`Error::UnsupportedPlatform`] on this platform.
+  pub fn build_pump<CommandType: Send + std::fmt::Debug + 'static>(
+    self,
+    _callbacks: Box<dyn HwndLoopCallbacks<CommandType>>,
+  ) -> Result<HwndPump<CommandType>> {
+    Err(Error::UnsupportedPlatform)
+  }
+
+  /// Create an [`ExternalLoopAdapter`] with this configuration, which always fails with
+  /// [`Error::UnsupportedPlatform`] on this platform.
+  pub fn build_external<CommandType: Send + std::fmt::Debug + 'static>(
+    self,
+    _callbacks: Box<dyn HwndLoopCallbacks<CommandType>>,
+  ) -> Result<ExternalLoopAdapter<CommandType>> {
+    Err(Error::UnsupportedPlatform)
+  }
+
+  /// Run a loop with this configuration on the current thread, which always fails with
+  /// [`Error::UnsupportedPlatform`] on this platform.
+  pub fn run_here<CommandType: Send + std::fmt::Debug + 'static, F: FnOnce(LoopHandle<CommandType>)>(
+    self,
+    _callbacks: Box<dyn HwndLoopCallbacks<CommandType>>,
+    _ready: F,
+  ) -> Result<()> {
+    Err(Error::UnsupportedPlatform)
+  }
+}
+
+/// An event loop backed by a Win32 window and thread, which can't be created on this platform.
+pub struct HwndLoop<CommandType: Send + std::fmt::Debug + 'static> {
+  handle: LoopHandle<CommandType>,
+}
+
+impl<CommandType: Send + std::fmt::Debug + 'static> HwndLoop<CommandType> {
+  /// Create a new [`HwndLoop`].
+  ///
+  /// This always panics on this platform; use [`HwndLoopBuilder::try_build`] to find out whether
+  /// loops are supported instead.
+  pub fn new(callbacks: Box<dyn HwndLoopCallbacks<CommandType>>) -> HwndLoop<CommandType> {
+    HwndLoopBuilder::new().build(callbacks)
+  }
+
+  /// Run a loop on the current thread, which always fails with [`Error::UnsupportedPlatform`] on
+  /// this platform.
+  pub fn run_here<F: FnOnce(LoopHandle<CommandType>)>(
+    callbacks: Box<dyn HwndLoopCallbacks<CommandType>>,
+    ready: F,
+  ) -> Result<()> {
+    HwndLoopBuilder::new().run_here(callbacks, ready)
+  }
+
+  /// Terminate the loop and hand back its callbacks.
+  pub fn shutdown_into_inner(self) -> Result<Box<dyn HwndLoopCallbacks<CommandType>>> {
+    match self.handle.never {}
+  }
+
+  /// Wait for the loop to terminate, and take the value that its callbacks left.
+  pub fn join<V: Any + Send>(self) -> Option<V> {
+    match self.handle.never {}
+  }
+
+  /// Get a handle for sending commands to the loop.
+  pub fn handle(&self) -> LoopHandle<CommandType> {
+    self.handle.clone()
+  }
+}
+
+impl<CommandType: Send + std::fmt::Debug + 'static> std::ops::Deref for HwndLoop<CommandType> {
+  type Target = LoopHandle<CommandType>;
+
+  fn deref(&self) -> &LoopHandle<CommandType> {
+    &self.handle
+  }
+}
+
+impl<CommandType: Send + std::fmt::Debug + 'static> Clone for HwndLoop<CommandType> {
+  fn clone(&self) -> HwndLoop<CommandType> {
+    HwndLoop {
+      handle: self.handle.clone(),
+    }
+  }
+}
+
+/// Holds back a loop's user commands until it's dropped.
+pub struct DeferGuard<CommandType: Send + std::fmt::Debug + 'static> {
+  _handle: LoopHandle<CommandType>,
+}
+
+/// A timer resolution requested with [`LoopHandle::request_timer_resolution`].
+pub struct TimerResolution<CommandType: Send + std::fmt::Debug + 'static> {
+  _handle: LoopHandle<CommandType>,
+}
+
+/// Keeps the system awake until it's dropped.
+pub struct KeepAwakeGuard<CommandType: Send + std::fmt::Debug + 'static> {
+  _handle: LoopHandle<CommandType>,
+}
+
+/// A handle for sending commands to a loop from any thread.
+pub struct LoopHandle<CommandType: Send + std::fmt::Debug + 'static> {
+  never: Unsupported,
+  _marker: PhantomData<fn() -> CommandType>,
+}
+
+impl<CommandType: Send + std::fmt::Debug + 'static> Clone for LoopHandle<CommandType> {
+  fn clone(&self) -> LoopHandle<CommandType> {
+    match self.never {}
+  }
+}
+
+impl<CommandType: Send + std::fmt::Debug + 'static> LoopHandle<CommandType> {
+  /// Get the loop's window.
+  pub fn hwnd(&self) -> HwndWrapper {
+    match self.never {}
+  }
+
+  /// Send a command to the loop.
+  pub fn send_command(&self, _cmd: CommandType) -> Result<CommandSeq> {
+    match self.never {}
+  }
+
+  /// Send a command to the loop that's dropped if it isn't handled by `deadline`.
+  pub fn send_command_with_deadline(&self, _cmd: CommandType, _deadline: Instant) -> Result<CommandSeq> {
+    match self.never {}
+  }
+
+  /// Send a command to the loop that jumps ahead of every command that's still waiting.
+  pub fn send_urgent_command(&self, _cmd: CommandType) -> Result<()> {
+    match self.never {}
+  }
+
+  /// Ask for the system timer to tick at least every `resolution`.
+  pub fn request_timer_resolution(&self, _resolution: Duration) -> Result<TimerResolution<CommandType>> {
+    match self.never {}
+  }
+
+  /// Keep the system awake while the returned guard is around.
+  pub fn keep_awake(&self, _flags: KeepAwake) -> Result<KeepAwakeGuard<CommandType>> {
+    match self.never {}
+  }
+
+  /// Hold back user commands until the returned guard is dropped.
+  pub fn defer_commands(&self) -> DeferGuard<CommandType> {
+    match self.never {}
+  }
+
+  /// The messages that the loop's window handled most recently, oldest first.
+  pub fn spy_log(&self) -> Vec<spy::SpyRecord> {
+    match self.never {}
+  }
+
+  /// The window messages and commands that the loop spent the most time handling since the last
+  /// snapshot.
+  pub fn profile_snapshot(&self, _top: usize) -> profile::ProfileSnapshot<CommandType> {
+    match self.never {}
+  }
+
+  /// Send a command to the loop, and wait for it to be handled.
+  pub fn call(&self, _cmd: CommandType) -> Result<()> {
+    match self.never {}
+  }
+
+  /// Send a group of commands to be handled back to back, and wait for them to be handled.
+  pub fn call_transaction<I: IntoIterator<Item = CommandType>>(&self, _cmds: I) -> Result<()> {
+    match self.never {}
+  }
+
+  /// The sequence number of the last command that the loop handled.
+  pub fn last_processed_seq(&self) -> Option<CommandSeq> {
+    match self.never {}
+  }
+
+  /// Tell the loop to terminate.
+  pub fn terminate(&self) -> Result<()> {
+    match self.never {}
+  }
+
+  /// Wait for the commands sent from the current thread to be handled.
+  pub fn flush(&self) -> Result<()> {
+    match self.never {}
+  }
+
+  /// Wait for the commands sent from every thread to be handled.
+  pub fn flush_all(&self) -> Result<()> {
+    match self.never {}
+  }
+
+  /// Wait for the command numbered `seq` to be handled.
+  pub fn flush_until(&self, _seq: CommandSeq, _timeout: Duration) -> Result<()> {
+    match self.never {}
+  }
+
+  /// Store `value` in one of the window's slots.
+  pub fn set_slot<T: Any + Send>(&self, _key: usize, _value: T) -> Result<()> {
+    match self.never {}
+  }
+
+  /// Empty one of the window's slots.
+  pub fn clear_slot(&self, _key: usize) -> Result<()> {
+    match self.never {}
+  }
+
+  /// Run `f` on the handler thread while the loop is being torn down.
+  pub fn on_teardown<F: FnOnce(HWND) + Send + 'static>(&self, _f: F) -> Result<()> {
+    match self.never {}
+  }
+
+  /// Run `f` on the handler thread the next time the loop's window receives `msg`.
+  pub fn once<F: FnOnce(HWND, UINT, WPARAM, LPARAM) + Send + 'static>(&self, _msg: UINT, _f: F) -> Result<()> {
+    match self.never {}
+  }
+
+  /// Take the loop's first event receiver.
+  pub fn event_receiver<E: Clone + Send + 'static>(&self) -> Option<EventReceiver<E>> {
+    match self.never {}
+  }
+
+  /// Subscribe another receiver to the loop's events.
+  pub fn subscribe<E: Clone + Send + 'static>(&self) -> Option<EventReceiver<E>> {
+    match self.never {}
+  }
+
+  /// Subscribe another receiver to the loop's events, with a policy for when it falls behind.
+  pub fn subscribe_with<E: Clone + Send + 'static>(&self, _policy: Backpressure) -> Option<EventReceiver<E>> {
+    match self.never {}
+  }
+
+  /// Start waiting for the next time the loop's window receives `msg`.
+  pub fn expect_message(&self, _msg: UINT) -> Result<MessageWaiter> {
+    match self.never {}
+  }
+
+  /// Block until the loop's window next receives `msg`, returning its WPARAM and LPARAM.
+  pub fn wait_for_message(&self, _msg: UINT, _timeout: Duration) -> Result<(WPARAM, LPARAM)> {
+    match self.never {}
+  }
+
+  /// Stop handling commands until [`LoopHandle::resume`] is called.
+  pub fn pause(&self) -> Result<()> {
+    match self.never {}
+  }
+
+  /// Resume handling commands after [`LoopHandle::pause`].
+  pub fn resume(&self) -> Result<()> {
+    match self.never {}
+  }
+
+  /// Get a snapshot of how busy the loop is.
+  pub fn status(&self) -> LoopStatus {
+    match self.never {}
+  }
+
+  /// Remove the pending commands that match `pred`, returning how many were removed.
+  pub fn cancel_pending<F: FnMut(&CommandType) -> bool>(&self, _pred: F) -> usize {
+    match self.never {}
+  }
+
+  /// Remove the pending commands that match `pred`, and hand them back.
+  pub fn take_pending<F: FnMut(&CommandType) -> bool>(&self, _pred: F) -> Vec<CommandType> {
+    match self.never {}
+  }
+
+  /// Number of commands that have been sent but not yet handled.
+  pub fn pending_commands(&self) -> usize {
+    match self.never {}
+  }
+
+  /// Render the commands that are waiting in the loop's queues, in debug builds.
+  #[cfg(debug_assertions)]
+  pub fn snapshot_commands(&self) -> Vec<String> {
+    match self.never {}
+  }
+
+  /// The number of times that posting to the loop found its message queue full.
+  pub fn saturation_count(&self) -> usize {
+    match self.never {}
+  }
+}
+
+/// A loop whose messages are pumped manually by the thread that created it, which can't be created
+/// on this platform.
+pub struct HwndPump<CommandType: Send + std::fmt::Debug + 'static> {
+  handle: LoopHandle<CommandType>,
+}
+
+impl<CommandType: Send + std::fmt::Debug + 'static> HwndPump<CommandType> {
+  /// Create a new [`HwndPump`], which always fails with [`Error::UnsupportedPlatform`] on this
+  /// platform.
+  pub fn new(callbacks: Box<dyn HwndLoopCallbacks<CommandType>>) -> Result<HwndPump<CommandType>> {
+    HwndLoopBuilder::new().build_pump(callbacks)
+  }
+
+  /// Get a handle for sending commands to the pump from other threads.
+  pub fn handle(&self) -> LoopHandle<CommandType> {
+    self.handle.clone()
+  }
+
+  /// Process at most one wakeup without blocking.
+  pub fn pump_once(&mut self) -> PumpStatus {
+    match self.handle.never {}
+  }
+
+  /// Process wakeups until there's nothing left to do.
+  pub fn pump_pending(&mut self) -> PumpStatus {
+    match self.handle.never {}
+  }
+
+  /// Whether the pump has been told to terminate.
+  pub fn is_terminated(&self) -> bool {
+    match self.handle.never {}
+  }
+}
+
+/// A loop that piggybacks on a message pump that the current thread already runs, which can't be
+/// created on this platform.
+pub struct ExternalLoopAdapter<CommandType: Send + std::fmt::Debug + 'static> {
+  handle: LoopHandle<CommandType>,
+}
+
+impl<CommandType: Send + std::fmt::Debug + 'static> ExternalLoopAdapter<CommandType> {
+  /// Create a new [`ExternalLoopAdapter`], which always fails with [`Error::UnsupportedPlatform`]
+  /// on this platform.
+  pub fn new(callbacks: Box<dyn HwndLoopCallbacks<CommandType>>) -> Result<ExternalLoopAdapter<CommandType>> {
+    HwndLoopBuilder::new().build_external(callbacks)
+  }
+
+  /// Get a handle for sending commands to the loop from other threads.
+  pub fn handle(&self) -> LoopHandle<CommandType> {
+    self.handle.clone()
+  }
+
+  /// Whether the loop has been told to terminate through a handle.
+  pub fn is_terminated(&self) -> bool {
+    match self.handle.never {}
+  }
+}
+
+/// Terminate the loop whose callback is running on this thread, which always fails with
+/// [`Error::NotOnLoopThread`] on this platform, since no loop can be running.
+pub fn request_shutdown() -> Result<()> {
+  Err(Error::NotOnLoopThread)
+}
+
+/// Leave `value` for [`HwndLoop::join`], which always fails with [`Error::NotOnLoopThread`] on
+/// this platform, as with [`request_shutdown`].
+pub fn set_exit_value<V: Any + Send>(_value: V) -> Result<()> {
+  Err(Error::NotOnLoopThread)
+}
@@ -0,0 +1,413 @@
+//! Toast notifications, shown from the loop's thread.
+//!
+//! This is only available with the `toast` feature. WinRT is initialized on the handler thread
+//! the first time that a loop shows a toast, and torn down with the loop. Toasts are shown with
+//! [`LoopHandle::show_toast`](::LoopHandle::show_toast), and activations (the user clicking on the
+//! toast) are delivered back to the handler thread.
+//!
+//! Unpackaged applications can only show toasts for an AppUserModelID that has a Start menu
+//! shortcut.
+
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use winapi::ctypes::c_void;
+use winapi::shared::guiddef::{IsEqualGUID, GUID, REFIID};
+use winapi::shared::minwindef::ULONG;
+use winapi::shared::windef::HWND;
+use winapi::shared::winerror::{E_NOINTERFACE, HRESULT, RPC_E_CHANGED_MODE, S_OK};
+use winapi::winrt::hstring::HSTRING;
+use winapi::winrt::inspectable::IInspectable;
+use winapi::winrt::roapi::{RoActivateInstance, RoGetActivationFactory, RoInitialize, RoUninitialize};
+use winapi::winrt::roapi::RO_INIT_SINGLETHREADED;
+use winapi::winrt::winstring::{WindowsCreateString, WindowsDeleteString, WindowsGetStringRawBuffer};
+
+use util;
+use {Error, Result};
+
+const IID_IUNKNOWN: GUID = guid(0x0000_0000, 0x0000, 0x0000, [0xc0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x46]);
+const IID_IAGILE_OBJECT: GUID = guid(0x94ea_2b94, 0xe9cc, 0x49e0, [0xc0, 0xff, 0xee, 0x64, 0xca, 0x8f, 0x5b, 0x90]);
+const IID_IXML_DOCUMENT: GUID = guid(0xf7f3_a506, 0x1e87, 0x42d6, [0xbc, 0xfb, 0xb8, 0xc8, 0x09, 0xfa, 0x54, 0x94]);
+const IID_IXML_DOCUMENT_IO: GUID = guid(0x6cd0_e74e, 0xee65, 0x4489, [0x9e, 0xbf, 0xca, 0x43, 0xe8, 0x7b, 0xa6, 0x37]);
+const IID_ITOAST_NOTIFICATION_FACTORY: GUID =
+  guid(0x0412_4b20, 0x82c6, 0x4229, [0xb1, 0x09, 0xfd, 0x9e, 0xd4, 0x66, 0x2b, 0x53]);
+const IID_ITOAST_NOTIFICATION_MANAGER_STATICS: GUID =
+  guid(0x50ac_103f, 0xd235, 0x4598, [0xbb, 0xef, 0x98, 0xfe, 0x4d, 0x1a, 0x3a, 0xd4]);
+const IID_ITOAST_ACTIVATED_EVENT_ARGS: GUID =
+  guid(0xe3bf_92f3, 0xc197, 0x436f, [0x82, 0x65, 0x06, 0x25, 0x82, 0x4f, 0x8d, 0xac]);
+
+/// `TypedEventHandler<ToastNotification, Object>`, for `IToastNotification::add_Activated`.
+const IID_ACTIVATED_HANDLER: GUID = guid(0xab54_de2d, 0x97d9, 0x5528, [0xb6, 0xad, 0x10, 0x5a, 0xfe, 0x15, 0x65, 0x30]);
+
+const fn guid(data1: u32, data2: u16, data3: u16, data4: [u8; 8]) -> GUID {
+  GUID {
+    Data1: data1,
+    Data2: data2,
+    Data3: data3,
+    Data4: data4,
+  }
+}
+
+/// Vtable slots of the methods that we call, after IInspectable's six.
+const SLOT_LOAD_XML: usize = 6;
+const SLOT_CREATE_TOAST_NOTIFICATION: usize = 6;
+const SLOT_CREATE_TOAST_NOTIFIER_WITH_ID: usize = 7;
+const SLOT_SHOW: usize = 6;
+const SLOT_ADD_ACTIVATED: usize = 11;
+const SLOT_GET_ARGUMENTS: usize = 6;
+
+/// A toast notification to show with [`LoopHandle::show_toast`](::LoopHandle::show_toast).
+#[derive(Clone)]
+pub struct ToastBuilder {
+  pub(crate) app_id: String,
+  title: String,
+  body: String,
+  arguments: String,
+  xml: Option<String>,
+  pub(crate) on_activated: Option<Arc<dyn Fn(HWND, String) + Send + Sync>>,
+}
+
+impl std::fmt::Debug for ToastBuilder {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    f.debug_struct("ToastBuilder")
+      .field("app_id", &self.app_id)
+      .field("title", &self.title)
+      .field("body", &self.body)
+      .field("arguments", &self.arguments)
+      .field("xml", &self.xml)
+      .finish()
+  }
+}
+
+impl ToastBuilder {
+  /// Create an empty toast, shown on behalf of the application identified by `app_id`.
+  pub fn new(app_id: &str) -> ToastBuilder {
+    ToastBuilder {
+      app_id: app_id.to_string(),
+      title: String::new(),
+      body: String::new(),
+      arguments: String::new(),
+      xml: None,
+      on_activated: None,
+    }
+  }
+
+  /// Set the toast's first line of text.
+  pub fn title(mut self, title: &str) -> ToastBuilder {
+    self.title = title.to_string();
+    self
+  }
+
+  /// Set the toast's second line of text.
+  pub fn body(mut self, body: &str) -> ToastBuilder {
+    self.body = body.to_string();
+    self
+  }
+
+  /// Set the string passed to the activation callback when the toast is clicked.
+  pub fn arguments(mut self, arguments: &str) -> ToastBuilder {
+    self.arguments = arguments.to_string();
+    self
+  }
+
+  /// Use `xml` as the toast's content, instead of building it from the title, body, and
+  /// arguments.
+  pub fn xml(mut self, xml: &str) -> ToastBuilder {
+    self.xml = Some(xml.to_string());
+    self
+  }
+
+  /// Call `f` on the handler thread with the activation arguments when the toast (or one of its
+  /// buttons) is clicked, while the process is still running.
+  pub fn on_activated<F: Fn(HWND, String) + Send + Sync + 'static>(mut self, f: F) -> ToastBuilder {
+    self.on_activated = Some(Arc::new(f));
+    self
+  }
+
+  fn content(&self) -> String {
+    if let Some(ref xml) = self.xml {
+      return xml.clone();
+    }
+    format!(
+      "<toast launch=\"{}\"><visual><binding template=\"ToastGeneric\"><text>{}</text><text>{}</text></binding>\
+       </visual></toast>",
+      escape(&self.arguments),
+      escape(&self.title),
+      escape(&self.body)
+    )
+  }
+}
+
+fn escape(s: &str) -> String {
+  s.replace('&', "&amp;")
+    .replace('<', "&lt;")
+    .replace('>', "&gt;")
+    .replace('"', "&quot;")
+    .replace('\'', "&apos;")
+}
+
+fn check(hr: HRESULT) -> Result<()> {
+  util::check_hresult(hr).map_err(Error::from)
+}
+
+/// Initialize WinRT on the current thread, returning whether it needs to be uninitialized.
+pub(crate) fn initialize() -> Result<bool> {
+  match unsafe { RoInitialize(RO_INIT_SINGLETHREADED) } {
+    // Someone else already initialized COM differently, which is fine for our purposes.
+    RPC_E_CHANGED_MODE => Ok(false),
+    hr => check(hr).map(|()| true),
+  }
+}
+
+pub(crate) fn uninitialize() {
+  unsafe { RoUninitialize() };
+}
+
+/// An owned HSTRING.
+struct HString(HSTRING);
+
+impl HString {
+  fn new(s: &str) -> Result<HString> {
+    let wide: Vec<u16> = s.encode_utf16().collect();
+    let mut hstring = std::ptr::null_mut();
+    check(unsafe { WindowsCreateString(wide.as_ptr(), wide.len() as u32, &mut hstring) })?;
+    Ok(HString(hstring))
+  }
+
+  fn to_string_lossy(&self) -> String {
+    let mut len = 0;
+    let buf = unsafe { WindowsGetStringRawBuffer(self.0, &mut len) };
+    if buf.is_null() {
+      return String::new();
+    }
+    String::from_utf16_lossy(unsafe { std::slice::from_raw_parts(buf, len as usize) })
+  }
+}
+
+impl Drop for HString {
+  fn drop(&mut self) {
+    unsafe { WindowsDeleteString(self.0) };
+  }
+}
+
+/// An owned reference to a WinRT object, whose methods are called through their vtable slots.
+struct Object(NonNull<c_void>);
+
+impl Object {
+  unsafe fn from_raw(ptr: *mut c_void) -> Result<Object> {
+    match NonNull::new(ptr) {
+      Some(ptr) => Ok(Object(ptr)),
+      None => Err(std::io::Error::from_raw_os_error(E_NOINTERFACE).into()),
+    }
+  }
+
+  fn as_raw(&self) -> *mut c_void {
+    self.0.as_ptr()
+  }
+
+  /// Get the function in vtable slot `index`, which must have type `F`.
+  unsafe fn slot<F: Copy>(&self, index: usize) -> F {
+    let vtable = *(self.as_raw() as *const *const usize);
+    std::mem::transmute_copy(&*vtable.add(index))
+  }
+
+  fn query(&self, iid: &GUID) -> Result<Object> {
+    let mut result = std::ptr::null_mut();
+    unsafe {
+      let query_interface: unsafe extern "system" fn(*mut c_void, REFIID, *mut *mut c_void) -> HRESULT = self.slot(0);
+      check(query_interface(self.as_raw(), iid, &mut result))?;
+      Object::from_raw(result)
+    }
+  }
+
+  /// Call a method that takes one pointer-sized argument, and returns an object.
+  unsafe fn call_for_object<T>(&self, index: usize, arg: *mut T) -> Result<Object> {
+    let method: unsafe extern "system" fn(*mut c_void, *mut T, *mut *mut c_void) -> HRESULT = self.slot(index);
+    let mut result = std::ptr::null_mut();
+    check(method(self.as_raw(), arg, &mut result))?;
+    Object::from_raw(result)
+  }
+
+  /// Call a method that takes one pointer-sized argument, and returns nothing.
+  unsafe fn call<T>(&self, index: usize, arg: *mut T) -> Result<()> {
+    let method: unsafe extern "system" fn(*mut c_void, *mut T) -> HRESULT = self.slot(index);
+    check(method(self.as_raw(), arg))
+  }
+}
+
+impl Drop for Object {
+  fn drop(&mut self) {
+    unsafe {
+      let release: unsafe extern "system" fn(*mut c_void) -> ULONG = self.slot(2);
+      release(self.as_raw());
+    }
+  }
+}
+
+fn activation_factory(class: &str, iid: &GUID) -> Result<Object> {
+  let class = HString::new(class)?;
+  let mut factory = std::ptr::null_mut();
+  unsafe {
+    check(RoGetActivationFactory(class.0, iid, &mut factory))?;
+    Object::from_raw(factory)
+  }
+}
+
+fn activate_instance(class: &str) -> Result<Object> {
+  let class = HString::new(class)?;
+  let mut instance: *mut IInspectable = std::ptr::null_mut();
+  unsafe {
+    check(RoActivateInstance(class.0, &mut instance))?;
+    Object::from_raw(instance as *mut c_void)
+  }
+}
+
+/// Show `toast`, calling `activated` with its arguments from whatever thread WinRT raises the
+/// event on.
+pub(crate) fn show(toast: &ToastBuilder, activated: Option<Box<dyn Fn(String) + Send + Sync>>) -> Result<()> {
+  let document = activate_instance("Windows.Data.Xml.Dom.XmlDocument")?;
+  let content = HString::new(&toast.content())?;
+  unsafe { document.query(&IID_IXML_DOCUMENT_IO)?.call(SLOT_LOAD_XML, content.0)? };
+
+  let factory = activation_factory("Windows.UI.Notifications.ToastNotification", &IID_ITOAST_NOTIFICATION_FACTORY)?;
+  let document = document.query(&IID_IXML_DOCUMENT)?;
+  let notification = unsafe { factory.call_for_object(SLOT_CREATE_TOAST_NOTIFICATION, document.as_raw())? };
+
+  if let Some(activated) = activated {
+    let handler = ActivatedHandler::create(activated);
+    let mut token = 0i64;
+    unsafe {
+      let add_activated: unsafe extern "system" fn(*mut c_void, *mut c_void, *mut i64) -> HRESULT =
+        notification.slot(SLOT_ADD_ACTIVATED);
+      check(add_activated(notification.as_raw(), handler.as_raw(), &mut token))?;
+    }
+  }
+
+  let manager = activation_factory(
+    "Windows.UI.Notifications.ToastNotificationManager",
+    &IID_ITOAST_NOTIFICATION_MANAGER_STATICS,
+  )?;
+  let app_id = HString::new(&toast.app_id)?;
+  let notifier = unsafe { manager.call_for_object(SLOT_CREATE_TOAST_NOTIFIER_WITH_ID, app_id.0)? };
+  unsafe { notifier.call(SLOT_SHOW, notification.as_raw()) }
+}
+
+/// A `TypedEventHandler<ToastNotification, Object>` that forwards activation arguments to a
+/// closure.
+#[repr(C)]
+struct ActivatedHandler {
+  vtable: *const ActivatedHandlerVtable,
+  refs: AtomicUsize,
+  activated: Box<dyn Fn(String) + Send + Sync>,
+}
+
+#[repr(C)]
+struct ActivatedHandlerVtable {
+  query_interface: unsafe extern "system" fn(*mut ActivatedHandler, REFIID, *mut *mut c_void) -> HRESULT,
+  add_ref: unsafe extern "system" fn(*mut ActivatedHandler) -> ULONG,
+  release: unsafe extern "system" fn(*mut ActivatedHandler) -> ULONG,
+  invoke: unsafe extern "system" fn(*mut ActivatedHandler, *mut c_void, *mut c_void) -> HRESULT,
+}
+
+static ACTIVATED_HANDLER_VTABLE: ActivatedHandlerVtable = ActivatedHandlerVtable {
+  query_interface: ActivatedHandler::query_interface,
+  add_ref: ActivatedHandler::add_ref,
+  release: ActivatedHandler::release,
+  invoke: ActivatedHandler::invoke,
+};
+
+impl ActivatedHandler {
+  fn create(activated: Box<dyn Fn(String) + Send + Sync>) -> Object {
+    let handler = Box::new(ActivatedHandler {
+      vtable: &ACTIVATED_HANDLER_VTABLE,
+      refs: AtomicUsize::new(1),
+      activated,
+    });
+    Object(unsafe { NonNull::new_unchecked(Box::into_raw(handler) as *mut c_void) })
+  }
+
+  unsafe extern "system" fn query_interface(this: *mut Self, iid: REFIID, result: *mut *mut c_void) -> HRESULT {
+    let iid = &*iid;
+    let supported = [IID_IUNKNOWN, IID_IAGILE_OBJECT, IID_ACTIVATED_HANDLER];
+    if supported.iter().any(|supported| IsEqualGUID(iid, supported)) {
+      ActivatedHandler::add_ref(this);
+      *result = this as *mut c_void;
+      S_OK
+    } else {
+      *result = std::ptr::null_mut();
+      E_NOINTERFACE
+    }
+  }
+
+  unsafe extern "system" fn add_ref(this: *mut Self) -> ULONG {
+    ((*this).refs.fetch_add(1, Ordering::SeqCst) + 1) as ULONG
+  }
+
+  unsafe extern "system" fn release(this: *mut Self) -> ULONG {
+    let refs = (*this).refs.fetch_sub(1, Ordering::SeqCst) - 1;
+    if refs == 0 {
+      drop(Box::from_raw(this));
+    }
+    refs as ULONG
+  }
+
+  unsafe extern "system" fn invoke(this: *mut Self, _sender: *mut c_void, args: *mut c_void) -> HRESULT {
+    // Activations from a button or the toast itself carry ToastActivatedEventArgs.
+    let arguments = match NonNull::new(args) {
+      Some(args) => {
+        let args = std::mem::ManuallyDrop::new(Object(args));
+        args.query(&IID_ITOAST_ACTIVATED_EVENT_ARGS).and_then(|args| {
+          let get_arguments: unsafe extern "system" fn(*mut c_void, *mut HSTRING) -> HRESULT =
+            args.slot(SLOT_GET_ARGUMENTS);
+          let mut arguments = std::ptr::null_mut();
+          check(get_arguments(args.as_raw(), &mut arguments))?;
+          Ok(HString(arguments).to_string_lossy())
+        })
+      }
+      None => Ok(String::new()),
+    };
+    match arguments {
+      Ok(arguments) => ((*this).activated)(arguments),
+      Err(err) => warn!("failed to get toast activation arguments: {}", err),
+    }
+    S_OK
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::{escape, ToastBuilder};
+
+  #[test]
+  fn escaping() {
+    assert_eq!("plain", escape("plain"));
+    assert_eq!(
+      "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&apos;s&lt;/a&gt;",
+      escape("<a href=\"x\">Tom & Jerry's</a>")
+    );
+    assert_eq!("&amp;amp;", escape("&amp;"));
+  }
+
+  #[test]
+  fn content() {
+    let toast = ToastBuilder::new("hwndloop.test")
+      .title("Disk <full>")
+      .body("\"C:\" & 'D:'")
+      .arguments("action=open&id=1");
+    assert_eq!(
+      "<toast launch=\"action=open&amp;id=1\"><visual><binding template=\"ToastGeneric\"><text>Disk &lt;full&gt;</text>\
+       <text>&quot;C:&quot; &amp; &apos;D:&apos;</text></binding></visual></toast>",
+      toast.content()
+    );
+
+    // Raw XML is used as is, instead of anything built from the other fields.
+    let xml = "<toast><visual><binding template=\"ToastGeneric\"><text>&amp;</text></binding></visual></toast>";
+    assert_eq!(xml, toast.xml(xml).content());
+    assert_eq!(
+      "<toast launch=\"\"><visual><binding template=\"ToastGeneric\"><text></text><text></text></binding></visual>\
+       </toast>",
+      ToastBuilder::new("hwndloop.test").content()
+    );
+  }
+}
@@ -1,5 +1,16 @@
-use winapi::shared::minwindef::{ATOM, HINSTANCE};
-use winapi::um::winnt::LPWSTR;
+use winapi::ctypes::c_int;
+use winapi::shared::basetsd::{LONG_PTR, ULONG_PTR};
+use winapi::shared::minwindef::{ATOM, BOOL, FALSE, HINSTANCE, LPARAM, LRESULT, TRUE, UINT, WPARAM};
+use winapi::shared::windef::HWND;
+use winapi::shared::winerror::{FAILED, HRESULT};
+use winapi::um::handleapi::CloseHandle;
+use winapi::um::synchapi::{CreateEventW, SetEvent};
+use winapi::um::winnt::{HANDLE, LPWSTR};
+use winapi::um::winuser::{DefWindowProcA, DefWindowProcW, IsWindowUnicode, RegisterWindowMessageW};
+#[cfg(target_pointer_width = "64")]
+use winapi::um::winuser::{GetClassLongPtrW, GetWindowLongPtrW, SetClassLongPtrW, SetWindowLongPtrW};
+#[cfg(target_pointer_width = "32")]
+use winapi::um::winuser::{GetClassLongW, GetWindowLongW, SetClassLongW, SetWindowLongW};
 
 extern "C" {
   pub static __ImageBase: u8;
@@ -17,3 +28,142 @@ pub fn atom_to_lpwstr(atom: ATOM) -> LPWSTR {
 pub fn to_utf16(s: &str) -> Vec<u16> {
   s.encode_utf16().chain(Some(0).into_iter()).collect()
 }
+
+/// Get the id of the window message registered as `name`, registering it if needed.
+pub fn register_message(name: &str) -> UINT {
+  let msg = unsafe { RegisterWindowMessageW(to_utf16(name).as_ptr()) };
+  assert_ne!(0, msg, "failed to register window message {}", name);
+  msg
+}
+
+/// Call the default window procedure that matches whether `hwnd` is a Unicode window.
+pub unsafe fn def_window_proc(hwnd: HWND, msg: UINT, w: WPARAM, l: LPARAM) -> LRESULT {
+  if IsWindowUnicode(hwnd) != FALSE {
+    DefWindowProcW(hwnd, msg, w, l)
+  } else {
+    DefWindowProcA(hwnd, msg, w, l)
+  }
+}
+
+// On 32-bit Windows, the `*LongPtr` functions are macros over the `*Long` ones, and winapi exports
+// them with those signatures, which take and return a `LONG` (or `DWORD`) instead of something
+// pointer-sized. These wrappers take care of the conversions, so that callers can store pointers
+// without caring about the target.
+
+/// Read a pointer-sized value from a window's extra bytes.
+#[cfg(target_pointer_width = "64")]
+pub unsafe fn get_window_long_ptr(hwnd: HWND, index: c_int) -> LONG_PTR {
+  GetWindowLongPtrW(hwnd, index)
+}
+
+/// Read a pointer-sized value from a window's extra bytes.
+#[cfg(target_pointer_width = "32")]
+pub unsafe fn get_window_long_ptr(hwnd: HWND, index: c_int) -> LONG_PTR {
+  GetWindowLongW(hwnd, index) as LONG_PTR
+}
+
+/// Replace a pointer-sized value in a window's extra bytes, returning the previous one.
+#[cfg(target_pointer_width = "64")]
+pub unsafe fn set_window_long_ptr(hwnd: HWND, index: c_int, value: LONG_PTR) -> LONG_PTR {
+  SetWindowLongPtrW(hwnd, index, value)
+}
+
+/// Replace a pointer-sized value in a window's extra bytes, returning the previous one.
+#[cfg(target_pointer_width = "32")]
+pub unsafe fn set_window_long_ptr(hwnd: HWND, index: c_int, value: LONG_PTR) -> LONG_PTR {
+  SetWindowLongW(hwnd, index, value as i32) as LONG_PTR
+}
+
+/// Read a pointer-sized value from a window class's extra bytes, or one of its `GCL_*` fields.
+#[cfg(target_pointer_width = "64")]
+pub unsafe fn get_class_long_ptr(hwnd: HWND, index: c_int) -> ULONG_PTR {
+  GetClassLongPtrW(hwnd, index)
+}
+
+/// Read a pointer-sized value from a window class's extra bytes, or one of its `GCL_*` fields.
+#[cfg(target_pointer_width = "32")]
+pub unsafe fn get_class_long_ptr(hwnd: HWND, index: c_int) -> ULONG_PTR {
+  GetClassLongW(hwnd, index) as ULONG_PTR
+}
+
+/// Replace a pointer-sized value in a window class's extra bytes, returning the previous one.
+#[cfg(target_pointer_width = "64")]
+pub unsafe fn set_class_long_ptr(hwnd: HWND, index: c_int, value: LONG_PTR) -> ULONG_PTR {
+  SetClassLongPtrW(hwnd, index, value)
+}
+
+/// Replace a pointer-sized value in a window class's extra bytes, returning the previous one.
+#[cfg(target_pointer_width = "32")]
+pub unsafe fn set_class_long_ptr(hwnd: HWND, index: c_int, value: LONG_PTR) -> ULONG_PTR {
+  SetClassLongW(hwnd, index, value as i32) as ULONG_PTR
+}
+
+pub fn check_hresult(hr: HRESULT) -> std::io::Result<()> {
+  if FAILED(hr) {
+    return Err(std::io::Error::from_raw_os_error(hr));
+  }
+  Ok(())
+}
+
+/// An owned event, which resets automatically unless it's created with
+/// [`Event::new_manual_reset`].
+pub struct Event(HANDLE);
+unsafe impl Send for Event {}
+unsafe impl Sync for Event {}
+
+impl Event {
+  pub fn new() -> std::io::Result<Event> {
+    Event::create(FALSE)
+  }
+
+  /// Create an event that stays signalled until it's reset.
+  pub fn new_manual_reset() -> std::io::Result<Event> {
+    Event::create(TRUE)
+  }
+
+  fn create(manual_reset: BOOL) -> std::io::Result<Event> {
+    let handle = unsafe { CreateEventW(std::ptr::null_mut(), manual_reset, FALSE, std::ptr::null()) };
+    if handle.is_null() {
+      return Err(std::io::Error::last_os_error());
+    }
+    Ok(Event(handle))
+  }
+
+  pub fn handle(&self) -> HANDLE {
+    self.0
+  }
+
+  pub fn set(&self) -> std::io::Result<()> {
+    if unsafe { SetEvent(self.0) } == FALSE {
+      return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+  }
+}
+
+impl Drop for Event {
+  fn drop(&mut self) {
+    unsafe { CloseHandle(self.0) };
+  }
+}
+
+/// An owned kernel object handle.
+pub struct OwnedHandle(pub HANDLE);
+unsafe impl Send for OwnedHandle {}
+unsafe impl Sync for OwnedHandle {}
+
+impl OwnedHandle {
+  /// Take ownership of `handle`, or fail with the last error if it's null.
+  pub fn check(handle: HANDLE) -> std::io::Result<OwnedHandle> {
+    if handle.is_null() {
+      return Err(std::io::Error::last_os_error());
+    }
+    Ok(OwnedHandle(handle))
+  }
+}
+
+impl Drop for OwnedHandle {
+  fn drop(&mut self) {
+    unsafe { CloseHandle(self.0) };
+  }
+}
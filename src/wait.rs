@@ -0,0 +1,99 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use winapi::shared::minwindef::{LPARAM, WPARAM};
+
+use {Error, Result};
+
+struct WaitState {
+  result: Option<Result<(WPARAM, LPARAM)>>,
+  waker: Option<Waker>,
+}
+
+struct WaitShared {
+  state: Mutex<WaitState>,
+  cond: Condvar,
+}
+
+impl WaitShared {
+  fn complete(&self, result: Result<(WPARAM, LPARAM)>) {
+    let mut state = self.state.lock().unwrap();
+    if state.result.is_none() {
+      state.result = Some(result);
+      if let Some(waker) = state.waker.take() {
+        waker.wake();
+      }
+      self.cond.notify_all();
+    }
+  }
+}
+
+/// The loop thread's end of a [`MessageWaiter`], which fails the wait if it's dropped without the
+/// message showing up (e.g. because the loop was torn down).
+pub(crate) struct WaitCompletion(Arc<WaitShared>);
+
+impl WaitCompletion {
+  pub(crate) fn complete(self, w: WPARAM, l: LPARAM) {
+    self.0.complete(Ok((w, l)));
+  }
+}
+
+impl Drop for WaitCompletion {
+  fn drop(&mut self) {
+    self.0.complete(Err(Error::Terminated));
+  }
+}
+
+/// Waits for a window message, as registered by
+/// [`LoopHandle::expect_message`](::LoopHandle::expect_message).
+///
+/// Either block on it with [`MessageWaiter::wait`], or `.await` it.
+pub struct MessageWaiter(Arc<WaitShared>);
+
+impl MessageWaiter {
+  pub(crate) fn new() -> (MessageWaiter, WaitCompletion) {
+    let shared = Arc::new(WaitShared {
+      state: Mutex::new(WaitState {
+        result: None,
+        waker: None,
+      }),
+      cond: Condvar::new(),
+    });
+    (MessageWaiter(shared.clone()), WaitCompletion(shared))
+  }
+
+  /// Block until the message arrives, returning its WPARAM and LPARAM, or fail with
+  /// [`Error::TimedOut`] if it doesn't arrive within `timeout`.
+  pub fn wait(self, timeout: Duration) -> Result<(WPARAM, LPARAM)> {
+    let deadline = Instant::now() + timeout;
+    let mut state = self.0.state.lock().unwrap();
+    loop {
+      if let Some(result) = state.result.take() {
+        return result;
+      }
+      let now = Instant::now();
+      if now >= deadline {
+        return Err(Error::TimedOut);
+      }
+      state = self.0.cond.wait_timeout(state, deadline - now).unwrap().0;
+    }
+  }
+}
+
+impl Future for MessageWaiter {
+  type Output = Result<(WPARAM, LPARAM)>;
+
+  fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+    let mut state = self.0.state.lock().unwrap();
+    match state.result.take() {
+      Some(result) => Poll::Ready(result),
+      None => {
+        state.waker = Some(cx.waker().clone());
+        Poll::Pending
+      }
+    }
+  }
+}
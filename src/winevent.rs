@@ -0,0 +1,100 @@
+//! Accessibility events from other windows, via `SetWinEventHook`.
+//!
+//! Hooks installed with [`LoopHandle::hook_winevents`](::LoopHandle::hook_winevents) are
+//! out-of-context, so the system queues each event to the handler thread and the loop's message
+//! pump delivers it to
+//! [`HwndLoopCallbacks::handle_win_event`](::HwndLoopCallbacks::handle_win_event). Hooks are
+//! removed when the loop terminates.
+
+use std::cell::RefCell;
+
+use winapi::shared::minwindef::DWORD;
+use winapi::shared::windef::{HWINEVENTHOOK, HWND};
+use winapi::um::winnt::LONG;
+use winapi::um::winuser::*;
+
+use Result;
+
+/// An event reported by a WinEvent hook.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WinEvent {
+  /// This window became the foreground window.
+  ForegroundChanged(HWND),
+
+  /// Keyboard focus moved to this window.
+  FocusChanged(HWND),
+
+  /// This window was created.
+  WindowCreated(HWND),
+
+  /// This window was destroyed.
+  WindowDestroyed(HWND),
+
+  /// This window is being minimized.
+  Minimized(HWND),
+
+  /// This window is being restored from being minimized.
+  Restored(HWND),
+
+  /// Anything else, with the arguments that the hook was called with.
+  Other {
+    event: DWORD,
+    hwnd: HWND,
+    object: LONG,
+    child: LONG,
+  },
+}
+
+impl WinEvent {
+  pub(crate) fn from_raw(event: DWORD, hwnd: HWND, object: LONG, child: LONG) -> WinEvent {
+    // Object events are raised for all sorts of things inside of windows too.
+    let is_window = object == OBJID_WINDOW && child == CHILDID_SELF;
+    match event {
+      EVENT_SYSTEM_FOREGROUND => WinEvent::ForegroundChanged(hwnd),
+      EVENT_OBJECT_FOCUS => WinEvent::FocusChanged(hwnd),
+      EVENT_OBJECT_CREATE if is_window => WinEvent::WindowCreated(hwnd),
+      EVENT_OBJECT_DESTROY if is_window => WinEvent::WindowDestroyed(hwnd),
+      EVENT_SYSTEM_MINIMIZESTART => WinEvent::Minimized(hwnd),
+      EVENT_SYSTEM_MINIMIZEEND => WinEvent::Restored(hwnd),
+      _ => WinEvent::Other {
+        event,
+        hwnd,
+        object,
+        child,
+      },
+    }
+  }
+}
+
+thread_local! {
+  /// The window of the loop that installed each hook on this thread, since the hook procedure
+  /// isn't given any context of its own.
+  static HOOKS: RefCell<Vec<(HWINEVENTHOOK, HWND)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// A WinEvent hook installed on the current thread, which is removed when dropped.
+pub(crate) struct WinEventHook(HWINEVENTHOOK);
+
+impl WinEventHook {
+  /// Hook the events from `min` to `max` for the loop that owns `hwnd`.
+  pub(crate) fn install(hwnd: HWND, min: DWORD, max: DWORD, proc: WINEVENTPROC) -> Result<WinEventHook> {
+    let hook = unsafe { SetWinEventHook(min, max, std::ptr::null_mut(), proc, 0, 0, WINEVENT_OUTOFCONTEXT) };
+    if hook.is_null() {
+      return Err(std::io::Error::last_os_error().into());
+    }
+    HOOKS.with(|hooks| hooks.borrow_mut().push((hook, hwnd)));
+    Ok(WinEventHook(hook))
+  }
+}
+
+impl Drop for WinEventHook {
+  fn drop(&mut self) {
+    HOOKS.with(|hooks| hooks.borrow_mut().retain(|&(hook, _)| hook != self.0));
+    unsafe { UnhookWinEvent(self.0) };
+  }
+}
+
+/// The window of the loop that installed `hook`, if it's still installed.
+pub(crate) fn loop_window(hook: HWINEVENTHOOK) -> Option<HWND> {
+  HOOKS.with(|hooks| hooks.borrow().iter().find(|&&(h, _)| h == hook).map(|&(_, hwnd)| hwnd))
+}
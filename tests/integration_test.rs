@@ -3,48 +3,117 @@
 extern crate hwndloop;
 extern crate winapi;
 
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+#[cfg(feature = "windows-sys")]
+extern crate windows_sys;
+
 #[cfg(test)]
 mod test {
   use hwndloop::*;
 
   use std::collections::VecDeque;
-  use std::sync::mpsc::{channel, Sender};
+  use std::sync::atomic::{AtomicBool, Ordering};
+  use std::sync::{Arc, Mutex};
+  use std::sync::mpsc::{channel, Receiver, Sender};
 
-  use winapi::shared::minwindef::{FALSE, LPARAM, LRESULT, UINT, WPARAM};
+  use winapi::shared::minwindef::{FALSE, LPARAM, LRESULT, TRUE, UINT, WORD, WPARAM};
   use winapi::shared::windef::HWND;
-  use winapi::um::winuser::{DefWindowProcW, PostMessageA, WM_USER};
+  use winapi::shared::winerror::S_FALSE;
+  use winapi::um::combaseapi::{CoInitializeEx, CoUninitialize};
+  use winapi::um::handleapi::CloseHandle;
+  use winapi::um::objbase::COINIT_APARTMENTTHREADED;
+  use winapi::um::processthreadsapi::{GetCurrentProcessId, GetCurrentProcessorNumber, GetProcessShutdownParameters};
+  use winapi::um::synchapi::CreateEventW;
+  use winapi::um::winuser::{
+    CreateWindowExW, DefWindowProcW, DestroyWindow, DispatchMessageW, GetClassLongPtrW, GetMessageW, GetWindowTextW,
+    IsWindow, IsWindowUnicode, PeekMessageW, PostMessageA, RegisterWindowMessageA, SendMessageA, SendMessageW,
+    SendNotifyMessageA, SetWindowLongPtrW, SetWindowTextW, EVENT_OBJECT_CREATE, GCL_CBWNDEXTRA, HWND_MESSAGE, MSG,
+    PM_REMOVE, SC_MONITORPOWER, SC_SCREENSAVE, WM_APP, WM_CHAR, WM_COMMAND, WM_CREATE, WM_GETTEXTLENGTH, WM_KEYDOWN,
+    WM_NCCREATE, WM_NULL, WM_SETTINGCHANGE, WM_SYSCOMMAND, WM_USER,
+  };
 
   #[derive(Debug)]
   enum TestCommand {
     Push(i32),
     Pop(Sender<Option<i32>>),
     GetHWND(Sender<HwndWrapper>),
+    Block(Receiver<()>),
+    Mark(Arc<AtomicBool>),
+    Nested(Arc<AtomicBool>),
+    ForeignPump,
+    GetSlot(usize, Sender<Option<i32>>),
+    Emit(i32),
   }
 
   struct Test {
     queue: VecDeque<i32>,
+    events: Option<EventEmitter<i32>>,
   }
 
   impl Test {
     fn new() -> Test {
-      Test { queue: VecDeque::new() }
+      Test {
+        queue: VecDeque::new(),
+        events: None,
+      }
     }
   }
 
   impl HwndLoopCallbacks<TestCommand> for Test {
+    fn set_up(&mut self, _hwnd: HWND, context: &SetUpContext<TestCommand>) {
+      if let Some(&i) = context.user_data::<i32>() {
+        context.handle().send_command(TestCommand::Push(i)).unwrap();
+      }
+      self.events = context.event_emitter();
+    }
+
     fn handle_message(&mut self, hwnd: HWND, msg: UINT, w: WPARAM, l: LPARAM) -> LRESULT {
-      if msg == WM_USER {
+      if msg == WM_USER || msg == WM_CHAR {
         self.queue.push_back(w as i32);
+      } else if msg == WM_APP {
+        self.queue.push_back(hwndloop::dispatch::in_send_message() as i32);
+      } else if msg == WM_APP + 1 {
+        self.queue.push_back(hwndloop::dispatch::reply_now(w as LRESULT) as i32);
+        self.queue.push_back(hwndloop::dispatch::has_replied() as i32);
+        self.queue.push_back(hwndloop::dispatch::reply_now(0) as i32);
+        return 0;
       }
 
       unsafe { DefWindowProcW(hwnd, msg, w, l) }
     }
 
+    fn handle_accelerator(&mut self, _hwnd: HWND, id: WORD) {
+      self.queue.push_back(i32::from(id));
+    }
+
+    fn handle_dropped_command(&mut self, _hwnd: HWND, cmd: TestCommand, _reason: DropReason) {
+      if let TestCommand::Push(i) = cmd {
+        self.queue.push_back(-i);
+      }
+    }
+
     fn handle_command(&mut self, hwnd: HWND, cmd: TestCommand) {
       match cmd {
         TestCommand::Push(i) => self.queue.push_back(i),
         TestCommand::Pop(tx) => tx.send(self.queue.pop_front()).unwrap(),
-        TestCommand::GetHWND(tx) => tx.send(HwndWrapper(hwnd)).unwrap(),
+        TestCommand::GetHWND(tx) => tx.send(HwndWrapper::new(hwnd)).unwrap(),
+        TestCommand::Block(rx) => rx.recv().unwrap(),
+        TestCommand::Mark(flag) => flag.store(true, Ordering::SeqCst),
+        TestCommand::Nested(flag) => {
+          hwndloop::run_nested(|| flag.load(Ordering::SeqCst)).unwrap();
+          self.queue.push_back(-1);
+        }
+        TestCommand::ForeignPump => {
+          // Like a modal dialog would, while the callback is running.
+          let mut msg: MSG = unsafe { std::mem::zeroed() };
+          while unsafe { PeekMessageW(&mut msg, std::ptr::null_mut(), 0, 0, PM_REMOVE) } != FALSE {
+            unsafe { DispatchMessageW(&msg) };
+          }
+        }
+        TestCommand::Emit(i) => self.events.as_ref().unwrap().emit(i).unwrap(),
+        TestCommand::GetSlot(key, tx) => tx.send(hwndloop::slots::get_slot(hwnd, key).unwrap_or(None)).unwrap(),
       }
     }
   }
@@ -52,40 +121,189 @@ mod test {
   #[test]
   fn smoke() {
     let hwndloop = hwndloop::HwndLoop::new(Box::new(Test::new()));
-    hwndloop.send_command(TestCommand::Push(1));
+    hwndloop.send_command(TestCommand::Push(1)).unwrap();
     let (tx, rx) = channel();
-    hwndloop.send_command(TestCommand::Pop(tx));
+    hwndloop.send_command(TestCommand::Pop(tx)).unwrap();
     assert_eq!(Some(1), rx.recv().unwrap());
   }
 
+  #[test]
+  fn command_seq() {
+    let hwndloop = hwndloop::HwndLoop::new(Box::new(Test::new()));
+    assert_eq!(None, hwndloop.last_processed_seq());
+    let first = hwndloop.send_command(TestCommand::Push(1)).unwrap();
+    let second = hwndloop.send_command(TestCommand::Push(2)).unwrap();
+    assert!(first < second);
+    hwndloop.flush().unwrap();
+    assert!(hwndloop.last_processed_seq() >= Some(second));
+
+    // Commands held back by a pause don't count until they're handled.
+    hwndloop.pause().unwrap();
+    let third = hwndloop.send_command(TestCommand::Push(3)).unwrap();
+    hwndloop.flush().unwrap();
+    assert!(hwndloop.last_processed_seq() < Some(third));
+    hwndloop.resume().unwrap();
+    assert!(hwndloop.last_processed_seq() >= Some(third));
+  }
+
+  #[test]
+  fn flush_until() {
+    let hwndloop = hwndloop::HwndLoop::new(Box::new(Test::new()));
+    let (block_tx, block_rx) = channel();
+    hwndloop.send_command(TestCommand::Block(block_rx)).unwrap();
+    let seq = hwndloop.send_command(TestCommand::Push(1)).unwrap();
+
+    let short = std::time::Duration::from_millis(50);
+    assert!(matches!(hwndloop.flush_until(seq, short), Err(Error::TimedOut)));
+    block_tx.send(()).unwrap();
+    hwndloop.flush_until(seq, std::time::Duration::from_secs(10)).unwrap();
+  }
+
+  struct FallibleTest;
+
+  impl HwndLoopCallbacks<TestCommand> for FallibleTest {
+    fn try_handle_command(
+      &mut self,
+      _hwnd: HWND,
+      cmd: TestCommand,
+    ) -> std::result::Result<(), Box<dyn std::error::Error + Send + Sync>> {
+      match cmd {
+        TestCommand::Push(i) if i < 0 => Err(format!("negative: {}", i).into()),
+        _ => Ok(()),
+      }
+    }
+  }
+
+  #[test]
+  fn call() {
+    let hwndloop = hwndloop::HwndLoop::new(Box::new(FallibleTest));
+    hwndloop.call(TestCommand::Push(1)).unwrap();
+    match hwndloop.call(TestCommand::Push(-1)) {
+      Err(Error::Command(err)) => assert_eq!("negative: -1", err.to_string()),
+      result => panic!("unexpected result: {:?}", result),
+    }
+
+    // Without a caller to report to, failures are only logged.
+    hwndloop.send_command(TestCommand::Push(-2)).unwrap();
+    hwndloop.call(TestCommand::Push(2)).unwrap();
+  }
+
+  #[test]
+  fn call_transaction() {
+    let hwndloop = hwndloop::HwndLoop::new(Box::new(Test::new()));
+    let senders: Vec<_> = (0..4)
+      .map(|thread| {
+        let handle = hwndloop.handle();
+        std::thread::spawn(move || {
+          for i in 0..10 {
+            let base = 1000 + (thread * 100 + i) * 10;
+            let cmds = (0..3).map(|j| TestCommand::Push(base + j));
+            handle.call_transaction(cmds).unwrap();
+          }
+        })
+      })
+      .collect();
+    for i in 0..100 {
+      hwndloop.send_command(TestCommand::Push(i)).unwrap();
+    }
+    for sender in senders {
+      sender.join().unwrap();
+    }
+
+    let mut seen = Vec::new();
+    loop {
+      let (tx, rx) = channel();
+      hwndloop.send_command(TestCommand::Pop(tx)).unwrap();
+      match rx.recv().unwrap() {
+        Some(i) => seen.push(i),
+        None => break,
+      }
+    }
+    assert_eq!(100 + 4 * 10 * 3, seen.len());
+    for (index, &i) in seen.iter().enumerate() {
+      if i >= 1000 && i % 10 == 0 {
+        assert_eq!(&[i + 1, i + 2], &seen[index + 1..index + 3]);
+      }
+    }
+
+    // Failures are reported after the rest of the group is handled.
+    let hwndloop = hwndloop::HwndLoop::new(Box::new(FallibleTest));
+    let cmds = vec![TestCommand::Push(1), TestCommand::Push(-1), TestCommand::Push(-2)];
+    match hwndloop.call_transaction(cmds) {
+      Err(Error::Command(err)) => assert_eq!("negative: -1", err.to_string()),
+      result => panic!("unexpected result: {:?}", result),
+    }
+    hwndloop.terminate().unwrap();
+    assert!(matches!(hwndloop.call_transaction(vec![TestCommand::Push(2)]), Err(Error::Terminated)));
+  }
+
+  #[test]
+  fn internal_messages() {
+    let hwndloop = hwndloop::HwndLoop::new(Box::new(Test::new()));
+
+    // Our pokes get dispatched by someone else's message pump.
+    let (block_tx, block_rx) = channel();
+    hwndloop.send_command(TestCommand::Block(block_rx)).unwrap();
+    hwndloop.send_command(TestCommand::ForeignPump).unwrap();
+    hwndloop.send_command(TestCommand::Push(1)).unwrap();
+    block_tx.send(()).unwrap();
+    let (tx, rx) = channel();
+    hwndloop.send_command(TestCommand::Pop(tx)).unwrap();
+    assert_eq!(Some(1), rx.recv_timeout(std::time::Duration::from_secs(10)).unwrap());
+
+    // Lookalikes are ignored.
+    let flush = unsafe { RegisterWindowMessageA(b"WM_HWNDLOOP_FLUSH\0".as_ptr() as *const i8) };
+    assert_ne!(FALSE, unsafe { PostMessageA(hwndloop.hwnd().0, flush, 12345, 0) });
+    assert_eq!(0, unsafe { SendMessageA(hwndloop.hwnd().0, flush, 12345, 0) });
+    hwndloop.flush().unwrap();
+  }
+
   #[test]
   fn winmsg() {
     let hwndloop = hwndloop::HwndLoop::new(Box::new(Test::new()));
     let (tx, rx) = channel();
-    hwndloop.send_command(TestCommand::GetHWND(tx));
+    hwndloop.send_command(TestCommand::GetHWND(tx)).unwrap();
 
     let hwnd = rx.recv().unwrap();
     assert_ne!(FALSE, unsafe { PostMessageA(hwnd.0, WM_USER, 123 as WPARAM, 0) });
 
-    hwndloop.flush();
+    hwndloop.flush_all().unwrap();
 
     let (tx, rx) = channel();
-    hwndloop.send_command(TestCommand::Pop(tx));
+    hwndloop.send_command(TestCommand::Pop(tx)).unwrap();
     assert_eq!(Some(123), rx.recv().unwrap());
   }
 
+  #[test]
+  fn concurrent_flush_all() {
+    let hwndloop = hwndloop::HwndLoop::new(Box::new(Test::new()));
+    let threads: Vec<_> = (0..2)
+      .map(|_| {
+        let handle = hwndloop.handle();
+        std::thread::spawn(move || {
+          for _ in 0..100 {
+            handle.flush_all().unwrap();
+          }
+        })
+      })
+      .collect();
+    for thread in threads {
+      thread.join().unwrap();
+    }
+  }
+
   #[test]
   fn ordering() {
     let hwndloop = hwndloop::HwndLoop::new(Box::new(Test::new()));
     let (tx, rx) = channel();
-    hwndloop.send_command(TestCommand::GetHWND(tx));
+    hwndloop.send_command(TestCommand::GetHWND(tx)).unwrap();
 
     let hwnd = rx.recv().unwrap();
 
     let (begin, end) = (0, 10000);
     for i in begin..end {
       if i % 2 == 0 {
-        hwndloop.send_command(TestCommand::Push(i));
+        hwndloop.send_command(TestCommand::Push(i)).unwrap();
       } else {
         assert_ne!(FALSE, unsafe { PostMessageA(hwnd.0, WM_USER, i as WPARAM, 0) });
       }
@@ -93,8 +311,1878 @@ mod test {
 
     for i in begin..end {
       let (tx, rx) = channel();
-      hwndloop.send_command(TestCommand::Pop(tx));
+      hwndloop.send_command(TestCommand::Pop(tx)).unwrap();
+      assert_eq!(Some(i), rx.recv().unwrap());
+    }
+  }
+
+  #[test]
+  fn saturation() {
+    let hwndloop = hwndloop::HwndLoop::new(Box::new(Test::new()));
+    let (block_tx, block_rx) = channel();
+    hwndloop.send_command(TestCommand::Block(block_rx)).unwrap();
+
+    // Overflow the window's message queue while the loop is stuck.
+    let count = 12000;
+    for i in 0..count {
+      hwndloop.send_command(TestCommand::Push(i)).unwrap();
+    }
+
+    block_tx.send(()).unwrap();
+    hwndloop.flush().unwrap();
+
+    for i in 0..count {
+      let (tx, rx) = channel();
+      hwndloop.send_command(TestCommand::Pop(tx)).unwrap();
+      assert_eq!(Some(i), rx.recv().unwrap());
+    }
+  }
+
+  #[test]
+  fn concurrent_flush() {
+    let hwndloop = Arc::new(hwndloop::HwndLoop::new(Box::new(Test::new())));
+    let threads: Vec<_> = (0..32)
+      .map(|_| {
+        let hwndloop = hwndloop.clone();
+        std::thread::spawn(move || {
+          for _ in 0..100 {
+            let flag = Arc::new(AtomicBool::new(false));
+            hwndloop.send_command(TestCommand::Mark(flag.clone())).unwrap();
+            hwndloop.flush().unwrap();
+            assert!(flag.load(Ordering::SeqCst));
+          }
+        })
+      })
+      .collect();
+
+    for thread in threads {
+      thread.join().unwrap();
+    }
+  }
+
+  #[test]
+  fn pause() {
+    let hwndloop = hwndloop::HwndLoop::new(Box::new(Test::new()));
+    let (tx, rx) = channel();
+    hwndloop.send_command(TestCommand::GetHWND(tx)).unwrap();
+    let hwnd = rx.recv().unwrap();
+
+    hwndloop.pause().unwrap();
+
+    let flag = Arc::new(AtomicBool::new(false));
+    hwndloop.send_command(TestCommand::Mark(flag.clone())).unwrap();
+    hwndloop.send_command(TestCommand::Push(1)).unwrap();
+    assert_ne!(FALSE, unsafe { PostMessageA(hwnd.0, WM_USER, 2 as WPARAM, 0) });
+    hwndloop.flush_all().unwrap();
+    assert!(!flag.load(Ordering::SeqCst));
+
+    hwndloop.resume().unwrap();
+    hwndloop.flush().unwrap();
+    assert!(flag.load(Ordering::SeqCst));
+
+    for i in 1..3 {
+      let (tx, rx) = channel();
+      hwndloop.send_command(TestCommand::Pop(tx)).unwrap();
+      assert_eq!(Some(i), rx.recv().unwrap());
+    }
+  }
+
+  #[test]
+  fn nested() {
+    let hwndloop = hwndloop::HwndLoop::new(Box::new(Test::new()));
+    let flag = Arc::new(AtomicBool::new(false));
+    hwndloop.send_command(TestCommand::Nested(flag.clone())).unwrap();
+    hwndloop.send_command(TestCommand::Push(1)).unwrap();
+    hwndloop.send_command(TestCommand::Mark(flag)).unwrap();
+
+    for &i in &[1, -1] {
+      let (tx, rx) = channel();
+      hwndloop.send_command(TestCommand::Pop(tx)).unwrap();
+      assert_eq!(Some(i), rx.recv().unwrap());
+    }
+
+    assert!(hwndloop::run_nested(|| true).is_err());
+  }
+
+  #[test]
+  fn translate_messages() {
+    let hwndloop = hwndloop::HwndLoopBuilder::new()
+      .translate_messages(true)
+      .build(Box::new(Test::new()));
+    let (tx, rx) = channel();
+    hwndloop.send_command(TestCommand::GetHWND(tx)).unwrap();
+
+    let hwnd = rx.recv().unwrap();
+    assert_ne!(FALSE, unsafe { PostMessageA(hwnd.0, WM_KEYDOWN, 'A' as WPARAM, 1) });
+
+    hwndloop.flush_all().unwrap();
+
+    let (tx, rx) = channel();
+    hwndloop.send_command(TestCommand::Pop(tx)).unwrap();
+    assert_eq!(Some('a' as i32), rx.recv().unwrap());
+  }
+
+  #[test]
+  fn accelerators() {
+    let hwndloop = hwndloop::HwndLoop::new(Box::new(Test::new()));
+
+    // Without a table, WM_COMMAND that looks like an accelerator is an ordinary message.
+    unsafe { SendMessageA(hwndloop.hwnd().0, WM_COMMAND, 0x0001_0007, 0) };
+    let (tx, rx) = channel();
+    hwndloop.send_command(TestCommand::Pop(tx)).unwrap();
+    assert_eq!(None, rx.recv().unwrap());
+
+    let table = AcceleratorTable::new(&[Accelerator::new('A' as WORD, 0, 42)]).unwrap();
+    hwndloop.set_accelerators(Some(table)).unwrap();
+
+    let (tx, rx) = channel();
+    hwndloop.send_command(TestCommand::GetHWND(tx)).unwrap();
+    let hwnd = rx.recv().unwrap();
+    assert_ne!(FALSE, unsafe { PostMessageA(hwnd.0, WM_KEYDOWN, 'A' as WPARAM, 1) });
+    hwndloop.flush_all().unwrap();
+
+    let (tx, rx) = channel();
+    hwndloop.send_command(TestCommand::Pop(tx)).unwrap();
+    assert_eq!(Some(42), rx.recv().unwrap());
+  }
+
+  #[test]
+  fn dialogs() {
+    use std::sync::atomic::AtomicUsize;
+    use winapi::um::winuser::{DefDlgProcW, RegisterClassW, VK_TAB, WNDCLASSW, WS_POPUP};
+
+    // At least DLGWINDOWEXTRA, which winapi doesn't have, on either architecture.
+    const DIALOG_EXTRA: i32 = 64;
+
+    static KEYDOWNS: AtomicUsize = AtomicUsize::new(0);
+
+    unsafe extern "system" fn dialog_proc(hwnd: HWND, msg: UINT, w: WPARAM, l: LPARAM) -> LRESULT {
+      if msg == WM_KEYDOWN {
+        KEYDOWNS.fetch_add(1, Ordering::SeqCst);
+      }
+      DefDlgProcW(hwnd, msg, w, l)
+    }
+
+    let class_name: Vec<u16> = "hwndloop-test-dialog".encode_utf16().chain(Some(0)).collect();
+    let class = WNDCLASSW {
+      style: 0,
+      lpfnWndProc: Some(dialog_proc),
+      cbClsExtra: 0,
+      cbWndExtra: DIALOG_EXTRA,
+      hInstance: std::ptr::null_mut(),
+      hIcon: std::ptr::null_mut(),
+      hCursor: std::ptr::null_mut(),
+      hbrBackground: std::ptr::null_mut(),
+      lpszMenuName: std::ptr::null(),
+      lpszClassName: class_name.as_ptr(),
+    };
+    assert_ne!(0, unsafe { RegisterClassW(&class) });
+
+    // Dialogs have to be created on the handler thread.
+    let hwndloop = HwndLoop::new(Box::new(Test::new()));
+    let create_dialog = || {
+      let (tx, rx) = channel();
+      let class_name = class_name.clone();
+      hwndloop
+        .once(WM_APP + 3, move |_, _, _, _| {
+          let dialog = unsafe {
+            CreateWindowExW(
+              0,
+              class_name.as_ptr(),
+              std::ptr::null(),
+              WS_POPUP,
+              0,
+              0,
+              100,
+              100,
+              std::ptr::null_mut(),
+              std::ptr::null_mut(),
+              std::ptr::null_mut(),
+              std::ptr::null_mut(),
+            )
+          };
+          tx.send(HwndWrapper::new(dialog)).unwrap();
+        })
+        .unwrap();
+      hwndloop.flush().unwrap();
+      unsafe { SendMessageA(hwndloop.hwnd().0, WM_APP + 3, 0, 0) };
+      let dialog = rx.recv().unwrap();
+      assert!(!dialog.0.is_null());
+      dialog
+    };
+    let tab = |dialog: &HwndWrapper| {
+      assert_ne!(FALSE, unsafe { PostMessageA(dialog.0, WM_KEYDOWN, VK_TAB as WPARAM, 0) });
+      hwndloop.flush_all().unwrap();
+      KEYDOWNS.load(Ordering::SeqCst)
+    };
+
+    // Tab moves the focus around a registered dialog, instead of reaching its window procedure.
+    let dialog = create_dialog();
+    hwndloop.register_dialog(dialog.clone()).unwrap();
+    assert_eq!(0, tab(&dialog));
+    hwndloop.unregister_dialog(dialog.clone()).unwrap();
+    assert_eq!(1, tab(&dialog));
+
+    // A dialog that's destroyed while it's registered is forgotten, and the next one still works.
+    hwndloop.register_dialog(dialog.clone()).unwrap();
+    let destroy = dialog.0 as usize;
+    hwndloop
+      .once(WM_APP + 5, move |_, _, _, _| unsafe { assert_ne!(FALSE, DestroyWindow(destroy as HWND)) })
+      .unwrap();
+    hwndloop.flush().unwrap();
+    unsafe { SendMessageA(hwndloop.hwnd().0, WM_APP + 5, 0, 0) };
+    assert!(!dialog.is_valid());
+
+    let dialog = create_dialog();
+    hwndloop.register_dialog(dialog.clone()).unwrap();
+    assert_eq!(1, tab(&dialog));
+  }
+
+  #[test]
+  fn pump() {
+    let mut pump = hwndloop::HwndPump::new(Box::new(Test::new())).unwrap();
+    assert_eq!(PumpStatus::Idle, pump.pump_once());
+
+    let handle = pump.handle();
+    std::thread::spawn(move || handle.send_command(TestCommand::Push(7)).unwrap())
+      .join()
+      .unwrap();
+
+    let (tx, rx) = channel();
+    pump.handle().send_command(TestCommand::Pop(tx)).unwrap();
+    assert!(rx.try_recv().is_err());
+
+    assert_eq!(PumpStatus::Idle, pump.pump_pending());
+    assert_eq!(Some(7), rx.try_recv().unwrap());
+  }
+
+  #[cfg(debug_assertions)]
+  #[test]
+  fn strict() {
+    let mut pump = HwndLoopBuilder::new().strict(true).build_pump(Box::new(Test::new())).unwrap();
+    let handle = pump.handle();
+    handle.send_command(TestCommand::Push(1)).unwrap();
+    assert_eq!(PumpStatus::Idle, pump.pump_pending());
+
+    // Flushing from the pump's own thread would never return.
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| handle.flush()));
+    assert!(result.is_err());
+
+    // Other threads are fine.
+    let thread = std::thread::spawn(move || handle.flush());
+    while !thread.is_finished() {
+      pump.pump_pending();
+      std::thread::sleep(std::time::Duration::from_millis(1));
+    }
+    thread.join().unwrap().unwrap();
+
+    // Overwritten window data is found in the window procedure, and panicked on by the pump.
+    let hwnd = pump.handle().hwnd().0;
+    let fake: [usize; 2] = [0, 1];
+    let original = unsafe { SetWindowLongPtrW(hwnd, 0, fake.as_ptr() as _) };
+    unsafe { PostMessageA(hwnd, WM_NULL, 0, 0) };
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| pump.pump_pending()));
+    assert!(result.is_err());
+    unsafe { SetWindowLongPtrW(hwnd, 0, original) };
+  }
+
+  #[test]
+  fn run_here() {
+    let mut client = None;
+    hwndloop::HwndLoop::run_here(Box::new(Test::new()), |handle| {
+      client = Some(std::thread::spawn(move || {
+        handle.send_command(TestCommand::Push(3)).unwrap();
+        let (tx, rx) = channel();
+        handle.send_command(TestCommand::Pop(tx)).unwrap();
+        let result = rx.recv().unwrap();
+        handle.terminate().unwrap();
+        result
+      }));
+    })
+    .unwrap();
+
+    assert_eq!(Some(3), client.unwrap().join().unwrap());
+  }
+
+  #[test]
+  fn external_loop() {
+    let adapter = hwndloop::ExternalLoopAdapter::new(Box::new(Test::new())).unwrap();
+    let handle = adapter.handle();
+    let client = std::thread::spawn(move || {
+      handle.send_command(TestCommand::Push(5)).unwrap();
+      let (tx, rx) = channel();
+      handle.send_command(TestCommand::Pop(tx)).unwrap();
+      handle.flush().unwrap();
+      handle.terminate().unwrap();
+      rx.recv().unwrap()
+    });
+
+    // Stand-in for a GUI framework's message loop.
+    let mut msg: MSG = unsafe { std::mem::zeroed() };
+    while !adapter.is_terminated() {
+      assert!(unsafe { GetMessageW(&mut msg, std::ptr::null_mut(), 0, 0) } > 0);
+      unsafe { DispatchMessageW(&msg) };
+    }
+
+    assert_eq!(Some(5), client.join().unwrap());
+    assert!(adapter.handle().flush().is_err());
+  }
+
+  #[test]
+  fn status() {
+    let hwndloop = hwndloop::HwndLoop::new(Box::new(Test::new()));
+    let (block_tx, block_rx) = channel();
+    hwndloop.send_command(TestCommand::Block(block_rx)).unwrap();
+    hwndloop.send_command(TestCommand::Push(1)).unwrap();
+    hwndloop.send_command(TestCommand::Push(2)).unwrap();
+
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    let status = hwndloop.status();
+    assert_eq!(2, status.pending_commands);
+    assert!(status.since_last_dispatch >= std::time::Duration::from_millis(50));
+
+    block_tx.send(()).unwrap();
+    hwndloop.flush().unwrap();
+    let status = hwndloop.status();
+    assert_eq!(0, status.pending_commands);
+    assert!(status.since_last_dispatch < std::time::Duration::from_millis(50));
+  }
+
+  #[test]
+  fn message_filter() {
+    let hwndloop = hwndloop::HwndLoopBuilder::new()
+      .message_filter(WM_USER, WM_USER)
+      .build(Box::new(Test::new()));
+    let (tx, rx) = channel();
+    hwndloop.send_command(TestCommand::GetHWND(tx)).unwrap();
+    let hwnd = rx.recv().unwrap();
+
+    assert_ne!(FALSE, unsafe { PostMessageA(hwnd.0, WM_CHAR, 1 as WPARAM, 0) });
+    assert_ne!(FALSE, unsafe { PostMessageA(hwnd.0, WM_USER, 2 as WPARAM, 0) });
+    hwndloop.flush_all().unwrap();
+
+    // The WM_CHAR is still stuck in the queue.
+    let (tx, rx) = channel();
+    hwndloop.send_command(TestCommand::Pop(tx)).unwrap();
+    assert_eq!(Some(2), rx.recv().unwrap());
+    let (tx, rx) = channel();
+    hwndloop.send_command(TestCommand::Pop(tx)).unwrap();
+    assert_eq!(None, rx.recv().unwrap());
+  }
+
+  #[test]
+  fn in_send_message() {
+    let hwndloop = hwndloop::HwndLoop::new(Box::new(Test::new()));
+    let (tx, rx) = channel();
+    hwndloop.send_command(TestCommand::GetHWND(tx)).unwrap();
+    let hwnd = rx.recv().unwrap();
+
+    unsafe { SendMessageA(hwnd.0, WM_APP, 0, 0) };
+    assert_ne!(FALSE, unsafe { PostMessageA(hwnd.0, WM_APP, 0, 0) });
+    hwndloop.flush_all().unwrap();
+
+    for &i in &[1, 0] {
+      let (tx, rx) = channel();
+      hwndloop.send_command(TestCommand::Pop(tx)).unwrap();
+      assert_eq!(Some(i), rx.recv().unwrap());
+    }
+    assert!(!hwndloop::dispatch::in_send_message());
+  }
+
+  struct OptionalTest;
+
+  impl HwndLoopCallbacks<TestCommand> for OptionalTest {
+    fn try_handle_message(&mut self, _hwnd: HWND, msg: UINT, _w: WPARAM, _l: LPARAM) -> Option<LRESULT> {
+      if msg == WM_USER {
+        Some(42)
+      } else {
+        None
+      }
+    }
+  }
+
+  #[test]
+  fn try_handle_message() {
+    let hwndloop = hwndloop::HwndLoop::new(Box::new(OptionalTest));
+    let hwnd = hwndloop.hwnd().0;
+    assert_eq!(42, unsafe { SendMessageA(hwnd, WM_USER, 0, 0) });
+    let len = unsafe { SendMessageA(hwnd, WM_GETTEXTLENGTH, 0, 0) };
+    assert_eq!("rawinput window".len(), len as usize);
+  }
+
+  #[test]
+  fn unicode_text() {
+    let hwndloop = hwndloop::HwndLoop::new(Box::new(OptionalTest));
+    let hwnd = hwndloop.hwnd().0;
+    assert_ne!(FALSE, unsafe { IsWindowUnicode(hwnd) });
+
+    // The default handling stores and returns the text as UTF-16, without a round trip through
+    // the ANSI code page.
+    let text = "h\u{e9}llo \u{2713} \u{1f980}";
+    let wide: Vec<u16> = text.encode_utf16().chain(Some(0)).collect();
+    assert_ne!(FALSE, unsafe { SetWindowTextW(hwnd, wide.as_ptr()) });
+    let mut buf = [0u16; 64];
+    let len = unsafe { GetWindowTextW(hwnd, buf.as_mut_ptr(), buf.len() as i32) };
+    assert_eq!(text, String::from_utf16(&buf[..len as usize]).unwrap());
+  }
+
+  #[test]
+  fn send_request() {
+    let hwndloop = hwndloop::HwndLoop::new(Box::new(Test::new()));
+    let (tx, rx) = channel();
+    hwndloop.send_command(TestCommand::GetHWND(tx)).unwrap();
+    let hwnd = rx.recv().unwrap();
+    let timeout = std::time::Duration::from_millis(50);
+
+    let len = hwndloop::dispatch::send_request(hwnd.clone(), WM_GETTEXTLENGTH, 0, 0, timeout).unwrap();
+    assert_eq!("rawinput window".len(), len as usize);
+
+    let (block_tx, block_rx) = channel();
+    hwndloop.send_command(TestCommand::Block(block_rx)).unwrap();
+    match hwndloop::dispatch::send_request(hwnd, WM_NULL, 0, 0, timeout) {
+      Err(Error::TimedOut) => {}
+      result => panic!("unexpected result: {:?}", result),
+    }
+    block_tx.send(()).unwrap();
+  }
+
+  #[test]
+  fn reply_now() {
+    let hwndloop = hwndloop::HwndLoop::new(Box::new(Test::new()));
+    let (tx, rx) = channel();
+    hwndloop.send_command(TestCommand::GetHWND(tx)).unwrap();
+    let hwnd = rx.recv().unwrap();
+
+    assert_eq!(7, unsafe { SendMessageA(hwnd.0, WM_APP + 1, 7, 0) });
+    for &i in &[1, 1, 0] {
+      let (tx, rx) = channel();
+      hwndloop.send_command(TestCommand::Pop(tx)).unwrap();
+      assert_eq!(Some(i), rx.recv().unwrap());
+    }
+  }
+
+  #[test]
+  fn urgent_command() {
+    let hwndloop = hwndloop::HwndLoop::new(Box::new(Test::new()));
+    let (block_tx, block_rx) = channel();
+    hwndloop.send_command(TestCommand::Block(block_rx)).unwrap();
+    hwndloop.send_command(TestCommand::Push(1)).unwrap();
+    hwndloop.send_command(TestCommand::Push(2)).unwrap();
+    hwndloop.send_urgent_command(TestCommand::Push(3)).unwrap();
+    hwndloop.send_urgent_command(TestCommand::Push(4)).unwrap();
+    block_tx.send(()).unwrap();
+
+    for &i in &[3, 4, 1, 2] {
+      let (tx, rx) = channel();
+      hwndloop.send_command(TestCommand::Pop(tx)).unwrap();
+      assert_eq!(Some(i), rx.recv().unwrap());
+    }
+  }
+
+  #[test]
+  fn defer_commands() {
+    let hwndloop = hwndloop::HwndLoop::new(Box::new(Test::new()));
+    let hwnd = hwndloop.hwnd().0;
+    let guard = hwndloop.defer_commands();
+    hwndloop.send_command(TestCommand::Push(1)).unwrap();
+    assert_ne!(FALSE, unsafe { PostMessageA(hwnd, WM_USER, 2, 0) });
+
+    // The message gets through while the command is held back.
+    hwndloop.flush().unwrap();
+    drop(guard);
+    for &i in &[2, 1] {
+      let (tx, rx) = channel();
+      hwndloop.send_command(TestCommand::Pop(tx)).unwrap();
+      assert_eq!(Some(i), rx.recv().unwrap());
+    }
+  }
+
+  #[test]
+  fn pending_commands() {
+    let hwndloop = hwndloop::HwndLoop::new(Box::new(Test::new()));
+    let (block_tx, block_rx) = channel();
+    hwndloop.send_command(TestCommand::Block(block_rx)).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    let seq = hwndloop.send_command(TestCommand::Push(1)).unwrap();
+    hwndloop.send_urgent_command(TestCommand::Push(2)).unwrap();
+    assert_eq!(2, hwndloop.pending_commands());
+    #[cfg(debug_assertions)]
+    assert_eq!(
+      vec!["urgent Push(2)".to_string(), format!("{} Push(1)", seq)],
+      hwndloop.snapshot_commands()
+    );
+
+    // The loop's own commands don't count.
+    hwndloop.once(WM_NULL, |_hwnd, _msg, _w, _l| {}).unwrap();
+    hwndloop.on_teardown(|_hwnd| {}).unwrap();
+    assert_eq!(2, hwndloop.pending_commands());
+    #[cfg(debug_assertions)]
+    assert_eq!(2, hwndloop.snapshot_commands().len());
+    block_tx.send(()).unwrap();
+    hwndloop.flush().unwrap();
+    assert_eq!(0, hwndloop.pending_commands());
+
+    // Commands that the loop is holding on to are still pending.
+    let guard = hwndloop.defer_commands();
+    hwndloop.send_command(TestCommand::Push(3)).unwrap();
+    hwndloop.flush().unwrap();
+    assert_eq!(1, hwndloop.pending_commands());
+    drop(guard);
+    hwndloop.call(TestCommand::Push(4)).unwrap();
+    assert_eq!(0, hwndloop.pending_commands());
+  }
+
+  #[test]
+  fn cancel_pending() {
+    let hwndloop = hwndloop::HwndLoop::new(Box::new(Test::new()));
+    let (block_tx, block_rx) = channel();
+    hwndloop.send_command(TestCommand::Block(block_rx)).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    for i in 1..=3 {
+      hwndloop.send_command(TestCommand::Push(i)).unwrap();
+    }
+    hwndloop.send_urgent_command(TestCommand::Push(4)).unwrap();
+    let last = hwndloop.send_command(TestCommand::Push(6)).unwrap();
+
+    let even = |cmd: &TestCommand| matches!(cmd, TestCommand::Push(i) if i % 2 == 0);
+    let taken = hwndloop.take_pending(even);
+    assert_eq!("[Push(4), Push(2), Push(6)]", format!("{:?}", taken));
+    assert_eq!(0, hwndloop.cancel_pending(even));
+    block_tx.send(()).unwrap();
+
+    // The last command was cancelled, but the loop still catches up to it.
+    hwndloop.flush_until(last, std::time::Duration::from_secs(10)).unwrap();
+    for &i in &[1, 3] {
+      let (tx, rx) = channel();
+      hwndloop.send_command(TestCommand::Pop(tx)).unwrap();
+      assert_eq!(Some(i), rx.recv().unwrap());
+    }
+  }
+
+  #[test]
+  fn command_deadline() {
+    let hwndloop = hwndloop::HwndLoop::new(Box::new(Test::new()));
+    let (block_tx, block_rx) = channel();
+    hwndloop.send_command(TestCommand::Block(block_rx)).unwrap();
+    let now = std::time::Instant::now();
+    let short = now + std::time::Duration::from_millis(20);
+    let long = now + std::time::Duration::from_secs(60);
+    hwndloop.send_command_with_deadline(TestCommand::Push(1), short).unwrap();
+    hwndloop.send_command_with_deadline(TestCommand::Push(2), long).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    block_tx.send(()).unwrap();
+
+    // The expired command is handed back as dropped instead.
+    for &i in &[-1, 2] {
+      let (tx, rx) = channel();
+      hwndloop.send_command(TestCommand::Pop(tx)).unwrap();
       assert_eq!(Some(i), rx.recv().unwrap());
     }
   }
+
+  struct DeadLetters(Arc<Mutex<Vec<(i32, DropReason)>>>);
+
+  impl HwndLoopCallbacks<TestCommand> for DeadLetters {
+    fn handle_command(&mut self, _hwnd: HWND, cmd: TestCommand) {
+      if let TestCommand::Block(rx) = cmd {
+        rx.recv().unwrap();
+      }
+    }
+
+    fn handle_dropped_command(&mut self, _hwnd: HWND, cmd: TestCommand, reason: DropReason) {
+      if let TestCommand::Push(i) = cmd {
+        self.0.lock().unwrap().push((i, reason));
+      }
+    }
+  }
+
+  #[test]
+  fn dropped_commands() {
+    let dropped = Arc::new(Mutex::new(Vec::new()));
+    let hwndloop = hwndloop::HwndLoop::new(Box::new(DeadLetters(dropped.clone())));
+    let (block_tx, block_rx) = channel();
+    hwndloop.send_command(TestCommand::Block(block_rx)).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(50));
+
+    hwndloop.send_command_with_deadline(TestCommand::Push(1), std::time::Instant::now()).unwrap();
+    hwndloop.send_command(TestCommand::Push(2)).unwrap();
+    assert_eq!(1, hwndloop.cancel_pending(|cmd| matches!(cmd, TestCommand::Push(2))));
+    hwndloop.terminate().unwrap();
+    hwndloop.send_command(TestCommand::Push(3)).unwrap();
+    block_tx.send(()).unwrap();
+    drop(hwndloop);
+
+    let expected = vec![(1, DropReason::Expired), (2, DropReason::Cancelled), (3, DropReason::Terminated)];
+    assert_eq!(expected, *dropped.lock().unwrap());
+  }
+
+  #[test]
+  fn erased_commands() {
+    use hwndloop::erased::{ErasedCallbacks, ErasedCommand, LoopCommand};
+    use std::any::Any;
+
+    // Two commands that know nothing about each other, sharing one loop.
+    #[derive(Debug)]
+    struct Append(i32);
+
+    impl LoopCommand for Append {
+      fn execute(self: Box<Self>, state: &mut dyn Any, _hwnd: HWND) {
+        state.downcast_mut::<Vec<i32>>().unwrap().push(self.0);
+      }
+    }
+
+    #[derive(Debug)]
+    struct Sum(Sender<i32>);
+
+    impl LoopCommand for Sum {
+      fn execute(self: Box<Self>, state: &mut dyn Any, _hwnd: HWND) {
+        self.0.send(state.downcast_ref::<Vec<i32>>().unwrap().iter().sum()).unwrap();
+      }
+    }
+
+    let hwndloop: HwndLoop<ErasedCommand> = HwndLoop::new(Box::new(ErasedCallbacks::new(Vec::<i32>::new())));
+    hwndloop.send_erased(Append(1)).unwrap();
+    hwndloop.send_command(Box::new(Append(2))).unwrap();
+    let (tx, rx) = channel();
+    hwndloop.send_erased(Sum(tx)).unwrap();
+    assert_eq!(3, rx.recv().unwrap());
+  }
+
+  #[test]
+  fn windowless() {
+    let hwndloop = hwndloop::HwndLoopBuilder::new().windowless().build(Box::new(Test::new()));
+    assert!(hwndloop.hwnd().0.is_null());
+    let (tx, rx) = channel();
+    hwndloop.send_command(TestCommand::GetHWND(tx)).unwrap();
+    assert!(rx.recv().unwrap().0.is_null());
+
+    hwndloop.pause().unwrap();
+    hwndloop.send_command(TestCommand::Push(1)).unwrap();
+    hwndloop.flush().unwrap();
+    assert_eq!(1, hwndloop.pending_commands());
+    hwndloop.resume().unwrap();
+    hwndloop.flush_all().unwrap();
+    let (tx, rx) = channel();
+    hwndloop.send_command(TestCommand::Pop(tx)).unwrap();
+    assert_eq!(Some(1), rx.recv().unwrap());
+
+    // Anything that needs a window is refused.
+    let result = hwndloop::HwndLoopBuilder::new().windowless().user_slots(1).try_build(Box::new(Test::new()));
+    assert!(matches!(result, Err(Error::Os(_))));
+  }
+
+  #[test]
+  fn stack_size() {
+    // More than the default stack can hold.
+    let hwndloop = hwndloop::HwndLoopBuilder::new()
+      .stack_size(16 << 20)
+      .on_start(|_| {
+        let buf = [1u8; 4 << 20];
+        assert_eq!(4 << 20, std::hint::black_box(&buf).iter().map(|&b| b as usize).sum::<usize>());
+      })
+      .build(Box::new(Test::new()));
+    hwndloop.flush().unwrap();
+  }
+
+  #[test]
+  fn affinity() {
+    let hwndloop = hwndloop::HwndLoopBuilder::new()
+      .affinity(Affinity::Mask(1))
+      .ideal_processor(0)
+      .on_start(|_| assert_eq!(0, unsafe { GetCurrentProcessorNumber() }))
+      .build(Box::new(Test::new()));
+    hwndloop.flush().unwrap();
+
+    // Processors that don't exist are refused.
+    let result = hwndloop::HwndLoopBuilder::new().affinity(Affinity::Mask(0)).try_build(Box::new(Test::new()));
+    assert!(result.is_err());
+
+    // An empty set of CPU sets lets the thread run anywhere.
+    let hwndloop = hwndloop::HwndLoopBuilder::new()
+      .affinity(Affinity::CpuSets(Vec::new()))
+      .try_build(Box::new(Test::new()))
+      .unwrap();
+    hwndloop.flush().unwrap();
+  }
+
+  #[test]
+  fn mmcss_task() {
+    let hwndloop = hwndloop::HwndLoopBuilder::new().mmcss_task("Games").build(Box::new(Test::new()));
+    hwndloop.flush().unwrap();
+    drop(hwndloop);
+
+    let result = hwndloop::HwndLoopBuilder::new().mmcss_task("No Such Task").try_build(Box::new(Test::new()));
+    assert!(result.is_err());
+  }
+
+  #[test]
+  fn timer_resolution() {
+    let hwndloop = hwndloop::HwndLoop::new(Box::new(Test::new()));
+    let first = hwndloop.request_timer_resolution(std::time::Duration::from_millis(1)).unwrap();
+    let second = hwndloop.request_timer_resolution(std::time::Duration::from_micros(500)).unwrap();
+    drop(first);
+
+    // Whatever's still held is released by the loop, and the guard is harmless afterwards.
+    let handle = hwndloop.handle();
+    drop(hwndloop);
+    assert!(matches!(
+      handle.request_timer_resolution(std::time::Duration::from_millis(1)),
+      Err(Error::Terminated)
+    ));
+    drop(second);
+  }
+
+  #[test]
+  fn keep_awake() {
+    let hwndloop = hwndloop::HwndLoop::new(Box::new(Test::new()));
+    let system = hwndloop
+      .keep_awake(KeepAwake {
+        system: true,
+        display: false,
+      })
+      .unwrap();
+    let display = hwndloop
+      .keep_awake(KeepAwake {
+        system: true,
+        display: true,
+      })
+      .unwrap();
+    drop(system);
+    hwndloop.flush().unwrap();
+    assert_eq!(hwndloop.status().pending_commands, 0);
+
+    let handle = hwndloop.handle();
+    drop(hwndloop);
+    assert!(matches!(handle.keep_awake(KeepAwake::default()), Err(Error::Terminated)));
+    drop(display);
+  }
+
+  #[test]
+  fn msg_name() {
+    assert_eq!(Some("WM_SIZE"), hwndloop::msg_name(0x0005));
+    assert_eq!(Some("WM_SETTINGCHANGE"), hwndloop::msg_name(0x001a));
+    assert_eq!(None, hwndloop::msg_name(WM_USER + 1));
+    assert_eq!(None, hwndloop::msg_name(WM_APP));
+
+    let registered = unsafe { RegisterWindowMessageA(b"hwndloop-test-msg-name\0".as_ptr() as *const i8) };
+    assert_eq!(Some("hwndloop-test-msg-name"), hwndloop::msg_name(registered));
+    let command = unsafe { RegisterWindowMessageA(b"WM_HWNDLOOP_COMMAND\0".as_ptr() as *const i8) };
+    assert_eq!(Some("WM_HWNDLOOP_COMMAND"), hwndloop::msg_name(command));
+  }
+
+  #[test]
+  fn params() {
+    use hwndloop::params::*;
+
+    let size = unsafe { Params::decode(0x0005, 2, (480 << 16) | 640) };
+    let expected = Size {
+      kind: SizeKind::Maximized,
+      width: 640,
+      height: 480,
+    };
+    assert_eq!(Some(Params::Size(expected)), size);
+
+    let control = 0x1234 as HWND;
+    let accelerator = unsafe { Params::decode(0x0111, (1 << 16) | 7, 0) };
+    assert_eq!(Some(Params::Command(Command::Accelerator(7))), accelerator);
+    match unsafe { Params::decode(0x0111, (5 << 16) | 7, control as LPARAM) } {
+      Some(Params::Command(Command::Control { id: 7, code: 5, hwnd })) => assert_eq!(control, hwnd),
+      result => panic!("unexpected result: {:?}", result),
+    }
+
+    let hotkey = Hotkey {
+      id: 3,
+      modifiers: 0x0002,
+      vk: 0x41,
+    };
+    assert_eq!(Some(Params::Hotkey(hotkey)), unsafe { Params::decode(0x0312, 3, (0x41 << 16) | 0x0002) });
+    assert_eq!(None, unsafe { Params::decode(WM_NULL, 0, 0) });
+
+    // What the parameters point at is copied out.
+    let header = [20u32, 5, 0, 0x0403_0201, 0x0807_0605];
+    let arrival = unsafe { Params::decode(0x0219, 0x8000, header.as_ptr() as LPARAM) };
+    let expected = DeviceBroadcast {
+      device_type: 5,
+      data: vec![1, 2, 3, 4, 5, 6, 7, 8],
+    };
+    assert_eq!(Some(Params::DeviceChange(DeviceChange::Arrival(expected))), arrival);
+
+    let setting = [0x1234_5678u32, 0, 0, 0, 1, 42];
+    match unsafe { Params::decode(0x0218, 0x8013, setting.as_ptr() as LPARAM) } {
+      Some(Params::PowerBroadcast(PowerBroadcast::PowerSettingChange(changed))) => {
+        assert_eq!(0x1234_5678, changed.setting.Data1);
+        assert_eq!(vec![42], changed.data);
+      }
+      result => panic!("unexpected result: {:?}", result),
+    }
+  }
+
+  #[cfg(feature = "windows-sys")]
+  #[test]
+  fn windows_sys_interop() {
+    let hwndloop = HwndLoop::new(Box::new(Test::new()));
+    let hwnd: windows_sys::Win32::Foundation::HWND = hwndloop.hwnd().into();
+    assert_eq!(hwndloop.hwnd().0 as usize, hwnd as usize);
+    assert_eq!(hwndloop.hwnd().0, HwndWrapper::from(hwnd).0);
+  }
+
+  #[test]
+  fn hwnd_validity() {
+    use std::time::Duration;
+
+    let hwndloop = HwndLoop::new(Box::new(Test::new()));
+    let hwnd = hwndloop.hwnd();
+    assert!(hwnd.is_valid());
+
+    // Wrapping the raw handle again still ties it to the loop.
+    let rewrapped = HwndWrapper::new(hwnd.0);
+    drop(hwndloop);
+
+    assert!(!hwnd.is_valid());
+    let result = hwndloop::dispatch::send_request(hwnd, WM_NULL, 0, 0, Duration::from_secs(1));
+    assert!(matches!(result, Err(Error::Terminated)));
+    let result = hwndloop::dispatch::send_request(rewrapped, WM_NULL, 0, 0, Duration::from_secs(1));
+    assert!(matches!(result, Err(Error::Terminated)));
+    assert!(!HwndWrapper::new(std::ptr::null_mut()).is_valid());
+  }
+
+  #[test]
+  fn request_shutdown() {
+    assert!(matches!(hwndloop::request_shutdown(), Err(Error::NotOnLoopThread)));
+
+    let hwndloop = HwndLoop::new(Box::new(Test::new()));
+    let hwnd = hwndloop.hwnd().0;
+    hwndloop.once(WM_APP + 3, |_, _, _, _| hwndloop::request_shutdown().unwrap()).unwrap();
+    hwndloop.flush().unwrap();
+
+    // Sent rather than posted, so the pump only finds out about the request from its wake event.
+    unsafe { SendMessageA(hwnd, WM_APP + 3, 0, 0) };
+    assert!(matches!(hwndloop.flush(), Err(Error::Terminated)));
+    assert!(matches!(hwndloop.send_command(TestCommand::Push(1)), Err(Error::Terminated)));
+  }
+
+  #[test]
+  fn exit_value() {
+    assert!(matches!(hwndloop::set_exit_value(0), Err(Error::NotOnLoopThread)));
+
+    let hwndloop = HwndLoop::new(Box::new(Test::new()));
+    let hwnd = hwndloop.hwnd().0;
+    hwndloop
+      .once(WM_APP + 3, |_, _, w, _| {
+        hwndloop::set_exit_value(format!("device {} went away", w)).unwrap();
+        hwndloop::request_shutdown().unwrap();
+      })
+      .unwrap();
+    hwndloop.flush().unwrap();
+    unsafe { PostMessageA(hwnd, WM_APP + 3, 7, 0) };
+    assert_eq!(Some("device 7 went away".to_string()), hwndloop.join::<String>());
+
+    // Nothing is left by a loop that was terminated from outside.
+    let hwndloop = HwndLoop::new(Box::new(Test::new()));
+    hwndloop.terminate().unwrap();
+    assert_eq!(None, hwndloop.join::<String>());
+  }
+
+  #[test]
+  fn terminate_policy() {
+    use std::time::Duration;
+
+    #[derive(Debug)]
+    enum Preempted {
+      Wait(Receiver<()>),
+      DropOwner,
+      Record(i32),
+    }
+
+    struct Recorder {
+      owner: Arc<Mutex<Option<HwndLoop<Preempted>>>>,
+      handled: Arc<Mutex<Vec<i32>>>,
+      dropped: Arc<Mutex<Vec<i32>>>,
+      torn_down: Sender<()>,
+    }
+
+    impl HwndLoopCallbacks<Preempted> for Recorder {
+      fn tear_down(&mut self, _hwnd: HWND, _context: &mut TearDownContext) {
+        self.torn_down.send(()).unwrap();
+      }
+
+      fn handle_dropped_command(&mut self, _hwnd: HWND, cmd: Preempted, reason: DropReason) {
+        assert_eq!(DropReason::Terminated, reason);
+        if let Preempted::Record(i) = cmd {
+          self.dropped.lock().unwrap().push(i);
+        }
+      }
+
+      fn handle_command(&mut self, _hwnd: HWND, cmd: Preempted) {
+        match cmd {
+          Preempted::Wait(rx) => rx.recv().unwrap(),
+          Preempted::DropOwner => drop(self.owner.lock().unwrap().take()),
+          Preempted::Record(i) => self.handled.lock().unwrap().push(i),
+        }
+      }
+    }
+
+    let owner = Arc::new(Mutex::new(None));
+    let handled = Arc::new(Mutex::new(Vec::new()));
+    let dropped = Arc::new(Mutex::new(Vec::new()));
+    let (tx, rx) = channel();
+    let hwndloop = HwndLoopBuilder::new()
+      .terminate_policy(TerminatePolicy::Preempt { grace: Duration::from_secs(0) })
+      .build(Box::new(Recorder {
+        owner: owner.clone(),
+        handled: handled.clone(),
+        dropped: dropped.clone(),
+        torn_down: tx,
+      }));
+    let handle = hwndloop.handle();
+    *owner.lock().unwrap() = Some(hwndloop);
+
+    // Everything is queued by the time the owner goes away.
+    let (release, wait) = channel();
+    handle.send_command(Preempted::Wait(wait)).unwrap();
+    handle.send_command(Preempted::Record(1)).unwrap();
+    handle.send_command(Preempted::DropOwner).unwrap();
+    handle.send_command(Preempted::Record(2)).unwrap();
+    handle.send_command(Preempted::Record(3)).unwrap();
+    release.send(()).unwrap();
+
+    rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(vec![1], *handled.lock().unwrap());
+    assert_eq!(vec![2, 3], *dropped.lock().unwrap());
+  }
+
+  #[test]
+  fn startup_failure() {
+    use std::time::Duration;
+
+    struct BadStart(Option<Duration>);
+
+    impl HwndLoopCallbacks<()> for BadStart {
+      fn set_up(&mut self, _hwnd: HWND, _context: &SetUpContext<()>) {
+        match self.0 {
+          Some(delay) => std::thread::sleep(delay),
+          None => panic!("no device"),
+        }
+      }
+    }
+
+    match HwndLoopBuilder::new().try_build(Box::new(BadStart(None))) {
+      Err(Error::Panicked(message)) => assert_eq!("no device", message),
+      result => panic!("unexpected result: {:?}", result.err()),
+    }
+
+    let result = HwndLoopBuilder::new()
+      .startup_timeout(Duration::from_millis(50))
+      .try_build(Box::new(BadStart(Some(Duration::from_millis(500)))));
+    assert!(matches!(result, Err(Error::TimedOut)));
+
+    // Building panics with the actual error, rather than blaming the window.
+    let result = std::panic::catch_unwind(|| HwndLoopBuilder::new().build(Box::new(BadStart(None))));
+    let payload = result.err().unwrap();
+    assert_eq!(
+      Some("failed to start HwndLoop: loop thread panicked: no device"),
+      payload.downcast_ref::<String>().map(String::as_str)
+    );
+  }
+
+  #[test]
+  fn thread_control() {
+    let hwndloop = HwndLoopBuilder::new().thread_control(true).build(Box::new(Test::new()));
+    let hwnd = hwndloop.hwnd().0;
+
+    // Destroy the window out from under the loop.
+    hwndloop.once(WM_APP + 2, |hwnd, _, _, _| unsafe { assert_ne!(FALSE, DestroyWindow(hwnd)) }).unwrap();
+    hwndloop.flush().unwrap();
+    unsafe { SendMessageA(hwnd, WM_APP + 2, 0, 0) };
+    assert!(!hwndloop.hwnd().is_valid());
+
+    hwndloop.send_command(TestCommand::Push(1)).unwrap();
+    hwndloop.send_command(TestCommand::ForeignPump).unwrap();
+    hwndloop.send_command(TestCommand::Push(2)).unwrap();
+    hwndloop.flush_all().unwrap();
+    let (tx, rx) = channel();
+    hwndloop.send_command(TestCommand::Pop(tx.clone())).unwrap();
+    hwndloop.send_command(TestCommand::Pop(tx)).unwrap();
+    assert_eq!(Some(1), rx.recv().unwrap());
+    assert_eq!(Some(2), rx.recv().unwrap());
+
+    assert!(HwndLoopBuilder::new().thread_control(true).build_external(Box::new(Test::new())).is_err());
+  }
+
+  #[test]
+  fn coalesce() {
+    use std::time::Duration;
+
+    struct Coalesced(Sender<(UINT, WPARAM)>, Sender<String>);
+
+    impl HwndLoopCallbacks<()> for Coalesced {
+      fn handle_message(&mut self, hwnd: HWND, msg: UINT, w: WPARAM, l: LPARAM) -> LRESULT {
+        if msg == WM_APP + 5 || msg == WM_APP + 6 {
+          self.0.send((msg, w)).unwrap();
+          return 0;
+        }
+        if msg == WM_SETTINGCHANGE {
+          let s = l as *const u16;
+          let len = (0..).take_while(|&i| unsafe { *s.add(i) } != 0).count();
+          self.1.send(String::from_utf16_lossy(unsafe { std::slice::from_raw_parts(s, len) })).unwrap();
+          return 0;
+        }
+        unsafe { DefWindowProcW(hwnd, msg, w, l) }
+      }
+    }
+
+    let (tx, rx) = channel();
+    let (setting_tx, setting_rx) = channel();
+    let hwndloop = HwndLoopBuilder::new()
+      .coalesce(WM_APP + 5, Duration::from_secs(5))
+      .coalesce(WM_APP + 5, Duration::from_millis(100))
+      .coalesce(WM_SETTINGCHANGE, Duration::from_millis(100))
+      .build(Box::new(Coalesced(tx, setting_tx)));
+    let hwnd = hwndloop.hwnd().0;
+
+    // The flood is held back, while other messages go straight through.
+    for i in 0..50 {
+      unsafe { SendMessageA(hwnd, WM_APP + 5, i, 0) };
+    }
+    unsafe { SendMessageA(hwnd, WM_APP + 6, 0, 0) };
+    assert_eq!((WM_APP + 6, 0), rx.try_recv().unwrap());
+    assert_eq!((WM_APP + 5, 49), rx.recv_timeout(Duration::from_secs(1)).unwrap());
+    assert!(rx.recv_timeout(Duration::from_millis(300)).is_err());
+
+    // The next one starts a new window.
+    unsafe { SendMessageA(hwnd, WM_APP + 5, 50, 0) };
+    assert_eq!((WM_APP + 5, 50), rx.recv_timeout(Duration::from_secs(1)).unwrap());
+
+    // What the latest one pointed at is copied, since it's gone once the send returns.
+    for setting in &["Policy", "Environment"] {
+      let mut setting: Vec<u16> = setting.encode_utf16().chain(Some(0)).collect();
+      unsafe { SendMessageW(hwnd, WM_SETTINGCHANGE, 0, setting.as_ptr() as LPARAM) };
+      setting.iter_mut().for_each(|c| *c = 'x' as u16);
+    }
+    assert_eq!("Environment", setting_rx.recv_timeout(Duration::from_secs(1)).unwrap());
+
+    let result = HwndLoopBuilder::new()
+      .windowless()
+      .coalesce(WM_APP + 5, Duration::from_millis(100))
+      .try_build(Box::new(Test::new()));
+    assert!(result.is_err());
+  }
+
+  #[test]
+  fn window_destroyed() {
+    use std::sync::atomic::AtomicUsize;
+
+    struct Counted(Arc<AtomicUsize>);
+
+    impl Drop for Counted {
+      fn drop(&mut self) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+      }
+    }
+
+    impl HwndLoopCallbacks<()> for Counted {}
+
+    let drops = Arc::new(AtomicUsize::new(0));
+    let hwndloop = HwndLoop::new(Box::new(Counted(drops.clone())));
+    let hwnd = hwndloop.hwnd().0;
+    hwndloop.once(WM_APP + 2, |hwnd, _, _, _| unsafe { assert_ne!(FALSE, DestroyWindow(hwnd)) }).unwrap();
+    hwndloop.flush().unwrap();
+
+    // The loop can't be reached without its window, so it shuts down.
+    unsafe { SendMessageA(hwnd, WM_APP + 2, 0, 0) };
+    assert!(!hwndloop.hwnd().is_valid());
+    assert!(matches!(hwndloop.flush(), Err(Error::Terminated)));
+    drop(hwndloop);
+    assert_eq!(1, drops.load(Ordering::SeqCst));
+  }
+
+  #[test]
+  fn drop_during_dispatch() {
+    use std::cell::RefCell;
+
+    thread_local! {
+      static ADAPTER: RefCell<Option<ExternalLoopAdapter<()>>> = const { RefCell::new(None) };
+    }
+
+    struct SelfDropping {
+      dropped: Arc<AtomicBool>,
+    }
+
+    impl Drop for SelfDropping {
+      fn drop(&mut self) {
+        self.dropped.store(true, Ordering::SeqCst);
+      }
+    }
+
+    impl HwndLoopCallbacks<()> for SelfDropping {
+      fn handle_message(&mut self, hwnd: HWND, msg: UINT, w: WPARAM, l: LPARAM) -> LRESULT {
+        if msg == WM_APP + 4 {
+          let adapter = ADAPTER.with(|adapter| adapter.borrow_mut().take());
+          drop(adapter);
+
+          // The callbacks outlive the owner until they've returned.
+          assert!(!self.dropped.load(Ordering::SeqCst));
+          return 0;
+        }
+        unsafe { DefWindowProcW(hwnd, msg, w, l) }
+      }
+    }
+
+    let dropped = Arc::new(AtomicBool::new(false));
+    let adapter = ExternalLoopAdapter::new(Box::new(SelfDropping {
+      dropped: dropped.clone(),
+    }))
+    .unwrap();
+    let hwnd = adapter.handle().hwnd().0;
+    ADAPTER.with(|cell| *cell.borrow_mut() = Some(adapter));
+
+    unsafe { SendMessageA(hwnd, WM_APP + 4, 0, 0) };
+    assert!(dropped.load(Ordering::SeqCst));
+    assert_eq!(FALSE, unsafe { IsWindow(hwnd) });
+  }
+
+  #[test]
+  fn drop_on_loop_thread() {
+    use std::time::Duration;
+
+    struct SelfOwned {
+      owner: Arc<Mutex<Option<HwndLoop<()>>>>,
+      torn_down: Sender<()>,
+    }
+
+    impl HwndLoopCallbacks<()> for SelfOwned {
+      fn tear_down(&mut self, _hwnd: HWND, _context: &mut TearDownContext) {
+        self.torn_down.send(()).unwrap();
+      }
+
+      fn handle_command(&mut self, _hwnd: HWND, _cmd: ()) {
+        // The last clone, which terminates the loop from its own thread.
+        drop(self.owner.lock().unwrap().take());
+      }
+    }
+
+    let owner = Arc::new(Mutex::new(None));
+    let (tx, rx) = channel();
+    let hwndloop = HwndLoop::new(Box::new(SelfOwned {
+      owner: owner.clone(),
+      torn_down: tx,
+    }));
+    let handle = hwndloop.handle();
+    *owner.lock().unwrap() = Some(hwndloop);
+
+    handle.send_command(()).unwrap();
+    rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(matches!(handle.flush(), Err(Error::Terminated)));
+  }
+
+  #[test]
+  fn spy() {
+    use hwndloop::spy::MessageOrigin;
+
+    let hwndloop = HwndLoopBuilder::new().spy(4).build(Box::new(Test::new()));
+    let hwnd = hwndloop.hwnd().0;
+    unsafe {
+      for i in 0..8 {
+        SendMessageA(hwnd, WM_NULL, i, 0);
+      }
+      SendNotifyMessageA(hwnd, WM_NULL, 8, 0);
+      PostMessageA(hwnd, WM_APP, 1, 2);
+    }
+    hwndloop.flush_all().unwrap();
+
+    let log = hwndloop.spy_log();
+    assert_eq!(4, log.len());
+    assert_eq!((WM_NULL, 7), (log[1].msg, log[1].wparam));
+    assert_eq!(Some("WM_NULL"), log[1].name());
+    assert!(matches!(log[1].origin, MessageOrigin::SentFromOtherThread { .. }));
+
+    // Messages sent without waiting for a reply still came from another thread.
+    assert_eq!((WM_NULL, 8), (log[2].msg, log[2].wparam));
+    assert!(matches!(log[2].origin, MessageOrigin::SentFromOtherThread { .. }));
+    assert_eq!((WM_APP, 1, 2), (log[3].msg, log[3].wparam, log[3].lparam));
+    assert_eq!(MessageOrigin::Posted, log[3].origin);
+
+    // Loops that weren't asked to spy don't keep anything.
+    let hwndloop = HwndLoop::new(Box::new(Test::new()));
+    unsafe { SendMessageA(hwndloop.hwnd().0, WM_NULL, 0, 0) };
+    assert!(hwndloop.spy_log().is_empty());
+  }
+
+  #[test]
+  fn profile() {
+    use std::mem::discriminant;
+    use std::time::Duration;
+
+    let hwndloop = HwndLoopBuilder::new().profile(true).build(Box::new(Test::new()));
+    let hwnd = hwndloop.hwnd().0;
+    for i in 0..3 {
+      hwndloop.send_command(TestCommand::Push(i)).unwrap();
+    }
+    let (block_tx, block_rx) = channel();
+    hwndloop.send_command(TestCommand::Block(block_rx)).unwrap();
+    std::thread::sleep(Duration::from_millis(50));
+    block_tx.send(()).unwrap();
+    unsafe { SendMessageA(hwnd, WM_NULL, 0, 0) };
+    hwndloop.flush().unwrap();
+
+    let snapshot = hwndloop.profile_snapshot(10);
+    assert_eq!(discriminant(&TestCommand::Block(channel().1)), snapshot.commands[0].0);
+    let pushes = snapshot.commands.iter().find(|&&(cmd, _)| cmd == discriminant(&TestCommand::Push(0)));
+    assert_eq!(3, pushes.unwrap().1.count);
+    assert!(snapshot.messages.iter().any(|&(msg, time)| msg == WM_NULL && time.count == 1));
+
+    // Each snapshot starts over, and only has as many as it asked for.
+    hwndloop.send_command(TestCommand::Push(3)).unwrap();
+    hwndloop.send_command(TestCommand::Mark(Arc::new(AtomicBool::new(false)))).unwrap();
+    hwndloop.flush().unwrap();
+    let snapshot = hwndloop.profile_snapshot(1);
+    assert!(snapshot.commands.len() <= 2);
+    assert!(snapshot.commands.iter().all(|&(_, time)| time.count == 1));
+
+    // Loops that weren't asked to profile don't keep anything.
+    let hwndloop = HwndLoop::new(Box::new(Test::new()));
+    hwndloop.send_command(TestCommand::Push(0)).unwrap();
+    hwndloop.flush().unwrap();
+    assert!(hwndloop.profile_snapshot(10).commands.is_empty());
+  }
+
+  #[test]
+  fn slots() {
+    let hwndloop = hwndloop::HwndLoopBuilder::new().user_slots(2).build(Box::new(Test::new()));
+    hwndloop.set_slot(0, 5i32).unwrap();
+    hwndloop.set_slot(1, "not an i32").unwrap();
+    hwndloop.set_slot(2, 6i32).unwrap();
+
+    for &(key, expected) in &[(0, Some(5)), (1, None), (2, None)] {
+      let (tx, rx) = channel();
+      hwndloop.send_command(TestCommand::GetSlot(key, tx)).unwrap();
+      assert_eq!(expected, rx.recv().unwrap());
+    }
+
+    let (tx, rx) = channel();
+    hwndloop.send_command(TestCommand::GetHWND(tx)).unwrap();
+    let hwnd = rx.recv().unwrap();
+    match hwndloop::slots::get_slot::<i32>(hwnd.0, 0) {
+      Err(Error::NotOnLoopThread) => {}
+      result => panic!("unexpected result: {:?}", result),
+    }
+  }
+
+  #[test]
+  fn pointer_sized_window_extra() {
+    // The loop's own pointer and each slot take up one pointer's worth of extra bytes, which is
+    // 4 bytes on 32-bit Windows, where the LongPtr functions are really the Long ones.
+    let hwndloop = hwndloop::HwndLoopBuilder::new().user_slots(3).build(Box::new(Test::new()));
+    let extra = unsafe { GetClassLongPtrW(hwndloop.hwnd().0, GCL_CBWNDEXTRA) } as usize;
+    assert_eq!(4 * std::mem::size_of::<usize>(), extra);
+
+    hwndloop.set_slot(2, -1i32).unwrap();
+    let (tx, rx) = channel();
+    hwndloop.send_command(TestCommand::GetSlot(2, tx)).unwrap();
+    assert_eq!(Some(-1), rx.recv().unwrap());
+  }
+
+  /// Records the messages it sees, and 0 when it's set up.
+  struct Startup(Arc<Mutex<Vec<UINT>>>, Option<DrainHandle>);
+
+  impl HwndLoopCallbacks<TestCommand> for Startup {
+    fn tear_down(&mut self, hwnd: HWND, context: &mut TearDownContext) {
+      // Wait for a message that's posted at the last moment.
+      assert_ne!(FALSE, unsafe { PostMessageA(hwnd, WM_USER, 0, 0) });
+      self.1 = Some(context.drain(std::time::Duration::from_secs(10)));
+    }
+
+    fn set_up(&mut self, _hwnd: HWND, _context: &SetUpContext<TestCommand>) {
+      self.0.lock().unwrap().push(0);
+    }
+
+    fn handle_message(&mut self, hwnd: HWND, msg: UINT, w: WPARAM, l: LPARAM) -> LRESULT {
+      self.0.lock().unwrap().push(msg);
+      if msg == WM_USER {
+        if let Some(ref drain) = self.1 {
+          drain.finish();
+        }
+      }
+      unsafe { DefWindowProcW(hwnd, msg, w, l) }
+    }
+  }
+
+  #[test]
+  fn creation_messages() {
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let hwndloop = hwndloop::HwndLoop::new(Box::new(Startup(seen.clone(), None)));
+    hwndloop.flush().unwrap();
+
+    let seen = seen.lock().unwrap();
+    let set_up = seen.iter().position(|&msg| msg == 0).unwrap();
+    assert!(seen[..set_up].contains(&WM_NCCREATE));
+    assert!(seen[..set_up].contains(&WM_CREATE));
+  }
+
+  #[test]
+  fn set_up_context() {
+    let hwndloop = hwndloop::HwndLoopBuilder::new().user_data(9i32).build(Box::new(Test::new()));
+    let (tx, rx) = channel();
+    hwndloop.send_command(TestCommand::Pop(tx)).unwrap();
+    assert_eq!(Some(9), rx.recv().unwrap());
+  }
+
+  #[test]
+  fn tear_down_drain() {
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let start = std::time::Instant::now();
+    drop(hwndloop::HwndLoop::new(Box::new(Startup(seen.clone(), None))));
+    assert!(start.elapsed() < std::time::Duration::from_secs(10));
+    assert_eq!(Some(&WM_USER), seen.lock().unwrap().last());
+  }
+
+  #[test]
+  fn on_teardown() {
+    let order = Arc::new(Mutex::new(Vec::new()));
+    let hwndloop = hwndloop::HwndLoop::new(Box::new(Test::new()));
+    for i in 1..3 {
+      let order = order.clone();
+      hwndloop.on_teardown(move |_hwnd| order.lock().unwrap().push(i)).unwrap();
+    }
+    hwndloop.flush().unwrap();
+    assert!(order.lock().unwrap().is_empty());
+
+    drop(hwndloop);
+    assert_eq!(vec![2, 1], *order.lock().unwrap());
+  }
+
+  #[test]
+  fn clone() {
+    let torn_down = Arc::new(AtomicBool::new(false));
+    let hwndloop = hwndloop::HwndLoop::new(Box::new(Test::new()));
+    let flag = torn_down.clone();
+    hwndloop.on_teardown(move |_hwnd| flag.store(true, Ordering::SeqCst)).unwrap();
+
+    let clone = hwndloop.clone();
+    drop(hwndloop);
+    assert!(!torn_down.load(Ordering::SeqCst));
+    clone.send_command(TestCommand::Push(1)).unwrap();
+    let (tx, rx) = channel();
+    clone.send_command(TestCommand::Pop(tx)).unwrap();
+    assert_eq!(Some(1), rx.recv().unwrap());
+
+    drop(clone);
+    assert!(torn_down.load(Ordering::SeqCst));
+  }
+
+  #[test]
+  fn shutdown_into_inner() {
+    let hwndloop = hwndloop::HwndLoop::new(Box::new(Test::new()));
+    hwndloop.send_command(TestCommand::Push(1)).unwrap();
+    let handle = hwndloop.handle();
+    let mut callbacks = hwndloop.shutdown_into_inner().unwrap();
+    assert!(handle.send_command(TestCommand::Push(2)).is_err());
+
+    let (tx, rx) = channel();
+    callbacks.handle_command(std::ptr::null_mut(), TestCommand::Pop(tx));
+    assert_eq!(Some(1), rx.recv().unwrap());
+
+    let hwndloop = hwndloop::HwndLoop::new(Box::new(Test::new()));
+    hwndloop.terminate().unwrap();
+    assert!(hwndloop.shutdown_into_inner().is_err());
+  }
+
+  #[test]
+  fn on_start() {
+    let started = Arc::new(AtomicBool::new(false));
+    let flag = started.clone();
+    let hwndloop = hwndloop::HwndLoopBuilder::new()
+      .on_start(move |hwnd| {
+        flag.store(true, Ordering::SeqCst);
+        assert_ne!(FALSE, unsafe { PostMessageA(hwnd, WM_USER, 4, 0) });
+      })
+      .build(Box::new(Test::new()));
+    assert!(started.load(Ordering::SeqCst));
+
+    hwndloop.flush_all().unwrap();
+    let (tx, rx) = channel();
+    hwndloop.send_command(TestCommand::Pop(tx)).unwrap();
+    assert_eq!(Some(4), rx.recv().unwrap());
+  }
+
+  #[test]
+  fn once() {
+    let hwndloop = hwndloop::HwndLoop::new(Box::new(Test::new()));
+    let (tx, rx) = channel();
+    hwndloop.send_command(TestCommand::GetHWND(tx)).unwrap();
+    let hwnd = rx.recv().unwrap();
+
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let hook_seen = seen.clone();
+    hwndloop.once(WM_USER, move |_hwnd, _msg, w, _l| hook_seen.lock().unwrap().push(w)).unwrap();
+    hwndloop.flush().unwrap();
+
+    for i in 1..3 {
+      assert_ne!(FALSE, unsafe { PostMessageA(hwnd.0, WM_USER, i, 0) });
+    }
+    hwndloop.flush_all().unwrap();
+    assert_eq!(vec![1], *seen.lock().unwrap());
+  }
+
+  /// Run a future to completion on the current thread.
+  fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    struct ThreadWaker(std::thread::Thread);
+
+    impl std::task::Wake for ThreadWaker {
+      fn wake(self: Arc<Self>) {
+        self.0.unpark();
+      }
+    }
+
+    let waker = std::task::Waker::from(Arc::new(ThreadWaker(std::thread::current())));
+    let mut cx = std::task::Context::from_waker(&waker);
+    let mut future = Box::pin(future);
+    loop {
+      if let std::task::Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+        return output;
+      }
+      std::thread::park();
+    }
+  }
+
+  #[test]
+  fn wait_for_message() {
+    let hwndloop = hwndloop::HwndLoop::new(Box::new(Test::new()));
+    let hwnd = hwndloop.hwnd();
+    let timeout = std::time::Duration::from_secs(10);
+
+    let waiter = hwndloop.expect_message(WM_APP + 2).unwrap();
+    assert_ne!(FALSE, unsafe { PostMessageA(hwnd.0, WM_APP + 2, 5, 6) });
+    assert_eq!((5, 6), waiter.wait(timeout).unwrap());
+
+    let waiter = hwndloop.expect_message(WM_APP + 2).unwrap();
+    assert_ne!(FALSE, unsafe { PostMessageA(hwnd.0, WM_APP + 2, 7, 8) });
+    assert_eq!((7, 8), block_on(waiter).unwrap());
+
+    match hwndloop.wait_for_message(WM_APP + 3, std::time::Duration::from_millis(50)) {
+      Err(Error::TimedOut) => {}
+      result => panic!("unexpected result: {:?}", result),
+    }
+  }
+
+  #[test]
+  fn events() {
+    let hwndloop = hwndloop::HwndLoopBuilder::new().events::<i32>().build(Box::new(Test::new()));
+    let events = hwndloop.event_receiver::<i32>().unwrap();
+    assert!(hwndloop.event_receiver::<i32>().is_none());
+
+    hwndloop.send_command(TestCommand::Emit(3)).unwrap();
+    hwndloop.send_command(TestCommand::Emit(4)).unwrap();
+    assert_eq!(3, events.recv().unwrap());
+    assert_eq!(4, events.recv().unwrap());
+    assert_eq!(None, events.try_recv().unwrap());
+
+    drop(hwndloop);
+    assert!(events.recv().is_err());
+  }
+
+  #[test]
+  fn broadcast() {
+    let hwndloop = hwndloop::HwndLoopBuilder::new().events::<i32>().build(Box::new(Test::new()));
+    let first = hwndloop.event_receiver::<i32>().unwrap();
+    hwndloop.send_command(TestCommand::Emit(1)).unwrap();
+    hwndloop.flush().unwrap();
+
+    let second = hwndloop.subscribe::<i32>().unwrap();
+    hwndloop.send_command(TestCommand::Emit(2)).unwrap();
+    hwndloop.flush().unwrap();
+
+    assert_eq!(1, first.recv().unwrap());
+    assert_eq!(2, first.recv().unwrap());
+    assert_eq!(2, second.recv().unwrap());
+    assert_eq!(None, second.try_recv().unwrap());
+    assert!(hwndloop.subscribe::<u32>().is_none());
+  }
+
+  #[test]
+  fn backpressure() {
+    let hwndloop = hwndloop::HwndLoopBuilder::new().events::<i32>().build(Box::new(Test::new()));
+    let oldest = hwndloop.subscribe_with::<i32>(Backpressure::DropOldest(2)).unwrap();
+    let newest = hwndloop.subscribe_with::<i32>(Backpressure::DropNewest(2)).unwrap();
+    for i in 0..4 {
+      hwndloop.send_command(TestCommand::Emit(i)).unwrap();
+    }
+    hwndloop.flush().unwrap();
+
+    assert_eq!(vec![2, 3], vec![oldest.recv().unwrap(), oldest.recv().unwrap()]);
+    assert_eq!(vec![0, 1], vec![newest.recv().unwrap(), newest.recv().unwrap()]);
+    assert_eq!(2, oldest.dropped());
+    assert_eq!(2, newest.dropped());
+    assert_eq!(4, hwndloop.status().dropped_events);
+
+    // The first receiver can be bounded before it's taken.
+    let hwndloop = hwndloop::HwndLoopBuilder::new()
+      .events_with::<i32>(Backpressure::DropOldest(1))
+      .build(Box::new(Test::new()));
+    for i in 0..3 {
+      hwndloop.send_command(TestCommand::Emit(i)).unwrap();
+    }
+    hwndloop.flush().unwrap();
+    let first = hwndloop.event_receiver::<i32>().unwrap();
+    assert_eq!(Some(2), first.try_recv().unwrap());
+    assert_eq!(None, first.try_recv().unwrap());
+
+    // A receiver that blocks at 0 still has room for one event.
+    let blocking = hwndloop.subscribe_with::<i32>(Backpressure::Block(0)).unwrap();
+    hwndloop.send_command(TestCommand::Emit(3)).unwrap();
+    hwndloop.flush().unwrap();
+    assert_eq!(3, blocking.recv().unwrap());
+
+    // Even if it's switched to that later.
+    blocking.set_backpressure(Backpressure::Block(0));
+    hwndloop.send_command(TestCommand::Emit(4)).unwrap();
+    hwndloop.flush().unwrap();
+    assert_eq!(4, blocking.recv().unwrap());
+  }
+
+  struct PayloadTest(Sender<Vec<u8>>);
+
+  impl HwndLoopCallbacks<()> for PayloadTest {
+    fn handle_payload(&mut self, _hwnd: HWND, payload: payload::SharedPayload) {
+      self.0.send(payload.to_vec()).unwrap();
+    }
+  }
+
+  #[test]
+  fn shared_payload() {
+    let (tx, rx) = channel();
+    let hwndloop = HwndLoop::new(Box::new(PayloadTest(tx)));
+    let data: Vec<u8> = (0..4 * 1024 * 1024).map(|i| i as u8).collect();
+
+    // Payloads skip over names that someone else has already taken, whatever they created.
+    let pid = unsafe { GetCurrentProcessId() };
+    let squatted: Vec<_> = (0..8)
+      .map(|seq| {
+        let name = match seq {
+          0..=3 => format!("Local\\hwndloop-payload-{}-{}-done", pid, seq),
+          _ => format!("Local\\hwndloop-payload-{}-{}", pid, seq),
+        };
+        let name: Vec<u16> = name.encode_utf16().chain(Some(0)).collect();
+        unsafe { CreateEventW(std::ptr::null_mut(), TRUE, FALSE, name.as_ptr()) }
+      })
+      .collect();
+
+    let pending = payload::send_payload(hwndloop.hwnd(), &data).unwrap();
+    assert!(rx.recv().unwrap() == data);
+    pending.wait(std::time::Duration::from_secs(10)).unwrap();
+    assert!(pending.is_released());
+
+    // The notification is held back while the loop is paused.
+    hwndloop.pause().unwrap();
+    let pending = payload::send_payload(hwndloop.hwnd(), &data).unwrap();
+    match pending.wait(std::time::Duration::from_millis(10)) {
+      Err(Error::TimedOut) => {}
+      result => panic!("unexpected result: {:?}", result),
+    }
+    hwndloop.resume().unwrap();
+    pending.wait(std::time::Duration::from_secs(10)).unwrap();
+
+    for event in squatted {
+      unsafe { CloseHandle(event) };
+    }
+  }
+
+  #[test]
+  fn service_outside_scm() {
+    // Without a service control manager to talk to, there's nothing to run.
+    let callbacks = |_| -> Box<dyn HwndLoopCallbacks<TestCommand>> { Box::new(Test::new()) };
+    let result = service::run("hwndloop-test", HwndLoopBuilder::new(), callbacks);
+    match result {
+      Err(Error::Os(ref err)) if err.raw_os_error() == Some(1063) => {}
+      result => panic!("unexpected result: {:?}", result),
+    }
+
+    // Controls are sent to the loop's window, so there has to be one.
+    let result = service::run("hwndloop-test", HwndLoopBuilder::new().windowless(), callbacks);
+    match result {
+      Err(Error::Os(ref err)) if err.kind() == std::io::ErrorKind::InvalidInput => {}
+      result => panic!("unexpected result: {:?}", result),
+    }
+  }
+
+  #[test]
+  fn missing_desktop() {
+    let result = HwndLoopBuilder::new()
+      .desktop(Desktop::Named("hwndloop-nonexistent-desktop".to_string()))
+      .try_build(Box::new(Test::new()));
+    match result {
+      Err(Error::Os(_)) => {}
+      Err(err) => panic!("unexpected error: {}", err),
+      Ok(_) => panic!("created a loop on a nonexistent desktop"),
+    }
+  }
+
+  struct ComTest(Sender<i32>);
+
+  impl HwndLoopCallbacks<()> for ComTest {
+    fn set_up(&mut self, _hwnd: HWND, _context: &SetUpContext<()>) {
+      // Joining the apartment that the thread is already in succeeds without doing anything.
+      let hr = unsafe { CoInitializeEx(std::ptr::null_mut(), COINIT_APARTMENTTHREADED) };
+      if hr >= 0 {
+        unsafe { CoUninitialize() };
+      }
+      self.0.send(hr).unwrap();
+    }
+  }
+
+  #[test]
+  fn com_apartment() {
+    let (tx, rx) = channel();
+    let _hwndloop = HwndLoopBuilder::new()
+      .com_apartment(ComApartment::SingleThreaded)
+      .build(Box::new(ComTest(tx)));
+    assert_eq!(S_FALSE, rx.recv().unwrap());
+  }
+
+  #[cfg(feature = "audio")]
+  #[test]
+  fn audio_notifications() {
+    let hwndloop = HwndLoopBuilder::new()
+      .com_apartment(ComApartment::SingleThreaded)
+      .audio_notifications()
+      .try_build(Box::new(Test::new()))
+      .unwrap();
+    hwndloop.flush().unwrap();
+  }
+
+  #[test]
+  fn network_notifications() {
+    // There's no portable way to change the network configuration from a test, so just make sure
+    // that the notifications can be set up and don't get in the way.
+    let hwndloop = HwndLoopBuilder::new().network_notifications().build(Box::new(Test::new()));
+    hwndloop.send_command(TestCommand::Push(1)).unwrap();
+    let (tx, rx) = channel();
+    hwndloop.send_command(TestCommand::Pop(tx)).unwrap();
+    assert_eq!(Some(1), rx.recv().unwrap());
+  }
+
+  struct IdleTest(Sender<hwndloop::idle::IdleEvent>);
+
+  impl HwndLoopCallbacks<TestCommand> for IdleTest {
+    fn handle_command(&mut self, _hwnd: HWND, _cmd: TestCommand) {}
+
+    fn handle_idle_event(&mut self, _hwnd: HWND, event: hwndloop::idle::IdleEvent) {
+      let _ = self.0.send(event);
+    }
+  }
+
+  #[test]
+  fn idle_threshold() {
+    // Nobody's been idle for less than no time at all, so the first check reports it.
+    let (tx, rx) = channel();
+    let _hwndloop = HwndLoopBuilder::new()
+      .idle_threshold(std::time::Duration::from_millis(0))
+      .build(Box::new(IdleTest(tx)));
+    let event = rx.recv_timeout(std::time::Duration::from_secs(5)).unwrap();
+    assert!(matches!(event, hwndloop::idle::IdleEvent::UserIdle(_)));
+
+    let result = HwndLoopBuilder::new()
+      .windowless()
+      .idle_threshold(std::time::Duration::from_secs(60))
+      .try_build(Box::new(Test::new()));
+    assert!(matches!(result, Err(Error::Os(_))));
+  }
+
+  struct WinEventTest(Sender<HwndWrapper>);
+
+  impl HwndLoopCallbacks<TestCommand> for WinEventTest {
+    fn handle_win_event(&mut self, _hwnd: HWND, event: winevent::WinEvent) {
+      if let winevent::WinEvent::WindowCreated(hwnd) = event {
+        let _ = self.0.send(HwndWrapper::new(hwnd));
+      }
+    }
+  }
+
+  #[test]
+  fn hook_winevents() {
+    let (tx, rx) = channel();
+    let hwndloop = HwndLoop::new(Box::new(WinEventTest(tx)));
+    hwndloop.hook_winevents(EVENT_OBJECT_CREATE..=EVENT_OBJECT_CREATE).unwrap();
+
+    let class = "STATIC\0".encode_utf16().collect::<Vec<u16>>();
+    let hwnd = unsafe {
+      CreateWindowExW(
+        0,
+        class.as_ptr(),
+        std::ptr::null(),
+        0,
+        0,
+        0,
+        0,
+        0,
+        HWND_MESSAGE,
+        std::ptr::null_mut(),
+        std::ptr::null_mut(),
+        std::ptr::null_mut(),
+      )
+    };
+    assert!(!hwnd.is_null());
+
+    // Other windows might be created at the same time, so look for ours.
+    let timeout = std::time::Duration::from_secs(10);
+    while rx.recv_timeout(timeout).unwrap().0 != hwnd {}
+    unsafe { DestroyWindow(hwnd) };
+  }
+
+  struct FontChangeTest(Sender<()>);
+
+  impl HwndLoopCallbacks<TestCommand> for FontChangeTest {
+    fn handle_font_change(&mut self, _hwnd: HWND) {
+      let _ = self.0.send(());
+    }
+  }
+
+  #[test]
+  fn font_change() {
+    let (tx, rx) = channel();
+    let _hwndloop = HwndLoopBuilder::new()
+      .receive_broadcasts()
+      .build(Box::new(FontChangeTest(tx)));
+    dispatch::broadcast_font_change().unwrap();
+    rx.recv_timeout(std::time::Duration::from_secs(10)).unwrap();
+  }
+
+  struct ScreensaverTest(Sender<Option<MonitorPower>>);
+
+  impl HwndLoopCallbacks<TestCommand> for ScreensaverTest {
+    fn handle_screensaver_request(&mut self, _hwnd: HWND) -> bool {
+      let _ = self.0.send(None);
+      false
+    }
+
+    fn handle_monitor_power_request(&mut self, _hwnd: HWND, state: MonitorPower) -> bool {
+      let _ = self.0.send(Some(state));
+      false
+    }
+  }
+
+  #[test]
+  fn screensaver_requests() {
+    let (tx, rx) = channel();
+    let hwndloop = HwndLoopBuilder::new()
+      .receive_broadcasts()
+      .build(Box::new(ScreensaverTest(tx)));
+    // Vetoed, so neither of these actually happens. The low bits are the system's, and ignored.
+    unsafe {
+      SendMessageA(hwndloop.hwnd().0, WM_SYSCOMMAND, SC_SCREENSAVE as WPARAM | 2, 0);
+      SendMessageA(hwndloop.hwnd().0, WM_SYSCOMMAND, SC_MONITORPOWER as WPARAM, 2);
+    }
+    assert_eq!(None, rx.try_recv().unwrap());
+    assert_eq!(Some(MonitorPower::Off), rx.try_recv().unwrap());
+  }
+
+  #[test]
+  fn shutdown_level() {
+    let _hwndloop = HwndLoopBuilder::new().shutdown_level(0x180).build(Box::new(Test::new()));
+    let (mut level, mut flags) = (0, 0);
+    assert_ne!(FALSE, unsafe { GetProcessShutdownParameters(&mut level, &mut flags) });
+    assert_eq!(0x180, level);
+
+    assert!(HwndLoopBuilder::new()
+      .shutdown_level(0x1000)
+      .try_build(Box::new(Test::new()))
+      .is_err());
+  }
+
+  #[cfg(feature = "serde")]
+  #[derive(Debug, Serialize, Deserialize)]
+  enum IpcCommand {
+    Push(i32),
+  }
+
+  #[cfg(feature = "serde")]
+  struct IpcTest(Sender<i32>);
+
+  #[cfg(feature = "serde")]
+  impl HwndLoopCallbacks<IpcCommand> for IpcTest {
+    fn handle_command(&mut self, _hwnd: HWND, cmd: IpcCommand) {
+      match cmd {
+        IpcCommand::Push(i) => self.0.send(i).unwrap(),
+      }
+    }
+  }
+
+  #[cfg(feature = "serde")]
+  #[test]
+  fn ipc() {
+    let (tx, rx) = channel();
+    let hwndloop = hwndloop::HwndLoopBuilder::new()
+      .ipc_commands::<IpcCommand>()
+      .build(Box::new(IpcTest(tx)));
+    let timeout = std::time::Duration::from_secs(10);
+
+    hwndloop::ipc::send_command(hwndloop.hwnd(), &IpcCommand::Push(3), timeout).unwrap();
+    assert_eq!(3, rx.recv().unwrap());
+
+    // Not a variant that the receiver knows about.
+    match hwndloop::ipc::send_command(hwndloop.hwnd(), &99u32, timeout) {
+      Err(Error::Rejected) => {}
+      result => panic!("unexpected result: {:?}", result),
+    }
+
+    // Anyone can send WM_COPYDATA, including with nothing to copy.
+    use winapi::um::winuser::{COPYDATASTRUCT, WM_COPYDATA};
+    let mut empty = COPYDATASTRUCT {
+      dwData: 0x4857_4c50,
+      cbData: 8,
+      lpData: std::ptr::null_mut(),
+    };
+    assert_eq!(0, unsafe { SendMessageW(hwndloop.hwnd().0, WM_COPYDATA, 0, 0) });
+    assert_eq!(0, unsafe { SendMessageW(hwndloop.hwnd().0, WM_COPYDATA, 0, &mut empty as *mut _ as LPARAM) });
+    hwndloop::ipc::send_command(hwndloop.hwnd(), &IpcCommand::Push(4), timeout).unwrap();
+    assert_eq!(4, rx.recv().unwrap());
+  }
+
+  #[cfg(feature = "toast")]
+  #[test]
+  fn toast_failure() {
+    // WinRT is initialized for the first toast, which then fails to load its content, and that's
+    // reported instead of taking the loop down.
+    let hwndloop = HwndLoop::new(Box::new(Test::new()));
+    let toast = hwndloop::toast::ToastBuilder::new("hwndloop.test").xml("<toast");
+    match hwndloop.show_toast(toast) {
+      Err(Error::Os(_)) => {}
+      result => panic!("unexpected result: {:?}", result),
+    }
+    hwndloop.flush().unwrap();
+  }
+
+  #[cfg(feature = "dde")]
+  struct DdeTest(Sender<(String, String)>);
+
+  #[cfg(feature = "dde")]
+  impl HwndLoopCallbacks<TestCommand> for DdeTest {
+    fn handle_dde_execute(&mut self, _hwnd: HWND, topic: &str, command: &str) -> bool {
+      let _ = self.0.send((topic.to_string(), command.to_string()));
+      command.starts_with("[open(")
+    }
+  }
+
+  #[cfg(feature = "dde")]
+  #[test]
+  fn dde() {
+    let (tx, rx) = channel();
+    let application = format!("hwndloop-test-{}", std::process::id());
+    let _hwndloop = HwndLoopBuilder::new()
+      .dde_server(&application, &["System", "Files"])
+      .build(Box::new(DdeTest(tx)));
+    let timeout = std::time::Duration::from_secs(10);
+
+    hwndloop::dde::execute(&application, "files", "[open(\"a.txt\")]", timeout).unwrap();
+    assert_eq!(("Files".to_string(), "[open(\"a.txt\")]".to_string()), rx.recv().unwrap());
+
+    match hwndloop::dde::execute(&application, "System", "[close()]", timeout) {
+      Err(Error::Rejected) => {}
+      result => panic!("unexpected result: {:?}", result),
+    }
+    assert!(matches!(
+      hwndloop::dde::execute(&application, "Printers", "[open()]", timeout),
+      Err(Error::Os(_))
+    ));
+  }
 }
@@ -0,0 +1,128 @@
+#![cfg(all(windows, feature = "fault-injection"))]
+
+extern crate hwndloop;
+extern crate winapi;
+
+#[cfg(test)]
+mod test {
+  use hwndloop::fault::{self, Faults};
+  use hwndloop::*;
+
+  use std::sync::mpsc::{channel, Sender};
+  use std::sync::{Arc, Mutex, Once};
+  use std::time::Duration;
+
+  use winapi::shared::windef::HWND;
+  use winapi::um::winuser::WM_NULL;
+
+  #[derive(Debug)]
+  enum StressCommand {
+    Record(usize, usize),
+    Count(Sender<usize>),
+  }
+
+  struct Stress(Arc<Mutex<Vec<(usize, usize)>>>);
+
+  impl HwndLoopCallbacks<StressCommand> for Stress {
+    fn handle_command(&mut self, _hwnd: HWND, cmd: StressCommand) {
+      match cmd {
+        StressCommand::Record(thread, i) => self.0.lock().unwrap().push((thread, i)),
+        StressCommand::Count(tx) => tx.send(self.0.lock().unwrap().len()).unwrap(),
+      }
+    }
+  }
+
+  /// Every test in this suite runs with the same faults injected.
+  fn inject() {
+    static INJECT: Once = Once::new();
+    INJECT.call_once(|| {
+      fault::inject(
+        Faults {
+          delay_probability: 0.01,
+          max_delay: Duration::from_millis(5),
+          post_failure_probability: 0.2,
+        },
+        0x1234_5678,
+      )
+    });
+  }
+
+  #[test]
+  fn ordering_under_post_failures() {
+    inject();
+    let records = Arc::new(Mutex::new(Vec::new()));
+    let hwndloop = HwndLoop::new(Box::new(Stress(records.clone())));
+    let threads = 4;
+    let count = 2000;
+
+    let senders: Vec<_> = (0..threads)
+      .map(|thread| {
+        let handle = hwndloop.handle();
+        std::thread::spawn(move || {
+          for i in 0..count {
+            // Sends can only fail if the wake event can't be set.
+            handle.send_command(StressCommand::Record(thread, i)).unwrap();
+            if i % 100 == 0 {
+              handle.flush().unwrap();
+            }
+          }
+          handle.flush().unwrap();
+        })
+      })
+      .collect();
+    for sender in senders {
+      sender.join().unwrap();
+    }
+
+    let records = records.lock().unwrap();
+    assert_eq!(threads * count, records.len());
+    for thread in 0..threads {
+      let mine: Vec<usize> = records.iter().filter(|r| r.0 == thread).map(|r| r.1).collect();
+      assert_eq!((0..count).collect::<Vec<_>>(), mine);
+    }
+  }
+
+  #[test]
+  fn message_storm() {
+    inject();
+    let hwndloop = HwndLoop::new(Box::new(Stress(Arc::new(Mutex::new(Vec::new())))));
+    let handle = hwndloop.handle();
+    let storm = std::thread::spawn(move || fault::message_storm(handle.hwnd(), WM_NULL, 50000));
+
+    for i in 0..1000 {
+      hwndloop.send_command(StressCommand::Record(0, i)).unwrap();
+    }
+    assert!(storm.join().unwrap() > 0);
+    hwndloop.flush_all().unwrap();
+
+    let (tx, rx) = channel();
+    hwndloop.send_command(StressCommand::Count(tx)).unwrap();
+    assert_eq!(1000, rx.recv().unwrap());
+  }
+
+  #[test]
+  fn flush_while_terminating() {
+    inject();
+    for _ in 0..20 {
+      let hwndloop = HwndLoop::new(Box::new(Stress(Arc::new(Mutex::new(Vec::new())))));
+      let flushers: Vec<_> = (0..8)
+        .map(|_| {
+          let handle = hwndloop.handle();
+          std::thread::spawn(move || loop {
+            match handle.flush() {
+              Ok(()) => {}
+              Err(Error::Terminated) => return,
+              Err(err) => panic!("unexpected flush failure: {}", err),
+            }
+          })
+        })
+        .collect();
+
+      std::thread::sleep(Duration::from_millis(5));
+      drop(hwndloop);
+      for flusher in flushers {
+        flusher.join().unwrap();
+      }
+    }
+  }
+}
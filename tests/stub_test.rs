@@ -0,0 +1,69 @@
+#![cfg(not(windows))]
+
+extern crate hwndloop;
+
+#[cfg(test)]
+mod test {
+  use std::time::Duration;
+
+  use hwndloop::*;
+
+  struct Test;
+  impl HwndLoopCallbacks<()> for Test {}
+
+  #[test]
+  fn unsupported_platform() {
+    match HwndLoopBuilder::new().try_build(Box::new(Test)) {
+      Err(Error::UnsupportedPlatform) => {}
+      Err(err) => panic!("unexpected error: {}", err),
+      Ok(_) => panic!("created a loop on an unsupported platform"),
+    }
+    match HwndPump::new(Box::new(Test)) {
+      Err(Error::UnsupportedPlatform) => {}
+      Err(err) => panic!("unexpected error: {}", err),
+      Ok(_) => panic!("created a pump on an unsupported platform"),
+    }
+    assert!(std::panic::catch_unwind(|| HwndLoop::new(Box::new(Test))).is_err());
+    match request_shutdown() {
+      Err(Error::NotOnLoopThread) => {}
+      result => panic!("unexpected result: {:?}", result),
+    }
+    match set_exit_value(1) {
+      Err(Error::NotOnLoopThread) => {}
+      result => panic!("unexpected result: {:?}", result),
+    }
+  }
+
+  #[test]
+  fn stand_ins() {
+    let builder = HwndLoopBuilder::new()
+      .events::<i32>()
+      .events_with::<u32>(Backpressure::DropOldest(1))
+      .on_start(|_hwnd| {})
+      .profile(true)
+      .spy(16)
+      .idle_threshold(Duration::from_secs(60));
+    match builder.build_external(Box::new(Test)) {
+      Err(Error::UnsupportedPlatform) => {}
+      Err(err) => panic!("unexpected error: {}", err),
+      Ok(_) => panic!("created an adapter on an unsupported platform"),
+    }
+
+    // None of these can be reached, but they have to keep compiling.
+    let _ = |handle: LoopHandle<()>| -> Result<()> {
+      handle.set_slot(0, 1)?;
+      handle.clear_slot(0)?;
+      handle.on_teardown(|_hwnd| {})?;
+      handle.once(0, |_hwnd, _msg, _w, _l| {})?;
+      handle.expect_message(0)?.wait(Duration::from_secs(1))?;
+      handle.wait_for_message(0, Duration::from_secs(1))?;
+      let receiver = handle.event_receiver::<i32>().or_else(|| handle.subscribe()).unwrap();
+      receiver.set_backpressure(Backpressure::Block(1));
+      handle.subscribe_with::<i32>(Backpressure::Unbounded);
+      receiver.recv_timeout(Duration::from_secs(1)).map(|_| ())
+    };
+    let _ = |handle: LoopHandle<()>| handle.profile_snapshot(10).messages.len();
+    let _ = |handle: LoopHandle<()>| handle.spy_log().iter().any(|record| record.origin == spy::MessageOrigin::Posted);
+    let _ = |context: &SetUpContext<()>| context.event_emitter::<i32>().unwrap().emit(1);
+  }
+}